@@ -0,0 +1,22 @@
+/* A/B layout shared with the firmware, see `../memory.x` */
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  FLASH            : ORIGIN = 0x08000000, LENGTH = 64K
+  BOOTLOADER_STATE : ORIGIN = 0x08010000, LENGTH = 64K
+  ACTIVE           : ORIGIN = 0x08020000, LENGTH = 1920K
+  DFU              : ORIGIN = 0x08200000, LENGTH = 1928K
+  SETTINGS         : ORIGIN = 0x083E2000, LENGTH = 8K
+  KEYMAP           : ORIGIN = 0x083E4000, LENGTH = 8K
+  RAM              : ORIGIN = 0x20000000, LENGTH = 2496K
+}
+
+/* Offsets from the start of the flash, used by the bootloader */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::entry;
+use embassy_boot_stm32::{
+    AlignedBuffer, BlockingFirmwareUpdater, BootLoader, BootLoaderConfig, FirmwareUpdaterConfig,
+    State,
+};
+use embassy_stm32::{
+    bind_interrupts,
+    flash::{Flash, FLASH_BASE, WRITE_SIZE},
+    peripherals::USB_OTG_HS,
+    usb::{self, Driver},
+    Config,
+};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_usb::Builder;
+use embassy_usb_dfu::{consts::DfuAttributes, usb_dfu, Control, ResetImmediate};
+
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    OTG_HS => usb::InterruptHandler<USB_OTG_HS>;
+});
+
+/// Size in bytes of the chunks swapped between the active and DFU partitions,
+/// which is a flash page.
+const PAGE_SIZE: usize = 8192;
+/// Size in bytes of the blocks downloaded over DFU.
+const DFU_BLOCK_SIZE: usize = 4096;
+
+/// USB vendor ID, which must match the one of the firmware for DFU tools to
+/// find the device once it is detached.
+const USB_VID: u16 = 0xc0de;
+/// USB product ID, which must match the one of the firmware.
+const USB_PID: u16 = 0xcafe;
+
+#[entry]
+fn main() -> ! {
+    // Same system and USB clocks as the firmware, which also halves the AHB
+    // clock for the RNG and enables the LSI, neither needed here
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        use embassy_stm32::time::Hertz;
+
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(16_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll1 = Some(Pll {
+            source: PllSource::HSE,    // 16 MHz
+            prediv: PllPreDiv::DIV2,   // source / 2 = 8MHz
+            mul: PllMul::MUL60,        // 8MHz * 60 = 480MHz
+            divr: Some(PllDiv::DIV3),  // 480MHz / 3 = 160MHz (sys_ck)
+            divq: Some(PllDiv::DIV10), // 480MHz / 10 = 48MHz (USB)
+            divp: Some(PllDiv::DIV15), // 480MHz / 15 = 32MHz (USBOTG)
+        });
+        config.rcc.sys = Sysclk::PLL1_R;
+        config.rcc.voltage_range = VoltageScale::RANGE1;
+        config.rcc.mux.iclksel = mux::Iclksel::PLL1_Q;
+        config.rcc.mux.otghssel = mux::Otghssel::PLL1_P;
+    }
+    let p = embassy_stm32::init(config);
+
+    let flash = Mutex::new(RefCell::new(Flash::new_blocking(p.FLASH)));
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    let active_offset = config.active.offset();
+
+    // Swaps the partitions after an update, or back if the new firmware did
+    // not mark itself as booted
+    let bootloader = BootLoader::prepare::<_, _, _, PAGE_SIZE>(config);
+    defmt::info!("BOOT | State: {:?}", bootloader.state);
+
+    // The firmware asked to be updated, so receive the new one over USB
+    if bootloader.state == State::DfuDetach {
+        let mut ep_out_buffer = [0; 256];
+        let mut hal_config = usb::Config::default();
+        hal_config.vbus_detection = false;
+        let driver = Driver::new_hs(
+            p.USB_OTG_HS,
+            Irqs,
+            p.PA12,
+            p.PA11,
+            &mut ep_out_buffer,
+            hal_config,
+        );
+
+        let mut config = embassy_usb::Config::new(USB_VID, USB_PID);
+        config.manufacturer = Some("etiennecollin");
+        config.product = Some("wave-rs bootloader");
+
+        let updater_config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut magic = AlignedBuffer([0; WRITE_SIZE]);
+        let updater = BlockingFirmwareUpdater::new(updater_config, &mut magic.0);
+        let mut state = Control::new(updater, DfuAttributes::CAN_DOWNLOAD);
+
+        let mut config_descriptor = [0; 256];
+        let mut bos_descriptor = [0; 256];
+        let mut control_buf = [0; DFU_BLOCK_SIZE];
+        let mut builder = Builder::new(
+            driver,
+            config,
+            &mut config_descriptor,
+            &mut bos_descriptor,
+            &mut [],
+            &mut control_buf,
+        );
+        usb_dfu::<_, _, _, ResetImmediate, DFU_BLOCK_SIZE>(&mut builder, &mut state);
+
+        // Resets once the download is complete
+        let mut usb = builder.build();
+        embassy_futures::block_on(usb.run());
+    }
+
+    unsafe { bootloader.load(FLASH_BASE as u32 + active_offset) }
+}
@@ -0,0 +1,114 @@
+//! Runs the unit tests of the firmware on the computer.
+//!
+//! The firmware only builds for the keyboard, so this crate compiles the
+//! modules that do not touch the hardware from their source files, next to a
+//! configuration for the tests and stand-ins for the hardware modules they
+//! import.
+
+pub mod config {
+    use usbd_human_interface_device::page::Keyboard;
+
+    use crate::keyboard::{
+        action::{k, KeyAction::Single},
+        layers::{Layer, Layers},
+    };
+
+    pub mod keymap {
+        use embassy_time::Duration;
+
+        use crate::keyboard::{combo::Combo, leds::HostLed};
+
+        pub const TAPPING_TERM: Duration = Duration::from_millis(200);
+        pub const TAP_DANCE_TERM: Duration = Duration::from_millis(200);
+        pub const ONE_SHOT_TIMEOUT: Duration = Duration::from_millis(1000);
+        pub const COMBO_TIMEOUT: Duration = Duration::from_millis(50);
+        pub const COMBOS: &[Combo] = &[];
+        pub const MACRO_STEP_DELAY: Duration = Duration::from_millis(5);
+        pub const LED_LAYERS: &[(HostLed, usize)] = &[(HostLed::NumLock, 1)];
+    }
+
+    pub mod send_string {
+        use embassy_time::Duration;
+
+        use crate::keyboard::host_layout::HostLayout;
+
+        pub const HOST_LAYOUT: HostLayout = HostLayout::Us;
+        pub const TYPING_DELAY: Duration = Duration::from_millis(5);
+    }
+
+    pub mod unicode {
+        use crate::keyboard::unicode::UnicodeMode;
+
+        pub const UNICODE_MODE: UnicodeMode = UnicodeMode::Linux;
+    }
+
+    pub mod midi {
+        pub const MIDI_CHANNEL: u8 = 0;
+    }
+
+    pub const NKRO_MAX_KEYS: usize = 10;
+    pub const NUMBER_LAYERS: usize = 1;
+
+    pub const MATRIX_COLUMNS_NUMBER: usize = 5;
+    pub const MATRIX_ROWS_NUMBER: usize = 4;
+
+    pub const LAYOUT: Layers<NUMBER_LAYERS, MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER> =
+        Layers::new([Layer::new(
+            [[Single(k(Keyboard::A)); MATRIX_COLUMNS_NUMBER]; MATRIX_ROWS_NUMBER],
+        )]);
+}
+
+#[path = "../../src/keyboard"]
+pub mod keyboard {
+    pub mod action;
+    pub mod combo;
+    pub mod debounce;
+    pub mod event;
+    pub mod hold_tap;
+    pub mod host_layout;
+    pub mod keymap;
+    pub mod layers;
+    pub mod leds;
+    pub mod macros;
+    pub mod midi;
+    pub mod mouse_keys;
+    pub mod report;
+    pub mod send_string;
+    pub mod settings;
+    pub mod tap_dance;
+    pub mod unicode;
+
+    /// Stand-in for the DMA scan, which only provides the type of the samples.
+    pub mod dma {
+        pub type LinkedListWord = u32;
+    }
+}
+
+#[path = "../../src/usb"]
+pub mod usb {
+    pub mod disk_files;
+    pub mod fat;
+    pub mod raw_hid;
+    pub mod shell;
+    pub mod via;
+
+    pub const HID_RAW_REPORT_SIZE: usize = 32;
+    pub const SHELL_LINE_SIZE: usize = 64;
+    pub const SHELL_HISTORY_SIZE: usize = 8;
+}
+
+/// Drops the logs of the firmware, which is built without `defmt-rtt`.
+#[defmt::global_logger]
+struct NoLogger;
+
+unsafe impl defmt::Logger for NoLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
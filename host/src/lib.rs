@@ -0,0 +1,167 @@
+use std::{fmt, io, time::Duration};
+
+use serialport::SerialPort;
+use wave_rs_protocol::{
+    encode, CobsAccumulator, Event, FeedResult, Info, Message, Request, RequestBody, ResponseBody,
+    FRAME_DELIMITER, MAX_FRAME_SIZE, PROTOCOL_VERSION,
+};
+
+pub use wave_rs_protocol as protocol;
+
+/// Time after which a request without a response fails.
+pub const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Error of a [`Client`].
+#[derive(Debug)]
+pub enum Error {
+    Serial(serialport::Error),
+    Io(io::Error),
+    /// The request could not be encoded.
+    Encoding(protocol::EncodingError),
+    /// The keyboard answered the request with an error.
+    Request(protocol::Error),
+    /// The keyboard uses another version of the protocol.
+    UnsupportedVersion(u16),
+    /// The keyboard answered with a response of another kind.
+    UnexpectedResponse(Box<ResponseBody>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Serial(e) => write!(f, "serial port: {}", e),
+            Error::Io(e) => write!(f, "serial port: {}", e),
+            Error::Encoding(e) => write!(f, "encoding: {}", e),
+            Error::Request(e) => write!(f, "keyboard: {}", e),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "the keyboard uses version {} of the protocol, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            Error::UnexpectedResponse(response) => write!(f, "unexpected response {:?}", response),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Client of the protocol of the keyboard, over its serial port.
+///
+/// Requests are sent one at a time. Events received while waiting for a
+/// response are kept until [`Client::next_event`] is called.
+pub struct Client {
+    port: Box<dyn SerialPort>,
+    accumulator: CobsAccumulator<MAX_FRAME_SIZE>,
+    buf: [u8; MAX_FRAME_SIZE],
+    /// Range of `buf` received but not fed to the accumulator yet.
+    pending: (usize, usize),
+    next_id: u32,
+    events: Vec<Event>,
+}
+
+impl Client {
+    /// Opens the serial port of the keyboard and switches it from the shell to
+    /// the protocol.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let port = serialport::new(path, 115_200).timeout(TIMEOUT).open()?;
+        let mut client = Self {
+            port,
+            accumulator: CobsAccumulator::new(),
+            buf: [0; MAX_FRAME_SIZE],
+            pending: (0, 0),
+            next_id: 1,
+            events: Vec::new(),
+        };
+        client.port.write_all(&[FRAME_DELIMITER])?;
+        client.info()?;
+        Ok(client)
+    }
+
+    /// Sends a request and waits for its response.
+    pub fn request(&mut self, body: RequestBody) -> Result<ResponseBody, Error> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        let mut frame = [0; MAX_FRAME_SIZE];
+        let frame = encode(&Request::new(id, body), &mut frame).map_err(Error::Encoding)?;
+        self.port.write_all(frame)?;
+
+        loop {
+            match self.read_message()? {
+                Message::Response(response) if response.version != PROTOCOL_VERSION => {
+                    return Err(Error::UnsupportedVersion(response.version));
+                }
+                Message::Response(response) if response.id == id => {
+                    return response.result.map_err(Error::Request);
+                }
+                // Answer to a request that timed out
+                Message::Response(_) => {}
+                Message::Event(event) => self.events.push(event),
+            }
+        }
+    }
+
+    /// Returns the information about the keyboard.
+    pub fn info(&mut self) -> Result<Info, Error> {
+        match self.request(RequestBody::GetInfo)? {
+            ResponseBody::Info(info) => Ok(info),
+            response => Err(Error::UnexpectedResponse(Box::new(response))),
+        }
+    }
+
+    /// Waits for the next event, such as a key event once subscribed with
+    /// [`RequestBody::SubscribeKeyEvents`].
+    ///
+    /// Fails with [`io::ErrorKind::TimedOut`] if no event is received within
+    /// [`TIMEOUT`].
+    pub fn next_event(&mut self) -> Result<Event, Error> {
+        if !self.events.is_empty() {
+            return Ok(self.events.remove(0));
+        }
+        loop {
+            if let Message::Event(event) = self.read_message()? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Reads the next message of the keyboard.
+    ///
+    /// Frames that cannot be decoded, such as the output of the shell before
+    /// the switch to the protocol, are skipped.
+    fn read_message(&mut self) -> Result<Message, Error> {
+        loop {
+            let (start, end) = self.pending;
+            if start == end {
+                let n = self.port.read(&mut self.buf)?;
+                self.pending = (0, n);
+                continue;
+            }
+
+            let (message, remaining) = match self.accumulator.feed::<Message>(&self.buf[start..end])
+            {
+                FeedResult::Consumed => (None, 0),
+                FeedResult::OverFull(remaining) | FeedResult::DeserError(remaining) => {
+                    (None, remaining.len())
+                }
+                FeedResult::Success { data, remaining } => (Some(data), remaining.len()),
+            };
+            self.pending = (end - remaining, end);
+            if let Some(message) = message {
+                return Ok(message);
+            }
+        }
+    }
+}
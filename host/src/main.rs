@@ -0,0 +1,282 @@
+use std::{io, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use wave_rs_host::{
+    protocol::{
+        Event, HostLayout, KeyPosition, LayerState, RequestBody, ResponseBody, Settings,
+        UnicodeMode, MAX_SEND_STRING_SIZE,
+    },
+    Client, Error,
+};
+
+/// Configures a wave-rs keyboard over its serial port.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Serial port of the keyboard, such as /dev/ttyACM0 or COM3.
+    #[arg(short, long)]
+    port: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Shows the firmware and the size of the keymap.
+    Info,
+    /// Shows the VIA keycode of a key.
+    GetKey { layer: u8, row: u8, col: u8 },
+    /// Sets the VIA keycode of a key, in decimal or 0x-prefixed hexadecimal.
+    SetKey {
+        layer: u8,
+        row: u8,
+        col: u8,
+        #[arg(value_parser = parse_keycode)]
+        keycode: u16,
+    },
+    /// Shows the active layers.
+    Layers,
+    /// Sets the active layers as a bitmask, and the default layer.
+    SetLayers { active: u32, default_layer: u8 },
+    /// Shows the settings, or changes the given ones.
+    Settings {
+        #[arg(long)]
+        tapping_term_ms: Option<u32>,
+        #[arg(long)]
+        tap_dance_term_ms: Option<u32>,
+        #[arg(long)]
+        one_shot_timeout_ms: Option<u32>,
+        #[arg(long)]
+        combo_timeout_ms: Option<u32>,
+        /// Keyboard layout of the host: us, uk, fr, de or dvorak.
+        #[arg(long, value_parser = parse_host_layout)]
+        host_layout: Option<HostLayout>,
+        #[arg(long)]
+        typing_delay_ms: Option<u32>,
+        /// How the host types Unicode: linux, macos, wincompose or windows.
+        #[arg(long, value_parser = parse_unicode_mode)]
+        unicode_mode: Option<UnicodeMode>,
+    },
+    /// Shows the counters and the keys held down.
+    Diagnostics,
+    /// Prints every key press and release until interrupted.
+    Watch,
+    /// Types a text on the host, with the host layout of the settings.
+    Send {
+        #[arg(value_parser = parse_text)]
+        text: String,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    let mut client = Client::open(&cli.port)?;
+    match cli.command {
+        Command::Info => {
+            let info = client.info()?;
+            println!("Firmware: wave-rs {}", info.firmware_version);
+            println!("Protocol: {}", info.protocol_version);
+            println!(
+                "Keymap: {} layers, {} rows, {} columns",
+                info.layers, info.rows, info.columns
+            );
+        }
+        Command::GetKey { layer, row, col } => {
+            let key = KeyPosition { layer, row, col };
+            match client.request(RequestBody::GetKey(key))? {
+                ResponseBody::Key(_, keycode) => println!("{:#06x}", keycode),
+                response => return Err(Error::UnexpectedResponse(Box::new(response))),
+            }
+        }
+        Command::SetKey {
+            layer,
+            row,
+            col,
+            keycode,
+        } => {
+            let key = KeyPosition { layer, row, col };
+            client.request(RequestBody::SetKey(key, keycode))?;
+        }
+        Command::Layers => {
+            let state = client.request(RequestBody::GetLayerState)?;
+            print_layers(state)?;
+        }
+        Command::SetLayers {
+            active,
+            default_layer,
+        } => {
+            let state = LayerState {
+                active,
+                default_layer,
+            };
+            let state = client.request(RequestBody::SetLayerState(state))?;
+            print_layers(state)?;
+        }
+        Command::Settings {
+            tapping_term_ms,
+            tap_dance_term_ms,
+            one_shot_timeout_ms,
+            combo_timeout_ms,
+            host_layout,
+            typing_delay_ms,
+            unicode_mode,
+        } => {
+            let mut settings = match client.request(RequestBody::GetSettings)? {
+                ResponseBody::Settings(settings) => settings,
+                response => return Err(Error::UnexpectedResponse(Box::new(response))),
+            };
+            let changed = tapping_term_ms.is_some()
+                || tap_dance_term_ms.is_some()
+                || one_shot_timeout_ms.is_some()
+                || combo_timeout_ms.is_some()
+                || host_layout.is_some()
+                || typing_delay_ms.is_some()
+                || unicode_mode.is_some();
+            if changed {
+                settings = Settings {
+                    tapping_term_ms: tapping_term_ms.unwrap_or(settings.tapping_term_ms),
+                    tap_dance_term_ms: tap_dance_term_ms.unwrap_or(settings.tap_dance_term_ms),
+                    one_shot_timeout_ms: one_shot_timeout_ms
+                        .unwrap_or(settings.one_shot_timeout_ms),
+                    combo_timeout_ms: combo_timeout_ms.unwrap_or(settings.combo_timeout_ms),
+                    host_layout: host_layout.unwrap_or(settings.host_layout),
+                    typing_delay_ms: typing_delay_ms.unwrap_or(settings.typing_delay_ms),
+                    unicode_mode: unicode_mode.unwrap_or(settings.unicode_mode),
+                };
+                client.request(RequestBody::SetSettings(settings))?;
+            }
+            println!("Tapping term: {} ms", settings.tapping_term_ms);
+            println!("Tap dance term: {} ms", settings.tap_dance_term_ms);
+            println!("One-shot timeout: {} ms", settings.one_shot_timeout_ms);
+            println!("Combo timeout: {} ms", settings.combo_timeout_ms);
+            println!("Host layout: {}", host_layout_name(settings.host_layout));
+            println!("Typing delay: {} ms", settings.typing_delay_ms);
+            println!("Unicode mode: {}", unicode_mode_name(settings.unicode_mode));
+        }
+        Command::Diagnostics => {
+            let diagnostics = match client.request(RequestBody::GetDiagnostics)? {
+                ResponseBody::Diagnostics(diagnostics) => diagnostics,
+                response => return Err(Error::UnexpectedResponse(Box::new(response))),
+            };
+            println!("Uptime: {} ms", diagnostics.uptime_ms);
+            println!("Scans: {}", diagnostics.scans);
+            println!("Key presses: {}", diagnostics.key_presses);
+            println!("Keyboard reports: {}", diagnostics.keyboard_reports);
+            for (col, rows) in diagnostics.matrix.iter().enumerate() {
+                println!("Column {}: {:#034b}", col, rows);
+            }
+        }
+        Command::Watch => {
+            client.request(RequestBody::SubscribeKeyEvents)?;
+            loop {
+                match client.next_event() {
+                    Ok(Event::Key(event)) => println!(
+                        "{:>12} us  ({}, {}) {}",
+                        event.at_us,
+                        event.row,
+                        event.col,
+                        if event.pressed { "pressed" } else { "released" }
+                    ),
+                    Ok(Event::Lagged(count)) => println!("{} events dropped", count),
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Command::Send { text } => {
+            let text = text
+                .as_str()
+                .try_into()
+                .expect("length checked by parse_text");
+            client.request(RequestBody::SendString(text))?;
+        }
+    }
+    Ok(())
+}
+
+fn print_layers(state: ResponseBody) -> Result<(), Error> {
+    let ResponseBody::LayerState(state) = state else {
+        return Err(Error::UnexpectedResponse(Box::new(state)));
+    };
+    for layer in 0..u32::BITS {
+        if state.active & (1 << layer) != 0 {
+            let default = if layer == state.default_layer as u32 {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("Layer {}: active{}", layer, default);
+        }
+    }
+    Ok(())
+}
+
+fn parse_keycode(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn parse_text(s: &str) -> Result<String, String> {
+    if s.len() > MAX_SEND_STRING_SIZE {
+        return Err(format!("longer than {} bytes", MAX_SEND_STRING_SIZE));
+    }
+    Ok(s.to_string())
+}
+
+const HOST_LAYOUTS: [(&str, HostLayout); 5] = [
+    ("us", HostLayout::Us),
+    ("uk", HostLayout::Uk),
+    ("fr", HostLayout::FrenchAzerty),
+    ("de", HostLayout::GermanQwertz),
+    ("dvorak", HostLayout::Dvorak),
+];
+
+fn parse_host_layout(s: &str) -> Result<HostLayout, String> {
+    HOST_LAYOUTS
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, layout)| *layout)
+        .ok_or_else(|| String::from("expected us, uk, fr, de or dvorak"))
+}
+
+fn host_layout_name(layout: HostLayout) -> &'static str {
+    HOST_LAYOUTS
+        .iter()
+        .find(|(_, l)| *l == layout)
+        .map_or("unknown", |(name, _)| name)
+}
+
+const UNICODE_MODES: [(&str, UnicodeMode); 4] = [
+    ("linux", UnicodeMode::Linux),
+    ("macos", UnicodeMode::MacOs),
+    ("wincompose", UnicodeMode::WinCompose),
+    ("windows", UnicodeMode::WindowsAltCode),
+];
+
+fn parse_unicode_mode(s: &str) -> Result<UnicodeMode, String> {
+    UNICODE_MODES
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, mode)| *mode)
+        .ok_or_else(|| String::from("expected linux, macos, wincompose or windows"))
+}
+
+fn unicode_mode_name(mode: UnicodeMode) -> &'static str {
+    UNICODE_MODES
+        .iter()
+        .find(|(_, m)| *m == mode)
+        .map_or("unknown", |(name, _)| name)
+}
@@ -0,0 +1,26 @@
+/* A/B layout shared with the bootloader, see `bootloader/memory.x` */
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  BOOTLOADER       : ORIGIN = 0x08000000, LENGTH = 64K
+  BOOTLOADER_STATE : ORIGIN = 0x08010000, LENGTH = 64K
+  FLASH            : ORIGIN = 0x08020000, LENGTH = 1920K
+  DFU              : ORIGIN = 0x08200000, LENGTH = 1928K
+  SETTINGS         : ORIGIN = 0x083E2000, LENGTH = 8K
+  KEYMAP           : ORIGIN = 0x083E4000, LENGTH = 8K
+  RAM              : ORIGIN = 0x20000000, LENGTH = 2496K
+}
+
+/* Offsets from the start of the flash, used by the firmware updater */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOTLOADER);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOTLOADER);
+
+/* Offsets of the settings and keymap saved by the firmware, see `src/keyboard/storage.rs` */
+__settings_start = ORIGIN(SETTINGS) - ORIGIN(BOOTLOADER);
+__settings_end = ORIGIN(SETTINGS) + LENGTH(SETTINGS) - ORIGIN(BOOTLOADER);
+
+__keymap_start = ORIGIN(KEYMAP) - ORIGIN(BOOTLOADER);
+__keymap_end = ORIGIN(KEYMAP) + LENGTH(KEYMAP) - ORIGIN(BOOTLOADER);
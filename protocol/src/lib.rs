@@ -0,0 +1,384 @@
+#![no_std]
+
+use core::fmt;
+
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+pub use postcard::{
+    accumulator::{CobsAccumulator, FeedResult},
+    Error as EncodingError,
+};
+
+/// Version of the protocol, bumped on every change of the messages that breaks
+/// older hosts or firmwares.
+///
+/// Variants added at the end of an enum do not break the protocol: a firmware
+/// that does not know a request answers it with [`Error::Malformed`].
+pub const PROTOCOL_VERSION: u16 = 3;
+/// Byte ending every frame, which never appears inside a COBS frame.
+///
+/// A terminal never sends it either, so the firmware switches the serial port
+/// from the shell to this protocol when it receives it.
+pub const FRAME_DELIMITER: u8 = 0x00;
+/// Maximum size in bytes of a frame, delimiter included.
+pub const MAX_FRAME_SIZE: usize = 256;
+/// Maximum length of the firmware version in [`Info`].
+pub const FIRMWARE_VERSION_SIZE: usize = 16;
+/// Maximum number of columns of the matrix in [`Diagnostics`].
+pub const MAX_MATRIX_COLUMNS: usize = 32;
+/// Maximum size in bytes of the text of [`RequestBody::SendString`].
+pub const MAX_SEND_STRING_SIZE: usize = 128;
+
+/// A request of the host, answered by a [`Response`] with the same `id`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    /// Version of the protocol used by the host.
+    pub version: u16,
+    /// Identifier chosen by the host to match the response.
+    pub id: u32,
+    pub body: RequestBody,
+}
+
+impl Request {
+    pub const fn new(id: u32, body: RequestBody) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            body,
+        }
+    }
+}
+
+/// The fields every [`Request`] starts with.
+///
+/// They are decoded on their own when the body of a request cannot be, so the
+/// [`Error::Malformed`] response still has the `id` of the request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RequestHeader {
+    pub version: u16,
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RequestBody {
+    /// Returns the [`Info`] of the keyboard.
+    GetInfo,
+    /// Returns the keycode of a key of the live keymap.
+    GetKey(KeyPosition),
+    /// Sets the keycode of a key of the live keymap.
+    SetKey(KeyPosition, Keycode),
+    /// Returns the [`LayerState`].
+    GetLayerState,
+    /// Sets the [`LayerState`] and returns it.
+    SetLayerState(LayerState),
+    /// Returns the [`Settings`].
+    GetSettings,
+    /// Sets the [`Settings`] and returns them.
+    SetSettings(Settings),
+    /// Returns the [`Diagnostics`].
+    GetDiagnostics,
+    /// Sends an [`Event::Key`] for every key press and release until
+    /// [`RequestBody::UnsubscribeKeyEvents`] or the port is closed.
+    SubscribeKeyEvents,
+    /// Stops sending key events.
+    UnsubscribeKeyEvents,
+    /// Types a text on the host, with the host layout of the [`Settings`].
+    SendString(String<MAX_SEND_STRING_SIZE>),
+}
+
+/// A message sent by the firmware.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    Response(Response),
+    Event(Event),
+}
+
+/// The response to a [`Request`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    /// Version of the protocol used by the firmware.
+    pub version: u16,
+    /// Identifier of the request, or `0` if not even the [`RequestHeader`] of
+    /// the request could be decoded.
+    pub id: u32,
+    pub result: Result<ResponseBody, Error>,
+}
+
+impl Response {
+    pub const fn new(id: u32, result: Result<ResponseBody, Error>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            result,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ResponseBody {
+    Info(Info),
+    Key(KeyPosition, Keycode),
+    LayerState(LayerState),
+    Settings(Settings),
+    Diagnostics(Diagnostics),
+    /// The request succeeded and has nothing to return.
+    Done,
+}
+
+/// An event sent by the firmware without being requested.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    Key(KeyEvent),
+    /// Key events were dropped because the host did not read them fast enough.
+    Lagged(u64),
+}
+
+/// The reason a request failed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Error {
+    /// The host uses another version of the protocol.
+    UnsupportedVersion,
+    /// The request could not be decoded.
+    Malformed,
+    /// The layer, row or column does not exist.
+    OutOfRange,
+    /// The keycode cannot be set, such as a layer key to a layer that does not
+    /// exist.
+    UnsupportedKeycode,
+    /// The firmware cannot serve the request right now, such as a subscription
+    /// when every subscriber to the key events is in use.
+    Busy,
+    /// The host layout cannot type this character.
+    UnsupportedCharacter(char),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            Error::Malformed => write!(f, "malformed request"),
+            Error::OutOfRange => write!(f, "out of range"),
+            Error::UnsupportedKeycode => write!(f, "unsupported keycode"),
+            Error::Busy => write!(f, "busy"),
+            Error::UnsupportedCharacter(c) => {
+                write!(f, "the host layout cannot type {:?}", c)
+            }
+        }
+    }
+}
+
+/// A keycode of the VIA protocol.
+///
+/// Keys only defined in the firmware, such as tap dances and macros, are
+/// `0x7E00`.
+pub type Keycode = u16;
+
+/// A key of the keymap.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyPosition {
+    pub layer: u8,
+    pub row: u8,
+    pub col: u8,
+}
+
+/// Static information about the keyboard.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Info {
+    pub protocol_version: u16,
+    pub firmware_version: String<FIRMWARE_VERSION_SIZE>,
+    pub layers: u8,
+    pub rows: u8,
+    pub columns: u8,
+}
+
+/// The layers of the keymap that are in use.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LayerState {
+    /// Bitmask of the active layers, which includes the default layer.
+    pub active: u32,
+    pub default_layer: u8,
+}
+
+/// Settings of the keyboard that can be changed without flashing a firmware.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    /// Time in milliseconds after which an undecided hold-tap key resolves to
+    /// a hold.
+    pub tapping_term_ms: u32,
+    /// Time in milliseconds after which a tap dance key stops counting taps.
+    pub tap_dance_term_ms: u32,
+    /// Time in milliseconds after which a tapped one-shot modifier is dropped.
+    pub one_shot_timeout_ms: u32,
+    /// Time window in milliseconds in which all the keys of a combo must be
+    /// pressed.
+    pub combo_timeout_ms: u32,
+    /// Keyboard layout selected on the host, used to type sent strings.
+    pub host_layout: HostLayout,
+    /// Delay in milliseconds after each key press and release of a sent
+    /// string.
+    pub typing_delay_ms: u32,
+    /// How the host lets the keyboard type characters from their code point.
+    pub unicode_mode: UnicodeMode,
+}
+
+/// Keyboard layout selected on the host, which decides the keys pressed to
+/// type each character.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum HostLayout {
+    Us,
+    Uk,
+    FrenchAzerty,
+    GermanQwertz,
+    Dvorak,
+}
+
+/// How the host lets the keyboard type characters from their code point.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum UnicodeMode {
+    /// Ctrl+Shift+U with IBus.
+    Linux,
+    /// Unicode Hex Input.
+    MacOs,
+    WinCompose,
+    /// Alt and the keypad, with `EnableHexNumpad`.
+    WindowsAltCode,
+}
+
+/// Counters and live state of the keyboard.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    pub uptime_ms: u64,
+    /// Number of scans of the matrix since boot.
+    pub scans: u32,
+    /// Number of key presses since boot.
+    pub key_presses: u32,
+    /// Number of keyboard reports sent to the host since boot.
+    pub keyboard_reports: u32,
+    /// Bitmask of the rows held down in each column, after debouncing.
+    pub matrix: Vec<u32, MAX_MATRIX_COLUMNS>,
+}
+
+/// A key of the matrix that went down or up.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+    /// Time since boot in microseconds at which the change was detected.
+    pub at_us: u64,
+}
+
+/// Encodes a message into a COBS frame, delimiter included.
+pub fn encode<'a, T: Serialize>(
+    message: &T,
+    buf: &'a mut [u8],
+) -> Result<&'a mut [u8], EncodingError> {
+    postcard::to_slice_cobs(message, buf)
+}
+
+/// Decodes a COBS frame, which is modified in place.
+pub fn decode<'a, T: Deserialize<'a>>(frame: &'a mut [u8]) -> Result<T, EncodingError> {
+    postcard::from_bytes_cobs(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let request = Request::new(
+            7,
+            RequestBody::SetKey(
+                KeyPosition {
+                    layer: 1,
+                    row: 2,
+                    col: 3,
+                },
+                0x0004,
+            ),
+        );
+        let frame = encode(&request, &mut buf).unwrap();
+        assert_eq!(frame.last(), Some(&FRAME_DELIMITER));
+        assert!(!frame[..frame.len() - 1].contains(&FRAME_DELIMITER));
+        assert_eq!(decode::<Request>(frame).unwrap(), request);
+    }
+
+    #[test]
+    fn largest_message_fits() {
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let message = Message::Response(Response::new(
+            u32::MAX,
+            Ok(ResponseBody::Diagnostics(Diagnostics {
+                uptime_ms: u64::MAX,
+                scans: u32::MAX,
+                key_presses: u32::MAX,
+                keyboard_reports: u32::MAX,
+                matrix: Vec::from_slice(&[u32::MAX; MAX_MATRIX_COLUMNS]).unwrap(),
+            })),
+        ));
+        assert!(encode(&message, &mut buf).is_ok());
+
+        let mut text = String::new();
+        while text.push('~').is_ok() {}
+        let request = Request::new(u32::MAX, RequestBody::SendString(text));
+        assert!(encode(&request, &mut buf).is_ok());
+    }
+
+    #[test]
+    fn accumulator() {
+        let mut stream = [0; 2 * MAX_FRAME_SIZE];
+        let first = Request::new(1, RequestBody::GetInfo);
+        let second = Request::new(2, RequestBody::SubscribeKeyEvents);
+        let len = encode(&first, &mut stream).unwrap().len();
+        let len = len + encode(&second, &mut stream[len..]).unwrap().len();
+
+        // Frames split across packets are reassembled
+        let mut accumulator = CobsAccumulator::<MAX_FRAME_SIZE>::new();
+        let mut requests = [None, None];
+        let mut count = 0;
+        for packet in stream[..len].chunks(3) {
+            let mut window = packet;
+            while !window.is_empty() {
+                window = match accumulator.feed::<Request>(window) {
+                    FeedResult::Consumed => break,
+                    FeedResult::Success { data, remaining } => {
+                        requests[count] = Some(data);
+                        count += 1;
+                        remaining
+                    }
+                    _ => panic!("invalid frame"),
+                };
+            }
+        }
+        assert_eq!(requests, [Some(first), Some(second)]);
+    }
+
+    #[test]
+    fn header() {
+        // A request with a body unknown to the firmware, such as a variant
+        // added later
+        let mut frame = [0; MAX_FRAME_SIZE];
+        let len = encode(&(PROTOCOL_VERSION, 7u32, 0x7Fu8), &mut frame)
+            .unwrap()
+            .len();
+
+        let mut accumulator = CobsAccumulator::<MAX_FRAME_SIZE>::new();
+        assert!(matches!(
+            accumulator.feed::<Request>(&frame[..len]),
+            FeedResult::DeserError(_)
+        ));
+        match accumulator.feed::<RequestHeader>(&frame[..len]) {
+            FeedResult::Success { data, .. } => assert_eq!(
+                data,
+                RequestHeader {
+                    version: PROTOCOL_VERSION,
+                    id: 7
+                }
+            ),
+            _ => panic!("invalid header"),
+        }
+    }
+}
@@ -18,6 +18,9 @@ pub mod scan {
     ///
     /// The effective timer frequency is `FREQUENCY` * [`MATRIX_COLUMNS_NUMBER`](super::MATRIX_COLUMNS_NUMBER).
     pub const FREQUENCY: Hertz = Hertz(4000);
+    /// The frequency at which the full matrix is scanned while the host is
+    /// suspended.
+    pub const SUSPENDED_FREQUENCY: Hertz = Hertz(100);
     /// The maximum value for the counter compare register.
     pub const CC_MAX: u32 = 1000;
     /// The counter compare value at which the DMA set the column GPIO pins.
@@ -26,6 +29,153 @@ pub mod scan {
     pub const CC_2: u32 = 1000;
 }
 
+/// Debouncing configuration
+pub mod debounce {
+    use embassy_time::Duration;
+
+    use crate::keyboard::debounce::DebounceAlgorithm;
+
+    /// The debouncing algorithm applied to every key of the matrix.
+    pub const DEBOUNCE_ALGORITHM: DebounceAlgorithm = DebounceAlgorithm::Eager;
+    /// The time a key must be stable (deferred) or is ignored after a change
+    /// (eager).
+    pub const DEBOUNCE_TIME: Duration = Duration::from_millis(5);
+}
+
+/// Keymap configuration
+pub mod keymap {
+    use embassy_time::Duration;
+
+    use crate::keyboard::{combo::Combo, leds::HostLed};
+
+    /// The time after which an undecided hold-tap key resolves to a hold.
+    pub const TAPPING_TERM: Duration = Duration::from_millis(200);
+    /// The time after which a tap dance key stops counting taps, and within
+    /// which pressing a tapped repeat key again holds its tap action.
+    pub const TAP_DANCE_TERM: Duration = Duration::from_millis(200);
+    /// The time after which a tapped one-shot modifier is dropped if no other
+    /// key was pressed.
+    pub const ONE_SHOT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+    /// The time window in which all the keys of a combo must be pressed.
+    pub const COMBO_TIMEOUT: Duration = Duration::from_millis(50);
+    /// The combos of the keymap, as positions `(row, col)` of the matrix.
+    ///
+    /// For example, `Combo::new(&[(3, 0), (3, 1)], Single(k(Keyboard::Escape)))`
+    /// sends Escape when the first two keys of the last row are pressed
+    /// together.
+    pub const COMBOS: &[Combo] = &[];
+
+    /// The delay after each key press and release of a macro, so the host
+    /// receives every report.
+    pub const MACRO_STEP_DELAY: Duration = Duration::from_millis(5);
+
+    /// The layers active while a host LED is on, such as a numpad layer
+    /// following Num Lock.
+    pub const LED_LAYERS: &[(HostLed, usize)] = &[];
+}
+
+/// Host LED indicators configuration
+pub mod leds {
+    use crate::keyboard::leds::HostLed;
+
+    /// Number of indicator pins showing the host LEDs.
+    pub const LED_INDICATORS_NUMBER: usize = 3;
+    /// The host LED shown by each indicator pin.
+    pub const LED_INDICATORS: [HostLed; LED_INDICATORS_NUMBER] =
+        [HostLed::NumLock, HostLed::CapsLock, HostLed::ScrollLock];
+}
+
+/// Send string configuration
+pub mod send_string {
+    use embassy_time::Duration;
+
+    use crate::keyboard::host_layout::HostLayout;
+
+    /// The keyboard layout selected on the host, which decides the keys typing
+    /// the strings sent by host tools.
+    pub const HOST_LAYOUT: HostLayout = HostLayout::Us;
+    /// The delay after each key press and release of a sent string, which sets
+    /// the typing rate.
+    pub const TYPING_DELAY: Duration = Duration::from_millis(5);
+}
+
+/// Unicode input configuration
+pub mod unicode {
+    use crate::keyboard::unicode::UnicodeMode;
+
+    /// How the host lets the keyboard type characters from their code point,
+    /// until another mode is saved in the settings.
+    pub const UNICODE_MODE: UnicodeMode = UnicodeMode::Linux;
+}
+
+/// Settings and keymap storage configuration
+pub mod settings {
+    use embassy_time::Duration;
+
+    /// The time the settings must stay unchanged before they are saved in
+    /// flash, so a burst of changes only erases the flash once.
+    pub const SETTINGS_SAVE_DELAY: Duration = Duration::from_secs(2);
+    /// The time the keymap edited by host tools must stay unchanged before it
+    /// is saved in flash, as they set the keys one request at a time.
+    pub const KEYMAP_SAVE_DELAY: Duration = Duration::from_secs(2);
+}
+
+/// MIDI configuration
+pub mod midi {
+    /// The channel of the MIDI messages sent by the keys, from 0 to 15.
+    pub const MIDI_CHANNEL: u8 = 0;
+}
+
+/// Mass storage configuration
+pub mod msc {
+    /// Whether the keyboard shows up as a small disk holding its keymap and
+    /// settings as text files.
+    ///
+    /// The disk takes one of the IN endpoints of the USB peripheral, see
+    /// [`USB_USED_IN_ENDPOINTS`](crate::usb::USB_USED_IN_ENDPOINTS).
+    pub const MSC_ENABLED: bool = true;
+}
+
+/// WebUSB configuration
+pub mod webusb {
+    /// Whether the keyboard has a vendor interface that browser configurators
+    /// can claim with WebUSB, which speaks the protocol of the raw HID
+    /// interface.
+    ///
+    /// The interface takes one of the IN endpoints of the USB peripheral, see
+    /// [`USB_USED_IN_ENDPOINTS`](crate::usb::USB_USED_IN_ENDPOINTS).
+    pub const WEBUSB_ENABLED: bool = true;
+}
+
+pub mod mouse {
+    use embassy_time::Duration;
+
+    use crate::keyboard::mouse_keys::{Acceleration, AccelerationCurve};
+
+    /// The acceleration of the mouse movement keys.
+    pub const MOUSE_MOVEMENT: Acceleration = Acceleration {
+        interval: Duration::from_millis(16),
+        initial: 1,
+        max: 20,
+        time_to_max: Duration::from_millis(1000),
+        curve: AccelerationCurve::Quadratic,
+    };
+    /// The acceleration of the mouse scroll keys.
+    pub const MOUSE_SCROLL: Acceleration = Acceleration {
+        interval: Duration::from_millis(80),
+        initial: 1,
+        max: 4,
+        time_to_max: Duration::from_millis(2000),
+        curve: AccelerationCurve::Linear,
+    };
+    /// The speeds the speed keys step through, in percent of the accelerated
+    /// speed.
+    pub const MOUSE_SPEEDS: &[u8] = &[25, 50, 100, 200, 400];
+    /// The index in [`MOUSE_SPEEDS`] of the speed at startup.
+    pub const MOUSE_DEFAULT_SPEED: usize = 2;
+}
+
 pub const NKRO_MAX_KEYS: usize = 10;
 pub const NUMBER_LAYERS: usize = 1;
 
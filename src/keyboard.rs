@@ -1,6 +1,22 @@
 pub mod action;
+pub mod combo;
 pub mod debounce;
 pub mod dma;
+pub mod event;
+pub mod hold_tap;
+pub mod host_layout;
+pub mod indicators;
+pub mod keymap;
 pub mod layers;
+pub mod leds;
+pub mod macros;
+pub mod midi;
 pub mod mouse;
+pub mod mouse_keys;
+pub mod report;
 pub mod scan;
+pub mod send_string;
+pub mod settings;
+pub mod storage;
+pub mod tap_dance;
+pub mod unicode;
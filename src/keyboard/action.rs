@@ -1,5 +1,6 @@
-use cortex_m::singleton;
-use usbd_human_interface_device::page::Keyboard;
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use super::macros::Macro;
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -19,11 +20,61 @@ pub enum Mouse {
     SpeedDown,
 }
 
+/// Generic Desktop system control usages.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(u8)]
+pub enum System {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    /// Wakes the host up, also through USB remote wakeup.
+    WakeUp = 0x83,
+}
+
+/// MIDI messages and controls of the MIDI notes.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Midi {
+    /// Plays a note while the key is held.
+    ///
+    /// The note is shifted by the octave and the transposition, see
+    /// [`MidiState`](super::midi::MidiState).
+    NoteOn {
+        note: u8,
+        velocity: u8,
+    },
+    /// Sends a control change when pressed.
+    ControlChange {
+        controller: u8,
+        value: u8,
+    },
+    /// Selects a program when pressed.
+    ProgramChange(u8),
+    OctaveUp,
+    OctaveDown,
+    /// Shifts the notes up by a semitone.
+    TransposeUp,
+    /// Shifts the notes down by a semitone.
+    TransposeDown,
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Action {
     Mouse(Mouse),
     Keyboard(Keyboard),
+    /// A media or application control key (usage page 0x0C).
+    Consumer(Consumer),
+    /// A system power control key.
+    System(System),
+    /// Types a character from its code point, with the Unicode mode of the
+    /// settings.
+    Unicode(char),
+    /// Types a string from the code points of its characters, like
+    /// [`Action::Unicode`].
+    UnicodeString(&'static str),
+    /// Sends MIDI messages on the USB MIDI interface.
+    Midi(Midi),
 }
 
 /// Shortcut for creating a mouse action.
@@ -36,15 +87,149 @@ pub const fn k(key: Keyboard) -> Action {
     Action::Keyboard(key)
 }
 
+/// Shortcut for creating a system control action.
+pub const fn s(key: System) -> Action {
+    Action::System(key)
+}
+
+/// Shortcut for creating a consumer control action.
+pub const fn c(key: Consumer) -> Action {
+    Action::Consumer(key)
+}
+
+/// Shortcut for creating a MIDI action.
+pub const fn midi(midi: Midi) -> Action {
+    Action::Midi(midi)
+}
+
+/// Shortcut for creating a Unicode character action.
+pub const fn uc(c: char) -> Action {
+    Action::Unicode(c)
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum KeyAction {
     NoOp,
     Transparent,
     Single(Action),
+    /// Activates a layer while the key is held (MO).
     Layer(usize),
+    /// Toggles a layer on and off (TG).
+    ToggleLayer(usize),
+    /// Activates a layer and deactivates every other layer except the default
+    /// layer (TO).
+    ToLayer(usize),
+    /// Activates a layer for the next key press only (OSL).
+    ///
+    /// Holding the key acts like [`KeyAction::Layer`].
+    OneShotLayer(usize),
+    /// Activates a layer while held and sends an action when tapped (LT).
+    LayerTap(LayerTapAction),
+    /// Sets the default layer.
     DefaultLayer(usize),
     HoldTap(HoldTapAction),
+    /// Sends different actions depending on how many times the key is tapped,
+    /// and on whether it is held after the taps.
+    TapDance(TapDanceAction),
+    /// Plays a sequence of steps when pressed.
+    Macro(Macro),
+    /// Plays the macro at this index of the dynamic macros when pressed.
+    DynamicMacro(u8),
+    /// Applies a modifier to the next key only (OSM).
+    ///
+    /// Holding the key acts like a normal modifier, and tapping it twice locks
+    /// the modifier until it is tapped again. Keys that are not modifiers act
+    /// like a normal key.
+    OneShot(Keyboard),
+}
+
+impl KeyAction {
+    /// Checks if the key can send a mouse action.
+    pub fn has_mouse_action(&self) -> bool {
+        self.has_action(|action| matches!(action, Action::Mouse(_)))
+    }
+
+    /// Checks if the key can send a MIDI action.
+    pub fn has_midi_action(&self) -> bool {
+        self.has_action(|action| matches!(action, Action::Midi(_)))
+    }
+
+    /// Checks if any action the key can send matches `f`.
+    fn has_action(&self, f: impl Fn(&Action) -> bool) -> bool {
+        match self {
+            KeyAction::Single(action) => f(action),
+            KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)) => {
+                f(&action.hold) || f(&action.tap)
+            }
+            KeyAction::LayerTap(action) => f(&action.tap),
+            KeyAction::TapDance(TapDanceAction::Table(dance)) => {
+                dance.taps.iter().chain(dance.holds).any(f)
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks if the key only acts on the layer state when pressed.
+    pub const fn is_layer_key(&self) -> bool {
+        matches!(
+            self,
+            KeyAction::Layer(_)
+                | KeyAction::ToggleLayer(_)
+                | KeyAction::ToLayer(_)
+                | KeyAction::OneShotLayer(_)
+                | KeyAction::DefaultLayer(_)
+        )
+    }
+}
+
+/// Shortcut for creating a momentary layer key.
+pub const fn mo(layer: usize) -> KeyAction {
+    KeyAction::Layer(layer)
+}
+
+/// Shortcut for creating a toggle layer key.
+pub const fn tg(layer: usize) -> KeyAction {
+    KeyAction::ToggleLayer(layer)
+}
+
+/// Shortcut for creating a to layer key.
+pub const fn to(layer: usize) -> KeyAction {
+    KeyAction::ToLayer(layer)
+}
+
+/// Shortcut for creating a one-shot layer key.
+pub const fn osl(layer: usize) -> KeyAction {
+    KeyAction::OneShotLayer(layer)
+}
+
+/// Shortcut for creating a one-shot modifier key.
+pub const fn osm(modifier: Keyboard) -> KeyAction {
+    KeyAction::OneShot(modifier)
+}
+
+/// Shortcut for creating a layer-tap key with the default hold-tap behavior.
+pub const fn lt(layer: usize, tap: Action) -> KeyAction {
+    KeyAction::LayerTap(LayerTapAction {
+        layer,
+        tap,
+        config: HoldTapConfig::Default,
+    })
+}
+
+/// Shortcut for creating a tap dance key from a table.
+pub const fn td(dance: &'static TapDance) -> KeyAction {
+    KeyAction::TapDance(TapDanceAction::Table(dance))
+}
+
+/// Shortcut for creating a hold-tap key that repeats its tap action when
+/// tapped then held.
+pub const fn repeat(hold: Action, tap: Action) -> KeyAction {
+    KeyAction::TapDance(TapDanceAction::Repeat(HoldTapAction {
+        hold,
+        tap,
+        config: HoldTapConfig::Default,
+    }))
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -62,3 +247,61 @@ pub struct HoldTapAction {
     pub tap: Action,
     pub config: HoldTapConfig,
 }
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LayerTapAction {
+    pub layer: usize,
+    pub tap: Action,
+    pub config: HoldTapConfig,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TapDanceAction {
+    /// Looks up the actions in a tap dance table.
+    Table(&'static TapDance),
+    /// Acts as a hold-tap key, except that tapping then holding the key holds
+    /// its tap action, which the host then auto-repeats.
+    Repeat(HoldTapAction),
+}
+
+/// Tap dance table.
+///
+/// Tapping the key `n` times sends `taps[n - 1]`, and holding the key after
+/// `n` taps holds `holds[n]`.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TapDance {
+    /// Actions sent when the key is tapped once, twice, and so on.
+    pub taps: &'static [Action],
+    /// Actions held when the key is held after zero, one, and so on taps.
+    pub holds: &'static [Action],
+}
+
+impl TapDance {
+    /// Returns the action sent when the key is tapped `count` times.
+    ///
+    /// Counts past the end of the table use its last tap action.
+    pub fn tap(&self, count: usize) -> Option<Action> {
+        let index = count.checked_sub(1)?;
+        self.taps.get(index).or(self.taps.last()).copied()
+    }
+
+    /// Returns the action held when the key is held after `count` taps.
+    ///
+    /// Without a hold action for `count`, the tap action of `count + 1` taps is
+    /// held instead.
+    pub fn hold(&self, count: usize) -> Option<Action> {
+        self.holds
+            .get(count)
+            .copied()
+            .or_else(|| self.tap(count + 1))
+    }
+
+    /// Returns the number of taps after which tapping again cannot change the
+    /// outcome.
+    pub fn max_taps(&self) -> usize {
+        self.taps.len().max(self.holds.len()).max(1)
+    }
+}
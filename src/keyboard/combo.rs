@@ -0,0 +1,394 @@
+use core::cmp::Reverse;
+
+use defmt::warn;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use super::{action::KeyAction, event::KeyEvent};
+
+/// Row of the virtual keys used to represent combos.
+///
+/// A combo is pressed and released as the key `(COMBO_ROW, index)`, where
+/// `index` is its position in the combo table.
+pub const COMBO_ROW: u8 = u8::MAX;
+/// Maximum number of keys in a combo.
+pub const COMBO_MAX_KEYS: usize = 8;
+/// Maximum number of combos held at the same time.
+pub const COMBO_MAX_ACTIVE: usize = 4;
+/// Maximum number of events output by the combo engine for a single input.
+pub const COMBO_OUTPUT_SIZE: usize = COMBO_MAX_KEYS + 2;
+
+/// Events output by the combo engine.
+pub type ComboOutput = Vec<KeyEvent, COMBO_OUTPUT_SIZE>;
+
+/// A set of matrix positions that, pressed together, act as another key.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Combo {
+    /// Positions `(row, col)` of the keys of the combo.
+    pub keys: &'static [(u8, u8)],
+    pub action: KeyAction,
+    /// Bitmask of the layers on which the combo is available, or `None` if it
+    /// is available on every layer.
+    pub layers: Option<u32>,
+}
+
+impl Combo {
+    pub const fn new(keys: &'static [(u8, u8)], action: KeyAction) -> Self {
+        assert!(keys.len() <= COMBO_MAX_KEYS, "Too many keys in combo");
+        Self {
+            keys,
+            action,
+            layers: None,
+        }
+    }
+
+    /// Restricts the combo to the layers of the `layers` bitmask.
+    pub const fn on_layers(mut self, layers: u32) -> Self {
+        self.layers = Some(layers);
+        self
+    }
+
+    fn contains(&self, row: u8, col: u8) -> bool {
+        self.keys.contains(&(row, col))
+    }
+
+    fn is_available(&self, layer_state: u32) -> bool {
+        self.keys.len() > 1 && self.layers.is_none_or(|layers| layers & layer_state != 0)
+    }
+}
+
+/// A combo that was triggered and whose keys are not all released yet.
+#[derive(Debug, Copy, Clone)]
+struct ActiveCombo {
+    index: u8,
+    /// Bitmask of the keys of the combo still held.
+    held: u8,
+}
+
+/// Combo detection stage of the keymap.
+///
+/// Presses of keys that belong to a combo are held back until they either
+/// complete a combo, or can no longer be part of one. When several combos
+/// match, the longest one wins. Keys that do not end up in a combo are output
+/// in their original order.
+pub struct ComboEngine {
+    combos: &'static [Combo],
+    timeout: Duration,
+    /// Layer state when the first pending key was pressed.
+    layer_state: u32,
+    /// Presses waiting to be matched against the combos.
+    pending: Vec<KeyEvent, COMBO_MAX_KEYS>,
+    active: Vec<ActiveCombo, COMBO_MAX_ACTIVE>,
+}
+
+impl ComboEngine {
+    pub const fn new(combos: &'static [Combo], timeout: Duration) -> Self {
+        assert!(combos.len() <= COMBO_ROW as usize, "Too many combos");
+        Self {
+            combos,
+            timeout,
+            layer_state: 0,
+            pending: Vec::new(),
+            active: Vec::new(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the combo triggered by the virtual key `(COMBO_ROW, col)`.
+    pub fn get(&self, col: u8) -> Option<&Combo> {
+        self.combos.get(col as usize)
+    }
+
+    /// Returns when the pending keys stop waiting for a combo, if any is
+    /// pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.first().map(|first| first.at + self.timeout)
+    }
+
+    /// Processes a key event of the matrix with the current `layer_state`.
+    pub fn process(&mut self, event: KeyEvent, layer_state: u32) -> ComboOutput {
+        let mut output = ComboOutput::new();
+        if event.pressed {
+            self.press(event, layer_state, &mut output);
+        } else {
+            self.release(event, &mut output);
+        }
+        output
+    }
+
+    /// Resolves the pending keys if their combo window expired at `now`.
+    pub fn tick(&mut self, now: Instant) -> ComboOutput {
+        let mut output = ComboOutput::new();
+        if self.next_deadline().is_some_and(|deadline| now >= deadline) {
+            self.resolve(&mut output);
+        }
+        output
+    }
+
+    /// Drops the pending keys and the active combos.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.active.clear();
+    }
+
+    fn press(&mut self, event: KeyEvent, layer_state: u32, output: &mut ComboOutput) {
+        if !self.pending.is_empty() {
+            if self.is_candidate(&event) {
+                let _ = self.pending.push(event);
+                if self.is_complete() {
+                    self.resolve(output);
+                }
+                return;
+            }
+            // The new key cannot be part of a combo with the pending keys
+            self.resolve(output);
+        }
+
+        self.layer_state = layer_state;
+        if self.is_candidate(&event) {
+            let _ = self.pending.push(event);
+        } else {
+            let _ = output.push(event);
+        }
+    }
+
+    fn release(&mut self, event: KeyEvent, output: &mut ComboOutput) {
+        if self
+            .pending
+            .iter()
+            .any(|e| (e.row, e.col) == (event.row, event.col))
+        {
+            self.resolve(output);
+        }
+
+        for i in 0..self.active.len() {
+            let active = &mut self.active[i];
+            let combo = &self.combos[active.index as usize];
+            let Some(position) = combo.keys.iter().position(|&k| k == (event.row, event.col))
+            else {
+                continue;
+            };
+
+            // The combo is released with its first key
+            let was_held = active.held == mask(combo.keys.len());
+            active.held &= !(1 << position);
+            if was_held {
+                let _ = output.push(KeyEvent::release(COMBO_ROW, active.index, event.at));
+            }
+            if active.held == 0 {
+                self.active.swap_remove(i);
+            }
+            return;
+        }
+
+        let _ = output.push(event);
+    }
+
+    /// Returns the combos available for the pending keys.
+    fn available(&self) -> impl Iterator<Item = (usize, &Combo)> {
+        let layer_state = self.layer_state;
+        self.combos
+            .iter()
+            .enumerate()
+            .filter(move |(_, combo)| combo.is_available(layer_state))
+    }
+
+    /// Checks if a combo contains every pending key and `event`.
+    fn is_candidate(&self, event: &KeyEvent) -> bool {
+        self.available().any(|(_, combo)| {
+            self.pending
+                .iter()
+                .chain(Some(event))
+                .all(|e| combo.contains(e.row, e.col))
+        })
+    }
+
+    /// Checks if the pending keys form a combo and no longer combo could still
+    /// be completed.
+    fn is_complete(&self) -> bool {
+        let mut candidates = self
+            .available()
+            .filter(|(_, combo)| self.pending.iter().all(|e| combo.contains(e.row, e.col)));
+        candidates.all(|(_, combo)| combo.keys.len() == self.pending.len())
+    }
+
+    /// Triggers the longest combo made of pending keys and outputs the other
+    /// pending keys in order.
+    fn resolve(&mut self, output: &mut ComboOutput) {
+        let mut best =
+            self.available()
+                .filter(|(_, combo)| {
+                    combo.keys.iter().all(|&(row, col)| {
+                        self.pending.iter().any(|e| (e.row, e.col) == (row, col))
+                    })
+                })
+                .max_by_key(|&(i, combo)| (combo.keys.len(), Reverse(i)))
+                .map(|(i, combo)| (i, *combo));
+
+        if best.is_some() && self.active.is_full() {
+            warn!("COMBO | Too many active combos");
+            best = None;
+        }
+
+        let mut triggered = false;
+        for event in core::mem::take(&mut self.pending) {
+            match best {
+                Some((index, combo)) if combo.contains(event.row, event.col) => {
+                    // The combo is pressed in place of its first key
+                    if !triggered {
+                        triggered = true;
+                        let _ = output.push(KeyEvent::press(COMBO_ROW, index as u8, event.at));
+                        let _ = self.active.push(ActiveCombo {
+                            index: index as u8,
+                            held: mask(combo.keys.len()),
+                        });
+                    }
+                }
+                _ => {
+                    let _ = output.push(event);
+                }
+            }
+        }
+    }
+}
+
+/// Returns a bitmask with the `len` lowest bits set.
+const fn mask(len: usize) -> u8 {
+    ((1u16 << len) - 1) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::Keyboard;
+
+    use super::*;
+    use crate::keyboard::{action::k, keymap::tests::at};
+
+    const COMBOS: &[Combo] = &[
+        Combo::new(&[(0, 0), (0, 1)], KeyAction::Single(k(Keyboard::A))),
+        Combo::new(&[(0, 0), (0, 1), (0, 2)], KeyAction::Single(k(Keyboard::B))),
+        Combo::new(&[(0, 2), (0, 3)], KeyAction::Single(k(Keyboard::C))).on_layers(0b10),
+    ];
+
+    fn engine() -> ComboEngine {
+        ComboEngine::new(COMBOS, Duration::from_millis(50))
+    }
+
+    fn combo_press(index: u8, ms: u64) -> KeyEvent {
+        KeyEvent::press(COMBO_ROW, index, at(ms))
+    }
+
+    fn combo_release(index: u8, ms: u64) -> KeyEvent {
+        KeyEvent::release(COMBO_ROW, index, at(ms))
+    }
+
+    #[test]
+    fn overlap() {
+        // The keys of the shorter combo wait for the longer one
+        let mut engine = engine();
+        assert_eq!(engine.process(KeyEvent::press(0, 0, at(0)), 1), []);
+        assert_eq!(engine.process(KeyEvent::press(0, 1, at(10)), 1), []);
+        assert_eq!(engine.next_deadline(), Some(at(50)));
+        assert_eq!(engine.tick(at(49)), []);
+        assert_eq!(engine.tick(at(50)), [combo_press(0, 0)]);
+        assert_eq!(
+            engine.process(KeyEvent::release(0, 0, at(60)), 1),
+            [combo_release(0, 60)]
+        );
+        assert_eq!(engine.process(KeyEvent::release(0, 1, at(70)), 1), []);
+
+        // The longest combo triggers as soon as it is complete
+        assert_eq!(engine.process(KeyEvent::press(0, 1, at(100)), 1), []);
+        assert_eq!(engine.process(KeyEvent::press(0, 0, at(110)), 1), []);
+        assert_eq!(
+            engine.process(KeyEvent::press(0, 2, at(120)), 1),
+            [combo_press(1, 100)]
+        );
+        assert_eq!(engine.next_deadline(), None);
+        assert_eq!(
+            engine.process(KeyEvent::release(0, 2, at(130)), 1),
+            [combo_release(1, 130)]
+        );
+        assert_eq!(engine.process(KeyEvent::release(0, 0, at(140)), 1), []);
+        assert_eq!(engine.process(KeyEvent::release(0, 1, at(150)), 1), []);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut engine = engine();
+        engine.process(KeyEvent::press(0, 0, at(0)), 1);
+        assert_eq!(engine.tick(at(50)), [KeyEvent::press(0, 0, at(0))]);
+        assert_eq!(engine.next_deadline(), None);
+        assert_eq!(engine.process(KeyEvent::press(0, 1, at(60)), 1), []);
+        assert_eq!(engine.tick(at(110)), [KeyEvent::press(0, 1, at(60))]);
+
+        engine.set_timeout(Duration::from_millis(20));
+        engine.process(KeyEvent::press(0, 0, at(200)), 1);
+        assert_eq!(engine.next_deadline(), Some(at(220)));
+    }
+
+    #[test]
+    fn not_a_combo() {
+        // Keys that cannot complete a combo are output in order
+        let mut engine = engine();
+        assert_eq!(engine.process(KeyEvent::press(0, 0, at(0)), 1), []);
+        assert_eq!(
+            engine.process(KeyEvent::press(0, 3, at(10)), 1),
+            [KeyEvent::press(0, 0, at(0)), KeyEvent::press(0, 3, at(10))]
+        );
+        assert_eq!(
+            engine.process(KeyEvent::release(0, 0, at(20)), 1),
+            [KeyEvent::release(0, 0, at(20))]
+        );
+        assert_eq!(
+            engine.process(KeyEvent::release(0, 3, at(30)), 1),
+            [KeyEvent::release(0, 3, at(30))]
+        );
+    }
+
+    #[test]
+    fn partial_release() {
+        // Releasing a pending key resolves the combo of the keys pressed so
+        // far, and the combo is released with its first key
+        let mut engine = engine();
+        engine.process(KeyEvent::press(0, 0, at(0)), 1);
+        engine.process(KeyEvent::press(0, 1, at(5)), 1);
+        assert_eq!(
+            engine.process(KeyEvent::release(0, 0, at(10)), 1),
+            [combo_press(0, 0), combo_release(0, 10)]
+        );
+        assert_eq!(engine.process(KeyEvent::release(0, 1, at(20)), 1), []);
+
+        // A lone key released before the timeout is a plain tap
+        engine.process(KeyEvent::press(0, 1, at(100)), 1);
+        assert_eq!(
+            engine.process(KeyEvent::release(0, 1, at(110)), 1),
+            [
+                KeyEvent::press(0, 1, at(100)),
+                KeyEvent::release(0, 1, at(110))
+            ]
+        );
+    }
+
+    #[test]
+    fn layers() {
+        let mut engine = engine();
+        engine.process(KeyEvent::press(0, 2, at(0)), 1);
+        assert_eq!(
+            engine.process(KeyEvent::press(0, 3, at(5)), 1),
+            [KeyEvent::press(0, 2, at(0)), KeyEvent::press(0, 3, at(5))]
+        );
+        engine.process(KeyEvent::release(0, 2, at(10)), 1);
+        engine.process(KeyEvent::release(0, 3, at(15)), 1);
+
+        engine.process(KeyEvent::press(0, 2, at(100)), 0b11);
+        assert_eq!(
+            engine.process(KeyEvent::press(0, 3, at(105)), 0b11),
+            [combo_press(2, 100)]
+        );
+    }
+}
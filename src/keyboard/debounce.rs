@@ -1,8 +1,211 @@
-use embassy_stm32::gpio::AnyPin;
-use embassy_time::Instant;
+use embassy_time::{Duration, Instant};
 
-pub struct Debouncer {
-    pin: AnyPin,
-    is_debounced: bool,
-    timestamp: Instant,
+use super::dma::LinkedListWord;
+
+/// Debouncing algorithm used by the [`Debouncer`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DebounceAlgorithm {
+    /// Report a state change as soon as it is seen, then ignore the key for
+    /// the debounce time.
+    ///
+    /// This has no added latency, but is sensitive to noise on the lines.
+    Eager,
+    /// Report a state change only once the key has been stable for the
+    /// debounce time.
+    ///
+    /// This adds the debounce time as latency, but filters out noise.
+    Deferred,
+}
+
+/// Per-key debouncer for the whole key matrix.
+///
+/// The matrix is represented the same way the DMA reads it: one word per
+/// column, where bit `row` is set when the key at `(row, col)` is pressed.
+/// Each key has its own timer, so a bouncing key never delays another one.
+///
+/// # Generics
+///
+/// - `M`: Number of rows in the matrix.
+/// - `N`: Number of columns in the matrix.
+pub struct Debouncer<const M: usize, const N: usize> {
+    algorithm: DebounceAlgorithm,
+    debounce_time: Duration,
+    /// Debounced state of the matrix.
+    stable: [LinkedListWord; N],
+    /// Last raw sample of the matrix.
+    raw: [LinkedListWord; N],
+    /// Per-key deadline of the running timer, if any.
+    deadlines: [[Option<Instant>; M]; N],
+}
+
+impl<const M: usize, const N: usize> Debouncer<M, N> {
+    pub const fn new(algorithm: DebounceAlgorithm, debounce_time: Duration) -> Self {
+        Self {
+            algorithm,
+            debounce_time,
+            stable: [0; N],
+            raw: [0; N],
+            deadlines: [[None; M]; N],
+        }
+    }
+
+    /// Feeds a raw sample of the matrix taken at `now` and returns the
+    /// debounced state of the matrix.
+    pub fn update(&mut self, sample: &[LinkedListWord; N], now: Instant) -> &[LinkedListWord; N] {
+        for (col, &bits) in sample.iter().enumerate() {
+            for row in 0..M {
+                let mask = 1 << row;
+                let raw = bits & mask;
+                let deadline = &mut self.deadlines[col][row];
+
+                match self.algorithm {
+                    DebounceAlgorithm::Eager => {
+                        // The key is locked until its deadline is reached
+                        if deadline.is_some_and(|d| now < d) {
+                            continue;
+                        }
+                        *deadline = None;
+
+                        if raw != self.stable[col] & mask {
+                            self.stable[col] ^= mask;
+                            *deadline = Some(now + self.debounce_time);
+                        }
+                    }
+                    DebounceAlgorithm::Deferred => {
+                        // Restart the timer on every raw change
+                        if raw != self.raw[col] & mask {
+                            *deadline = Some(now + self.debounce_time);
+                        }
+
+                        if deadline.is_some_and(|d| now >= d) {
+                            *deadline = None;
+                            self.stable[col] = (self.stable[col] & !mask) | raw;
+                        }
+                    }
+                }
+            }
+            self.raw[col] = bits;
+        }
+
+        &self.stable
+    }
+
+    /// Returns the debounced state of the matrix.
+    pub fn state(&self) -> &[LinkedListWord; N] {
+        &self.stable
+    }
+
+    /// Checks if the key at `(row, col)` is pressed after debouncing.
+    pub fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.stable[col] & (1 << row) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds a trace of `(time in ms, raw sample, debounced state)` to a
+    /// debouncer of two rows and one column, with a debounce time of 5 ms.
+    fn check(algorithm: DebounceAlgorithm, trace: &[(u64, LinkedListWord, LinkedListWord)]) {
+        let mut debouncer = Debouncer::<2, 1>::new(algorithm, Duration::from_millis(5));
+        for &(ms, sample, state) in trace {
+            let now = Instant::from_millis(ms);
+            assert_eq!(debouncer.update(&[sample], now), &[state], "at {} ms", ms);
+        }
+    }
+
+    #[test]
+    fn eager_bounces() {
+        check(
+            DebounceAlgorithm::Eager,
+            &[
+                // The press is reported at once, and its bounces ignored
+                (0, 0b01, 0b01),
+                (1, 0b00, 0b01),
+                (2, 0b01, 0b01),
+                (3, 0b00, 0b01),
+                (4, 0b01, 0b01),
+                (10, 0b01, 0b01),
+                // Same for the release
+                (20, 0b00, 0b00),
+                (21, 0b01, 0b00),
+                (24, 0b00, 0b00),
+                (30, 0b00, 0b00),
+            ],
+        );
+    }
+
+    #[test]
+    fn eager_lock() {
+        check(
+            DebounceAlgorithm::Eager,
+            &[
+                // A glitch is reported, then released once the key unlocks
+                (0, 0b01, 0b01),
+                (1, 0b00, 0b01),
+                (4, 0b00, 0b01),
+                (5, 0b00, 0b00),
+                // A tap shorter than the debounce time is kept until then
+                (20, 0b01, 0b01),
+                (22, 0b00, 0b01),
+                (25, 0b00, 0b00),
+            ],
+        );
+    }
+
+    #[test]
+    fn deferred_bounces() {
+        check(
+            DebounceAlgorithm::Deferred,
+            &[
+                // Every bounce restarts the timer of the key
+                (0, 0b01, 0b00),
+                (1, 0b00, 0b00),
+                (2, 0b01, 0b00),
+                (6, 0b01, 0b00),
+                (7, 0b01, 0b01),
+                // Same for the release
+                (20, 0b00, 0b01),
+                (21, 0b01, 0b01),
+                (23, 0b00, 0b01),
+                (27, 0b00, 0b01),
+                (28, 0b00, 0b00),
+            ],
+        );
+    }
+
+    #[test]
+    fn deferred_noise() {
+        check(
+            DebounceAlgorithm::Deferred,
+            &[
+                // Glitches shorter than the debounce time are never reported
+                (0, 0b01, 0b00),
+                (1, 0b00, 0b00),
+                (10, 0b00, 0b00),
+                (20, 0b01, 0b00),
+                (25, 0b01, 0b01),
+                (30, 0b00, 0b01),
+                (34, 0b01, 0b01),
+                (45, 0b01, 0b01),
+            ],
+        );
+    }
+
+    #[test]
+    fn per_key_timers() {
+        for algorithm in [DebounceAlgorithm::Eager, DebounceAlgorithm::Deferred] {
+            let mut debouncer = Debouncer::<2, 2>::new(algorithm, Duration::from_millis(5));
+            // The key of row 0 keeps bouncing while the key of row 1 is pressed
+            for ms in 0..5 {
+                debouncer.update(&[(ms % 2) as LinkedListWord, 0], Instant::from_millis(ms));
+            }
+            debouncer.update(&[0b01, 0b10], Instant::from_millis(5));
+            debouncer.update(&[0b01, 0b10], Instant::from_millis(10));
+            assert!(debouncer.is_pressed(1, 1), "{:?}", algorithm);
+            assert_eq!(debouncer.state(), &[0b01, 0b10], "{:?}", algorithm);
+        }
+    }
 }
@@ -112,12 +112,16 @@ pub fn configure_dma_scan(
 
 pub struct DmaTimer<T: AdvancedInstance4Channel> {
     timer: Timer<'static, T>,
+    /// Compare values of channel 1 and 2, and maximum compare value, as set by
+    /// [`DmaTimer::configure`].
+    compare_values: (u32, u32, u32),
 }
 
 impl<T: AdvancedInstance4Channel> DmaTimer<T> {
     pub fn new(timer: Peri<'static, T>) -> Self {
         Self {
             timer: Timer::new(timer),
+            compare_values: (0, 0, 0),
         }
     }
 
@@ -149,6 +153,8 @@ impl<T: AdvancedInstance4Channel> DmaTimer<T> {
             assert!(compare_value_ch_2 <= compare_value_max);
         }
 
+        self.compare_values = (compare_value_ch_1, compare_value_ch_2, compare_value_max);
+
         // General configuration
         self.timer.set_autoreload_preload(true);
         self.timer.set_max_compare_value(compare_value_max);
@@ -169,6 +175,27 @@ impl<T: AdvancedInstance4Channel> DmaTimer<T> {
         self.timer.set_cc_dma_enable_state(Channel::Ch2, true);
     }
 
+    /// Changes the frequency at which the full matrix is scanned.
+    ///
+    /// Setting the frequency picks a new prescaler and auto-reload value, so
+    /// the compare values of [`DmaTimer::configure`] are scaled to the new
+    /// auto-reload value to keep triggering at the same point of the period.
+    pub fn set_frequency(&mut self, frequency: Hertz) {
+        let (compare_value_ch_1, compare_value_ch_2, compare_value_max) = self.compare_values;
+        self.timer
+            .set_frequency(frequency * MATRIX_COLUMNS_NUMBER as u32);
+
+        let max_compare_value = self.timer.get_max_compare_value();
+        let scale = |compare_value: u32| {
+            (compare_value as u64 * max_compare_value as u64 / compare_value_max.max(1) as u64)
+                as u32
+        };
+        self.timer
+            .set_compare_value(Channel::Ch1, scale(compare_value_ch_1));
+        self.timer
+            .set_compare_value(Channel::Ch2, scale(compare_value_ch_2));
+    }
+
     /// Start the timer.
     pub fn start(&mut self) {
         self.timer.start();
@@ -0,0 +1,97 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::Instant;
+
+use super::dma::LinkedListWord;
+
+/// Maximum number of key events buffered for each subscriber.
+pub const KEY_EVENTS_CAPACITY: usize = 32;
+/// Maximum number of tasks subscribed to the key events.
+pub const KEY_EVENTS_SUBSCRIBERS: usize = 4;
+/// Maximum number of tasks publishing key events.
+pub const KEY_EVENTS_PUBLISHERS: usize = 2;
+
+/// Channel on which every key press and release of the matrix is published.
+pub static KEY_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    KeyEvent,
+    KEY_EVENTS_CAPACITY,
+    KEY_EVENTS_SUBSCRIBERS,
+    KEY_EVENTS_PUBLISHERS,
+> = PubSubChannel::new();
+
+/// A change in the state of a key of the matrix.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    /// Whether the key went down (`true`) or up (`false`).
+    pub pressed: bool,
+    /// When the change was detected.
+    pub at: Instant,
+}
+
+impl KeyEvent {
+    pub const fn press(row: u8, col: u8, at: Instant) -> Self {
+        Self {
+            row,
+            col,
+            pressed: true,
+            at,
+        }
+    }
+
+    pub const fn release(row: u8, col: u8, at: Instant) -> Self {
+        Self {
+            row,
+            col,
+            pressed: false,
+            at,
+        }
+    }
+}
+
+/// Detects key presses and releases between two consecutive scans of the matrix.
+///
+/// # Generics
+///
+/// - `M`: Number of rows in the matrix.
+/// - `N`: Number of columns in the matrix.
+pub struct EdgeDetector<const M: usize, const N: usize> {
+    previous: [LinkedListWord; N],
+}
+
+impl<const M: usize, const N: usize> EdgeDetector<M, N> {
+    pub const fn new() -> Self {
+        Self { previous: [0; N] }
+    }
+
+    /// Compares `current` with the previous scan and returns an event for every
+    /// key whose state changed, ordered by column then row.
+    pub fn update(
+        &mut self,
+        current: &[LinkedListWord; N],
+        at: Instant,
+    ) -> impl Iterator<Item = KeyEvent> {
+        let previous = core::mem::replace(&mut self.previous, *current);
+        let current = *current;
+
+        (0..N).flat_map(move |col| {
+            let changed = previous[col] ^ current[col];
+            (0..M)
+                .filter(move |row| changed & (1 << row) != 0)
+                .map(move |row| KeyEvent {
+                    row: row as u8,
+                    col: col as u8,
+                    pressed: current[col] & (1 << row) != 0,
+                    at,
+                })
+        })
+    }
+}
+
+impl<const M: usize, const N: usize> Default for EdgeDetector<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,208 @@
+use embassy_time::Instant;
+
+use super::{
+    action::{HoldTapConfig, KeyAction, TapDanceAction},
+    event::KeyEvent,
+};
+
+/// Outcome of a hold-tap key.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HoldTapDecision {
+    Hold,
+    Tap,
+}
+
+/// A hold-tap key that was pressed and is waiting to resolve to either its hold
+/// or its tap action.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PendingHoldTap {
+    pub row: u8,
+    pub col: u8,
+    /// The key that was pressed, either a [`KeyAction::HoldTap`], a
+    /// [`KeyAction::LayerTap`] or a [`TapDanceAction::Repeat`].
+    pub action: KeyAction,
+    /// When the tapping term of the key expires.
+    pub deadline: Instant,
+}
+
+impl PendingHoldTap {
+    /// Creates a pending hold-tap key.
+    ///
+    /// Returns `None` if `action` is not a hold-tap key.
+    pub const fn new(row: u8, col: u8, action: KeyAction, deadline: Instant) -> Option<Self> {
+        match action {
+            KeyAction::HoldTap(_)
+            | KeyAction::LayerTap(_)
+            | KeyAction::TapDance(TapDanceAction::Repeat(_)) => Some(Self {
+                row,
+                col,
+                action,
+                deadline,
+            }),
+            _ => None,
+        }
+    }
+
+    pub const fn config(&self) -> HoldTapConfig {
+        match self.action {
+            KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)) => {
+                action.config
+            }
+            KeyAction::LayerTap(action) => action.config,
+            _ => HoldTapConfig::Default,
+        }
+    }
+
+    /// Returns the key the hold-tap key acts as once resolved.
+    pub const fn resolve(&self, decision: HoldTapDecision) -> KeyAction {
+        match (self.action, decision) {
+            (
+                KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)),
+                HoldTapDecision::Hold,
+            ) => KeyAction::Single(action.hold),
+            (
+                KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)),
+                HoldTapDecision::Tap,
+            ) => KeyAction::Single(action.tap),
+            (KeyAction::LayerTap(action), HoldTapDecision::Hold) => KeyAction::Layer(action.layer),
+            (KeyAction::LayerTap(action), HoldTapDecision::Tap) => KeyAction::Single(action.tap),
+            (action, _) => action,
+        }
+    }
+
+    /// Checks if `event` concerns the hold-tap key itself.
+    pub fn is_same_key(&self, event: &KeyEvent) -> bool {
+        (self.row, self.col) == (event.row, event.col)
+    }
+
+    /// Decides the outcome of the key after `event` was received.
+    ///
+    /// `buffered` are the events received since the key was pressed, excluding
+    /// `event`. Returns `None` if the key is still undecided.
+    ///
+    /// The key resolves as follows, depending on its [`HoldTapConfig`]:
+    ///
+    /// - Releasing the key within the tapping term always resolves to a tap.
+    /// - [`HoldTapConfig::Default`]: Other keys have no effect, the key only
+    ///   resolves to a hold once the tapping term expires.
+    /// - [`HoldTapConfig::HoldOnOtherKeyPress`]: Pressing another key resolves
+    ///   to a hold.
+    /// - [`HoldTapConfig::PermissiveHold`]: Pressing and releasing another key
+    ///   resolves to a hold.
+    pub fn decide<'a>(
+        &self,
+        event: &KeyEvent,
+        mut buffered: impl Iterator<Item = &'a KeyEvent>,
+    ) -> Option<HoldTapDecision> {
+        if self.is_same_key(event) {
+            return (!event.pressed).then_some(HoldTapDecision::Tap);
+        }
+
+        match self.config() {
+            HoldTapConfig::Default => None,
+            HoldTapConfig::HoldOnOtherKeyPress => event.pressed.then_some(HoldTapDecision::Hold),
+            HoldTapConfig::PermissiveHold => {
+                let tapped = !event.pressed
+                    && buffered.any(|e| e.pressed && (e.row, e.col) == (event.row, event.col));
+                tapped.then_some(HoldTapDecision::Hold)
+            }
+        }
+    }
+
+    /// Decides the outcome of the key at `now`.
+    ///
+    /// Returns [`HoldTapDecision::Hold`] once the tapping term expired.
+    pub fn decide_at(&self, now: Instant) -> Option<HoldTapDecision> {
+        (now >= self.deadline).then_some(HoldTapDecision::Hold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::Keyboard;
+
+    use super::*;
+    use crate::keyboard::{
+        action::{k, HoldTapAction},
+        keymap::tests::at,
+    };
+
+    /// A hold-tap key at `(0, 0)` pressed at 0 ms, with a tapping term of
+    /// 200 ms.
+    fn pending(config: HoldTapConfig) -> PendingHoldTap {
+        let action = KeyAction::HoldTap(HoldTapAction {
+            hold: k(Keyboard::LeftShift),
+            tap: k(Keyboard::F),
+            config,
+        });
+        PendingHoldTap::new(0, 0, action, at(200)).unwrap()
+    }
+
+    #[test]
+    fn new() {
+        let single = KeyAction::Single(k(Keyboard::A));
+        assert_eq!(PendingHoldTap::new(0, 0, single, at(0)), None);
+    }
+
+    #[test]
+    fn own_release() {
+        for config in [
+            HoldTapConfig::Default,
+            HoldTapConfig::HoldOnOtherKeyPress,
+            HoldTapConfig::PermissiveHold,
+        ] {
+            let key = pending(config);
+            let buffered = [KeyEvent::press(0, 1, at(10))];
+            assert_eq!(
+                key.decide(&KeyEvent::release(0, 0, at(50)), buffered.iter()),
+                Some(HoldTapDecision::Tap)
+            );
+        }
+    }
+
+    #[test]
+    fn other_keys() {
+        let press = KeyEvent::press(0, 1, at(10));
+        let release = KeyEvent::release(0, 1, at(20));
+        let buffered = [press];
+
+        let key = pending(HoldTapConfig::Default);
+        assert_eq!(key.decide(&press, [].iter()), None);
+        assert_eq!(key.decide(&release, buffered.iter()), None);
+
+        let key = pending(HoldTapConfig::HoldOnOtherKeyPress);
+        assert_eq!(key.decide(&press, [].iter()), Some(HoldTapDecision::Hold));
+
+        let key = pending(HoldTapConfig::PermissiveHold);
+        assert_eq!(key.decide(&press, [].iter()), None);
+        assert_eq!(
+            key.decide(&release, buffered.iter()),
+            Some(HoldTapDecision::Hold)
+        );
+        // A key pressed before the hold-tap key is only released
+        let other = KeyEvent::release(0, 2, at(20));
+        assert_eq!(key.decide(&other, buffered.iter()), None);
+    }
+
+    #[test]
+    fn tapping_term() {
+        let key = pending(HoldTapConfig::Default);
+        assert_eq!(key.decide_at(at(199)), None);
+        assert_eq!(key.decide_at(at(200)), Some(HoldTapDecision::Hold));
+    }
+
+    #[test]
+    fn resolve() {
+        let key = pending(HoldTapConfig::Default);
+        assert_eq!(
+            key.resolve(HoldTapDecision::Hold),
+            KeyAction::Single(k(Keyboard::LeftShift))
+        );
+        assert_eq!(
+            key.resolve(HoldTapDecision::Tap),
+            KeyAction::Single(k(Keyboard::F))
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+use super::macros::ascii_to_key;
+
+/// Maximum number of strokes typing a character, which is a dead key followed
+/// by the key it modifies.
+pub const MAX_STROKES: usize = 2;
+
+/// The strokes typing a character.
+pub type Strokes = Vec<Stroke, MAX_STROKES>;
+
+/// Keyboard layout selected on the host, which decides the keys typing each
+/// character.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum HostLayout {
+    #[default]
+    Us,
+    Uk,
+    FrenchAzerty,
+    GermanQwertz,
+    Dvorak,
+}
+
+/// A key tapped with the modifiers it needs.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Stroke {
+    pub key: Keyboard,
+    pub shift: bool,
+    /// Whether the right alt key, AltGr on most layouts, is held.
+    pub altgr: bool,
+}
+
+const fn key(key: Keyboard) -> Stroke {
+    Stroke {
+        key,
+        shift: false,
+        altgr: false,
+    }
+}
+
+const fn shift(key: Keyboard) -> Stroke {
+    Stroke {
+        key,
+        shift: true,
+        altgr: false,
+    }
+}
+
+const fn altgr(key: Keyboard) -> Stroke {
+    Stroke {
+        key,
+        shift: false,
+        altgr: true,
+    }
+}
+
+impl HostLayout {
+    pub const ALL: [HostLayout; 5] = [
+        HostLayout::Us,
+        HostLayout::Uk,
+        HostLayout::FrenchAzerty,
+        HostLayout::GermanQwertz,
+        HostLayout::Dvorak,
+    ];
+
+    /// Short name of the layout, as typed in the shell.
+    pub const fn name(self) -> &'static str {
+        match self {
+            HostLayout::Us => "us",
+            HostLayout::Uk => "uk",
+            HostLayout::FrenchAzerty => "fr",
+            HostLayout::GermanQwertz => "de",
+            HostLayout::Dvorak => "dvorak",
+        }
+    }
+
+    /// Returns the layout with a short name, see [`HostLayout::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    /// Returns the strokes typing a character, or `None` if the layout cannot
+    /// type it.
+    pub fn strokes(self, c: char) -> Option<Strokes> {
+        let mut strokes = Vec::new();
+        match self.stroke(c) {
+            Some(stroke) => strokes.push(stroke).ok()?,
+            None => {
+                let (dead_key, base) = self.dead_key(c)?;
+                strokes.push(dead_key).ok()?;
+                strokes.push(self.stroke(base)?).ok()?;
+            }
+        }
+        Some(strokes)
+    }
+
+    /// Returns the stroke typing a character with a single key.
+    fn stroke(self, c: char) -> Option<Stroke> {
+        match c {
+            '\n' => return Some(key(Keyboard::ReturnEnter)),
+            '\t' => return Some(key(Keyboard::Tab)),
+            ' ' => return Some(key(Keyboard::Space)),
+            _ => {}
+        }
+        match self {
+            HostLayout::Us => us(c),
+            HostLayout::Uk => uk(c),
+            HostLayout::FrenchAzerty => french_azerty(c),
+            HostLayout::GermanQwertz => german_qwertz(c),
+            HostLayout::Dvorak => dvorak(c),
+        }
+    }
+
+    /// Returns the dead key typing a character, followed by the character it
+    /// modifies.
+    fn dead_key(self, c: char) -> Option<(Stroke, char)> {
+        let dead_key = match (self, c) {
+            (HostLayout::FrenchAzerty, '~') => (altgr(Keyboard::Keyboard2), ' '),
+            (HostLayout::FrenchAzerty, '`') => (altgr(Keyboard::Keyboard7), ' '),
+            (HostLayout::FrenchAzerty, 'â' | 'ê' | 'î' | 'ô' | 'û') => {
+                (key(Keyboard::LeftBrace), without_accent(c))
+            }
+            (HostLayout::FrenchAzerty, 'ë' | 'ï' | 'ü') => {
+                (shift(Keyboard::LeftBrace), without_accent(c))
+            }
+            (HostLayout::GermanQwertz, '^') => (key(Keyboard::Grave), ' '),
+            (HostLayout::GermanQwertz, '`') => (shift(Keyboard::Equal), ' '),
+            _ => return None,
+        };
+        Some(dead_key)
+    }
+}
+
+fn without_accent(c: char) -> char {
+    match c {
+        'â' => 'a',
+        'ê' | 'ë' => 'e',
+        'î' | 'ï' => 'i',
+        'ô' => 'o',
+        _ => 'u',
+    }
+}
+
+/// Returns the key `n` usages after `base`.
+fn offset(base: Keyboard, n: u8) -> Keyboard {
+    Keyboard::from(u8::from(base) + n)
+}
+
+/// Returns the stroke typing a letter, with the key of each letter in
+/// `letters`.
+fn letter(c: char, letters: impl Fn(u8) -> Keyboard) -> Option<Stroke> {
+    match c {
+        'a'..='z' => Some(key(letters(c as u8 - b'a'))),
+        'A'..='Z' => Some(shift(letters(c as u8 - b'A'))),
+        _ => None,
+    }
+}
+
+fn us(c: char) -> Option<Stroke> {
+    ascii_to_key(c).map(|(key, shift)| Stroke {
+        key,
+        shift,
+        altgr: false,
+    })
+}
+
+/// The UK layout only moves a few symbols of the US layout.
+fn uk(c: char) -> Option<Stroke> {
+    let stroke = match c {
+        '"' => shift(Keyboard::Keyboard2),
+        '£' => shift(Keyboard::Keyboard3),
+        '€' => altgr(Keyboard::Keyboard4),
+        '@' => shift(Keyboard::Apostrophe),
+        '#' => key(Keyboard::NonUSHash),
+        '~' => shift(Keyboard::NonUSHash),
+        '\\' => key(Keyboard::NonUSBackslash),
+        '|' => shift(Keyboard::NonUSBackslash),
+        '¬' => shift(Keyboard::Grave),
+        _ => return us(c),
+    };
+    Some(stroke)
+}
+
+fn french_azerty(c: char) -> Option<Stroke> {
+    let stroke = match c {
+        'a'..='z' | 'A'..='Z' => {
+            // A and Q, and Z and W are swapped, and M is right of L
+            return letter(c, |n| match b'a' + n {
+                b'a' => Keyboard::Q,
+                b'q' => Keyboard::A,
+                b'z' => Keyboard::W,
+                b'w' => Keyboard::Z,
+                b'm' => Keyboard::Semicolon,
+                _ => offset(Keyboard::A, n),
+            });
+        }
+        // The digits are shifted
+        '1'..='9' => shift(offset(Keyboard::Keyboard1, c as u8 - b'1')),
+        '0' => shift(Keyboard::Keyboard0),
+        '&' => key(Keyboard::Keyboard1),
+        'é' => key(Keyboard::Keyboard2),
+        '"' => key(Keyboard::Keyboard3),
+        '#' => altgr(Keyboard::Keyboard3),
+        '\'' => key(Keyboard::Keyboard4),
+        '{' => altgr(Keyboard::Keyboard4),
+        '(' => key(Keyboard::Keyboard5),
+        '[' => altgr(Keyboard::Keyboard5),
+        '-' => key(Keyboard::Keyboard6),
+        '|' => altgr(Keyboard::Keyboard6),
+        'è' => key(Keyboard::Keyboard7),
+        '_' => key(Keyboard::Keyboard8),
+        '\\' => altgr(Keyboard::Keyboard8),
+        'ç' => key(Keyboard::Keyboard9),
+        '^' => altgr(Keyboard::Keyboard9),
+        'à' => key(Keyboard::Keyboard0),
+        '@' => altgr(Keyboard::Keyboard0),
+        ')' => key(Keyboard::Minus),
+        '°' => shift(Keyboard::Minus),
+        ']' => altgr(Keyboard::Minus),
+        '=' => key(Keyboard::Equal),
+        '+' => shift(Keyboard::Equal),
+        '}' => altgr(Keyboard::Equal),
+        '$' => key(Keyboard::RightBrace),
+        '£' => shift(Keyboard::RightBrace),
+        '¤' => altgr(Keyboard::RightBrace),
+        'ù' => key(Keyboard::Apostrophe),
+        '%' => shift(Keyboard::Apostrophe),
+        '*' => key(Keyboard::NonUSHash),
+        'µ' => shift(Keyboard::NonUSHash),
+        '²' => key(Keyboard::Grave),
+        '<' => key(Keyboard::NonUSBackslash),
+        '>' => shift(Keyboard::NonUSBackslash),
+        ',' => key(Keyboard::M),
+        '?' => shift(Keyboard::M),
+        ';' => key(Keyboard::Comma),
+        '.' => shift(Keyboard::Comma),
+        ':' => key(Keyboard::Dot),
+        '/' => shift(Keyboard::Dot),
+        '!' => key(Keyboard::ForwardSlash),
+        '§' => shift(Keyboard::ForwardSlash),
+        '€' => altgr(Keyboard::E),
+        _ => return None,
+    };
+    Some(stroke)
+}
+
+fn german_qwertz(c: char) -> Option<Stroke> {
+    let stroke = match c {
+        'a'..='z' | 'A'..='Z' => {
+            // Y and Z are swapped
+            return letter(c, |n| match b'a' + n {
+                b'y' => Keyboard::Z,
+                b'z' => Keyboard::Y,
+                _ => offset(Keyboard::A, n),
+            });
+        }
+        '1'..='9' => key(offset(Keyboard::Keyboard1, c as u8 - b'1')),
+        '0' => key(Keyboard::Keyboard0),
+        '!' => shift(Keyboard::Keyboard1),
+        '"' => shift(Keyboard::Keyboard2),
+        '²' => altgr(Keyboard::Keyboard2),
+        '§' => shift(Keyboard::Keyboard3),
+        '³' => altgr(Keyboard::Keyboard3),
+        '$' => shift(Keyboard::Keyboard4),
+        '%' => shift(Keyboard::Keyboard5),
+        '&' => shift(Keyboard::Keyboard6),
+        '/' => shift(Keyboard::Keyboard7),
+        '{' => altgr(Keyboard::Keyboard7),
+        '(' => shift(Keyboard::Keyboard8),
+        '[' => altgr(Keyboard::Keyboard8),
+        ')' => shift(Keyboard::Keyboard9),
+        ']' => altgr(Keyboard::Keyboard9),
+        '=' => shift(Keyboard::Keyboard0),
+        '}' => altgr(Keyboard::Keyboard0),
+        'ß' => key(Keyboard::Minus),
+        '?' => shift(Keyboard::Minus),
+        '\\' => altgr(Keyboard::Minus),
+        'ü' => key(Keyboard::LeftBrace),
+        'Ü' => shift(Keyboard::LeftBrace),
+        '+' => key(Keyboard::RightBrace),
+        '*' => shift(Keyboard::RightBrace),
+        '~' => altgr(Keyboard::RightBrace),
+        'ö' => key(Keyboard::Semicolon),
+        'Ö' => shift(Keyboard::Semicolon),
+        'ä' => key(Keyboard::Apostrophe),
+        'Ä' => shift(Keyboard::Apostrophe),
+        '#' => key(Keyboard::NonUSHash),
+        '\'' => shift(Keyboard::NonUSHash),
+        '°' => shift(Keyboard::Grave),
+        '<' => key(Keyboard::NonUSBackslash),
+        '>' => shift(Keyboard::NonUSBackslash),
+        '|' => altgr(Keyboard::NonUSBackslash),
+        ',' => key(Keyboard::Comma),
+        ';' => shift(Keyboard::Comma),
+        '.' => key(Keyboard::Dot),
+        ':' => shift(Keyboard::Dot),
+        '-' => key(Keyboard::ForwardSlash),
+        '_' => shift(Keyboard::ForwardSlash),
+        '@' => altgr(Keyboard::Q),
+        '€' => altgr(Keyboard::E),
+        'µ' => altgr(Keyboard::M),
+        _ => return None,
+    };
+    Some(stroke)
+}
+
+/// Keys of the letters `a` to `z` on the Dvorak layout.
+const DVORAK_LETTERS: [Keyboard; 26] = [
+    Keyboard::A,
+    Keyboard::N,
+    Keyboard::I,
+    Keyboard::H,
+    Keyboard::D,
+    Keyboard::Y,
+    Keyboard::U,
+    Keyboard::J,
+    Keyboard::G,
+    Keyboard::C,
+    Keyboard::V,
+    Keyboard::P,
+    Keyboard::M,
+    Keyboard::L,
+    Keyboard::S,
+    Keyboard::R,
+    Keyboard::X,
+    Keyboard::O,
+    Keyboard::Semicolon,
+    Keyboard::K,
+    Keyboard::F,
+    Keyboard::Dot,
+    Keyboard::Comma,
+    Keyboard::B,
+    Keyboard::T,
+    Keyboard::ForwardSlash,
+];
+
+/// The Dvorak layout keeps the digits and their symbols of the US layout.
+fn dvorak(c: char) -> Option<Stroke> {
+    let stroke = match c {
+        'a'..='z' | 'A'..='Z' => return letter(c, |n| DVORAK_LETTERS[n as usize]),
+        '[' => key(Keyboard::Minus),
+        '{' => shift(Keyboard::Minus),
+        ']' => key(Keyboard::Equal),
+        '}' => shift(Keyboard::Equal),
+        '\'' => key(Keyboard::Q),
+        '"' => shift(Keyboard::Q),
+        ',' => key(Keyboard::W),
+        '<' => shift(Keyboard::W),
+        '.' => key(Keyboard::E),
+        '>' => shift(Keyboard::E),
+        '/' => key(Keyboard::LeftBrace),
+        '?' => shift(Keyboard::LeftBrace),
+        '=' => key(Keyboard::RightBrace),
+        '+' => shift(Keyboard::RightBrace),
+        '-' => key(Keyboard::Apostrophe),
+        '_' => shift(Keyboard::Apostrophe),
+        ';' => key(Keyboard::Z),
+        ':' => shift(Keyboard::Z),
+        _ => return us(c),
+    };
+    Some(stroke)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn us_and_uk() {
+        assert_eq!(
+            HostLayout::Us.strokes('a').as_deref(),
+            Some(&[key(Keyboard::A)][..])
+        );
+        assert_eq!(
+            HostLayout::Us.strokes('@').as_deref(),
+            Some(&[shift(Keyboard::Keyboard2)][..])
+        );
+        assert_eq!(HostLayout::Us.strokes('€').as_deref(), None);
+        assert_eq!(
+            HostLayout::Uk.strokes('@').as_deref(),
+            Some(&[shift(Keyboard::Apostrophe)][..])
+        );
+        assert_eq!(
+            HostLayout::Uk.strokes('"').as_deref(),
+            Some(&[shift(Keyboard::Keyboard2)][..])
+        );
+        assert_eq!(
+            HostLayout::Uk.strokes('£').as_deref(),
+            Some(&[shift(Keyboard::Keyboard3)][..])
+        );
+        assert_eq!(
+            HostLayout::Uk.strokes('Q').as_deref(),
+            Some(&[shift(Keyboard::Q)][..])
+        );
+    }
+
+    #[test]
+    fn french_azerty() {
+        let layout = HostLayout::FrenchAzerty;
+        assert_eq!(
+            layout.strokes('a').as_deref(),
+            Some(&[key(Keyboard::Q)][..])
+        );
+        assert_eq!(
+            layout.strokes('Q').as_deref(),
+            Some(&[shift(Keyboard::A)][..])
+        );
+        assert_eq!(
+            layout.strokes('w').as_deref(),
+            Some(&[key(Keyboard::Z)][..])
+        );
+        assert_eq!(
+            layout.strokes('m').as_deref(),
+            Some(&[key(Keyboard::Semicolon)][..])
+        );
+        assert_eq!(
+            layout.strokes('b').as_deref(),
+            Some(&[key(Keyboard::B)][..])
+        );
+        assert_eq!(
+            layout.strokes('1').as_deref(),
+            Some(&[shift(Keyboard::Keyboard1)][..])
+        );
+        assert_eq!(
+            layout.strokes('é').as_deref(),
+            Some(&[key(Keyboard::Keyboard2)][..])
+        );
+        assert_eq!(
+            layout.strokes('@').as_deref(),
+            Some(&[altgr(Keyboard::Keyboard0)][..])
+        );
+        assert_eq!(
+            layout.strokes('ê').as_deref(),
+            Some(&[key(Keyboard::LeftBrace), key(Keyboard::E)][..])
+        );
+        assert_eq!(
+            layout.strokes('~').as_deref(),
+            Some(&[altgr(Keyboard::Keyboard2), key(Keyboard::Space)][..])
+        );
+        assert_eq!(layout.strokes('ñ').as_deref(), None);
+    }
+
+    #[test]
+    fn german_qwertz() {
+        let layout = HostLayout::GermanQwertz;
+        assert_eq!(
+            layout.strokes('z').as_deref(),
+            Some(&[key(Keyboard::Y)][..])
+        );
+        assert_eq!(
+            layout.strokes('Y').as_deref(),
+            Some(&[shift(Keyboard::Z)][..])
+        );
+        assert_eq!(
+            layout.strokes('ß').as_deref(),
+            Some(&[key(Keyboard::Minus)][..])
+        );
+        assert_eq!(
+            layout.strokes('Ä').as_deref(),
+            Some(&[shift(Keyboard::Apostrophe)][..])
+        );
+        assert_eq!(
+            layout.strokes('@').as_deref(),
+            Some(&[altgr(Keyboard::Q)][..])
+        );
+        assert_eq!(
+            layout.strokes('-').as_deref(),
+            Some(&[key(Keyboard::ForwardSlash)][..])
+        );
+        assert_eq!(
+            layout.strokes('^').as_deref(),
+            Some(&[key(Keyboard::Grave), key(Keyboard::Space)][..])
+        );
+        assert_eq!(layout.strokes('é').as_deref(), None);
+    }
+
+    #[test]
+    fn dvorak() {
+        let layout = HostLayout::Dvorak;
+        assert_eq!(
+            layout.strokes('o').as_deref(),
+            Some(&[key(Keyboard::S)][..])
+        );
+        assert_eq!(
+            layout.strokes('Z').as_deref(),
+            Some(&[shift(Keyboard::ForwardSlash)][..])
+        );
+        assert_eq!(
+            layout.strokes('-').as_deref(),
+            Some(&[key(Keyboard::Apostrophe)][..])
+        );
+        assert_eq!(
+            layout.strokes('7').as_deref(),
+            Some(&[key(Keyboard::Keyboard7)][..])
+        );
+        assert_eq!(
+            layout.strokes('|').as_deref(),
+            Some(&[shift(Keyboard::Backslash)][..])
+        );
+    }
+
+    #[test]
+    fn printable_ascii() {
+        // Every layout types every printable ASCII character
+        for layout in HostLayout::ALL {
+            for c in ' '..='~' {
+                assert!(layout.strokes(c).is_some(), "{:?} {:?}", layout, c);
+            }
+            assert_eq!(HostLayout::from_name(layout.name()), Some(layout));
+        }
+    }
+}
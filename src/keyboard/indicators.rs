@@ -0,0 +1,26 @@
+use defmt::debug;
+use embassy_stm32::gpio::Output;
+
+use crate::config::leds::{LED_INDICATORS, LED_INDICATORS_NUMBER};
+
+use super::leds::HOST_LEDS;
+
+/// Runs the LED indicators task.
+///
+/// Each pin shows the host LED at the same index in [`LED_INDICATORS`].
+#[embassy_executor::task]
+pub async fn led_indicators_task(mut pins: [Output<'static>; LED_INDICATORS_NUMBER]) -> ! {
+    let mut receiver = HOST_LEDS.receiver().expect("Failed to watch the host LEDs");
+
+    loop {
+        let leds = receiver.changed().await;
+        debug!("LEDS | Host LEDs set to {:?}", leds);
+        for (pin, led) in pins.iter_mut().zip(LED_INDICATORS) {
+            if leds.is_on(led) {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+    }
+}
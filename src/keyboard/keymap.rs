@@ -0,0 +1,1156 @@
+use defmt::{debug, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::config::{
+    keymap::{COMBOS, COMBO_TIMEOUT, LED_LAYERS, ONE_SHOT_TIMEOUT, TAPPING_TERM, TAP_DANCE_TERM},
+    midi::MIDI_CHANNEL,
+    NKRO_MAX_KEYS,
+};
+
+use super::{
+    action::{Action, KeyAction, Midi, Mouse, TapDanceAction},
+    combo::{ComboEngine, COMBO_ROW},
+    event::KeyEvent,
+    hold_tap::{HoldTapDecision, PendingHoldTap},
+    layers::{Layers, MAX_LAYERS},
+    leds::HostLeds,
+    macros::{MacroRef, MACRO_QUEUE_SIZE},
+    midi::{MidiMessage, MidiState, ALL_NOTES_OFF},
+    mouse_keys::MouseKeyEvent,
+    report::{is_modifier, modifier_bit, ConsumerReport, KeyboardReport, SystemReport},
+    settings::Settings,
+    tap_dance::{PendingTapDance, TapDanceDecision},
+};
+
+/// Maximum number of keyboard reports waiting to be sent to the host.
+pub const REPORT_QUEUE_SIZE: usize = 16;
+/// Maximum number of key events buffered while a key is undecided.
+pub const EVENT_BUFFER_SIZE: usize = 16;
+/// Maximum number of MIDI messages waiting to be sent to the host.
+pub const MIDI_QUEUE_SIZE: usize = 16;
+/// Maximum number of mouse key events waiting to be sent.
+pub const MOUSE_QUEUE_SIZE: usize = 16;
+/// Maximum number of commands waiting to be applied to the keymap.
+pub const KEYMAP_COMMANDS_SIZE: usize = 8;
+
+/// Channel on which other tasks send commands to the keymap.
+pub static KEYMAP_COMMANDS: Channel<CriticalSectionRawMutex, KeymapCommand, KEYMAP_COMMANDS_SIZE> =
+    Channel::new();
+
+/// A command sent to the keymap by another task.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KeymapCommand {
+    /// Presses a key on behalf of a macro.
+    PressMacroKey(Keyboard),
+    /// Releases a key pressed by a macro.
+    ReleaseMacroKey(Keyboard),
+    /// Releases every key pressed by macros.
+    ReleaseMacroKeys,
+    /// Applies new settings, see [`Settings`].
+    ApplySettings(Settings),
+}
+
+/// A key of the matrix that is held down, with the action it resolved to when
+/// it was pressed.
+///
+/// Keeping the resolved action guarantees a key releases what it pressed, even
+/// if the layers changed in the meantime.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct HeldKey {
+    row: u8,
+    col: u8,
+    action: KeyAction,
+    /// Whether another key was pressed while this key was held.
+    interrupted: bool,
+}
+
+/// Keymap engine.
+///
+/// It turns the key events of the matrix into keyboard, consumer control and
+/// system control reports by resolving every key through the [`Layers`]. A report is queued
+/// only when its content changes.
+///
+/// Key events first go through the [`ComboEngine`], which turns chorded keys
+/// into the virtual keys of their combo.
+///
+/// Some keys cannot be resolved when they are pressed, such as hold-tap and tap
+/// dance keys.
+/// While such a key is undecided, the following events are buffered and are
+/// replayed in order once it resolves. The engine never reads the clock: time
+/// only moves forward through the timestamps of the events and through
+/// [`Keymap::tick`], which must be called when [`Keymap::next_deadline`] is
+/// reached.
+///
+/// Tapped one-shot modifiers apply to the next key that is not a modifier, and
+/// are dropped after a timeout or when the layers are switched by a key.
+///
+/// Macro keys and Unicode actions only queue their macro, which is then played
+/// by another task through [`KeymapCommand`]s. The keys pressed by macros are
+/// kept apart from the keys of the matrix, and both are merged in the reports.
+///
+/// # Generics
+///
+/// - `L`: Number of layers.
+/// - `M`: Number of rows in the matrix.
+/// - `N`: Number of columns in the matrix.
+pub struct Keymap<const L: usize, const M: usize, const N: usize> {
+    layers: Layers<L, M, N>,
+    combos: ComboEngine,
+    held: Vec<HeldKey, NKRO_MAX_KEYS>,
+    reports: Deque<KeyboardReport, REPORT_QUEUE_SIZE>,
+    last_report: KeyboardReport,
+    consumer_reports: Deque<ConsumerReport, REPORT_QUEUE_SIZE>,
+    last_consumer_report: ConsumerReport,
+    system_reports: Deque<SystemReport, REPORT_QUEUE_SIZE>,
+    last_system_report: SystemReport,
+    tapping_term: Duration,
+    /// The hold-tap key waiting to resolve, if any.
+    hold_tap: Option<PendingHoldTap>,
+    /// Events received while a key is undecided.
+    buffer: Deque<KeyEvent, EVENT_BUFFER_SIZE>,
+    /// The one-shot layer waiting for the next key, if any.
+    one_shot_layer: Option<usize>,
+    tap_dance_term: Duration,
+    /// The tap dance key counting its taps, if any.
+    tap_dance: Option<PendingTapDance>,
+    /// Position and release time of the last repeat key resolved to a tap.
+    last_tap: Option<(u8, u8, Instant)>,
+    one_shot_timeout: Duration,
+    /// Bitmask of the one-shot modifiers waiting for the next key.
+    one_shot_mods: u8,
+    /// When the waiting one-shot modifiers are dropped.
+    one_shot_deadline: Option<Instant>,
+    /// Bitmask of the one-shot modifiers locked by a double tap.
+    locked_mods: u8,
+    /// The keyboard LEDs, as last set by the host.
+    host_leds: HostLeds,
+    /// Bitmask of the layers activated by the host LEDs.
+    led_layers: u32,
+    /// Keys pressed by macros.
+    macro_keys: KeyboardReport,
+    /// Macros waiting to be played.
+    macros: Deque<MacroRef, MACRO_QUEUE_SIZE>,
+    /// Octave and transposition of the MIDI notes.
+    midi: MidiState,
+    /// MIDI messages waiting to be sent.
+    midi_messages: Deque<MidiMessage, MIDI_QUEUE_SIZE>,
+    /// Whether MIDI messages were dropped, so every note must be stopped and
+    /// the held ones started again.
+    midi_dropped: bool,
+    /// Mouse key presses and releases waiting to be sent.
+    mouse_events: Deque<MouseKeyEvent, MOUSE_QUEUE_SIZE>,
+}
+
+impl<const L: usize, const M: usize, const N: usize> Keymap<L, M, N> {
+    pub const fn new(layers: Layers<L, M, N>) -> Self {
+        Self {
+            layers,
+            combos: ComboEngine::new(COMBOS, COMBO_TIMEOUT),
+            held: Vec::new(),
+            reports: Deque::new(),
+            last_report: KeyboardReport::new(),
+            consumer_reports: Deque::new(),
+            last_consumer_report: ConsumerReport::new(),
+            system_reports: Deque::new(),
+            last_system_report: SystemReport::new(None),
+            tapping_term: TAPPING_TERM,
+            hold_tap: None,
+            buffer: Deque::new(),
+            one_shot_layer: None,
+            tap_dance_term: TAP_DANCE_TERM,
+            tap_dance: None,
+            last_tap: None,
+            one_shot_timeout: ONE_SHOT_TIMEOUT,
+            one_shot_mods: 0,
+            one_shot_deadline: None,
+            locked_mods: 0,
+            host_leds: HostLeds::from_report(0),
+            led_layers: 0,
+            macro_keys: KeyboardReport::new(),
+            macros: Deque::new(),
+            midi: MidiState::new(),
+            midi_messages: Deque::new(),
+            midi_dropped: false,
+            mouse_events: Deque::new(),
+        }
+    }
+
+    /// Sets the time after which an undecided hold-tap key resolves to a hold.
+    pub fn set_tapping_term(&mut self, tapping_term: Duration) {
+        self.tapping_term = tapping_term;
+    }
+
+    /// Sets the time after which a tap dance key stops counting taps.
+    pub fn set_tap_dance_term(&mut self, tap_dance_term: Duration) {
+        self.tap_dance_term = tap_dance_term;
+    }
+
+    /// Sets the time after which a tapped one-shot modifier is dropped.
+    pub fn set_one_shot_timeout(&mut self, timeout: Duration) {
+        self.one_shot_timeout = timeout;
+    }
+
+    /// Sets the time window in which the keys of a combo must be pressed.
+    pub fn set_combo_timeout(&mut self, timeout: Duration) {
+        self.combos.set_timeout(timeout);
+    }
+
+    /// Applies the timings of the settings.
+    pub fn apply_settings(&mut self, settings: Settings) {
+        self.set_tapping_term(settings.tapping_term);
+        self.set_tap_dance_term(settings.tap_dance_term);
+        self.set_one_shot_timeout(settings.one_shot_timeout);
+        self.set_combo_timeout(settings.combo_timeout);
+    }
+
+    pub fn layers(&self) -> &Layers<L, M, N> {
+        &self.layers
+    }
+
+    pub fn layers_mut(&mut self) -> &mut Layers<L, M, N> {
+        &mut self.layers
+    }
+
+    /// Processes a key event of the matrix.
+    pub fn process(&mut self, event: KeyEvent) {
+        // Resolve what timed out before this event happened
+        self.tick(event.at);
+        for event in self.combos.process(event, self.layers.state()) {
+            self.handle(event);
+        }
+    }
+
+    /// Applies a command sent by another task.
+    pub fn command(&mut self, command: KeymapCommand) {
+        match command {
+            KeymapCommand::PressMacroKey(key) => {
+                if !self.macro_keys.press(key) {
+                    warn!("KEYMAP | Too many macro keys pressed");
+                }
+            }
+            KeymapCommand::ReleaseMacroKey(key) => self.macro_keys.release(key),
+            KeymapCommand::ReleaseMacroKeys => self.macro_keys.clear(),
+            KeymapCommand::ApplySettings(settings) => self.apply_settings(settings),
+        }
+        self.commit();
+    }
+
+    pub fn host_leds(&self) -> HostLeds {
+        self.host_leds
+    }
+
+    /// Updates the keyboard LEDs set by the host.
+    ///
+    /// The layers of [`LED_LAYERS`] follow their LED. They are tracked apart
+    /// from the layer keys, so a layer stays active while either its LED is on
+    /// or a key holds it.
+    pub fn set_host_leds(&mut self, leds: HostLeds) {
+        self.host_leds = leds;
+        for &(led, layer) in LED_LAYERS {
+            let result = if leds.is_on(led) {
+                self.layers
+                    .activate(layer)
+                    .map(|_| self.led_layers |= 1 << layer)
+            } else if self.is_led_layer(layer) {
+                self.led_layers &= !(1 << layer);
+                self.release_layer(layer);
+                Ok(())
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                warn!(
+                    "KEYMAP | Failed to follow {:?} on layer {}: {:?}",
+                    led, layer, e
+                );
+            }
+        }
+    }
+
+    /// Returns the next press or release of a mouse key, if any.
+    ///
+    /// Every transition is kept, so a mouse key tapped faster than the mouse
+    /// reports are sent is still clicked.
+    pub fn pop_mouse_event(&mut self) -> Option<MouseKeyEvent> {
+        self.mouse_events.pop_front()
+    }
+
+    /// Returns the next macro to play, if any.
+    pub fn pop_macro(&mut self) -> Option<MacroRef> {
+        self.macros.pop_front()
+    }
+
+    /// Returns the next MIDI message to send to the host, if any.
+    ///
+    /// Once messages were dropped because too many were waiting, every note is
+    /// stopped after the queued messages and the held notes are started again,
+    /// so no note hangs on the host.
+    pub fn pop_midi_message(&mut self) -> Option<MidiMessage> {
+        if self.midi_messages.is_empty() && core::mem::take(&mut self.midi_dropped) {
+            self.resync_midi();
+        }
+        self.midi_messages.pop_front()
+    }
+
+    /// Checks if MIDI messages are waiting to be sent.
+    pub fn has_midi_messages(&self) -> bool {
+        !self.midi_messages.is_empty() || self.midi_dropped
+    }
+
+    /// Returns when [`Keymap::tick`] must be called next, if it must be.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let hold_tap = self.hold_tap.map(|hold_tap| hold_tap.deadline);
+        let tap_dance = self.tap_dance.map(|tap_dance| tap_dance.deadline);
+        [
+            self.combos.next_deadline(),
+            hold_tap,
+            tap_dance,
+            self.one_shot_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Resolves the keys whose deadline is reached at `now`.
+    pub fn tick(&mut self, now: Instant) {
+        for event in self.combos.tick(now) {
+            self.handle(event);
+        }
+        self.tick_undecided(now);
+        if self
+            .one_shot_deadline
+            .is_some_and(|deadline| now >= deadline)
+        {
+            self.clear_one_shot_mods();
+            self.commit();
+        }
+    }
+
+    /// Resolves the undecided key if its term expired at `now`.
+    fn tick_undecided(&mut self, now: Instant) {
+        if let Some(decision) = self.hold_tap.and_then(|h| h.decide_at(now)) {
+            self.resolve_hold_tap(decision);
+        }
+        if let Some(tap_dance) = self.tap_dance {
+            if let Some(decision) = tap_dance.decide_at(now) {
+                self.resolve_tap_dance(decision, tap_dance.deadline);
+            }
+        }
+    }
+
+    /// Releases every held key and drops the undecided ones.
+    ///
+    /// Layers activated by the held keys are deactivated, unless a host LED
+    /// keeps them active.
+    pub fn release_all(&mut self) {
+        self.combos.clear();
+        self.hold_tap = None;
+        self.buffer.clear();
+        self.one_shot_layer = None;
+        self.tap_dance = None;
+        self.last_tap = None;
+        self.clear_one_shot_mods();
+        self.locked_mods = 0;
+        for key in core::mem::take(&mut self.held) {
+            match key.action {
+                KeyAction::Layer(layer) | KeyAction::OneShotLayer(layer) => {
+                    self.release_layer(layer);
+                }
+                KeyAction::Single(Action::Midi(Midi::NoteOn { note, .. })) => {
+                    self.queue_midi(MidiMessage::NoteOff {
+                        channel: MIDI_CHANNEL,
+                        note,
+                    });
+                }
+                KeyAction::Single(Action::Mouse(mouse)) => {
+                    self.queue_mouse(MouseKeyEvent::release(mouse));
+                }
+                _ => {}
+            }
+        }
+        self.commit();
+    }
+
+    /// Returns the next report to send to the host, if any.
+    pub fn pop_report(&mut self) -> Option<KeyboardReport> {
+        self.reports.pop_front()
+    }
+
+    /// Returns the next consumer control report to send to the host, if any.
+    pub fn pop_consumer_report(&mut self) -> Option<ConsumerReport> {
+        self.consumer_reports.pop_front()
+    }
+
+    /// Returns the next system control report to send to the host, if any.
+    pub fn pop_system_report(&mut self) -> Option<SystemReport> {
+        self.system_reports.pop_front()
+    }
+
+    /// Returns the report matching the current state of the keymap.
+    ///
+    /// This is the report to send to resynchronize the host after a report
+    /// failed to be sent.
+    pub fn current_report(&self) -> KeyboardReport {
+        self.last_report.clone()
+    }
+
+    /// Drops every report waiting to be sent.
+    pub fn clear_reports(&mut self) {
+        self.reports.clear();
+    }
+
+    /// Handles an event, buffering it if a key is undecided.
+    fn handle(&mut self, event: KeyEvent) {
+        if let Some(hold_tap) = self.hold_tap {
+            let decision = hold_tap.decide(&event, self.buffer.iter());
+            if hold_tap.is_same_key(&event) {
+                // The hold-tap key itself is never buffered
+                if let Some(decision) = decision {
+                    if decision == HoldTapDecision::Tap
+                        && matches!(hold_tap.action, KeyAction::TapDance(_))
+                    {
+                        self.last_tap = Some((event.row, event.col, event.at));
+                    }
+                    self.resolve_hold_tap(decision);
+                    self.handle(event);
+                }
+                return;
+            }
+
+            if self.buffer.push_back(event).is_err() {
+                warn!("KEYMAP | Event buffer full, resolving hold-tap as hold");
+                self.resolve_hold_tap(HoldTapDecision::Hold);
+                self.handle(event);
+                return;
+            }
+
+            if let Some(decision) = decision {
+                self.resolve_hold_tap(decision);
+            }
+            return;
+        }
+
+        if let Some(mut tap_dance) = self.tap_dance {
+            if tap_dance.is_same_key(&event) {
+                let decision = tap_dance.update(&event, self.tap_dance_term);
+                self.tap_dance = Some(tap_dance);
+                if let Some(decision) = decision {
+                    self.resolve_tap_dance(decision, event.at);
+                }
+                return;
+            }
+
+            // Pressing another key ends the tap dance
+            if event.pressed {
+                self.resolve_tap_dance(tap_dance.interrupt(), event.at);
+            }
+        }
+
+        if event.pressed {
+            self.press(event);
+        } else {
+            self.release(event);
+        }
+        self.commit();
+    }
+
+    /// Resolves the undecided hold-tap key and replays the buffered events.
+    fn resolve_hold_tap(&mut self, decision: HoldTapDecision) {
+        let Some(hold_tap) = self.hold_tap.take() else {
+            return;
+        };
+        debug!("KEYMAP | Hold-tap resolved to {:?}", decision);
+
+        self.execute(hold_tap.row, hold_tap.col, hold_tap.resolve(decision));
+        self.commit();
+
+        // Replay the buffered events, which may start a new undecided key
+        let buffer = core::mem::take(&mut self.buffer);
+        for event in buffer {
+            self.tick_undecided(event.at);
+            self.handle(event);
+        }
+    }
+
+    /// Resolves the tap dance key at `at`.
+    ///
+    /// If the key is not held anymore, its action is pressed and released.
+    fn resolve_tap_dance(&mut self, decision: TapDanceDecision, at: Instant) {
+        let Some(tap_dance) = self.tap_dance.take() else {
+            return;
+        };
+        debug!("KEYMAP | Tap dance resolved to {:?}", decision);
+
+        let (row, col) = (tap_dance.row, tap_dance.col);
+        self.execute(row, col, tap_dance.resolve(decision));
+        self.commit();
+        if !tap_dance.pressed {
+            self.release(KeyEvent::release(row, col, at));
+            self.commit();
+        }
+    }
+
+    fn press(&mut self, event: KeyEvent) {
+        let (row, col) = (event.row, event.col);
+
+        // The held keys were interrupted by this key
+        self.held.iter_mut().for_each(|key| key.interrupted = true);
+
+        let key = self.get_key(row, col);
+        match key {
+            KeyAction::TapDance(TapDanceAction::Table(dance)) => {
+                let term = self.tap_dance_term;
+                self.tap_dance = Some(PendingTapDance::new(row, col, dance, event.at, term));
+            }
+            // Tapping then holding a repeat key holds its tap action
+            KeyAction::TapDance(TapDanceAction::Repeat(action))
+                if self.last_tap.is_some_and(|(r, c, at)| {
+                    (r, c) == (row, col) && event.at <= at + self.tap_dance_term
+                }) =>
+            {
+                self.last_tap = None;
+                self.execute(row, col, KeyAction::Single(action.tap));
+            }
+            KeyAction::HoldTap(_) | KeyAction::LayerTap(_) | KeyAction::TapDance(_) => {
+                self.last_tap = None;
+                let deadline = event.at + self.tapping_term;
+                self.hold_tap = PendingHoldTap::new(row, col, key, deadline);
+            }
+            key => self.execute(row, col, key),
+        }
+
+        // A one-shot layer only applies to the next key
+        if !key.is_layer_key() && !matches!(key, KeyAction::OneShot(_)) {
+            if let Some(layer) = self.one_shot_layer.take() {
+                self.release_layer(layer);
+            }
+        }
+    }
+
+    /// Returns the key at a position of the matrix, or of a combo.
+    fn get_key(&self, row: u8, col: u8) -> KeyAction {
+        if row == COMBO_ROW {
+            return self
+                .combos
+                .get(col)
+                .map_or(KeyAction::NoOp, |combo| combo.action);
+        }
+        self.layers.get_key(row as usize, col as usize)
+    }
+
+    /// Executes a resolved key.
+    fn execute(&mut self, row: u8, col: u8, key: KeyAction) {
+        let result = match key {
+            KeyAction::Single(Action::Unicode(c)) => {
+                self.queue_macro(MacroRef::Unicode(c));
+                Ok(())
+            }
+            KeyAction::Single(Action::UnicodeString(text)) => {
+                self.queue_macro(MacroRef::UnicodeString(text));
+                Ok(())
+            }
+            KeyAction::Single(Action::Midi(midi)) => {
+                self.press_midi(row, col, midi);
+                Ok(())
+            }
+            KeyAction::Single(Action::Mouse(mouse)) => {
+                // Another key may already hold the same mouse key
+                if !self.is_mouse_key_held(mouse) {
+                    self.queue_mouse(MouseKeyEvent::press(mouse));
+                }
+                self.hold(row, col, key);
+                Ok(())
+            }
+            KeyAction::Single(_) => {
+                self.hold(row, col, key);
+                Ok(())
+            }
+            KeyAction::Layer(layer) | KeyAction::OneShotLayer(layer) => self
+                .layers
+                .activate(layer)
+                .map(|_| self.hold(row, col, key)),
+            KeyAction::ToggleLayer(layer) => {
+                self.clear_one_shot_mods();
+                self.layers.toggle(layer)
+            }
+            KeyAction::ToLayer(layer) => {
+                self.one_shot_layer = None;
+                self.clear_one_shot_mods();
+                self.layers.to(layer)
+            }
+            KeyAction::DefaultLayer(layer) => {
+                self.clear_one_shot_mods();
+                self.layers.set_default_layer(layer)
+            }
+            KeyAction::OneShot(modifier) => {
+                self.press_one_shot(row, col, modifier);
+                Ok(())
+            }
+            KeyAction::Macro(steps) => {
+                self.queue_macro(MacroRef::Static(steps));
+                Ok(())
+            }
+            KeyAction::DynamicMacro(index) => {
+                self.queue_macro(MacroRef::Dynamic(index));
+                Ok(())
+            }
+            KeyAction::NoOp
+            | KeyAction::Transparent
+            | KeyAction::HoldTap(_)
+            | KeyAction::LayerTap(_)
+            | KeyAction::TapDance(_) => Ok(()),
+        };
+
+        if let Err(e) = result {
+            warn!("KEYMAP | Failed to execute {:?}: {:?}", key, e);
+        }
+
+        // One-shot modifiers only apply to the next key that is not a modifier
+        let consumes_one_shot = match key {
+            KeyAction::Single(Action::Keyboard(code)) => !is_modifier(code),
+            KeyAction::Single(Action::Mouse(_) | Action::Unicode(_) | Action::UnicodeString(_)) => {
+                true
+            }
+            _ => false,
+        };
+        if consumes_one_shot && self.one_shot_mods != 0 {
+            self.commit();
+            self.clear_one_shot_mods();
+        }
+    }
+
+    fn queue_macro(&mut self, r#macro: MacroRef) {
+        if self.macros.push_back(r#macro).is_err() {
+            warn!("KEYMAP | Too many macros waiting to be played");
+        }
+    }
+
+    fn queue_midi(&mut self, message: MidiMessage) {
+        if self.midi_messages.push_back(message).is_err() {
+            warn!("KEYMAP | Too many MIDI messages waiting to be sent, stopping all notes");
+            self.midi_dropped = true;
+        }
+    }
+
+    /// Queues the messages stopping every note and starting the held ones.
+    fn resync_midi(&mut self) {
+        let _ = self.midi_messages.push_back(MidiMessage::ControlChange {
+            channel: MIDI_CHANNEL,
+            controller: ALL_NOTES_OFF,
+            value: 0,
+        });
+        for key in self.held.iter() {
+            if let KeyAction::Single(Action::Midi(Midi::NoteOn { note, velocity })) = key.action {
+                let _ = self.midi_messages.push_back(MidiMessage::NoteOn {
+                    channel: MIDI_CHANNEL,
+                    note,
+                    velocity,
+                });
+            }
+        }
+    }
+
+    fn queue_mouse(&mut self, event: MouseKeyEvent) {
+        if self.mouse_events.push_back(event).is_err() {
+            warn!("KEYMAP | Too many mouse key events waiting to be sent");
+        }
+    }
+
+    fn press_midi(&mut self, row: u8, col: u8, midi: Midi) {
+        let channel = MIDI_CHANNEL;
+        let message = match midi {
+            Midi::NoteOn { note, velocity } => {
+                let Some(note) = self.midi.note(note) else {
+                    warn!("KEYMAP | MIDI note {} shifted out of range", note);
+                    return;
+                };
+                // Hold the shifted note, which the release must stop even if
+                // the octave or the transposition changed in the meantime
+                let action = Action::Midi(Midi::NoteOn { note, velocity });
+                self.hold(row, col, KeyAction::Single(action));
+                MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                }
+            }
+            Midi::ControlChange { controller, value } => MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            },
+            Midi::ProgramChange(program) => MidiMessage::ProgramChange { channel, program },
+            Midi::OctaveUp | Midi::OctaveDown | Midi::TransposeUp | Midi::TransposeDown => {
+                self.midi.apply(midi);
+                debug!("KEYMAP | MIDI state: {:?}", self.midi);
+                return;
+            }
+        };
+        self.queue_midi(message);
+    }
+
+    fn press_one_shot(&mut self, row: u8, col: u8, modifier: Keyboard) {
+        let Some(bit) = modifier_bit(modifier) else {
+            self.hold(row, col, KeyAction::Single(Action::Keyboard(modifier)));
+            return;
+        };
+
+        if self.locked_mods & bit != 0 {
+            // Tapping a locked modifier unlocks it
+            self.locked_mods &= !bit;
+        } else if self.one_shot_mods & bit != 0 {
+            // Tapping a waiting modifier again locks it
+            self.one_shot_mods &= !bit;
+            self.locked_mods |= bit;
+        } else {
+            self.hold(row, col, KeyAction::OneShot(modifier));
+        }
+    }
+
+    fn clear_one_shot_mods(&mut self) {
+        self.one_shot_mods = 0;
+        self.one_shot_deadline = None;
+    }
+
+    fn release(&mut self, event: KeyEvent) {
+        let position = (event.row, event.col);
+        while let Some(i) = self.held.iter().position(|k| (k.row, k.col) == position) {
+            let key = self.held.remove(i);
+            match key.action {
+                KeyAction::Layer(layer) => self.release_layer(layer),
+                // Tapping a one-shot layer key arms it for the next key
+                KeyAction::OneShotLayer(layer) if !key.interrupted => {
+                    self.one_shot_layer = Some(layer)
+                }
+                KeyAction::OneShotLayer(layer) => self.release_layer(layer),
+                // Another key may still hold the same note
+                KeyAction::Single(Action::Midi(Midi::NoteOn { note, .. }))
+                    if !self.is_note_held(note) =>
+                {
+                    self.queue_midi(MidiMessage::NoteOff {
+                        channel: MIDI_CHANNEL,
+                        note,
+                    });
+                }
+                KeyAction::Single(Action::Mouse(mouse)) if !self.is_mouse_key_held(mouse) => {
+                    self.queue_mouse(MouseKeyEvent::release(mouse));
+                }
+                // Tapping a one-shot modifier makes it wait for the next key
+                KeyAction::OneShot(modifier) if !key.interrupted => {
+                    self.one_shot_mods |= modifier_bit(modifier).unwrap_or(0);
+                    self.one_shot_deadline = Some(event.at + self.one_shot_timeout);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn is_note_held(&self, note: u8) -> bool {
+        self.held.iter().any(|key| {
+            matches!(key.action, KeyAction::Single(Action::Midi(Midi::NoteOn { note: n, .. })) if n == note)
+        })
+    }
+
+    fn is_led_layer(&self, layer: usize) -> bool {
+        layer < MAX_LAYERS && self.led_layers & (1 << layer) != 0
+    }
+
+    fn is_mouse_key_held(&self, mouse: Mouse) -> bool {
+        self.held
+            .iter()
+            .any(|key| key.action == KeyAction::Single(Action::Mouse(mouse)))
+    }
+
+    /// Deactivates a layer unless a held key, a one-shot layer or a host LED
+    /// still uses it.
+    fn release_layer(&mut self, layer: usize) {
+        let in_use = self.one_shot_layer == Some(layer)
+            || self.is_led_layer(layer)
+            || self.held.iter().any(|key| {
+                matches!(key.action, KeyAction::Layer(l) | KeyAction::OneShotLayer(l) if l == layer)
+            });
+
+        if !in_use {
+            let _ = self.layers.deactivate(layer);
+        }
+    }
+
+    fn hold(&mut self, row: u8, col: u8, action: KeyAction) {
+        let key = HeldKey {
+            row,
+            col,
+            action,
+            interrupted: false,
+        };
+        if self.held.push(key).is_err() {
+            warn!("KEYMAP | More than {} keys pressed", NKRO_MAX_KEYS);
+        }
+    }
+
+    /// Builds the report matching the held keys, the one-shot modifiers and the
+    /// keys of the macros.
+    fn report(&self) -> KeyboardReport {
+        let mut report = self.macro_keys.clone();
+        for key in self.held.iter() {
+            if let KeyAction::Single(Action::Keyboard(code)) | KeyAction::OneShot(code) = key.action
+            {
+                report.press(code);
+            }
+        }
+        report.press_modifiers(self.one_shot_mods | self.locked_mods);
+        report
+    }
+
+    /// Builds the consumer control report matching the held keys.
+    fn consumer_report(&self) -> ConsumerReport {
+        let mut report = ConsumerReport::new();
+        for key in self.held.iter() {
+            if let KeyAction::Single(Action::Consumer(code)) = key.action {
+                if !report.press(code) {
+                    warn!("KEYMAP | Too many consumer control keys pressed");
+                }
+            }
+        }
+        report
+    }
+
+    /// Builds the system control report matching the last system key held.
+    fn system_report(&self) -> SystemReport {
+        let key = self.held.iter().rev().find_map(|key| match key.action {
+            KeyAction::Single(Action::System(code)) => Some(code),
+            _ => None,
+        });
+        SystemReport::new(key)
+    }
+
+    /// Queues the current reports if they differ from the last ones.
+    fn commit(&mut self) {
+        let report = self.report();
+        if !report.same_keys(&self.last_report) {
+            push_latest(&mut self.reports, report.clone());
+            self.last_report = report;
+        }
+
+        let report = self.consumer_report();
+        if report != self.last_consumer_report {
+            push_latest(&mut self.consumer_reports, report.clone());
+            self.last_consumer_report = report;
+        }
+
+        let report = self.system_report();
+        if report != self.last_system_report {
+            push_latest(&mut self.system_reports, report);
+            self.last_system_report = report;
+        }
+    }
+}
+
+/// Queues a report.
+///
+/// When the queue is full, the latest state replaces the newest queued report
+/// so the host always ends up in the right state.
+fn push_latest<T, const S: usize>(queue: &mut Deque<T, S>, report: T) {
+    if queue.is_full() {
+        queue.pop_back();
+    }
+    let _ = queue.push_back(report);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use usbd_human_interface_device::page::Keyboard::{self, LeftControl, LeftShift, A, F, J};
+
+    use super::*;
+    use crate::keyboard::{
+        action::{k, mo, repeat, HoldTapAction, HoldTapConfig},
+        layers::Layer,
+    };
+
+    /// Returns the instant `ms` milliseconds after boot.
+    pub(crate) fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// Returns the keys of the keyboard reports queued by the keymap.
+    pub(crate) fn reports<const L: usize, const M: usize, const N: usize>(
+        keymap: &mut Keymap<L, M, N>,
+    ) -> std::vec::Vec<std::vec::Vec<Keyboard>> {
+        core::iter::from_fn(|| keymap.pop_report())
+            .map(|report| report.keys().to_vec())
+            .collect()
+    }
+
+    /// A hold-tap key of shift and F, a key A, and a hold-tap key of control
+    /// and J.
+    fn hold_tap_keymap(config: HoldTapConfig) -> Keymap<1, 1, 3> {
+        let hold_tap = |hold, tap| KeyAction::HoldTap(HoldTapAction { hold, tap, config });
+        Keymap::new(Layers::new([Layer::new([[
+            hold_tap(k(LeftShift), k(F)),
+            KeyAction::Single(k(A)),
+            hold_tap(k(LeftControl), k(J)),
+        ]])]))
+    }
+
+    #[test]
+    fn tap() {
+        for config in [
+            HoldTapConfig::Default,
+            HoldTapConfig::HoldOnOtherKeyPress,
+            HoldTapConfig::PermissiveHold,
+        ] {
+            let mut keymap = hold_tap_keymap(config);
+            keymap.process(KeyEvent::press(0, 0, at(0)));
+            assert_eq!(keymap.next_deadline(), Some(at(200)));
+            keymap.process(KeyEvent::release(0, 0, at(199)));
+            assert_eq!(reports(&mut keymap), [vec![F], vec![]], "{:?}", config);
+            assert_eq!(keymap.next_deadline(), None);
+        }
+    }
+
+    #[test]
+    fn hold() {
+        let mut keymap = hold_tap_keymap(HoldTapConfig::Default);
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 1, at(50)));
+        // The other key waits for the hold-tap key to resolve
+        keymap.tick(at(199));
+        assert!(reports(&mut keymap).is_empty());
+        keymap.tick(at(200));
+        keymap.process(KeyEvent::release(0, 1, at(250)));
+        keymap.process(KeyEvent::release(0, 0, at(300)));
+        assert_eq!(
+            reports(&mut keymap),
+            [vec![LeftShift], vec![LeftShift, A], vec![LeftShift], vec![]]
+        );
+    }
+
+    #[test]
+    fn hold_without_tick() {
+        // An event received after the tapping term resolves the key first
+        let mut keymap = hold_tap_keymap(HoldTapConfig::Default);
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 1, at(250)));
+        assert_eq!(reports(&mut keymap), [vec![LeftShift], vec![LeftShift, A]]);
+    }
+
+    #[test]
+    fn default_interrupt() {
+        // Another key tapped within the tapping term keeps the tap
+        let mut keymap = hold_tap_keymap(HoldTapConfig::Default);
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 1, at(50)));
+        keymap.process(KeyEvent::release(0, 1, at(80)));
+        keymap.process(KeyEvent::release(0, 0, at(100)));
+        assert_eq!(reports(&mut keymap), [vec![F], vec![F, A], vec![F], vec![]]);
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut keymap = hold_tap_keymap(HoldTapConfig::HoldOnOtherKeyPress);
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 1, at(50)));
+        assert_eq!(reports(&mut keymap), [vec![LeftShift], vec![LeftShift, A]]);
+        assert_eq!(keymap.next_deadline(), None);
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut keymap = hold_tap_keymap(HoldTapConfig::PermissiveHold);
+        // Pressing another key is not enough
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 1, at(50)));
+        assert!(reports(&mut keymap).is_empty());
+        // Releasing it too resolves to a hold
+        keymap.process(KeyEvent::release(0, 1, at(80)));
+        keymap.process(KeyEvent::release(0, 0, at(100)));
+        assert_eq!(
+            reports(&mut keymap),
+            [vec![LeftShift], vec![LeftShift, A], vec![LeftShift], vec![]]
+        );
+
+        // Releasing the hold-tap key first is a roll, which resolves to a tap
+        keymap.process(KeyEvent::press(0, 0, at(1000)));
+        keymap.process(KeyEvent::press(0, 1, at(1050)));
+        keymap.process(KeyEvent::release(0, 0, at(1080)));
+        keymap.process(KeyEvent::release(0, 1, at(1100)));
+        assert_eq!(reports(&mut keymap), [vec![F], vec![F, A], vec![A], vec![]]);
+    }
+
+    #[test]
+    fn nested_hold_taps() {
+        // Two hold-tap keys held while another key is tapped both hold
+        let mut keymap = hold_tap_keymap(HoldTapConfig::PermissiveHold);
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 2, at(20)));
+        keymap.process(KeyEvent::press(0, 1, at(40)));
+        keymap.process(KeyEvent::release(0, 1, at(60)));
+        keymap.process(KeyEvent::release(0, 2, at(80)));
+        keymap.process(KeyEvent::release(0, 0, at(90)));
+        assert_eq!(
+            reports(&mut keymap),
+            [
+                vec![LeftShift],
+                vec![LeftShift, LeftControl],
+                vec![LeftShift, LeftControl, A],
+                vec![LeftShift, LeftControl],
+                vec![LeftShift],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn tapping_term_setting() {
+        let mut keymap = hold_tap_keymap(HoldTapConfig::Default);
+        keymap.set_tapping_term(Duration::from_millis(100));
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        assert_eq!(keymap.next_deadline(), Some(at(100)));
+        keymap.tick(at(100));
+        assert_eq!(reports(&mut keymap), [vec![LeftShift]]);
+    }
+
+    #[test]
+    fn repeat_within_tap_dance_term() {
+        let mut keymap = Keymap::new(Layers::new([Layer::new([[repeat(k(LeftShift), k(F))]])]));
+        keymap.set_tap_dance_term(Duration::from_millis(300));
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::release(0, 0, at(50)));
+        // Pressed again after the tapping term but within the tap dance term
+        keymap.process(KeyEvent::press(0, 0, at(280)));
+        keymap.process(KeyEvent::release(0, 0, at(600)));
+        assert_eq!(reports(&mut keymap), [vec![F], vec![], vec![F], vec![]]);
+    }
+
+    #[test]
+    fn mouse_events() {
+        let click = KeyAction::Single(Action::Mouse(Mouse::LeftClick));
+        let mut keymap: Keymap<1, 1, 2> = Keymap::new(Layers::new([Layer::new([[
+            KeyAction::HoldTap(HoldTapAction {
+                hold: k(LeftShift),
+                tap: Action::Mouse(Mouse::LeftClick),
+                config: HoldTapConfig::Default,
+            }),
+            click,
+        ]])]));
+        let events = |keymap: &mut Keymap<1, 1, 2>| {
+            core::iter::from_fn(|| keymap.pop_mouse_event()).collect::<std::vec::Vec<_>>()
+        };
+
+        // A tapped hold-tap key presses and releases its mouse key at once, and
+        // both transitions are kept
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::release(0, 0, at(50)));
+        assert_eq!(
+            events(&mut keymap),
+            [
+                MouseKeyEvent::press(Mouse::LeftClick),
+                MouseKeyEvent::release(Mouse::LeftClick),
+            ]
+        );
+
+        // The mouse key stays pressed until the last key holding it is released
+        keymap.process(KeyEvent::press(0, 1, at(100)));
+        keymap.process(KeyEvent::press(0, 0, at(110)));
+        keymap.process(KeyEvent::release(0, 0, at(120)));
+        assert_eq!(
+            events(&mut keymap),
+            [MouseKeyEvent::press(Mouse::LeftClick)]
+        );
+        keymap.process(KeyEvent::release(0, 1, at(130)));
+        assert_eq!(
+            events(&mut keymap),
+            [MouseKeyEvent::release(Mouse::LeftClick)]
+        );
+    }
+
+    #[test]
+    fn led_layers() {
+        // The test configuration follows Num Lock on layer 1
+        let num_lock = HostLeds::from_report(1);
+        let mut keymap: Keymap<2, 1, 1> =
+            Keymap::new(Layers::new([Layer::new([[mo(1)]]), Layer::default()]));
+
+        // Releasing a layer key keeps the layer of a LED that is on
+        keymap.set_host_leds(num_lock);
+        assert_eq!(keymap.layers().state(), 0b11);
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::release(0, 0, at(10)));
+        assert_eq!(keymap.layers().state(), 0b11);
+
+        // Turning the LED off keeps the layer of a held layer key
+        keymap.process(KeyEvent::press(0, 0, at(20)));
+        keymap.set_host_leds(HostLeds::from_report(0));
+        assert_eq!(keymap.layers().state(), 0b11);
+        keymap.process(KeyEvent::release(0, 0, at(30)));
+        assert_eq!(keymap.layers().state(), 0b01);
+
+        keymap.set_host_leds(num_lock);
+        keymap.process(KeyEvent::press(0, 0, at(40)));
+        keymap.release_all();
+        assert_eq!(keymap.layers().state(), 0b11);
+        keymap.set_host_leds(HostLeds::from_report(0));
+        assert_eq!(keymap.layers().state(), 0b01);
+    }
+
+    #[test]
+    fn midi_overflow() {
+        let note = |note| {
+            KeyAction::Single(Action::Midi(Midi::NoteOn {
+                note,
+                velocity: 100,
+            }))
+        };
+        let mut keymap: Keymap<1, 1, 10> = Keymap::new(Layers::new([Layer::new([[
+            note(60),
+            note(61),
+            note(62),
+            note(63),
+            note(64),
+            note(65),
+            note(66),
+            note(67),
+            note(68),
+            note(69),
+        ]])]));
+        let note_on = |note| MidiMessage::NoteOn {
+            channel: MIDI_CHANNEL,
+            note,
+            velocity: 100,
+        };
+
+        // The last note offs and the note on do not fit in the queue
+        for col in 0..10 {
+            keymap.process(KeyEvent::press(0, col, at(col as u64)));
+        }
+        for col in 0..10 {
+            keymap.process(KeyEvent::release(0, col, at(10 + col as u64)));
+        }
+        keymap.process(KeyEvent::press(0, 0, at(20)));
+        assert!(keymap.has_midi_messages());
+
+        let messages: std::vec::Vec<_> =
+            core::iter::from_fn(|| keymap.pop_midi_message()).collect();
+        assert_eq!(messages.len(), MIDI_QUEUE_SIZE + 2);
+        assert_eq!(
+            messages[..10],
+            (60..70).map(note_on).collect::<std::vec::Vec<_>>()
+        );
+        assert_eq!(
+            messages[MIDI_QUEUE_SIZE..],
+            [
+                MidiMessage::ControlChange {
+                    channel: MIDI_CHANNEL,
+                    controller: ALL_NOTES_OFF,
+                    value: 0,
+                },
+                note_on(60),
+            ]
+        );
+        assert!(!keymap.has_midi_messages());
+    }
+}
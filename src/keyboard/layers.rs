@@ -2,11 +2,13 @@ use core::ops::{Index, IndexMut};
 
 use crate::keyboard::action::KeyAction;
 
+/// Maximum number of layers, limited by the size of the layer state bitmask.
+pub const MAX_LAYERS: usize = u32::BITS as usize;
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum LayersError {
-    HighestLayerReached,
-    LowestLayerReached,
+    InvalidLayer,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -18,6 +20,12 @@ impl<const M: usize, const N: usize> Layer<M, N> {
     pub const fn new(keys: [[KeyAction; N]; M]) -> Self {
         Self { keys }
     }
+
+    /// Replaces a key of the layer.
+    pub const fn with_key(mut self, row: usize, col: usize, key: KeyAction) -> Self {
+        self.keys[row][col] = key;
+        self
+    }
 }
 
 impl<const M: usize, const N: usize> Default for Layer<M, N> {
@@ -41,85 +49,179 @@ impl<const M: usize, const N: usize> IndexMut<(usize, usize)> for Layer<M, N> {
     }
 }
 
+/// Layers of the keymap and their state.
+///
+/// The state is a bitmask of the active layers on top of a default layer,
+/// which is always active. A key is looked up from the highest active layer
+/// down, and [`KeyAction::Transparent`] falls through to the next active
+/// layer below.
 pub struct Layers<const L: usize, const M: usize, const N: usize> {
     layers: [Layer<M, N>; L],
-    current_layer: usize,
+    /// Bitmask of the active layers, excluding the default layer.
+    active: u32,
+    default_layer: usize,
 }
 
 impl<const L: usize, const M: usize, const N: usize> Layers<L, M, N> {
     pub const fn new(layers: [Layer<M, N>; L]) -> Self {
+        assert!(L > 0, "There must be at least one layer");
+        assert!(L <= MAX_LAYERS, "Too many layers");
         Self {
             layers,
-            current_layer: 0,
+            active: 0,
+            default_layer: 0,
         }
     }
 
     pub fn get_key(&self, row: usize, col: usize) -> KeyAction {
-        self.get_key_from_layer(self.current_layer, row, col)
+        match self.highest_active_layer_from(L) {
+            Some(layer) => self.get_key_from_layer(layer, row, col),
+            None => KeyAction::NoOp,
+        }
     }
 
     fn get_key_from_layer(&self, layer: usize, row: usize, col: usize) -> KeyAction {
         match self.layers[layer][(row, col)] {
-            KeyAction::Transparent => {
-                if layer == 0 {
-                    // If we are a the lowest layer, then there is no operation done
-                    KeyAction::NoOp
-                } else {
-                    // Else, get the key in the next layer
-                    self.get_key_from_layer(layer - 1, row, col)
-                }
-            }
+            KeyAction::Transparent => match self.highest_active_layer_from(layer) {
+                // Get the key in the next active layer
+                Some(layer) => self.get_key_from_layer(layer, row, col),
+                // If we are at the lowest active layer, then there is no operation done
+                None => KeyAction::NoOp,
+            },
             key => key,
         }
     }
 
+    /// Returns the highest active layer strictly below `layer`.
+    fn highest_active_layer_from(&self, layer: usize) -> Option<usize> {
+        let below = if layer >= MAX_LAYERS {
+            u32::MAX
+        } else {
+            (1 << layer) - 1
+        };
+        let state = self.state() & below;
+        (state != 0).then(|| (u32::BITS - 1 - state.leading_zeros()) as usize)
+    }
+
     pub fn set_key_from_layer(&mut self, layer: usize, row: usize, col: usize, key: KeyAction) {
         self.layers[layer][(row, col)] = key;
     }
 
-    pub fn layer_up(&mut self) -> Result<(), LayersError> {
-        if self.current_layer >= L {
-            Err(LayersError::HighestLayerReached)
-        } else {
-            self.current_layer += 1;
-            Ok(())
-        }
+    /// Returns the bitmask of the active layers, including the default layer.
+    pub fn state(&self) -> u32 {
+        self.active | (1 << self.default_layer)
+    }
+
+    pub fn is_active(&self, layer: usize) -> bool {
+        layer < L && self.state() & (1 << layer) != 0
+    }
+
+    /// Activates a layer (MO while held).
+    pub fn activate(&mut self, layer: usize) -> Result<(), LayersError> {
+        Self::check(layer)?;
+        self.active |= 1 << layer;
+        Ok(())
+    }
+
+    /// Deactivates a layer.
+    ///
+    /// The default layer stays active.
+    pub fn deactivate(&mut self, layer: usize) -> Result<(), LayersError> {
+        Self::check(layer)?;
+        self.active &= !(1 << layer);
+        Ok(())
+    }
+
+    /// Toggles a layer on and off (TG).
+    pub fn toggle(&mut self, layer: usize) -> Result<(), LayersError> {
+        Self::check(layer)?;
+        self.active ^= 1 << layer;
+        Ok(())
+    }
+
+    /// Activates a layer and deactivates every other layer except the default
+    /// layer (TO).
+    pub fn to(&mut self, layer: usize) -> Result<(), LayersError> {
+        Self::check(layer)?;
+        self.active = 1 << layer;
+        Ok(())
     }
 
-    pub fn layer_down(&mut self) -> Result<(), LayersError> {
-        if self.current_layer == 0 {
-            Err(LayersError::LowestLayerReached)
+    /// Replaces the whole layer state.
+    ///
+    /// Bits of layers that do not exist are ignored.
+    pub fn set_state(&mut self, state: u32) {
+        let mask = if L >= MAX_LAYERS {
+            u32::MAX
         } else {
-            self.current_layer -= 1;
-            Ok(())
-        }
+            (1 << L) - 1
+        };
+        self.active = state & mask;
     }
 
+    pub fn get_default_layer(&self) -> usize {
+        self.default_layer
+    }
+
+    /// Sets the default layer, which is always active.
+    pub fn set_default_layer(&mut self, layer: usize) -> Result<(), LayersError> {
+        Self::check(layer)?;
+        self.default_layer = layer;
+        Ok(())
+    }
+
+    /// Returns the highest active layer.
     pub fn get_current_layer_id(&self) -> usize {
-        self.current_layer
+        self.highest_active_layer_from(L)
+            .unwrap_or(self.default_layer)
     }
 
     pub fn get_current_layer(&self) -> &Layer<M, N> {
-        &self.layers[self.current_layer]
+        &self.layers[self.get_current_layer_id()]
     }
 
     pub fn get_layer(&self, layer: usize) -> &Layer<M, N> {
         &self.layers[layer]
     }
 
-    pub fn set_current_layer(&mut self, layer: usize) {
-        assert!(layer < L, "The current layer cannot b");
-        self.current_layer = layer;
+    /// Returns the layer targeted by a layer key, if it exists.
+    pub fn get_layer_from_key(&self, key: KeyAction) -> Option<usize> {
+        let layer = match key {
+            KeyAction::Layer(layer)
+            | KeyAction::ToggleLayer(layer)
+            | KeyAction::ToLayer(layer)
+            | KeyAction::OneShotLayer(layer)
+            | KeyAction::DefaultLayer(layer) => layer,
+            KeyAction::LayerTap(action) => action.layer,
+            _ => return None,
+        };
+
+        // Check if the layer is in range
+        (layer < L).then_some(layer)
     }
 
-    pub fn get_layer_from_key(&self, key: KeyAction) -> Option<usize> {
-        if let KeyAction::Layer(layer) = key {
-            // Check if the layer is in range
-            if layer < L {
-                return Some(layer);
-            }
+    /// Checks if any key of any layer can send a mouse action.
+    pub fn has_mouse_actions(&self) -> bool {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.keys.iter().flatten())
+            .any(KeyAction::has_mouse_action)
+    }
+
+    /// Checks if any key of any layer can send a MIDI action.
+    pub fn has_midi_actions(&self) -> bool {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.keys.iter().flatten())
+            .any(KeyAction::has_midi_action)
+    }
+
+    fn check(layer: usize) -> Result<(), LayersError> {
+        if layer < L {
+            Ok(())
+        } else {
+            Err(LayersError::InvalidLayer)
         }
-        None
     }
 }
 
@@ -127,7 +229,181 @@ impl<const L: usize, const M: usize, const N: usize> Default for Layers<L, M, N>
     fn default() -> Self {
         Self {
             layers: [Layer::default(); L],
-            current_layer: 0,
+            active: 0,
+            default_layer: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::Keyboard::{self, A, B, C, D, X};
+
+    use super::*;
+    use crate::keyboard::{
+        action::{k, lt, mo, osl, tg, to},
+        event::KeyEvent,
+        keymap::{
+            tests::{at, reports},
+            Keymap,
+        },
+    };
+
+    const T: KeyAction = KeyAction::Transparent;
+
+    fn key(key: Keyboard) -> KeyAction {
+        KeyAction::Single(k(key))
+    }
+
+    /// Three layers whose keys are, on the base layer: MO(1), A, TG(2), OSL(1),
+    /// LT(2, X), TO(2) and D. The other layers replace A and TO(2).
+    fn keymap() -> Keymap<3, 1, 7> {
+        Keymap::new(Layers::new([
+            Layer::new([[mo(1), key(A), tg(2), osl(1), lt(2, k(X)), to(2), key(D)]]),
+            Layer::new([[T, key(B), T, T, T, to(0), T]]),
+            Layer::new([[T, key(C), T, T, T, to(0), T]]),
+        ]))
+    }
+
+    /// Presses and releases a key of [`keymap`].
+    fn tap(keymap: &mut Keymap<3, 1, 7>, col: u8, ms: u64) {
+        keymap.process(KeyEvent::press(0, col, at(ms)));
+        keymap.process(KeyEvent::release(0, col, at(ms + 1)));
+    }
+
+    #[test]
+    fn state() {
+        let mut layers: Layers<3, 1, 1> = Layers::default();
+        assert_eq!(layers.state(), 0b001);
+        layers.activate(1).unwrap();
+        layers.toggle(2).unwrap();
+        assert_eq!(layers.state(), 0b111);
+        assert_eq!(layers.get_current_layer_id(), 2);
+        layers.to(1).unwrap();
+        assert_eq!(layers.state(), 0b011);
+        // The default layer stays active
+        layers.deactivate(0).unwrap();
+        assert_eq!(layers.state(), 0b011);
+        layers.set_default_layer(2).unwrap();
+        layers.set_state(0b1000);
+        assert_eq!(layers.state(), 0b100);
+        assert_eq!(layers.activate(3), Err(LayersError::InvalidLayer));
+        assert_eq!(layers.set_default_layer(3), Err(LayersError::InvalidLayer));
+    }
+
+    #[test]
+    fn transparency() {
+        let mut layers: Layers<3, 1, 2> = Layers::new([
+            Layer::new([[key(A), key(B)]]),
+            Layer::new([[key(C), T]]),
+            Layer::new([[T, T]]),
+        ]);
+        // Inactive layers are skipped
+        layers.activate(2).unwrap();
+        assert_eq!(layers.get_key(0, 0), key(A));
+        layers.activate(1).unwrap();
+        assert_eq!(layers.get_key(0, 0), key(C));
+        assert_eq!(layers.get_key(0, 1), key(B));
+        // Nothing is below the lowest active layer
+        layers.set_default_layer(1).unwrap();
+        layers.set_state(0);
+        assert_eq!(layers.get_key(0, 1), KeyAction::NoOp);
+    }
+
+    #[test]
+    fn momentary() {
+        let mut keymap = keymap();
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        tap(&mut keymap, 1, 10);
+        // MO stacks on a toggled layer and falls through to it
+        tap(&mut keymap, 2, 20);
+        assert_eq!(keymap.layers().state(), 0b111);
+        tap(&mut keymap, 1, 30);
+        keymap.process(KeyEvent::release(0, 0, at(40)));
+        assert_eq!(keymap.layers().state(), 0b101);
+        tap(&mut keymap, 1, 50);
+        tap(&mut keymap, 2, 60);
+        tap(&mut keymap, 1, 70);
+        assert_eq!(
+            reports(&mut keymap),
+            [
+                vec![B],
+                vec![],
+                vec![C],
+                vec![],
+                vec![C],
+                vec![],
+                vec![A],
+                vec![]
+            ]
+        );
+    }
+
+    #[test]
+    fn to_layer() {
+        let mut keymap = keymap();
+        tap(&mut keymap, 5, 0);
+        assert_eq!(keymap.layers().state(), 0b101);
+        tap(&mut keymap, 1, 10);
+        // TO(0) of layer 1 also leaves layer 2, and the held MO(1)
+        keymap.process(KeyEvent::press(0, 0, at(20)));
+        assert_eq!(keymap.layers().state(), 0b111);
+        tap(&mut keymap, 5, 30);
+        assert_eq!(keymap.layers().state(), 0b001);
+        keymap.process(KeyEvent::release(0, 0, at(40)));
+        tap(&mut keymap, 1, 50);
+        assert_eq!(reports(&mut keymap), [vec![C], vec![], vec![A], vec![]]);
+    }
+
+    #[test]
+    fn one_shot_layer() {
+        let mut keymap = keymap();
+        tap(&mut keymap, 3, 0);
+        assert_eq!(keymap.layers().state(), 0b011);
+        tap(&mut keymap, 1, 10);
+        assert_eq!(keymap.layers().state(), 0b001);
+        tap(&mut keymap, 1, 20);
+        assert_eq!(reports(&mut keymap), [vec![B], vec![], vec![A], vec![]]);
+    }
+
+    #[test]
+    fn layer_tap() {
+        let mut keymap = keymap();
+        tap(&mut keymap, 4, 0);
+        // Held past the tapping term, LT stacks its layer on MO(1)
+        keymap.process(KeyEvent::press(0, 0, at(10)));
+        keymap.process(KeyEvent::press(0, 4, at(20)));
+        keymap.tick(at(220));
+        assert_eq!(keymap.layers().state(), 0b111);
+        tap(&mut keymap, 1, 230);
+        keymap.process(KeyEvent::release(0, 4, at(240)));
+        assert_eq!(keymap.layers().state(), 0b011);
+        tap(&mut keymap, 1, 250);
+        keymap.process(KeyEvent::release(0, 0, at(260)));
+        assert_eq!(keymap.layers().state(), 0b001);
+        assert_eq!(
+            reports(&mut keymap),
+            [vec![X], vec![], vec![C], vec![], vec![B], vec![]]
+        );
+    }
+
+    #[test]
+    fn release_on_other_layer() {
+        // A key releases what it pressed, even after its layer is left
+        let mut keymap = keymap();
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::press(0, 1, at(10)));
+        keymap.process(KeyEvent::release(0, 0, at(20)));
+        keymap.process(KeyEvent::press(0, 6, at(30)));
+        keymap.process(KeyEvent::release(0, 1, at(40)));
+        keymap.process(KeyEvent::release(0, 6, at(50)));
+        assert_eq!(reports(&mut keymap), [vec![B], vec![B, D], vec![D], vec![]]);
+
+        // Same for a key pressed before its layer is toggled on
+        tap(&mut keymap, 2, 60);
+        keymap.process(KeyEvent::press(0, 1, at(70)));
+        tap(&mut keymap, 2, 80);
+        keymap.process(KeyEvent::release(0, 1, at(90)));
+        assert_eq!(reports(&mut keymap), [vec![C], vec![]]);
+    }
+}
@@ -0,0 +1,36 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
+
+/// Maximum number of tasks watching the host LEDs.
+pub const HOST_LEDS_RECEIVERS: usize = 4;
+
+/// The state of the keyboard LEDs, as last set by the host.
+pub static HOST_LEDS: Watch<CriticalSectionRawMutex, HostLeds, HOST_LEDS_RECEIVERS> = Watch::new();
+
+/// A keyboard LED controlled by the host, as the index of its bit in the LED
+/// output report.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum HostLed {
+    NumLock = 0,
+    CapsLock = 1,
+    ScrollLock = 2,
+    Compose = 3,
+    Kana = 4,
+}
+
+/// The state of the keyboard LEDs controlled by the host.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct HostLeds(u8);
+
+impl HostLeds {
+    /// Parses the LED output report sent by the host.
+    pub const fn from_report(report: u8) -> Self {
+        Self(report)
+    }
+
+    pub fn is_on(&self, led: HostLed) -> bool {
+        self.0 & (1 << led as u8) != 0
+    }
+}
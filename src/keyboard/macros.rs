@@ -0,0 +1,339 @@
+use core::cell::RefCell;
+
+use defmt::{debug, warn};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+use crate::config::keymap::MACRO_STEP_DELAY;
+
+use super::{
+    keymap::{KeymapCommand, KEYMAP_COMMANDS},
+    send_string::{type_string, SEND_STRINGS},
+    settings::settings,
+};
+
+/// Maximum number of macros waiting to be played.
+pub const MACRO_QUEUE_SIZE: usize = 4;
+
+/// Channel on which the macros triggered by the keymap are sent to be played.
+pub static MACROS: Channel<CriticalSectionRawMutex, MacroRef, MACRO_QUEUE_SIZE> = Channel::new();
+
+/// Number of macros in the dynamic macro buffer.
+pub const DYNAMIC_MACRO_COUNT: u8 = 16;
+/// Size in bytes of the dynamic macro buffer.
+pub const DYNAMIC_MACRO_BUFFER_SIZE: usize = 512;
+
+/// Macros edited at runtime, such as the ones recorded with VIA.
+///
+/// The buffer holds [`DYNAMIC_MACRO_COUNT`] macros, each ended by a NUL byte,
+/// in the format of the dynamic macros of QMK. ASCII characters are typed as
+/// is, while [`DYNAMIC_MACRO_PREFIX`] starts a key tap, press or release, or a
+/// delay. The macros are lost on reset.
+pub static DYNAMIC_MACROS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[u8; DYNAMIC_MACRO_BUFFER_SIZE]>,
+> = Mutex::new(RefCell::new([0; DYNAMIC_MACRO_BUFFER_SIZE]));
+
+/// Starts a command in a dynamic macro.
+///
+/// It is followed by the kind of command, then by its arguments:
+///
+/// - 1, 2 and 3 tap, press and release the key of the next byte.
+/// - 4 waits for the number of milliseconds written in ASCII digits up to the
+///   next `|`.
+/// - 5, 6 and 7 tap, press and release the 16-bit keycode of the next two
+///   bytes, in little-endian. It is a key with a bitmask of modifiers in its
+///   high byte: control, shift, alt and GUI, then right-hand modifiers. A high
+///   byte of 0xFF stands for 0, which would end the macro.
+pub const DYNAMIC_MACRO_PREFIX: u8 = 0x01;
+
+/// Maximum number of steps played for a single item of a dynamic macro, which
+/// is a key tapped with all four modifiers.
+pub const DYNAMIC_MACRO_ITEM_STEPS: usize = 9;
+
+/// A sequence of steps played by a macro key.
+pub type Macro = &'static [MacroStep];
+
+/// A macro waiting to be played.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MacroRef {
+    /// A macro built into the firmware.
+    Static(Macro),
+    /// The macro at this index of [`DYNAMIC_MACROS`].
+    Dynamic(u8),
+    /// A character typed from its code point, see
+    /// [`UnicodeMode`](super::unicode::UnicodeMode).
+    Unicode(char),
+    /// A string typed from the code points of its characters.
+    UnicodeString(&'static str),
+}
+
+/// A step of a [`Macro`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MacroStep {
+    /// Presses a key until it is released by a later step or by the end of the
+    /// macro.
+    Press(Keyboard),
+    Release(Keyboard),
+    /// Presses and releases a key.
+    Tap(Keyboard),
+    /// Waits for this many milliseconds.
+    Delay(u32),
+    /// Types an ASCII string, assuming the host uses a US layout.
+    Type(&'static str),
+}
+
+/// Runs the macro player task.
+///
+/// Macros sent on [`MACROS`] and strings sent on [`SEND_STRINGS`] are played
+/// one after the other. Their keys are pressed and released through
+/// [`KEYMAP_COMMANDS`], so the keymap merges them with the keys held by the
+/// user and the reports keep going through the keyboard writer. The keys a
+/// macro leaves pressed are released once it ends.
+#[embassy_executor::task]
+pub async fn macro_task() -> ! {
+    loop {
+        match select(MACROS.receive(), SEND_STRINGS.receive()).await {
+            Either::First(MacroRef::Static(steps)) => {
+                debug!("MACRO | Playing {} steps", steps.len());
+                for step in steps {
+                    play(*step).await;
+                }
+            }
+            Either::First(MacroRef::Dynamic(index)) => {
+                debug!("MACRO | Playing dynamic macro {}", index);
+                // Play a copy, so the macros can be edited in the meantime
+                let buffer = DYNAMIC_MACROS.lock(|buffer| *buffer.borrow());
+                let mut bytes = dynamic_macro(&buffer, index);
+                while let Some((steps, rest)) = next_dynamic_steps(bytes) {
+                    for step in steps {
+                        play(step).await;
+                    }
+                    bytes = rest;
+                }
+            }
+            Either::First(MacroRef::Unicode(c)) => type_unicode(c).await,
+            Either::First(MacroRef::UnicodeString(text)) => {
+                for c in text.chars() {
+                    type_unicode(c).await;
+                }
+            }
+            Either::Second(text) => type_string(&text).await,
+        }
+        KEYMAP_COMMANDS.send(KeymapCommand::ReleaseMacroKeys).await;
+    }
+}
+
+async fn play(step: MacroStep) {
+    match step {
+        MacroStep::Press(key) => press(key).await,
+        MacroStep::Release(key) => release(key).await,
+        MacroStep::Tap(key) => tap(key).await,
+        MacroStep::Delay(ms) => Timer::after(Duration::from_millis(ms as u64)).await,
+        MacroStep::Type(text) => {
+            for c in text.chars() {
+                match ascii_to_key(c) {
+                    Some((key, true)) => {
+                        press(Keyboard::LeftShift).await;
+                        tap(key).await;
+                        release(Keyboard::LeftShift).await;
+                    }
+                    Some((key, false)) => tap(key).await,
+                    None => warn!("MACRO | Cannot type {:?}", c),
+                }
+            }
+        }
+    }
+}
+
+/// Types a character with the Unicode mode and the host layout of the
+/// settings.
+async fn type_unicode(c: char) {
+    let settings = settings();
+    match settings.unicode_mode.steps(c, settings.host_layout) {
+        Some(steps) => {
+            for step in steps {
+                play(step).await;
+            }
+        }
+        None => warn!(
+            "MACRO | Cannot type {:?} with the {} Unicode mode",
+            c,
+            settings.unicode_mode.name()
+        ),
+    }
+}
+
+/// Presses a key, then waits so the host receives the report.
+async fn press(key: Keyboard) {
+    KEYMAP_COMMANDS
+        .send(KeymapCommand::PressMacroKey(key))
+        .await;
+    Timer::after(MACRO_STEP_DELAY).await;
+}
+
+/// Releases a key, then waits so the host receives the report.
+async fn release(key: Keyboard) {
+    KEYMAP_COMMANDS
+        .send(KeymapCommand::ReleaseMacroKey(key))
+        .await;
+    Timer::after(MACRO_STEP_DELAY).await;
+}
+
+async fn tap(key: Keyboard) {
+    press(key).await;
+    release(key).await;
+}
+
+/// Returns the key typing an ASCII character on a US layout, and whether shift
+/// must be held while typing it.
+pub fn ascii_to_key(c: char) -> Option<(Keyboard, bool)> {
+    let key = match c {
+        'a'..='z' => (offset(Keyboard::A, c as u8 - b'a'), false),
+        'A'..='Z' => (offset(Keyboard::A, c as u8 - b'A'), true),
+        '1'..='9' => (offset(Keyboard::Keyboard1, c as u8 - b'1'), false),
+        '0' => (Keyboard::Keyboard0, false),
+        '!' => (Keyboard::Keyboard1, true),
+        '@' => (Keyboard::Keyboard2, true),
+        '#' => (Keyboard::Keyboard3, true),
+        '$' => (Keyboard::Keyboard4, true),
+        '%' => (Keyboard::Keyboard5, true),
+        '^' => (Keyboard::Keyboard6, true),
+        '&' => (Keyboard::Keyboard7, true),
+        '*' => (Keyboard::Keyboard8, true),
+        '(' => (Keyboard::Keyboard9, true),
+        ')' => (Keyboard::Keyboard0, true),
+        '\n' => (Keyboard::ReturnEnter, false),
+        '\t' => (Keyboard::Tab, false),
+        ' ' => (Keyboard::Space, false),
+        '-' => (Keyboard::Minus, false),
+        '_' => (Keyboard::Minus, true),
+        '=' => (Keyboard::Equal, false),
+        '+' => (Keyboard::Equal, true),
+        '[' => (Keyboard::LeftBrace, false),
+        '{' => (Keyboard::LeftBrace, true),
+        ']' => (Keyboard::RightBrace, false),
+        '}' => (Keyboard::RightBrace, true),
+        '\\' => (Keyboard::Backslash, false),
+        '|' => (Keyboard::Backslash, true),
+        ';' => (Keyboard::Semicolon, false),
+        ':' => (Keyboard::Semicolon, true),
+        '\'' => (Keyboard::Apostrophe, false),
+        '"' => (Keyboard::Apostrophe, true),
+        '`' => (Keyboard::Grave, false),
+        '~' => (Keyboard::Grave, true),
+        ',' => (Keyboard::Comma, false),
+        '<' => (Keyboard::Comma, true),
+        '.' => (Keyboard::Dot, false),
+        '>' => (Keyboard::Dot, true),
+        '/' => (Keyboard::ForwardSlash, false),
+        '?' => (Keyboard::ForwardSlash, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Returns the bytes of a macro of the dynamic macro buffer, without its NUL
+/// terminator.
+///
+/// Macros past the end of the buffer are empty.
+pub fn dynamic_macro(buffer: &[u8], index: u8) -> &[u8] {
+    buffer
+        .split(|&byte| byte == 0)
+        .nth(index as usize)
+        .unwrap_or_default()
+}
+
+/// Parses the first item of a dynamic macro, see [`DYNAMIC_MACRO_PREFIX`].
+///
+/// Returns the steps of the item and the rest of the macro, or `None` at the
+/// end of the macro. Characters that cannot be typed and unknown commands
+/// play no step.
+pub fn next_dynamic_steps(
+    bytes: &[u8],
+) -> Option<(Vec<MacroStep, DYNAMIC_MACRO_ITEM_STEPS>, &[u8])> {
+    let mut steps = Vec::new();
+    let rest = match *bytes {
+        [] => return None,
+        [DYNAMIC_MACRO_PREFIX, kind @ 1..=3, key, ref rest @ ..] => {
+            chord(&mut steps, kind, 0, Keyboard::from(key));
+            rest
+        }
+        [DYNAMIC_MACRO_PREFIX, 4, ref rest @ ..] => {
+            let end = rest.iter().position(|&b| b == b'|').unwrap_or(rest.len());
+            let ms = rest[..end]
+                .iter()
+                .filter(|b| b.is_ascii_digit())
+                .fold(0u32, |ms, b| {
+                    ms.saturating_mul(10).saturating_add((b - b'0') as u32)
+                });
+            let _ = steps.push(MacroStep::Delay(ms));
+            rest.get(end + 1..).unwrap_or_default()
+        }
+        [DYNAMIC_MACRO_PREFIX, kind @ 5..=7, low, high, ref rest @ ..] => {
+            let modifiers = if high == 0xFF { 0 } else { high };
+            chord(&mut steps, kind - 4, modifiers, Keyboard::from(low));
+            rest
+        }
+        [DYNAMIC_MACRO_PREFIX, _, ref rest @ ..] => {
+            warn!("MACRO | Unknown dynamic macro command");
+            rest
+        }
+        [c, ref rest @ ..] => {
+            match ascii_to_key(c as char) {
+                // Shift is the second modifier
+                Some((key, shift)) => chord(&mut steps, 1, (shift as u8) << 1, key),
+                None => warn!("MACRO | Cannot type {:?}", c as char),
+            }
+            rest
+        }
+    };
+    Some((steps, rest))
+}
+
+/// Adds the steps tapping (1), pressing (2) or releasing (3) a key with
+/// modifiers.
+///
+/// The modifiers are a bitmask of control, shift, alt and GUI, then whether
+/// they are the right-hand ones.
+fn chord(
+    steps: &mut Vec<MacroStep, DYNAMIC_MACRO_ITEM_STEPS>,
+    kind: u8,
+    modifiers: u8,
+    key: Keyboard,
+) {
+    let first = if modifiers & 0x10 != 0 {
+        Keyboard::RightControl
+    } else {
+        Keyboard::LeftControl
+    };
+    let modifiers = (0..4)
+        .filter(|i| modifiers & (1 << i) != 0)
+        .map(|i| offset(first, i));
+
+    // The capacity fits four modifiers pressed then released around a tap
+    if kind != 3 {
+        steps.extend(modifiers.clone().map(MacroStep::Press));
+    }
+    let _ = steps.push(match kind {
+        1 => MacroStep::Tap(key),
+        2 => MacroStep::Press(key),
+        _ => MacroStep::Release(key),
+    });
+    if kind != 2 {
+        steps.extend(modifiers.map(MacroStep::Release));
+    }
+}
+
+/// Returns the key `n` usages after `base`.
+fn offset(base: Keyboard, n: u8) -> Keyboard {
+    Keyboard::from(u8::from(base) + n)
+}
@@ -0,0 +1,227 @@
+use super::{
+    action::{Action, KeyAction, Midi},
+    layers::Layer,
+};
+
+/// Highest octave shift, up or down.
+pub const MAX_OCTAVE: i8 = 4;
+/// Highest transposition in semitones, up or down.
+pub const MAX_TRANSPOSE: i8 = 11;
+/// Controller of the channel mode message stopping every note.
+pub const ALL_NOTES_OFF: u8 = 0x7B;
+
+/// A MIDI message sent to the host.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+}
+
+impl MidiMessage {
+    /// Encodes the message as a USB MIDI event packet of a cable.
+    ///
+    /// The packet is the code index number of the message, then the message
+    /// itself, padded with zeros. The channels and data bytes are masked to
+    /// their valid range.
+    pub fn to_packet(self, cable: u8) -> [u8; 4] {
+        let (kind, channel, data_1, data_2) = match self {
+            MidiMessage::NoteOff { channel, note } => (0x80, channel, note, 0),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (0x90, channel, note, velocity),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (0xB0, channel, controller, value),
+            MidiMessage::ProgramChange { channel, program } => (0xC0, channel, program, 0),
+        };
+        // The code index number of channel messages is their kind
+        [
+            (cable & 0x0F) << 4 | kind >> 4,
+            kind | channel & 0x0F,
+            data_1 & 0x7F,
+            data_2 & 0x7F,
+        ]
+    }
+}
+
+/// Octave and transposition applied to the notes of [`Midi::NoteOn`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MidiState {
+    octave: i8,
+    transpose: i8,
+}
+
+impl MidiState {
+    pub const fn new() -> Self {
+        Self {
+            octave: 0,
+            transpose: 0,
+        }
+    }
+
+    pub fn octave(&self) -> i8 {
+        self.octave
+    }
+
+    pub fn transpose(&self) -> i8 {
+        self.transpose
+    }
+
+    /// Returns the note played for a note of the keymap, or `None` if it is
+    /// shifted out of the MIDI range.
+    pub fn note(&self, note: u8) -> Option<u8> {
+        let note = note as i16 + 12 * self.octave as i16 + self.transpose as i16;
+        u8::try_from(note).ok().filter(|&note| note <= 0x7F)
+    }
+
+    /// Applies an octave or transposition key, up to [`MAX_OCTAVE`] and
+    /// [`MAX_TRANSPOSE`].
+    ///
+    /// The other actions do not change the state.
+    pub fn apply(&mut self, midi: Midi) {
+        match midi {
+            Midi::OctaveUp => self.octave = (self.octave + 1).min(MAX_OCTAVE),
+            Midi::OctaveDown => self.octave = (self.octave - 1).max(-MAX_OCTAVE),
+            Midi::TransposeUp => self.transpose = (self.transpose + 1).min(MAX_TRANSPOSE),
+            Midi::TransposeDown => self.transpose = (self.transpose - 1).max(-MAX_TRANSPOSE),
+            Midi::NoteOn { .. } | Midi::ControlChange { .. } | Midi::ProgramChange(_) => {}
+        }
+    }
+}
+
+/// Creates a layer playing consecutive notes, from `first_note` on the first
+/// key of the first row, row after row.
+///
+/// The keys of the octave and transposition can then be placed with
+/// [`Layer::with_key`].
+pub const fn midi_layer<const M: usize, const N: usize>(
+    first_note: u8,
+    velocity: u8,
+) -> Layer<M, N> {
+    let mut keys = [[KeyAction::NoOp; N]; M];
+    let mut row = 0;
+    while row < M {
+        let mut col = 0;
+        while col < N {
+            let note = first_note as usize + row * N + col;
+            if note <= 0x7F {
+                keys[row][col] = KeyAction::Single(Action::Midi(Midi::NoteOn {
+                    note: note as u8,
+                    velocity,
+                }));
+            }
+            col += 1;
+        }
+        row += 1;
+    }
+    Layer::new(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets() {
+        let note_on = MidiMessage::NoteOn {
+            channel: 1,
+            note: 60,
+            velocity: 100,
+        };
+        assert_eq!(note_on.to_packet(0), [0x09, 0x91, 60, 100]);
+
+        let note_off = MidiMessage::NoteOff {
+            channel: 1,
+            note: 60,
+        };
+        assert_eq!(note_off.to_packet(0), [0x08, 0x81, 60, 0]);
+
+        let control_change = MidiMessage::ControlChange {
+            channel: 15,
+            controller: 7,
+            value: 127,
+        };
+        assert_eq!(control_change.to_packet(2), [0x2B, 0xBF, 7, 127]);
+
+        let program_change = MidiMessage::ProgramChange {
+            channel: 0,
+            program: 5,
+        };
+        assert_eq!(program_change.to_packet(0), [0x0C, 0xC0, 5, 0]);
+
+        // Data bytes cannot set the status bit
+        let note_on = MidiMessage::NoteOn {
+            channel: 0,
+            note: 0xFF,
+            velocity: 0x80,
+        };
+        assert_eq!(note_on.to_packet(0), [0x09, 0x90, 0x7F, 0]);
+    }
+
+    #[test]
+    fn octave_and_transpose() {
+        let mut state = MidiState::new();
+        assert_eq!(state.note(60), Some(60));
+
+        state.apply(Midi::OctaveUp);
+        state.apply(Midi::TransposeDown);
+        assert_eq!(state.note(60), Some(71));
+        assert_eq!(state.note(120), None);
+
+        for _ in 0..10 {
+            state.apply(Midi::OctaveDown);
+            state.apply(Midi::TransposeDown);
+        }
+        assert_eq!(state.octave(), -MAX_OCTAVE);
+        assert_eq!(state.transpose(), -MAX_TRANSPOSE);
+        assert_eq!(state.note(60), Some(1));
+        assert_eq!(state.note(0), None);
+
+        state.apply(Midi::ProgramChange(1));
+        assert_eq!(state.note(60), Some(1));
+    }
+
+    #[test]
+    fn layer() {
+        let layer = midi_layer::<2, 3>(125, 100).with_key(
+            1,
+            2,
+            KeyAction::Single(Action::Midi(Midi::OctaveUp)),
+        );
+        let note = |note| {
+            KeyAction::Single(Action::Midi(Midi::NoteOn {
+                note,
+                velocity: 100,
+            }))
+        };
+        assert_eq!(layer[(0, 0)], note(125));
+        assert_eq!(layer[(0, 2)], note(127));
+        assert_eq!(layer[(1, 0)], KeyAction::NoOp);
+        assert_eq!(
+            layer[(1, 2)],
+            KeyAction::Single(Action::Midi(Midi::OctaveUp))
+        );
+    }
+}
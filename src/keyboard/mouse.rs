@@ -1,31 +1,66 @@
 use defmt::warn;
-use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
-use embassy_time::Timer;
-use embassy_usb::class::hid::HidWriter;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Instant, Timer};
 use packed_struct::PackedStruct;
-use usbd_human_interface_device::device::mouse::BootMouseReport;
 
-use crate::usb::HID_MOUSE_WRITER_N;
+use crate::{
+    config::mouse::{MOUSE_DEFAULT_SPEED, MOUSE_MOVEMENT, MOUSE_SCROLL, MOUSE_SPEEDS},
+    usb::hid::{write_extra_report, ExtraKeysWriter, MOUSE_REPORT_ID},
+};
+
+use super::mouse_keys::{MouseEngine, MouseKeyEvent};
+
+/// Maximum number of mouse key events waiting to be applied.
+pub const MOUSE_EVENTS_SIZE: usize = 16;
+
+/// Channel on which the presses and releases of the mouse keys, as resolved by
+/// the keymap, are sent to the mouse writer.
+pub static MOUSE_EVENTS: Channel<CriticalSectionRawMutex, MouseKeyEvent, MOUSE_EVENTS_SIZE> =
+    Channel::new();
 
 /// Runs a HID writer task.
+///
+/// The mouse key events received on [`MOUSE_EVENTS`] drive a [`MouseEngine`]
+/// in order, and its reports are sent to the host whenever the buttons change,
+/// and periodically while the mouse moves or scrolls. Each event is reported
+/// on its own, so a quick click is never merged away.
 #[embassy_executor::task]
-pub async fn mouse_writer_task(
-    mut writer: HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_MOUSE_WRITER_N>,
-) {
-    let mut y: i8 = 25;
+pub async fn mouse_writer_task(writer: &'static ExtraKeysWriter) {
+    let mut engine = MouseEngine::new(
+        MOUSE_MOVEMENT,
+        MOUSE_SCROLL,
+        MOUSE_SPEEDS,
+        MOUSE_DEFAULT_SPEED,
+    );
+    let mut buttons = 0;
     loop {
-        Timer::after_millis(500).await;
-        y = -y;
+        // Wait for the next mouse key event, or for the next report while
+        // moving
+        let event = match engine.next_deadline() {
+            Some(deadline) => match select(MOUSE_EVENTS.receive(), Timer::at(deadline)).await {
+                Either::First(event) => Some(event),
+                Either::Second(_) => None,
+            },
+            None => Some(MOUSE_EVENTS.receive().await),
+        };
+
+        let now = Instant::now();
+        if let Some(event) = event {
+            engine.apply(event, now);
+        }
 
-        let report = BootMouseReport {
-            buttons: 0,
-            x: 0,
-            y,
+        let report = engine.report(now);
+        let moved = report.x != 0
+            || report.y != 0
+            || report.vertical_wheel != 0
+            || report.horizontal_wheel != 0;
+        if !moved && report.buttons == buttons {
+            continue;
         }
-        .pack()
-        .unwrap();
+        buttons = report.buttons;
 
-        match writer.write(&report).await {
+        match write_extra_report(writer, MOUSE_REPORT_ID, &report.pack().unwrap()).await {
             Ok(()) => {}
             Err(e) => warn!("Failed to send report: {:?}", e),
         }
@@ -0,0 +1,285 @@
+use embassy_time::{Duration, Instant};
+use usbd_human_interface_device::device::mouse::WheelMouseReport;
+
+use super::action::Mouse;
+
+/// Shape of the acceleration of the mouse keys.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccelerationCurve {
+    /// Always moves at the maximum speed.
+    Constant,
+    /// Speeds up at a constant rate.
+    Linear,
+    /// Speeds up slowly at first, then faster.
+    Quadratic,
+}
+
+/// Acceleration of a mouse key while it is held.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Acceleration {
+    /// Time between two reports.
+    pub interval: Duration,
+    /// Distance of the first report.
+    pub initial: u8,
+    /// Distance of each report once the maximum speed is reached.
+    pub max: u8,
+    /// Time it takes to reach the maximum speed.
+    pub time_to_max: Duration,
+    pub curve: AccelerationCurve,
+}
+
+impl Acceleration {
+    /// Returns the distance of a report after the key was held for `held`.
+    pub fn delta(&self, held: Duration) -> u8 {
+        let initial = self.initial.min(self.max) as u64;
+        let range = self.max as u64 - initial;
+        let total = self.time_to_max.as_ticks().max(1);
+        let elapsed = held.as_ticks().min(total);
+
+        let progress = match self.curve {
+            AccelerationCurve::Constant => return self.max,
+            AccelerationCurve::Linear => range * elapsed / total,
+            AccelerationCurve::Quadratic => range * elapsed / total * elapsed / total,
+        };
+        (initial + progress) as u8
+    }
+}
+
+/// Set of the mouse keys held down.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MouseKeys(u16);
+
+impl MouseKeys {
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn insert(&mut self, key: Mouse) {
+        self.0 |= Self::bit(key);
+    }
+
+    pub fn remove(&mut self, key: Mouse) {
+        self.0 &= !Self::bit(key);
+    }
+
+    pub fn contains(&self, key: Mouse) -> bool {
+        self.0 & Self::bit(key) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the keys of `self` that are not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    const fn bit(key: Mouse) -> u16 {
+        1 << key as u16
+    }
+
+    fn is_moving(&self) -> bool {
+        [
+            Mouse::MoveUp,
+            Mouse::MoveDown,
+            Mouse::MoveLeft,
+            Mouse::MoveRight,
+        ]
+        .into_iter()
+        .any(|key| self.contains(key))
+    }
+
+    fn is_scrolling(&self) -> bool {
+        [
+            Mouse::ScrollUp,
+            Mouse::ScrollDown,
+            Mouse::ScrollLeft,
+            Mouse::ScrollRight,
+        ]
+        .into_iter()
+        .any(|key| self.contains(key))
+    }
+
+    /// Returns the direction, -1, 0 or 1, of a pair of opposite keys.
+    fn axis(&self, negative: Mouse, positive: Mouse) -> i16 {
+        self.contains(positive) as i16 - self.contains(negative) as i16
+    }
+}
+
+/// A press or release of a mouse key, as resolved by the keymap.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MouseKeyEvent {
+    pub key: Mouse,
+    pub pressed: bool,
+}
+
+impl MouseKeyEvent {
+    pub const fn press(key: Mouse) -> Self {
+        Self { key, pressed: true }
+    }
+
+    pub const fn release(key: Mouse) -> Self {
+        Self {
+            key,
+            pressed: false,
+        }
+    }
+}
+
+/// Movement or scrolling of the mouse keys.
+struct Motion {
+    acceleration: Acceleration,
+    /// When the motion started, if it is ongoing.
+    start: Option<Instant>,
+    /// When the next report of the motion is due.
+    next: Instant,
+}
+
+impl Motion {
+    const fn new(acceleration: Acceleration) -> Self {
+        Self {
+            acceleration,
+            start: None,
+            next: Instant::from_ticks(0),
+        }
+    }
+
+    fn update(&mut self, active: bool, now: Instant) {
+        match (active, self.start) {
+            (true, None) => {
+                self.start = Some(now);
+                self.next = now;
+            }
+            (false, Some(_)) => self.start = None,
+            _ => {}
+        }
+    }
+
+    /// Returns the distance to report at `now`, if a report is due.
+    fn step(&mut self, now: Instant) -> Option<u8> {
+        let start = self.start?;
+        if now < self.next {
+            return None;
+        }
+        self.next = now + self.acceleration.interval;
+        Some(self.acceleration.delta(now - start))
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.start.map(|_| self.next)
+    }
+}
+
+/// Mouse keys engine.
+///
+/// It turns the mouse keys held down into mouse reports. Buttons are combined
+/// across every held key, while movement and scrolling accelerate as long as
+/// one of their keys is held. The speed keys step through a table of speeds,
+/// in percent of the accelerated speed.
+///
+/// Like the keymap, the engine never reads the clock: reports must be built
+/// with [`MouseEngine::report`] when [`MouseEngine::next_deadline`] is reached.
+pub struct MouseEngine {
+    keys: MouseKeys,
+    movement: Motion,
+    scroll: Motion,
+    speeds: &'static [u8],
+    speed: usize,
+}
+
+impl MouseEngine {
+    pub const fn new(
+        movement: Acceleration,
+        scroll: Acceleration,
+        speeds: &'static [u8],
+        speed: usize,
+    ) -> Self {
+        assert!(speed < speeds.len(), "Invalid default mouse speed");
+        Self {
+            keys: MouseKeys::new(),
+            movement: Motion::new(movement),
+            scroll: Motion::new(scroll),
+            speeds,
+            speed,
+        }
+    }
+
+    /// Updates the held mouse keys.
+    pub fn set_keys(&mut self, keys: MouseKeys, now: Instant) {
+        let pressed = keys.difference(&self.keys);
+        if pressed.contains(Mouse::SpeedUp) {
+            self.speed = (self.speed + 1).min(self.speeds.len() - 1);
+        }
+        if pressed.contains(Mouse::SpeedDown) {
+            self.speed = self.speed.saturating_sub(1);
+        }
+
+        self.keys = keys;
+        self.movement.update(keys.is_moving(), now);
+        self.scroll.update(keys.is_scrolling(), now);
+    }
+
+    /// Presses or releases a mouse key.
+    pub fn apply(&mut self, event: MouseKeyEvent, now: Instant) {
+        let mut keys = self.keys;
+        if event.pressed {
+            keys.insert(event.key);
+        } else {
+            keys.remove(event.key);
+        }
+        self.set_keys(keys, now);
+    }
+
+    /// Returns when the next report is due, if the mouse is moving or
+    /// scrolling.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [self.movement.deadline(), self.scroll.deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Builds the report at `now`.
+    ///
+    /// Movement and scrolling are only included when their report is due.
+    pub fn report(&mut self, now: Instant) -> WheelMouseReport {
+        let keys = self.keys;
+        let buttons = [Mouse::LeftClick, Mouse::RightClick, Mouse::MiddleClick]
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, key)| keys.contains(key))
+            .fold(0, |buttons, (i, _)| buttons | 1 << i);
+
+        let mut report = WheelMouseReport {
+            buttons,
+            ..Default::default()
+        };
+        if let Some(delta) = self.movement.step(now) {
+            let delta = self.scale(delta);
+            report.x = clamp(keys.axis(Mouse::MoveLeft, Mouse::MoveRight) * delta);
+            report.y = clamp(keys.axis(Mouse::MoveUp, Mouse::MoveDown) * delta);
+        }
+        if let Some(delta) = self.scroll.step(now) {
+            let delta = self.scale(delta);
+            report.vertical_wheel = clamp(keys.axis(Mouse::ScrollDown, Mouse::ScrollUp) * delta);
+            report.horizontal_wheel =
+                clamp(keys.axis(Mouse::ScrollLeft, Mouse::ScrollRight) * delta);
+        }
+        report
+    }
+
+    /// Applies the current speed to a distance.
+    fn scale(&self, delta: u8) -> i16 {
+        // Never slow down to a standstill
+        (delta as u32 * self.speeds[self.speed] as u32 / 100).clamp(1, i8::MAX as u32) as i16
+    }
+}
+
+fn clamp(value: i16) -> i8 {
+    value.clamp(-(i8::MAX as i16), i8::MAX as i16) as i8
+}
@@ -0,0 +1,182 @@
+use heapless::Vec;
+use usbd_human_interface_device::{
+    device::{consumer::MultipleConsumerReport, keyboard::NKROBootKeyboardReport},
+    page::{Consumer, Keyboard},
+};
+
+use crate::config::NKRO_MAX_KEYS;
+
+use super::action::System;
+
+/// Maximum number of keys in a keyboard report.
+///
+/// This leaves room for the eight modifiers on top of the held keys.
+pub const REPORT_MAX_KEYS: usize = NKRO_MAX_KEYS + 8;
+
+/// Size in bytes of a boot keyboard report.
+pub const BOOT_REPORT_SIZE: usize = 8;
+/// Number of keys in a boot keyboard report, on top of the modifiers.
+pub const BOOT_REPORT_KEYS: usize = 6;
+
+/// Usage of the first modifier key, [`Keyboard::LeftControl`].
+const FIRST_MODIFIER: u8 = 0xE0;
+
+/// Returns the bit of a modifier key in a bitmask of modifiers, or `None` if
+/// the key is not a modifier.
+pub fn modifier_bit(key: Keyboard) -> Option<u8> {
+    let index = u8::from(key).checked_sub(FIRST_MODIFIER)?;
+    (index < 8).then(|| 1 << index)
+}
+
+/// Checks if a key is one of the eight modifier keys.
+pub fn is_modifier(key: Keyboard) -> bool {
+    modifier_bit(key).is_some()
+}
+
+/// Content of a keyboard report.
+///
+/// Keys are kept in the order they were pressed and without duplicates.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct KeyboardReport {
+    keys: Vec<Keyboard, REPORT_MAX_KEYS>,
+}
+
+impl KeyboardReport {
+    pub const fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Adds a key to the report.
+    ///
+    /// Returns `false` if the report is full.
+    pub fn press(&mut self, key: Keyboard) -> bool {
+        if key == Keyboard::NoEventIndicated || self.keys.contains(&key) {
+            return true;
+        }
+        self.keys.push(key).is_ok()
+    }
+
+    /// Adds the modifiers of a bitmask to the report.
+    pub fn press_modifiers(&mut self, modifiers: u8) {
+        for index in 0..8 {
+            if modifiers & (1 << index) != 0 {
+                self.press(Keyboard::from(FIRST_MODIFIER + index));
+            }
+        }
+    }
+
+    /// Removes a key from the report.
+    pub fn release(&mut self, key: Keyboard) {
+        self.keys.retain(|&k| k != key);
+    }
+
+    /// Removes every key from the report.
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    pub fn keys(&self) -> &[Keyboard] {
+        &self.keys
+    }
+
+    /// Checks if both reports hold the same keys, regardless of their order.
+    pub fn same_keys(&self, other: &Self) -> bool {
+        self.keys.len() == other.keys.len() && self.keys.iter().all(|k| other.keys.contains(k))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Builds the NKRO report sent to the host.
+    pub fn to_nkro(&self) -> NKROBootKeyboardReport {
+        NKROBootKeyboardReport::new(self.keys.iter().copied())
+    }
+
+    /// Builds the 6KRO report sent to the host in boot protocol.
+    ///
+    /// When more than six keys are held, every key slot holds
+    /// [`Keyboard::ErrorRollOver`] while the modifiers are still reported.
+    pub fn to_boot(&self) -> [u8; BOOT_REPORT_SIZE] {
+        // Modifiers, reserved byte, then the keys
+        let mut report = [0; BOOT_REPORT_SIZE];
+        let mut slots = 0;
+        for &key in &self.keys {
+            if let Some(bit) = modifier_bit(key) {
+                report[0] |= bit;
+            } else if slots < BOOT_REPORT_KEYS {
+                report[2 + slots] = key.into();
+                slots += 1;
+            } else {
+                report[2..].fill(Keyboard::ErrorRollOver.into());
+            }
+        }
+        report
+    }
+}
+
+/// Number of consumer control keys that can be held at the same time.
+pub const CONSUMER_SLOTS: usize = 4;
+
+/// Content of a consumer control report.
+///
+/// Keys are kept in the order they were pressed and without duplicates.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ConsumerReport {
+    keys: Vec<Consumer, CONSUMER_SLOTS>,
+}
+
+impl ConsumerReport {
+    pub const fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Adds a key to the report.
+    ///
+    /// Returns `false` if every slot of the report is taken.
+    pub fn press(&mut self, key: Consumer) -> bool {
+        if key == Consumer::Unassigned || self.keys.contains(&key) {
+            return true;
+        }
+        self.keys.push(key).is_ok()
+    }
+
+    /// Removes a key from the report.
+    pub fn release(&mut self, key: Consumer) {
+        self.keys.retain(|&k| k != key);
+    }
+
+    pub fn keys(&self) -> &[Consumer] {
+        &self.keys
+    }
+
+    /// Builds the multiple-slot report sent to the host.
+    pub fn to_multiple(&self) -> MultipleConsumerReport {
+        let mut codes = [Consumer::Unassigned; CONSUMER_SLOTS];
+        codes[..self.keys.len()].copy_from_slice(&self.keys);
+        MultipleConsumerReport { codes }
+    }
+}
+
+/// Content of a system control report.
+///
+/// The report has a single slot, so only the last system key pressed is sent.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SystemReport {
+    key: Option<System>,
+}
+
+impl SystemReport {
+    pub const fn new(key: Option<System>) -> Self {
+        Self { key }
+    }
+
+    pub fn key(&self) -> Option<System> {
+        self.key
+    }
+
+    /// Builds the report sent to the host.
+    pub fn to_bytes(&self) -> [u8; 1] {
+        [self.key.map_or(0, |key| key as u8)]
+    }
+}
@@ -1,25 +1,55 @@
-use defmt::{info, warn};
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use defmt::{debug, info};
 use embassy_stm32::{
     dma::{ReadableRingBuffer, WritableRingBuffer},
-    peripherals::USB_OTG_HS,
-    usb::Driver,
+    peripherals::TIM1,
 };
-use embassy_usb::class::hid::HidWriter;
-use heapless::Vec;
-use packed_struct::PackedStruct;
-use usbd_human_interface_device::{device::keyboard::NKROBootKeyboardReport, page::Keyboard};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
 
 use crate::{
-    config::{MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER, NKRO_MAX_KEYS},
-    usb::HID_KEYBOARD_WRITER_N,
+    config::{
+        debounce::{DEBOUNCE_ALGORITHM, DEBOUNCE_TIME},
+        scan::{FREQUENCY, SUSPENDED_FREQUENCY},
+        MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER,
+    },
+    usb::usb_device::{UsbState, REMOTE_WAKEUP, REMOTE_WAKEUP_ENABLED, USB_STATE},
+};
+
+use super::{
+    debounce::Debouncer,
+    dma::{DmaTimer, LinkedListWord, LINKED_LIST_LENGTH},
+    event::{EdgeDetector, KEY_EVENTS},
 };
 
-use super::dma::{LinkedListWord, LINKED_LIST_LENGTH};
+/// The state of the matrix after debouncing, updated after every scan.
+pub static MATRIX_STATE: Mutex<
+    CriticalSectionRawMutex,
+    Cell<[LinkedListWord; MATRIX_COLUMNS_NUMBER]>,
+> = Mutex::new(Cell::new([0; MATRIX_COLUMNS_NUMBER]));
+
+/// Number of scans of the matrix since boot.
+pub static SCANS: AtomicU32 = AtomicU32::new(0);
+
+/// Number of key presses published since boot.
+pub static KEY_PRESSES: AtomicU32 = AtomicU32::new(0);
 
-/// Runs a HID writer task.
+/// Runs the matrix scanning task.
+///
+/// Every scan of the matrix is debounced and compared with the previous one.
+/// Each key press and release is then published on [`KEY_EVENTS`], without
+/// ever waiting for a slow subscriber such as a host tool.
+///
+/// While the host is suspended, the matrix is scanned at a lower rate. If the
+/// host enabled remote wakeup, the first key press wakes it up and is not
+/// published, so it does not also type a character.
 #[embassy_executor::task]
 pub async fn keyboard_scan_task(
-    mut _writer: HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_KEYBOARD_WRITER_N>,
+    mut timer: DmaTimer<TIM1>,
     mut write_ring_buffer: WritableRingBuffer<'static, LinkedListWord, LINKED_LIST_LENGTH>,
     mut read_ring_buffer: ReadableRingBuffer<'static, LinkedListWord, LINKED_LIST_LENGTH>,
 ) {
@@ -29,42 +59,68 @@ pub async fn keyboard_scan_task(
     read_ring_buffer.start();
 
     // Pre‐allocate once
-    let mut pressed: Vec<(u8, u8), NKRO_MAX_KEYS> = Vec::new();
     let mut row_buf = [0; MATRIX_COLUMNS_NUMBER];
+    let mut debouncer = Debouncer::<MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER>::new(
+        DEBOUNCE_ALGORITHM,
+        DEBOUNCE_TIME,
+    );
+    let mut edges = EdgeDetector::<MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER>::new();
+    let publisher = KEY_EVENTS
+        .publisher()
+        .expect("Failed to create key event publisher");
+    let mut usb_state = USB_STATE.receiver().expect("Failed to watch the USB state");
+    let mut suspended = false;
+    // The key that woke the host up, whose release must not be published
+    let mut wake_key: Option<(u8, u8)> = None;
 
     loop {
         let _ = read_ring_buffer
             .read_exact(&mut row_buf)
             .await
             .expect("Failed to read from DMA");
+        let now = Instant::now();
+        SCANS.fetch_add(1, Ordering::Relaxed);
 
-        // Get the pressed keys
-        for (col, &bits) in row_buf.iter().enumerate() {
-            for row in 0..MATRIX_ROWS_NUMBER {
-                if (bits & (1 << row)) != 0 {
-                    info!("PA{}, PB{}", col, row);
-                    // Record the pressed key
-                    if pressed.push((row as u8, col as u8)).is_err() {
-                        warn!("More than {} keys pressed", NKRO_MAX_KEYS);
-                    };
-                }
-            }
+        // Slow the scanning down while the host is suspended
+        if let Some(state) = usb_state.try_changed() {
+            suspended = state == UsbState::Suspended;
+            let frequency = if suspended {
+                SUSPENDED_FREQUENCY
+            } else {
+                FREQUENCY
+            };
+            info!("SCAN | Scanning at {} Hz", frequency.0);
+            timer.set_frequency(frequency);
         }
 
-        // TODO: Convert the key positions to mapped keys
-        // pressed
-        //     .iter_mut()
-        //     .map(|key| key.0 = key.0 + 1)
-        //     .collect::<Vec<(u8, u8), NKRO_MAX_KEYS>>();
-
-        // TODO: Send the report to the host
-        // let report = NKROBootKeyboardReport::new([Keyboard::A]).pack().unwrap();
-        // match writer.write(&report).await {
-        //     Ok(()) => {}
-        //     Err(e) => warn!("Failed to send report: {:?}", e),
-        // };
+        // Filter out contact bounce
+        let matrix = debouncer.update(&row_buf, now);
+        MATRIX_STATE.lock(|state| state.set(*matrix));
 
-        // Clear the pressed keys for next scan
-        pressed.clear();
+        // Publish the keys that changed since the last scan
+        for event in edges.update(matrix, now) {
+            debug!("SCAN | {:?}", event);
+            let key = Some((event.row, event.col));
+            if event.pressed
+                && suspended
+                && wake_key.is_none()
+                && REMOTE_WAKEUP_ENABLED.load(Ordering::Relaxed)
+            {
+                info!("SCAN | Waking the host up");
+                wake_key = key;
+                REMOTE_WAKEUP.signal(());
+                continue;
+            }
+            if !event.pressed && wake_key == key {
+                wake_key = None;
+                continue;
+            }
+            if event.pressed {
+                KEY_PRESSES.fetch_add(1, Ordering::Relaxed);
+            }
+            // Never wait for a subscriber, which is told how many events it
+            // missed if it falls behind
+            publisher.publish_immediate(event);
+        }
     }
 }
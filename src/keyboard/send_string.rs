@@ -0,0 +1,112 @@
+use core::fmt;
+
+use defmt::{debug, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Timer};
+use heapless::String;
+use usbd_human_interface_device::page::Keyboard;
+
+use super::{
+    host_layout::{HostLayout, Stroke},
+    keymap::{KeymapCommand, KEYMAP_COMMANDS},
+    settings::settings,
+};
+
+/// Maximum size in bytes of a string sent by the host.
+pub const SEND_STRING_SIZE: usize = 128;
+/// Maximum number of strings waiting to be typed.
+pub const SEND_STRING_QUEUE_SIZE: usize = 2;
+
+/// Channel on which the strings sent by the host wait to be typed by the macro
+/// player.
+pub static SEND_STRINGS: Channel<
+    CriticalSectionRawMutex,
+    String<SEND_STRING_SIZE>,
+    SEND_STRING_QUEUE_SIZE,
+> = Channel::new();
+
+/// Error while queueing a string.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SendStringError {
+    /// The host layout cannot type this character.
+    UnsupportedCharacter(char, HostLayout),
+    /// The string is longer than [`SEND_STRING_SIZE`].
+    TooLong,
+    /// Too many strings are waiting to be typed.
+    QueueFull,
+}
+
+impl fmt::Display for SendStringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendStringError::UnsupportedCharacter(c, layout) => write!(
+                f,
+                "cannot type {:?} with the {} layout",
+                c,
+                layout.name()
+            ),
+            SendStringError::TooLong => write!(f, "string too long"),
+            SendStringError::QueueFull => write!(f, "too many strings waiting to be typed"),
+        }
+    }
+}
+
+/// Queues a string to be typed on the host.
+///
+/// Every character is checked against the host layout of the settings before
+/// the string is queued, so a string is either typed entirely or not at all.
+pub fn send_string(text: &str) -> Result<(), SendStringError> {
+    let layout = settings().host_layout;
+    if let Some(c) = text.chars().find(|&c| layout.strokes(c).is_none()) {
+        return Err(SendStringError::UnsupportedCharacter(c, layout));
+    }
+    let mut string = String::new();
+    string
+        .push_str(text)
+        .map_err(|_| SendStringError::TooLong)?;
+    SEND_STRINGS
+        .try_send(string)
+        .map_err(|_| SendStringError::QueueFull)
+}
+
+/// Types a string sent by the host.
+///
+/// Its keys are pressed and released through [`KEYMAP_COMMANDS`] like the keys
+/// of macros, at the rate of the settings.
+pub async fn type_string(text: &str) {
+    let settings = settings();
+    debug!("SEND | Typing {} bytes", text.len());
+    for c in text.chars() {
+        match settings.host_layout.strokes(c) {
+            Some(strokes) => {
+                for stroke in strokes {
+                    type_stroke(stroke, settings.typing_delay).await;
+                }
+            }
+            // The layout changed since the string was queued
+            None => warn!("SEND | Cannot type {:?}", c),
+        }
+    }
+}
+
+async fn type_stroke(stroke: Stroke, delay: Duration) {
+    let modifiers = [
+        (stroke.shift, Keyboard::LeftShift),
+        (stroke.altgr, Keyboard::RightAlt),
+    ];
+    for (_, modifier) in modifiers.iter().filter(|(held, _)| *held) {
+        send(KeymapCommand::PressMacroKey(*modifier), delay).await;
+    }
+    send(KeymapCommand::PressMacroKey(stroke.key), delay).await;
+    send(KeymapCommand::ReleaseMacroKey(stroke.key), delay).await;
+    for (_, modifier) in modifiers.iter().filter(|(held, _)| *held) {
+        send(KeymapCommand::ReleaseMacroKey(*modifier), delay).await;
+    }
+}
+
+/// Sends a command to the keymap, then waits so the host receives the report.
+async fn send(command: KeymapCommand, delay: Duration) {
+    KEYMAP_COMMANDS.send(command).await;
+    Timer::after(delay).await;
+}
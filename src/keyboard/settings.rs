@@ -0,0 +1,167 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::Duration;
+
+use crate::config::{
+    keymap::{COMBO_TIMEOUT, ONE_SHOT_TIMEOUT, TAPPING_TERM, TAP_DANCE_TERM},
+    send_string::{HOST_LAYOUT, TYPING_DELAY},
+    unicode::UNICODE_MODE,
+};
+
+use super::{
+    host_layout::HostLayout,
+    keymap::{KeymapCommand, KEYMAP_COMMANDS},
+    unicode::UnicodeMode,
+};
+
+/// The settings in use, which start from the configuration or from the
+/// settings saved in flash, and can be changed at runtime by host tools.
+pub static SETTINGS: Mutex<CriticalSectionRawMutex, Cell<Settings>> =
+    Mutex::new(Cell::new(Settings::new()));
+
+/// Signaled when the settings change, so they get saved in flash.
+pub static SETTINGS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Size in bytes of the settings saved in flash, see [`Settings::to_bytes`].
+pub const SETTINGS_SIZE: usize = 32;
+/// Starts the settings saved in flash. It changes with their format, so
+/// settings saved by an older firmware are ignored.
+const SETTINGS_MAGIC: u32 = 0x5752_5301;
+
+/// Settings of the keyboard that can be changed without flashing a firmware.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Settings {
+    /// See [`TAPPING_TERM`].
+    pub tapping_term: Duration,
+    /// See [`TAP_DANCE_TERM`].
+    pub tap_dance_term: Duration,
+    /// See [`ONE_SHOT_TIMEOUT`].
+    pub one_shot_timeout: Duration,
+    /// See [`COMBO_TIMEOUT`].
+    pub combo_timeout: Duration,
+    /// See [`HOST_LAYOUT`].
+    pub host_layout: HostLayout,
+    /// See [`TYPING_DELAY`].
+    pub typing_delay: Duration,
+    /// See [`UNICODE_MODE`].
+    pub unicode_mode: UnicodeMode,
+}
+
+impl Settings {
+    /// Creates the settings of the configuration.
+    pub const fn new() -> Self {
+        Self {
+            tapping_term: TAPPING_TERM,
+            tap_dance_term: TAP_DANCE_TERM,
+            one_shot_timeout: ONE_SHOT_TIMEOUT,
+            combo_timeout: COMBO_TIMEOUT,
+            host_layout: HOST_LAYOUT,
+            typing_delay: TYPING_DELAY,
+            unicode_mode: UNICODE_MODE,
+        }
+    }
+
+    /// Encodes the settings to be saved in flash.
+    ///
+    /// The bytes are a magic number, the durations in milliseconds, then
+    /// the index of the host layout in [`HostLayout::ALL`] and of the Unicode
+    /// mode in [`UnicodeMode::ALL`], all in little-endian. The rest is padded
+    /// like erased flash.
+    pub fn to_bytes(&self) -> [u8; SETTINGS_SIZE] {
+        let mut bytes = [0xFF; SETTINGS_SIZE];
+        let durations = [
+            self.tapping_term,
+            self.tap_dance_term,
+            self.one_shot_timeout,
+            self.combo_timeout,
+            self.typing_delay,
+        ];
+        bytes[..4].copy_from_slice(&SETTINGS_MAGIC.to_le_bytes());
+        for (chunk, duration) in bytes[4..24].chunks_exact_mut(4).zip(durations) {
+            chunk.copy_from_slice(&(duration.as_millis() as u32).to_le_bytes());
+        }
+        bytes[24] = index(&HostLayout::ALL, self.host_layout);
+        bytes[25] = index(&UnicodeMode::ALL, self.unicode_mode);
+        bytes
+    }
+
+    /// Decodes settings encoded by [`Settings::to_bytes`].
+    ///
+    /// Returns `None` if the bytes are not settings of this firmware, such as
+    /// erased flash.
+    pub fn from_bytes(bytes: &[u8; SETTINGS_SIZE]) -> Option<Self> {
+        if bytes[..4] != SETTINGS_MAGIC.to_le_bytes() {
+            return None;
+        }
+        let duration = |i: usize| {
+            let ms = u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+            Duration::from_millis(ms as u64)
+        };
+        Some(Self {
+            tapping_term: duration(4),
+            tap_dance_term: duration(8),
+            one_shot_timeout: duration(12),
+            combo_timeout: duration(16),
+            typing_delay: duration(20),
+            host_layout: *HostLayout::ALL.get(bytes[24] as usize)?,
+            unicode_mode: *UnicodeMode::ALL.get(bytes[25] as usize)?,
+        })
+    }
+}
+
+fn index<T: PartialEq>(all: &[T], value: T) -> u8 {
+    all.iter().position(|v| *v == value).unwrap_or_default() as u8
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the settings in use.
+pub fn settings() -> Settings {
+    SETTINGS.lock(|settings| settings.get())
+}
+
+/// Changes the settings in use and applies them to the keymap.
+///
+/// The settings are then saved in flash by the settings storage task.
+pub async fn set_settings(settings: Settings) {
+    SETTINGS.lock(|current| current.set(settings));
+    SETTINGS_CHANGED.signal(());
+    KEYMAP_COMMANDS
+        .send(KeymapCommand::ApplySettings(settings))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+        let settings = Settings {
+            tapping_term: Duration::from_millis(180),
+            host_layout: HostLayout::Dvorak,
+            typing_delay: Duration::from_millis(12),
+            unicode_mode: UnicodeMode::WinCompose,
+            ..Settings::new()
+        };
+        assert_eq!(Settings::from_bytes(&settings.to_bytes()), Some(settings));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert_eq!(Settings::from_bytes(&[0xFF; SETTINGS_SIZE]), None);
+
+        let mut bytes = Settings::new().to_bytes();
+        bytes[25] = UnicodeMode::ALL.len() as u8;
+        assert_eq!(Settings::from_bytes(&bytes), None);
+    }
+}
@@ -0,0 +1,276 @@
+use core::cell::Cell;
+
+use defmt::{error, info, warn};
+use embassy_stm32::flash::WRITE_SIZE;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::with_timeout;
+
+use crate::{
+    config::{
+        settings::{KEYMAP_SAVE_DELAY, SETTINGS_SAVE_DELAY},
+        LAYOUT, MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER, NUMBER_LAYERS,
+    },
+    usb::{
+        dfu::DfuFlash,
+        disk_files::FileKeys,
+        raw_hid::{decode_key, encode_key, KEY_SIZE},
+    },
+};
+
+use super::{
+    layers::Layers,
+    settings::{settings, Settings, SETTINGS, SETTINGS_CHANGED, SETTINGS_SIZE},
+};
+
+extern "C" {
+    static __settings_start: u32;
+    static __settings_end: u32;
+    static __keymap_start: u32;
+    static __keymap_end: u32;
+}
+
+/// Signaled when host tools edit the live keymap, so it gets saved in flash.
+pub static KEYMAP_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Keys of the keymap saved in flash, where `None` keeps the key of [`LAYOUT`].
+pub type SavedKeys = FileKeys<NUMBER_LAYERS, MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER>;
+
+/// Keys of the live keymap, copied by [`update_live_keys`].
+static LIVE_KEYS: Mutex<CriticalSectionRawMutex, Cell<SavedKeys>> = Mutex::new(Cell::new(
+    [[[None; MATRIX_COLUMNS_NUMBER]; MATRIX_ROWS_NUMBER]; NUMBER_LAYERS],
+));
+
+/// Starts the keymap saved in flash. It changes with its format, so a keymap
+/// saved by an older firmware is ignored.
+const KEYMAP_MAGIC: u32 = 0x5752_4B01;
+/// Size in bytes of the header of the saved keymap: the magic, the number of
+/// layers, rows and columns, and the hash of [`LAYOUT`].
+const KEYMAP_HEADER_SIZE: usize = 12;
+/// Size in bytes of the keymap saved in flash, padded to the write size of
+/// the flash.
+const KEYMAP_SIZE: usize = (KEYMAP_HEADER_SIZE
+    + NUMBER_LAYERS * MATRIX_ROWS_NUMBER * MATRIX_COLUMNS_NUMBER * KEY_SIZE)
+    .div_ceil(WRITE_SIZE)
+    * WRITE_SIZE;
+
+/// Returns the range of the settings partition described in `memory.x`, as
+/// offsets from the start of the flash.
+fn settings_partition() -> (u32, u32) {
+    // SAFETY: Only the addresses of the symbols are used, not their value
+    unsafe {
+        (
+            &__settings_start as *const u32 as u32,
+            &__settings_end as *const u32 as u32,
+        )
+    }
+}
+
+/// Returns the range of the keymap partition described in `memory.x`, as
+/// offsets from the start of the flash.
+fn keymap_partition() -> (u32, u32) {
+    // SAFETY: Only the addresses of the symbols are used, not their value
+    unsafe {
+        (
+            &__keymap_start as *const u32 as u32,
+            &__keymap_end as *const u32 as u32,
+        )
+    }
+}
+
+/// Loads the settings saved in flash, if any.
+///
+/// Must be called before the tasks using the settings are spawned.
+pub fn load_settings(flash: &DfuFlash) {
+    let (start, _) = settings_partition();
+    let mut bytes = [0; SETTINGS_SIZE];
+    let result = flash.lock(|flash| flash.borrow_mut().blocking_read(start, &mut bytes));
+    match result.map(|_| Settings::from_bytes(&bytes)) {
+        Ok(Some(settings)) => {
+            SETTINGS.lock(|current| current.set(settings));
+            info!("SETTINGS | Loaded the settings saved in flash");
+        }
+        Ok(None) => info!("SETTINGS | No settings saved, using the configuration"),
+        Err(e) => error!("SETTINGS | Failed to read the settings: {:?}", e),
+    }
+}
+
+/// Saves the settings in flash once they stop changing.
+#[embassy_executor::task]
+pub async fn settings_storage_task(flash: &'static DfuFlash) -> ! {
+    loop {
+        SETTINGS_CHANGED.wait().await;
+        while with_timeout(SETTINGS_SAVE_DELAY, SETTINGS_CHANGED.wait())
+            .await
+            .is_ok()
+        {}
+        save_settings(flash, settings());
+    }
+}
+
+fn save_settings(flash: &DfuFlash, settings: Settings) {
+    let (start, end) = settings_partition();
+    let bytes = settings.to_bytes();
+    let result = flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+
+        // Spare the flash if the settings went back to the saved ones
+        let mut saved = [0; SETTINGS_SIZE];
+        flash.blocking_read(start, &mut saved)?;
+        if saved == bytes {
+            return Ok(false);
+        }
+
+        flash.blocking_erase(start, end)?;
+        flash.blocking_write(start, &bytes)?;
+        Ok(true)
+    });
+    match result {
+        Ok(true) => info!("SETTINGS | Saved the settings in flash"),
+        Ok(false) => {}
+        Err(e) => error!("SETTINGS | Failed to save the settings: {:?}", e),
+    }
+}
+
+/// Loads the keymap saved in flash over [`LAYOUT`].
+///
+/// The saved keymap is ignored if [`LAYOUT`] changed since it was saved, so
+/// flashing a firmware with another layout starts from that layout.
+pub fn load_keymap(
+    flash: &DfuFlash,
+) -> Layers<NUMBER_LAYERS, MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER> {
+    let mut layers = LAYOUT;
+    let (start, _) = keymap_partition();
+    let mut bytes = [0; KEYMAP_SIZE];
+    let result = flash.lock(|flash| flash.borrow_mut().blocking_read(start, &mut bytes));
+    if let Err(e) = result {
+        error!("KEYMAP | Failed to read the keymap: {:?}", e);
+        return layers;
+    }
+    if bytes[..KEYMAP_HEADER_SIZE] != keymap_header() {
+        info!("KEYMAP | No keymap saved for this layout, using the configuration");
+        return layers;
+    }
+
+    let mut keys = bytes[KEYMAP_HEADER_SIZE..].chunks_exact(KEY_SIZE);
+    for layer in 0..NUMBER_LAYERS {
+        for row in 0..MATRIX_ROWS_NUMBER {
+            for col in 0..MATRIX_COLUMNS_NUMBER {
+                // Erased keys are the keys that only exist in the firmware
+                let bytes = keys.next().unwrap_or_default();
+                if bytes.iter().all(|&byte| byte == 0xFF) {
+                    continue;
+                }
+                match decode_key(bytes) {
+                    Ok(key) => layers.set_key_from_layer(layer, row, col, key),
+                    Err(_) => warn!(
+                        "KEYMAP | Invalid saved key at layer {}, row {}, col {}",
+                        layer, row, col
+                    ),
+                }
+            }
+        }
+    }
+    info!("KEYMAP | Loaded the keymap saved in flash");
+    layers
+}
+
+/// Saves the keymap in flash, to be loaded by [`load_keymap`] on the next boot.
+pub fn save_keymap(flash: &DfuFlash, keys: &SavedKeys) {
+    let (start, end) = keymap_partition();
+    let mut bytes = [0xFF; KEYMAP_SIZE];
+    bytes[..KEYMAP_HEADER_SIZE].copy_from_slice(&keymap_header());
+    let chunks = bytes[KEYMAP_HEADER_SIZE..].chunks_exact_mut(KEY_SIZE);
+    for (chunk, key) in chunks.zip(keys.iter().flatten().flatten()) {
+        if let Some(bytes) = key.and_then(|key| encode_key(key).ok()) {
+            chunk.copy_from_slice(&bytes);
+        }
+    }
+
+    let result = flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+
+        // Spare the flash if the keymap went back to the saved one
+        let mut saved = [0; KEYMAP_SIZE];
+        flash.blocking_read(start, &mut saved)?;
+        if saved == bytes {
+            return Ok(false);
+        }
+
+        flash.blocking_erase(start, end)?;
+        flash.blocking_write(start, &bytes)?;
+        Ok(true)
+    });
+    match result {
+        Ok(true) => info!("KEYMAP | Saved the keymap in flash"),
+        Ok(false) => {}
+        Err(e) => error!("KEYMAP | Failed to save the keymap: {:?}", e),
+    }
+}
+
+/// Saves the live keymap in flash once host tools stop editing it.
+#[embassy_executor::task]
+pub async fn keymap_storage_task(flash: &'static DfuFlash) -> ! {
+    loop {
+        KEYMAP_CHANGED.wait().await;
+        while with_timeout(KEYMAP_SAVE_DELAY, KEYMAP_CHANGED.wait())
+            .await
+            .is_ok()
+        {}
+        save_keymap(flash, &read_keys());
+    }
+}
+
+/// Copies the keys of the live keymap, to be read by [`read_keys`].
+///
+/// Must be called by the task owning the live keymap whenever it is edited.
+/// The keys that cannot be encoded only exist in the firmware and are `None`.
+pub fn update_live_keys(layers: &Layers<NUMBER_LAYERS, MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER>) {
+    let mut keys = [[[None; MATRIX_COLUMNS_NUMBER]; MATRIX_ROWS_NUMBER]; NUMBER_LAYERS];
+    for (layer, rows) in keys.iter_mut().enumerate() {
+        for (row, cols) in rows.iter_mut().enumerate() {
+            for (col, key) in cols.iter_mut().enumerate() {
+                let action = layers.get_layer(layer)[(row, col)];
+                *key = encode_key(action).is_ok().then_some(action);
+            }
+        }
+    }
+    LIVE_KEYS.lock(|live| live.set(keys));
+}
+
+/// Reads the live keymap as of its last edit.
+pub fn read_keys() -> SavedKeys {
+    LIVE_KEYS.lock(|live| live.get())
+}
+
+/// Returns the header of a keymap saved for this firmware.
+fn keymap_header() -> [u8; KEYMAP_HEADER_SIZE] {
+    let mut header = [0; KEYMAP_HEADER_SIZE];
+    header[..4].copy_from_slice(&KEYMAP_MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&[
+        NUMBER_LAYERS as u8,
+        MATRIX_ROWS_NUMBER as u8,
+        MATRIX_COLUMNS_NUMBER as u8,
+        0,
+    ]);
+    header[8..].copy_from_slice(&layout_hash().to_le_bytes());
+    header
+}
+
+/// Hashes the encoded keys of [`LAYOUT`] with FNV-1a.
+fn layout_hash() -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for layer in 0..NUMBER_LAYERS {
+        for row in 0..MATRIX_ROWS_NUMBER {
+            for col in 0..MATRIX_COLUMNS_NUMBER {
+                let key = LAYOUT.get_layer(layer)[(row, col)];
+                for byte in encode_key(key).unwrap_or([0xFF; KEY_SIZE]) {
+                    hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+                }
+            }
+        }
+    }
+    hash
+}
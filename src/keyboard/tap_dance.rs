@@ -0,0 +1,226 @@
+use embassy_time::{Duration, Instant};
+
+use super::{
+    action::{KeyAction, TapDance},
+    event::KeyEvent,
+};
+
+/// Outcome of a tap dance key.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TapDanceDecision {
+    /// The key was tapped this many times.
+    Tap(usize),
+    /// The key was held after being tapped this many times.
+    Hold(usize),
+}
+
+/// A tap dance key that is counting its taps.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PendingTapDance {
+    pub row: u8,
+    pub col: u8,
+    pub dance: &'static TapDance,
+    /// Number of times the key was tapped so far.
+    pub count: usize,
+    /// Whether the key is currently held down.
+    pub pressed: bool,
+    /// When the tapping term of the current press or release expires.
+    pub deadline: Instant,
+}
+
+impl PendingTapDance {
+    /// Starts counting the taps of a key pressed at `at`.
+    pub fn new(row: u8, col: u8, dance: &'static TapDance, at: Instant, term: Duration) -> Self {
+        Self {
+            row,
+            col,
+            dance,
+            count: 0,
+            pressed: true,
+            deadline: at + term,
+        }
+    }
+
+    /// Checks if `event` concerns the tap dance key itself.
+    pub fn is_same_key(&self, event: &KeyEvent) -> bool {
+        (self.row, self.col) == (event.row, event.col)
+    }
+
+    /// Updates the count with an event of the key itself.
+    ///
+    /// Returns a decision when no further tap could change the outcome.
+    pub fn update(&mut self, event: &KeyEvent, term: Duration) -> Option<TapDanceDecision> {
+        self.deadline = event.at + term;
+        self.pressed = event.pressed;
+        if event.pressed {
+            return None;
+        }
+
+        self.count += 1;
+        (self.count >= self.dance.max_taps()).then_some(TapDanceDecision::Tap(self.count))
+    }
+
+    /// Decides the outcome of the key at `now`.
+    ///
+    /// Returns a decision once the tapping term expired.
+    pub fn decide_at(&self, now: Instant) -> Option<TapDanceDecision> {
+        (now >= self.deadline).then(|| self.interrupt())
+    }
+
+    /// Decides the outcome of the key when another key is pressed.
+    pub fn interrupt(&self) -> TapDanceDecision {
+        if self.pressed {
+            TapDanceDecision::Hold(self.count)
+        } else {
+            TapDanceDecision::Tap(self.count)
+        }
+    }
+
+    /// Returns the key the tap dance key acts as once resolved.
+    pub fn resolve(&self, decision: TapDanceDecision) -> KeyAction {
+        let action = match decision {
+            TapDanceDecision::Tap(count) => self.dance.tap(count),
+            TapDanceDecision::Hold(count) => self.dance.hold(count),
+        };
+        action.map_or(KeyAction::NoOp, KeyAction::Single)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::Keyboard::{
+        self, LeftAlt, LeftControl, LeftShift, A, B, C, X,
+    };
+
+    use super::*;
+    use crate::keyboard::{
+        action::{k, repeat, td},
+        keymap::{
+            tests::{at, reports},
+            Keymap,
+        },
+        layers::{Layer, Layers},
+    };
+
+    /// Sends A or B when tapped once or twice, and holds Shift or Control when
+    /// held after zero or one tap.
+    static DANCE: TapDance = TapDance {
+        taps: &[k(A), k(B)],
+        holds: &[k(LeftShift), k(LeftControl)],
+    };
+
+    const TERM: Duration = Duration::from_millis(200);
+
+    fn key(key: Keyboard) -> KeyAction {
+        KeyAction::Single(k(key))
+    }
+
+    /// The tap dance key, a repeat key of Alt and X, and C.
+    fn keymap() -> Keymap<1, 1, 3> {
+        Keymap::new(Layers::new([Layer::new([[
+            td(&DANCE),
+            repeat(k(LeftAlt), k(X)),
+            key(C),
+        ]])]))
+    }
+
+    #[test]
+    fn tap_count() {
+        let mut dance = PendingTapDance::new(0, 0, &DANCE, at(0), TERM);
+        assert_eq!(dance.update(&KeyEvent::release(0, 0, at(50)), TERM), None);
+        assert_eq!(dance.decide_at(at(249)), None);
+        assert_eq!(dance.decide_at(at(250)), Some(TapDanceDecision::Tap(1)));
+        assert_eq!(dance.resolve(TapDanceDecision::Tap(1)), key(A));
+
+        // The last tap of the table decides without waiting
+        assert_eq!(dance.update(&KeyEvent::press(0, 0, at(100)), TERM), None);
+        assert_eq!(
+            dance.update(&KeyEvent::release(0, 0, at(150)), TERM),
+            Some(TapDanceDecision::Tap(2))
+        );
+        assert_eq!(dance.resolve(TapDanceDecision::Tap(2)), key(B));
+        assert_eq!(dance.resolve(TapDanceDecision::Tap(3)), key(B));
+    }
+
+    #[test]
+    fn hold() {
+        let mut dance = PendingTapDance::new(0, 0, &DANCE, at(0), TERM);
+        assert_eq!(dance.decide_at(at(200)), Some(TapDanceDecision::Hold(0)));
+        assert_eq!(dance.resolve(TapDanceDecision::Hold(0)), key(LeftShift));
+
+        dance.update(&KeyEvent::release(0, 0, at(50)), TERM);
+        dance.update(&KeyEvent::press(0, 0, at(100)), TERM);
+        assert_eq!(dance.decide_at(at(299)), None);
+        assert_eq!(dance.decide_at(at(300)), Some(TapDanceDecision::Hold(1)));
+        assert_eq!(dance.resolve(TapDanceDecision::Hold(1)), key(LeftControl));
+        // Without a hold action, the next tap action is held
+        assert_eq!(dance.resolve(TapDanceDecision::Hold(2)), key(B));
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut dance = PendingTapDance::new(0, 0, &DANCE, at(0), TERM);
+        assert_eq!(dance.interrupt(), TapDanceDecision::Hold(0));
+        dance.update(&KeyEvent::release(0, 0, at(50)), TERM);
+        assert_eq!(dance.interrupt(), TapDanceDecision::Tap(1));
+    }
+
+    #[test]
+    fn keymap_tap() {
+        let mut keymap = keymap();
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::release(0, 0, at(50)));
+        assert_eq!(keymap.next_deadline(), Some(at(250)));
+        keymap.tick(at(250));
+        keymap.process(KeyEvent::press(0, 0, at(300)));
+        keymap.process(KeyEvent::release(0, 0, at(350)));
+        keymap.process(KeyEvent::press(0, 0, at(400)));
+        keymap.process(KeyEvent::release(0, 0, at(450)));
+        assert_eq!(keymap.next_deadline(), None);
+        assert_eq!(reports(&mut keymap), [vec![A], vec![], vec![B], vec![]]);
+    }
+
+    #[test]
+    fn keymap_hold_on_last_tap() {
+        let mut keymap = keymap();
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::release(0, 0, at(50)));
+        keymap.process(KeyEvent::press(0, 0, at(100)));
+        keymap.tick(at(300));
+        keymap.process(KeyEvent::press(0, 2, at(310)));
+        keymap.process(KeyEvent::release(0, 0, at(400)));
+        assert_eq!(
+            reports(&mut keymap),
+            [vec![LeftControl], vec![LeftControl, C], vec![C]]
+        );
+    }
+
+    #[test]
+    fn keymap_interrupt() {
+        // Another key pressed after a tap decides the count so far
+        let mut keymap = keymap();
+        keymap.process(KeyEvent::press(0, 0, at(0)));
+        keymap.process(KeyEvent::release(0, 0, at(50)));
+        keymap.process(KeyEvent::press(0, 2, at(60)));
+        assert_eq!(reports(&mut keymap), [vec![A], vec![], vec![C]]);
+    }
+
+    #[test]
+    fn keymap_repeat() {
+        // Tapped then held, the repeat key holds its tap action
+        let mut keymap = keymap();
+        keymap.process(KeyEvent::press(0, 1, at(0)));
+        keymap.process(KeyEvent::release(0, 1, at(50)));
+        keymap.process(KeyEvent::press(0, 1, at(100)));
+        keymap.tick(at(500));
+        keymap.process(KeyEvent::release(0, 1, at(600)));
+        keymap.process(KeyEvent::press(0, 1, at(1000)));
+        keymap.tick(at(1200));
+        assert_eq!(
+            reports(&mut keymap),
+            [vec![X], vec![], vec![X], vec![], vec![LeftAlt]]
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use heapless::Vec;
+use usbd_human_interface_device::page::Keyboard;
+
+use super::{
+    host_layout::{HostLayout, Stroke},
+    macros::MacroStep,
+};
+
+/// Maximum number of macro steps typing a character, which is six hexadecimal
+/// digits typed with shift and AltGr after the Ctrl+Shift+U of Linux.
+pub const MAX_UNICODE_STEPS: usize = 40;
+
+/// The macro steps typing a character.
+pub type UnicodeSteps = Vec<MacroStep, MAX_UNICODE_STEPS>;
+
+/// How the host lets a keyboard type a character from its code point.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum UnicodeMode {
+    /// Ctrl+Shift+U, the code point in hexadecimal, then space, as supported
+    /// by IBus and GTK.
+    #[default]
+    Linux,
+    /// The code point in hexadecimal while Option is held, with the Unicode
+    /// Hex Input source of macOS selected.
+    ///
+    /// Characters past U+FFFF are typed as their two UTF-16 surrogates.
+    MacOs,
+    /// The compose key, `u`, the code point in hexadecimal, then enter, with
+    /// WinCompose and its default compose key, the right alt key.
+    WinCompose,
+    /// `+` on the keypad then the code point in hexadecimal while alt is held,
+    /// which Windows only supports after setting the `EnableHexNumpad` value of
+    /// `HKEY_CURRENT_USER\Control Panel\Input Method` to `1`.
+    ///
+    /// Characters past U+FFFF cannot be typed.
+    WindowsAltCode,
+}
+
+impl UnicodeMode {
+    pub const ALL: [UnicodeMode; 4] = [
+        UnicodeMode::Linux,
+        UnicodeMode::MacOs,
+        UnicodeMode::WinCompose,
+        UnicodeMode::WindowsAltCode,
+    ];
+
+    /// Short name of the mode, as typed in the shell.
+    pub const fn name(self) -> &'static str {
+        match self {
+            UnicodeMode::Linux => "linux",
+            UnicodeMode::MacOs => "macos",
+            UnicodeMode::WinCompose => "wincompose",
+            UnicodeMode::WindowsAltCode => "windows",
+        }
+    }
+
+    /// Returns the mode with a short name, see [`UnicodeMode::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    /// Returns the macro steps typing a character, or `None` if the mode
+    /// cannot type it.
+    ///
+    /// The letters and digits are typed with the keys of the host layout,
+    /// except on macOS where Unicode Hex Input replaces the layout.
+    pub fn steps(self, c: char, layout: HostLayout) -> Option<UnicodeSteps> {
+        let mut steps = Vec::new();
+        let code = c as u32;
+        match self {
+            UnicodeMode::Linux => {
+                push(&mut steps, MacroStep::Press(Keyboard::LeftControl))?;
+                push(&mut steps, MacroStep::Press(Keyboard::LeftShift))?;
+                push(&mut steps, MacroStep::Tap(key(layout, 'u')?))?;
+                push(&mut steps, MacroStep::Release(Keyboard::LeftShift))?;
+                push(&mut steps, MacroStep::Release(Keyboard::LeftControl))?;
+                hex(&mut steps, code, 1, layout)?;
+                push(&mut steps, MacroStep::Tap(Keyboard::Space))?;
+            }
+            UnicodeMode::MacOs => {
+                push(&mut steps, MacroStep::Press(Keyboard::LeftAlt))?;
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    hex(&mut steps, *unit as u32, 4, HostLayout::Us)?;
+                }
+                push(&mut steps, MacroStep::Release(Keyboard::LeftAlt))?;
+            }
+            UnicodeMode::WinCompose => {
+                push(&mut steps, MacroStep::Tap(Keyboard::RightAlt))?;
+                push(&mut steps, MacroStep::Tap(key(layout, 'u')?))?;
+                hex(&mut steps, code, 1, layout)?;
+                push(&mut steps, MacroStep::Tap(Keyboard::ReturnEnter))?;
+            }
+            UnicodeMode::WindowsAltCode => {
+                if code > 0xFFFF {
+                    return None;
+                }
+                push(&mut steps, MacroStep::Press(Keyboard::LeftAlt))?;
+                push(&mut steps, MacroStep::Tap(Keyboard::KeypadAdd))?;
+                for digit in hex_digits(code, 1) {
+                    match digit {
+                        // The digits must be typed on the keypad
+                        '0' => push(&mut steps, MacroStep::Tap(Keyboard::Keypad0))?,
+                        '1'..='9' => {
+                            let key = u8::from(Keyboard::Keypad1) + (digit as u8 - b'1');
+                            push(&mut steps, MacroStep::Tap(Keyboard::from(key)))?;
+                        }
+                        _ => push(&mut steps, MacroStep::Tap(key(layout, digit)?))?,
+                    }
+                }
+                push(&mut steps, MacroStep::Release(Keyboard::LeftAlt))?;
+            }
+        }
+        Some(steps)
+    }
+}
+
+fn push(steps: &mut UnicodeSteps, step: MacroStep) -> Option<()> {
+    steps.push(step).ok()
+}
+
+/// Returns the key typing a character without modifiers on a layout.
+fn key(layout: HostLayout, c: char) -> Option<Keyboard> {
+    match layout.strokes(c)?.as_slice() {
+        [Stroke {
+            key,
+            shift: false,
+            altgr: false,
+        }] => Some(*key),
+        _ => None,
+    }
+}
+
+/// Adds the steps typing a number in lowercase hexadecimal, with at least
+/// `width` digits.
+fn hex(steps: &mut UnicodeSteps, number: u32, width: usize, layout: HostLayout) -> Option<()> {
+    for digit in hex_digits(number, width) {
+        for stroke in layout.strokes(digit)? {
+            if stroke.shift {
+                push(steps, MacroStep::Press(Keyboard::LeftShift))?;
+            }
+            if stroke.altgr {
+                push(steps, MacroStep::Press(Keyboard::RightAlt))?;
+            }
+            push(steps, MacroStep::Tap(stroke.key))?;
+            if stroke.altgr {
+                push(steps, MacroStep::Release(Keyboard::RightAlt))?;
+            }
+            if stroke.shift {
+                push(steps, MacroStep::Release(Keyboard::LeftShift))?;
+            }
+        }
+    }
+    Some(())
+}
+
+/// Returns the lowercase hexadecimal digits of a number, with at least `width`
+/// digits.
+fn hex_digits(number: u32, width: usize) -> impl Iterator<Item = char> {
+    let len = (8 - number.leading_zeros() as usize / 4).max(width);
+    (0..len)
+        .rev()
+        .map(move |i| char::from_digit(number >> (4 * i) & 0xF, 16).unwrap_or('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MacroStep::{Press, Release, Tap};
+
+    const LEFT_CONTROL: Keyboard = Keyboard::LeftControl;
+    const LEFT_SHIFT: Keyboard = Keyboard::LeftShift;
+    const LEFT_ALT: Keyboard = Keyboard::LeftAlt;
+
+    #[test]
+    fn hex_digits_are_padded() {
+        let digits = |number, width| hex_digits(number, width).collect::<Vec<char, 8>>();
+        assert_eq!(digits(0xE9, 1), ['e', '9']);
+        assert_eq!(digits(0xE9, 4), ['0', '0', 'e', '9']);
+        assert_eq!(digits(0x1F600, 4), ['1', 'f', '6', '0', '0']);
+        assert_eq!(digits(0, 1), ['0']);
+    }
+
+    #[test]
+    fn linux() {
+        let expected = [
+            Press(LEFT_CONTROL),
+            Press(LEFT_SHIFT),
+            Tap(Keyboard::U),
+            Release(LEFT_SHIFT),
+            Release(LEFT_CONTROL),
+            Tap(Keyboard::E),
+            Tap(Keyboard::Keyboard9),
+            Tap(Keyboard::Space),
+        ];
+        assert_eq!(
+            UnicodeMode::Linux.steps('é', HostLayout::Us).as_deref(),
+            Some(&expected[..])
+        );
+
+        // The keys follow the host layout
+        let expected = [
+            Press(LEFT_CONTROL),
+            Press(LEFT_SHIFT),
+            Tap(Keyboard::F),
+            Release(LEFT_SHIFT),
+            Release(LEFT_CONTROL),
+            Tap(Keyboard::D),
+            Tap(Keyboard::Keyboard9),
+            Tap(Keyboard::Space),
+        ];
+        assert_eq!(
+            UnicodeMode::Linux.steps('é', HostLayout::Dvorak).as_deref(),
+            Some(&expected[..])
+        );
+
+        // Digits are shifted on AZERTY
+        let expected = [
+            Press(LEFT_CONTROL),
+            Press(LEFT_SHIFT),
+            Tap(Keyboard::U),
+            Release(LEFT_SHIFT),
+            Release(LEFT_CONTROL),
+            Tap(Keyboard::E),
+            Press(LEFT_SHIFT),
+            Tap(Keyboard::Keyboard9),
+            Release(LEFT_SHIFT),
+            Tap(Keyboard::Space),
+        ];
+        assert_eq!(
+            UnicodeMode::Linux
+                .steps('é', HostLayout::FrenchAzerty)
+                .as_deref(),
+            Some(&expected[..])
+        );
+
+        // The longest code points fit
+        assert!(UnicodeMode::Linux
+            .steps('\u{10FFFF}', HostLayout::FrenchAzerty)
+            .is_some());
+    }
+
+    #[test]
+    fn macos() {
+        let expected = [
+            Press(LEFT_ALT),
+            Tap(Keyboard::Keyboard0),
+            Tap(Keyboard::Keyboard0),
+            Tap(Keyboard::E),
+            Tap(Keyboard::Keyboard9),
+            Release(LEFT_ALT),
+        ];
+        assert_eq!(
+            UnicodeMode::MacOs.steps('é', HostLayout::Us).as_deref(),
+            Some(&expected[..])
+        );
+
+        // Unicode Hex Input ignores the host layout, and needs surrogates past
+        // U+FFFF
+        let expected = [
+            Press(LEFT_ALT),
+            Tap(Keyboard::D),
+            Tap(Keyboard::Keyboard8),
+            Tap(Keyboard::Keyboard3),
+            Tap(Keyboard::D),
+            Tap(Keyboard::D),
+            Tap(Keyboard::E),
+            Tap(Keyboard::Keyboard0),
+            Tap(Keyboard::Keyboard0),
+            Release(LEFT_ALT),
+        ];
+        assert_eq!(
+            UnicodeMode::MacOs
+                .steps('😀', HostLayout::FrenchAzerty)
+                .as_deref(),
+            Some(&expected[..])
+        );
+    }
+
+    #[test]
+    fn wincompose() {
+        let expected = [
+            Tap(Keyboard::RightAlt),
+            Tap(Keyboard::U),
+            Tap(Keyboard::Keyboard1),
+            Tap(Keyboard::F),
+            Tap(Keyboard::Keyboard6),
+            Tap(Keyboard::Keyboard0),
+            Tap(Keyboard::Keyboard0),
+            Tap(Keyboard::ReturnEnter),
+        ];
+        assert_eq!(
+            UnicodeMode::WinCompose
+                .steps('😀', HostLayout::Us)
+                .as_deref(),
+            Some(&expected[..])
+        );
+    }
+
+    #[test]
+    fn windows_alt_code() {
+        let expected = [
+            Press(LEFT_ALT),
+            Tap(Keyboard::KeypadAdd),
+            Tap(Keyboard::Keypad2),
+            Tap(Keyboard::Keypad0),
+            Tap(Keyboard::A),
+            Tap(Keyboard::C),
+            Release(LEFT_ALT),
+        ];
+        assert_eq!(
+            UnicodeMode::WindowsAltCode
+                .steps('€', HostLayout::Us)
+                .as_deref(),
+            Some(&expected[..])
+        );
+
+        // The letters follow the host layout
+        let expected = [
+            Press(LEFT_ALT),
+            Tap(Keyboard::KeypadAdd),
+            Tap(Keyboard::Keypad2),
+            Tap(Keyboard::Keypad0),
+            Tap(Keyboard::Q),
+            Tap(Keyboard::C),
+            Release(LEFT_ALT),
+        ];
+        assert_eq!(
+            UnicodeMode::WindowsAltCode
+                .steps('€', HostLayout::FrenchAzerty)
+                .as_deref(),
+            Some(&expected[..])
+        );
+
+        assert_eq!(
+            UnicodeMode::WindowsAltCode.steps('😀', HostLayout::Us),
+            None
+        );
+    }
+
+    #[test]
+    fn names() {
+        for mode in UnicodeMode::ALL {
+            assert_eq!(UnicodeMode::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(UnicodeMode::from_name("win"), None);
+    }
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(impl_trait_in_assoc_type)]
 
 pub mod config;
@@ -2,23 +2,36 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 
-use core::mem::forget;
-
 use embassy_executor::Spawner;
 use embassy_stm32::{
     gpio::{Input, Level, Output, Pull, Speed},
     Config,
 };
 use wave_rs::{
-    config::{scan::*, MATRIX_COLUMNS, MATRIX_ROWS},
+    config::{
+        keymap::COMBOS, msc::MSC_ENABLED, scan::*, webusb::WEBUSB_ENABLED, MATRIX_COLUMNS,
+        MATRIX_ROWS,
+    },
     keyboard::{
         dma::{configure_dma_scan, DmaTimer},
+        indicators::led_indicators_task,
+        macros::macro_task,
+        mouse::mouse_writer_task,
         scan::keyboard_scan_task,
+        storage::{keymap_storage_task, load_keymap, load_settings, settings_storage_task},
     },
     usb::{
-        hid::{hid_keyboard_reader_task, init_hid_keyboard},
+        dfu::{dfu_mark_booted_task, init_dfu},
+        hid::{
+            hid_consumer_writer_task, hid_keyboard_reader_task, hid_keyboard_writer_task,
+            hid_raw_task, hid_system_writer_task, init_hid_extra, init_hid_keyboard, init_hid_raw,
+        },
+        midi::{init_midi, midi_writer_task},
+        msc::{init_msc, msc_task},
+        raw_hid::KeyInterfaces,
         serial::{init_serial, usb_serial_task},
         usb_device::{init_usb, usb_task},
+        webusb::{init_webusb, webusb_task},
     },
 };
 
@@ -107,6 +120,13 @@ async fn main(spawner: Spawner) {
         panic!("Failed to initialize GPIO matrix. This should never happen.");
     }
 
+    // Indicators of the host LEDs, see `config::leds`
+    let led_pins = [
+        Output::new(p.PC7, Level::Low, Speed::Low),
+        Output::new(p.PB7, Level::Low, Speed::Low),
+        Output::new(p.PG2, Level::Low, Speed::Low),
+    ];
+
     // =========================================================================
     // USB Builder
     // =========================================================================
@@ -116,28 +136,64 @@ async fn main(spawner: Spawner) {
     // =========================================================================
     // Setup DFU
     // =========================================================================
-    // defmt::info!("Configuring DFU...");
-    // let flash = Flash::new_blocking(p.FLASH);
-    // let flash = Mutex::new(RefCell::new(flash));
-    //
-    // let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
-    // let mut magic = AlignedBuffer([0; WRITE_SIZE]);
-    // let mut firmware_state = BlockingFirmwareState::from_config(config, &mut magic.0);
-    // firmware_state.mark_booted().expect("Failed to mark booted");
-    // let mut state = Control::new(firmware_state, DfuAttributes::CAN_DOWNLOAD);
-    // usb_dfu::<_, _, ResetImmediate>(&mut builder, &mut state, Duration::from_millis(2500));
+    defmt::info!("Configuring DFU...");
+    let dfu_flash = init_dfu(&mut builder, p.FLASH).await;
+
+    // =========================================================================
+    // Load the settings and the keymap
+    // =========================================================================
+    defmt::info!("Loading settings...");
+    load_settings(dfu_flash);
+    let layers = load_keymap(dfu_flash);
 
     // =========================================================================
     // Initialize USB Peripherals
     // =========================================================================
     defmt::info!("Creating USB classes...");
+    // The IN endpoints of the classes are counted at compile time, see
+    // `usb::USB_USED_IN_ENDPOINTS`
 
     // Serial
     let class_serial = init_serial(&mut builder).await;
 
     // HID
     let (hid_keyboard_reader, hid_keyboard_writer) = init_hid_keyboard(&mut builder).await;
-    // let hid_mouse_writer = init_hid_mouse(&mut builder).await;
+    // The mouse is only exposed to the host if the keymap uses it, including
+    // the keymap saved in flash
+    let mouse_enabled =
+        layers.has_mouse_actions() || COMBOS.iter().any(|combo| combo.action.has_mouse_action());
+    let hid_extra_writer = init_hid_extra(&mut builder, mouse_enabled).await;
+    let (hid_raw_reader, hid_raw_writer) = init_hid_raw(&mut builder).await;
+
+    // MIDI is only exposed to the host if the keymap uses it
+    let midi_enabled =
+        layers.has_midi_actions() || COMBOS.iter().any(|combo| combo.action.has_midi_action());
+    let class_midi = if midi_enabled {
+        Some(init_midi(&mut builder).await)
+    } else {
+        None
+    };
+
+    // Keys needing an interface that was not created are refused by the
+    // configuration tools
+    let interfaces = KeyInterfaces {
+        mouse: mouse_enabled,
+        midi: class_midi.is_some(),
+    };
+
+    // Mass storage
+    let class_msc = if MSC_ENABLED {
+        Some(init_msc(&mut builder).await)
+    } else {
+        None
+    };
+
+    // WebUSB
+    let class_webusb = if WEBUSB_ENABLED {
+        Some(init_webusb(&mut builder).await)
+    } else {
+        None
+    };
 
     // Network
     // let (eth_runner, eth_device) = init_ethernet(&mut builder).await;
@@ -155,11 +211,8 @@ async fn main(spawner: Spawner) {
     let mut timer = DmaTimer::new(p.TIM1);
     timer.configure(FREQUENCY, CC_1, CC_2, CC_MAX);
     timer.start();
-
-    // Leak the timer, so it doesn't get dropped
-    // If it does get dropped, the DMA will stop working as the timer will be
-    // uninitialized
-    forget(timer);
+    // The timer is then handed to the scan task, which slows it down while
+    // the host is suspended. If it got dropped, the DMA would stop working.
 
     let (write_ring_buffer, read_ring_buffer) =
         configure_dma_scan(p.GPDMA1_CH0.into(), p.GPDMA1_CH1.into());
@@ -175,23 +228,75 @@ async fn main(spawner: Spawner) {
     // USB
     spawner.spawn(usb_task(usb)).unwrap();
 
+    // DFU
+    spawner.spawn(dfu_mark_booted_task(dfu_flash)).unwrap();
+
     // Serial
-    spawner.spawn(usb_serial_task(class_serial)).unwrap();
+    spawner
+        .spawn(usb_serial_task(class_serial, dfu_flash))
+        .unwrap();
 
     // HID keyboard
     spawner
         .spawn(hid_keyboard_reader_task(hid_keyboard_reader))
         .unwrap();
     spawner
-        .spawn(keyboard_scan_task(
+        .spawn(hid_keyboard_writer_task(
             hid_keyboard_writer,
+            layers,
+            interfaces,
+        ))
+        .unwrap();
+    spawner
+        .spawn(keyboard_scan_task(
+            timer,
             write_ring_buffer,
             read_ring_buffer,
         ))
         .unwrap();
+    spawner.spawn(macro_task()).unwrap();
+    spawner.spawn(settings_storage_task(dfu_flash)).unwrap();
+    spawner.spawn(keymap_storage_task(dfu_flash)).unwrap();
+
+    // Host LEDs
+    spawner.spawn(led_indicators_task(led_pins)).unwrap();
+
+    // HID consumer control
+    spawner
+        .spawn(hid_consumer_writer_task(hid_extra_writer))
+        .unwrap();
+
+    // HID system control
+    spawner
+        .spawn(hid_system_writer_task(hid_extra_writer))
+        .unwrap();
+
+    // HID raw
+    spawner
+        .spawn(hid_raw_task(hid_raw_reader, hid_raw_writer))
+        .unwrap();
 
     // HID mouse
-    // spawner.spawn(mouse_writer_task(hid_mouse_writer)).unwrap();
+    if mouse_enabled {
+        spawner.spawn(mouse_writer_task(hid_extra_writer)).unwrap();
+    }
+
+    // MIDI
+    if let Some(class_midi) = class_midi {
+        spawner.spawn(midi_writer_task(class_midi)).unwrap();
+    }
+
+    // Mass storage
+    if let Some(class_msc) = class_msc {
+        spawner
+            .spawn(msc_task(class_msc, dfu_flash, interfaces))
+            .unwrap();
+    }
+
+    // WebUSB
+    if let Some(class_webusb) = class_webusb {
+        spawner.spawn(webusb_task(class_webusb)).unwrap();
+    }
 
     // Network stack
     // spawner.spawn(usb_ethernet_task(eth_runner)).unwrap();
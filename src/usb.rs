@@ -1,7 +1,19 @@
+pub mod dfu;
+pub mod disk_files;
 pub mod ethernet;
+pub mod fat;
 pub mod hid;
+pub mod midi;
+pub mod msc;
+pub mod raw_hid;
+pub mod rpc;
 pub mod serial;
+pub mod shell;
 pub mod usb_device;
+pub mod via;
+pub mod webusb;
+
+use crate::config::{msc::MSC_ENABLED, webusb::WEBUSB_ENABLED};
 
 // =============================================================================
 // USB
@@ -22,13 +34,49 @@ pub const USB_RELEASE_VERSION: u16 = 0x0010;
 /// USB output buffer size.
 pub const USB_OUTPUT_BUFFER_SIZE: usize = 256;
 /// USB configuration descriptor size.
-pub const USB_CONFIG_DESC_SIZE: usize = 256;
+pub const USB_CONFIG_DESC_SIZE: usize = 512;
 /// USB BOS descriptor size.
-pub const USB_BOS_DESC_SIZE: usize = 64;
+pub const USB_BOS_DESC_SIZE: usize = 128;
 /// USB MSOS descriptor size.
-pub const USB_MSOS_DESC_SIZE: usize = 64;
+pub const USB_MSOS_DESC_SIZE: usize = 256;
 /// USB control buffer size.
 pub const USB_CONTROL_BUF_SIZE: usize = 64;
+/// Number of IN endpoints of the USB peripheral, besides the control endpoint.
+pub const USB_IN_ENDPOINTS: usize = 8;
+/// Number of IN endpoints taken by the USB classes.
+///
+/// The serial port takes 2, and the keyboard, extra keys, raw HID and MIDI
+/// interfaces take 1 each, as do the keymap disk and WebUSB when they are
+/// enabled. MIDI is counted even if the keymap does not use it, as the keymap
+/// saved in flash is only known when the keyboard starts.
+pub const USB_USED_IN_ENDPOINTS: usize =
+    2 + 1 + 1 + 1 + 1 + MSC_ENABLED as usize + WEBUSB_ENABLED as usize;
+const _: () = assert!(
+    USB_USED_IN_ENDPOINTS <= USB_IN_ENDPOINTS,
+    "The USB classes need more IN endpoints than the USB peripheral has"
+);
+
+// =============================================================================
+// DFU
+// =============================================================================
+/// Time in milliseconds given to the host to reset the device once detached.
+pub const DFU_DETACH_TIMEOUT_MS: u64 = 2500;
+
+// =============================================================================
+// Serial
+// =============================================================================
+/// Maximum size in bytes of a serial packet.
+pub const SERIAL_MAX_PACKET_SIZE: u16 = 64;
+/// Maximum length of a line typed in the shell.
+pub const SHELL_LINE_SIZE: usize = 64;
+/// Number of lines kept in the history of the shell.
+pub const SHELL_HISTORY_SIZE: usize = 8;
+/// Size in bytes of the output of the shell buffered before it is sent.
+pub const SHELL_OUTPUT_SIZE: usize = 1024;
+/// Interval in milliseconds between two refreshes of the matrix in the shell.
+pub const SHELL_MATRIX_REFRESH_MS: u64 = 50;
+/// Delay in milliseconds between the last output of the shell and a reset.
+pub const SHELL_REBOOT_DELAY_MS: u64 = 100;
 
 // =============================================================================
 // HID
@@ -41,13 +89,60 @@ pub const HID_KEYBOARD_MAX_PACKET_SIZE: u16 = 32;
 pub const HID_KEYBOARD_READER_N: usize = 1;
 /// Size in bytes of the keyboard report sent to the HID writer.
 pub const HID_KEYBOARD_WRITER_N: usize = 25;
+/// Delay in milliseconds before retrying to send a keyboard report that failed.
+pub const HID_KEYBOARD_RETRY_MS: u64 = 10;
+
+/// Polling interval of the HID device in milliseconds.
+pub const HID_EXTRA_POLL_MS: u8 = 8;
+/// Maximum size in bytes of a HID packet.
+pub const HID_EXTRA_MAX_PACKET_SIZE: u16 = 16;
+/// Size in bytes of the largest report sent to the HID writer, a consumer
+/// control report and its report ID.
+pub const HID_EXTRA_WRITER_N: usize = 9;
 
 /// Polling interval of the HID device in milliseconds.
-pub const HID_MOUSE_POLL_MS: u8 = 60;
+pub const HID_RAW_POLL_MS: u8 = 1;
 /// Maximum size in bytes of a HID packet.
-pub const HID_MOUSE_MAX_PACKET_SIZE: u16 = 8;
-/// Size in bytes of the mouse report sent to the HID writer.
-pub const HID_MOUSE_WRITER_N: usize = 5;
+pub const HID_RAW_MAX_PACKET_SIZE: u16 = 32;
+/// Size in bytes of the requests and responses of the raw HID interface.
+pub const HID_RAW_REPORT_SIZE: usize = 32;
+
+// =============================================================================
+// MIDI
+// =============================================================================
+/// Maximum size in bytes of a MIDI packet.
+pub const MIDI_MAX_PACKET_SIZE: u16 = 64;
+/// Delay in milliseconds before retrying to send MIDI messages while the MIDI
+/// writer is behind.
+pub const MIDI_RETRY_MS: u64 = 10;
+
+// =============================================================================
+// MSC
+// =============================================================================
+/// Maximum size in bytes of a mass storage packet.
+pub const MSC_MAX_PACKET_SIZE: u16 = 64;
+/// Size in bytes of the disk, which is kept in RAM.
+pub const MSC_DISK_SIZE: usize = 64 * 1024;
+/// Maximum size in bytes of a file read back from the disk.
+pub const MSC_FILE_SIZE: usize = 8 * 1024;
+/// Delay in milliseconds without writes after which the files of the disk are
+/// read back.
+pub const MSC_SYNC_DELAY_MS: u64 = 500;
+
+// =============================================================================
+// WebUSB
+// =============================================================================
+/// Page browsers offer to open when the keyboard is plugged in.
+pub const WEBUSB_LANDING_URL: &str = "https://github.com/etiennecollin/wave-rs";
+/// Vendor request code of the WebUSB descriptors.
+pub const WEBUSB_VENDOR_CODE: u8 = 0x01;
+/// Vendor request code of the MS OS 2.0 descriptors.
+pub const MSOS_VENDOR_CODE: u8 = 0x02;
+/// Maximum size in bytes of a packet of the vendor interface.
+pub const WEBUSB_MAX_PACKET_SIZE: u16 = 64;
+/// Interface GUIDs of the vendor interface, which Windows applications use to
+/// find it through WinUSB.
+pub const WEBUSB_INTERFACE_GUIDS: &[&str] = &["{3F8A2D6C-91B4-4E57-A0C3-5D7E2B9F1A64}"];
 
 // =============================================================================
 // Ethernet
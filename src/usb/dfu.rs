@@ -0,0 +1,92 @@
+use core::cell::RefCell;
+
+use cortex_m::peripheral::SCB;
+
+use defmt::{error, info};
+use embassy_boot_stm32::{AlignedBuffer, BlockingFirmwareState, FirmwareUpdaterConfig, State};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_stm32::{
+    flash::{Blocking, Flash, WRITE_SIZE},
+    peripherals::{FLASH, USB_OTG_HS},
+    usb::Driver,
+    Peri,
+};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Duration;
+use embassy_usb::Builder;
+use embassy_usb_dfu::{consts::DfuAttributes, usb_dfu, Control, ResetImmediate};
+use static_cell::StaticCell;
+
+use crate::usb::{usb_device::USB_CONFIGURED, DFU_DETACH_TIMEOUT_MS};
+
+/// The internal flash, shared by the DFU interface, the firmware state and the
+/// saved settings and keymap.
+///
+/// It is only used by the tasks of the thread mode executor, so erasing and
+/// writing it does not block the interrupts like a critical section would.
+pub type DfuFlash = Mutex<ThreadModeRawMutex, RefCell<Flash<'static, Blocking>>>;
+
+/// A partition of the flash described in `memory.x`.
+type DfuPartition = BlockingPartition<'static, ThreadModeRawMutex, Flash<'static, Blocking>>;
+
+/// Initializes the DFU runtime interface.
+///
+/// When a DFU tool detaches the device, the firmware resets into the
+/// bootloader, which receives the new firmware in the DFU partition and swaps
+/// it with the active one.
+pub async fn init_dfu(
+    builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
+    flash: Peri<'static, FLASH>,
+) -> &'static DfuFlash {
+    static DFU_FLASH: StaticCell<DfuFlash> = StaticCell::new();
+    let flash = DFU_FLASH.init(Mutex::new(RefCell::new(Flash::new_blocking(flash))));
+
+    static DFU_MAGIC: StaticCell<AlignedBuffer<WRITE_SIZE>> = StaticCell::new();
+    let magic = DFU_MAGIC.init(AlignedBuffer([0; WRITE_SIZE]));
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash);
+    let firmware_state = BlockingFirmwareState::from_config(config, &mut magic.0);
+
+    static DFU_CONTROL: StaticCell<Control<'static, DfuPartition, ResetImmediate>> =
+        StaticCell::new();
+    let control = DFU_CONTROL.init(Control::new(firmware_state, DfuAttributes::CAN_DOWNLOAD));
+    usb_dfu::<_, _, ResetImmediate>(
+        builder,
+        control,
+        Duration::from_millis(DFU_DETACH_TIMEOUT_MS),
+    );
+
+    flash
+}
+
+/// Marks the firmware as booted once the host configured the USB device.
+///
+/// After an update, the bootloader rolls the previous firmware back on the
+/// next reset unless the new one got this far, so a firmware that cannot be
+/// updated over USB anymore never stays installed.
+#[embassy_executor::task]
+pub async fn dfu_mark_booted_task(flash: &'static DfuFlash) {
+    USB_CONFIGURED.wait().await;
+
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash);
+    let mut magic = AlignedBuffer([0; WRITE_SIZE]);
+    let mut firmware_state = BlockingFirmwareState::from_config(config, &mut magic.0);
+    match firmware_state.get_state() {
+        Ok(State::Swap) => match firmware_state.mark_booted() {
+            Ok(()) => info!("DFU | Firmware updated and marked as booted"),
+            Err(e) => error!("DFU | Failed to mark the firmware as booted: {:?}", e),
+        },
+        Ok(_) => {}
+        Err(e) => error!("DFU | Failed to read the firmware state: {:?}", e),
+    }
+}
+
+/// Resets into the bootloader, which then waits for a new firmware over DFU.
+pub fn reboot_into_bootloader(flash: &'static DfuFlash) -> ! {
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash);
+    let mut magic = AlignedBuffer([0; WRITE_SIZE]);
+    let mut firmware_state = BlockingFirmwareState::from_config(config, &mut magic.0);
+    if let Err(e) = firmware_state.mark_dfu() {
+        error!("DFU | Failed to request the bootloader: {:?}", e);
+    }
+    SCB::sys_reset()
+}
@@ -0,0 +1,905 @@
+use core::fmt::{self, Debug, Write};
+
+use embassy_time::Duration;
+use heapless::{String, Vec};
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use crate::{
+    keyboard::{
+        action::{
+            Action, HoldTapAction, HoldTapConfig, KeyAction, LayerTapAction, System, TapDanceAction,
+        },
+        host_layout::HostLayout,
+        settings::Settings,
+        unicode::UnicodeMode,
+    },
+    usb::raw_hid::{KeyInterfaces, MOUSE_KEYS},
+};
+
+/// Maximum number of errors reported for a file.
+pub const MAX_FILE_ERRORS: usize = 16;
+
+/// Maximum length of a name quoted in an error, longer names are truncated.
+pub const MAX_NAME_SIZE: usize = 32;
+
+/// Maximum number of arguments of a key, such as `HoldTap(A,B,PermissiveHold)`.
+const MAX_ARGS: usize = 3;
+
+/// Name of the keys that only exist in the firmware.
+const FIRMWARE_KEY: &str = "Firmware";
+
+/// System control keys, which are not numbered from 0.
+const SYSTEM_KEYS: [System; 3] = [System::PowerDown, System::Sleep, System::WakeUp];
+
+/// Hold-tap configurations, the default one being left out of the key names.
+const HOLD_TAP_CONFIGS: [HoldTapConfig; 3] = [
+    HoldTapConfig::Default,
+    HoldTapConfig::HoldOnOtherKeyPress,
+    HoldTapConfig::PermissiveHold,
+];
+
+/// Keys of a keymap file, by layer, row and column.
+///
+/// `None` is a key that only exists in the firmware, such as a macro. It is
+/// written as `Firmware` and is kept as is when the file is read back.
+pub type FileKeys<const L: usize, const M: usize, const N: usize> =
+    [[[Option<KeyAction>; N]; M]; L];
+
+/// Errors found in a file, in the order of their lines.
+pub type FileErrors = Vec<FileError, MAX_FILE_ERRORS>;
+
+/// Error on a line of a file.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileError {
+    /// Line of the error, starting from 1.
+    pub line: usize,
+    pub kind: FileErrorKind,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FileErrorKind {
+    /// No key has this name.
+    UnknownKey(String<MAX_NAME_SIZE>),
+    /// The key needs a USB interface that was not created at boot.
+    UnavailableKey(String<MAX_NAME_SIZE>),
+    /// A key or a header refers to a layer that does not exist.
+    InvalidLayer(usize),
+    /// The layer was already given earlier in the file.
+    DuplicateLayer(usize),
+    /// The line is neither a layer header nor a row of keys.
+    InvalidHeader,
+    /// A row of keys comes before the first layer header.
+    MissingLayer,
+    /// A row does not have a key for every column.
+    WrongKeyCount { expected: usize, found: usize },
+    /// A layer does not have a row for every row of the matrix.
+    WrongRowCount { expected: usize, found: usize },
+    /// No setting has this name.
+    UnknownSetting(String<MAX_NAME_SIZE>),
+    /// The line is not a `name = value` setting.
+    InvalidSetting,
+    /// The value of the setting is out of range.
+    InvalidValue(String<MAX_NAME_SIZE>),
+    /// The file is not UTF-8 text.
+    InvalidText,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            FileErrorKind::UnknownKey(name) => write!(f, "unknown key `{}`", name),
+            FileErrorKind::UnavailableKey(name) => {
+                write!(f, "`{}` needs a USB interface not created at boot", name)
+            }
+            FileErrorKind::InvalidLayer(layer) => write!(f, "layer {} does not exist", layer),
+            FileErrorKind::DuplicateLayer(layer) => write!(f, "layer {} is already given", layer),
+            FileErrorKind::InvalidHeader => write!(f, "expected a header such as `[layer 0]`"),
+            FileErrorKind::MissingLayer => write!(f, "keys must follow a layer header"),
+            FileErrorKind::WrongKeyCount { expected, found } => {
+                write!(f, "expected {} keys, found {}", expected, found)
+            }
+            FileErrorKind::WrongRowCount { expected, found } => {
+                write!(f, "expected {} rows, found {}", expected, found)
+            }
+            FileErrorKind::UnknownSetting(name) => write!(f, "unknown setting `{}`", name),
+            FileErrorKind::InvalidSetting => write!(f, "expected `name = value`"),
+            FileErrorKind::InvalidValue(name) => write!(f, "invalid value for `{}`", name),
+            FileErrorKind::InvalidText => write!(f, "the file must be saved as UTF-8 text"),
+        }
+    }
+}
+
+/// Writes the keys of a keymap file.
+///
+/// Each layer starts with a `[layer n]` header, followed by a line of keys
+/// separated by spaces for each row. The keys are named like the actions of
+/// the configuration, such as `A`, `Consumer(Mute)` or `LayerTap(1,Space)`.
+pub fn write_keymap<W: Write, const L: usize, const M: usize, const N: usize>(
+    w: &mut W,
+    keys: &FileKeys<L, M, N>,
+) -> fmt::Result {
+    writeln!(w, "# wave-rs keymap: {} rows of {} keys per layer", M, N)?;
+    writeln!(
+        w,
+        "# Save this file to change the keymap, errors are written to ERRORS.TXT."
+    )?;
+    writeln!(
+        w,
+        "# `{}` keys only exist in the firmware and are kept as is.",
+        FIRMWARE_KEY
+    )?;
+    for (index, layer) in keys.iter().enumerate() {
+        writeln!(w, "\n[layer {}]", index)?;
+
+        // Align the columns on their longest key
+        let mut widths = [0; N];
+        for row in layer {
+            for (width, key) in widths.iter_mut().zip(row) {
+                let mut counter = Counter(0);
+                write_key(&mut counter, *key)?;
+                *width = counter.0.max(*width);
+            }
+        }
+
+        for row in layer {
+            for (col, key) in row.iter().enumerate() {
+                let mut counter = Counter(0);
+                write_key(&mut counter, *key)?;
+                write_key(w, *key)?;
+                if col + 1 < N {
+                    for _ in counter.0..widths[col] + 1 {
+                        w.write_char(' ')?;
+                    }
+                }
+            }
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the keys of a keymap file written by [`write_keymap`].
+///
+/// Lines starting with `#` are comments. Layers left out of the file keep the
+/// keys of `keys`, and keys that `interfaces` does not support are errors. The
+/// errors are pushed to `errors`, and `keys` is only changed if there are none.
+pub fn parse_keymap<const L: usize, const M: usize, const N: usize>(
+    text: &str,
+    keys: &mut FileKeys<L, M, N>,
+    interfaces: KeyInterfaces,
+    errors: &mut FileErrors,
+) {
+    /// Layer whose rows are being read.
+    enum State {
+        BeforeFirstLayer,
+        /// The header of the layer is invalid, so its rows are ignored.
+        Skipped,
+        Layer {
+            layer: usize,
+            header: usize,
+            rows: usize,
+        },
+    }
+
+    let mut parsed = *keys;
+    let mut error = |line: usize, kind: FileErrorKind| {
+        // Only the first errors are reported
+        let _ = errors.push(FileError { line, kind });
+    };
+    let check_rows = |state: &State| match *state {
+        State::Layer { header, rows, .. } if rows != M => Some((
+            header,
+            FileErrorKind::WrongRowCount {
+                expected: M,
+                found: rows,
+            },
+        )),
+        _ => None,
+    };
+    let mut given = [false; L];
+    let mut state = State::BeforeFirstLayer;
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = strip_comment(line);
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if let Some((line, kind)) = check_rows(&state) {
+                error(line, kind);
+            }
+            state = match parse_header(line) {
+                Some(layer) if layer >= L => {
+                    error(number, FileErrorKind::InvalidLayer(layer));
+                    State::Skipped
+                }
+                Some(layer) if given[layer] => {
+                    error(number, FileErrorKind::DuplicateLayer(layer));
+                    State::Skipped
+                }
+                Some(layer) => {
+                    given[layer] = true;
+                    State::Layer {
+                        layer,
+                        header: number,
+                        rows: 0,
+                    }
+                }
+                None => {
+                    error(number, FileErrorKind::InvalidHeader);
+                    State::Skipped
+                }
+            };
+            continue;
+        }
+
+        let (layer, rows) = match &mut state {
+            State::BeforeFirstLayer => {
+                error(number, FileErrorKind::MissingLayer);
+                continue;
+            }
+            State::Skipped => continue,
+            State::Layer { layer, rows, .. } => (*layer, rows),
+        };
+        let row = *rows;
+        *rows += 1;
+        if row >= M {
+            continue;
+        }
+
+        let found = line.split_whitespace().count();
+        if found != N {
+            error(number, FileErrorKind::WrongKeyCount { expected: N, found });
+            continue;
+        }
+        for (col, name) in line.split_whitespace().enumerate() {
+            match parse_key(name, L) {
+                Ok(Some(key)) if !interfaces.supports(key) => {
+                    error(number, FileErrorKind::UnavailableKey(truncate(name)))
+                }
+                Ok(key) => parsed[layer][row][col] = key,
+                Err(kind) => error(number, kind),
+            }
+        }
+    }
+    if let Some((line, kind)) = check_rows(&state) {
+        error(line, kind);
+    }
+
+    if errors.is_empty() {
+        *keys = parsed;
+    }
+}
+
+/// Writes the settings file, a `name = value` line per setting.
+pub fn write_settings<W: Write>(w: &mut W, settings: &Settings) -> fmt::Result {
+    writeln!(w, "# wave-rs settings")?;
+    writeln!(
+        w,
+        "# Save this file to change the settings, errors are written to ERRORS.TXT."
+    )?;
+    writeln!(w)?;
+    writeln!(w, "tapping_term_ms = {}", settings.tapping_term.as_millis())?;
+    writeln!(
+        w,
+        "tap_dance_term_ms = {}",
+        settings.tap_dance_term.as_millis()
+    )?;
+    writeln!(
+        w,
+        "one_shot_timeout_ms = {}",
+        settings.one_shot_timeout.as_millis()
+    )?;
+    writeln!(
+        w,
+        "combo_timeout_ms = {}",
+        settings.combo_timeout.as_millis()
+    )?;
+    writeln!(w, "typing_delay_ms = {}", settings.typing_delay.as_millis())?;
+
+    write!(w, "\n# One of")?;
+    for layout in HostLayout::ALL {
+        write!(w, " {}", layout.name())?;
+    }
+    writeln!(w, "\nhost_layout = {}", settings.host_layout.name())?;
+
+    write!(w, "\n# One of")?;
+    for mode in UnicodeMode::ALL {
+        write!(w, " {}", mode.name())?;
+    }
+    writeln!(w, "\nunicode_mode = {}", settings.unicode_mode.name())
+}
+
+/// Reads a settings file written by [`write_settings`].
+///
+/// Lines starting with `#` are comments. Settings left out of the file keep
+/// their value in `settings`. The errors are pushed to `errors`, and
+/// `settings` is only changed if there are none.
+pub fn parse_settings(text: &str, settings: &mut Settings, errors: &mut FileErrors) {
+    let mut parsed = *settings;
+    for (index, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+        if line.is_empty() {
+            continue;
+        }
+        let result = match line.split_once('=') {
+            Some((name, value)) => parse_setting(&mut parsed, name.trim(), value.trim()),
+            None => Err(FileErrorKind::InvalidSetting),
+        };
+        if let Err(kind) = result {
+            let _ = errors.push(FileError {
+                line: index + 1,
+                kind,
+            });
+        }
+    }
+    if errors.is_empty() {
+        *settings = parsed;
+    }
+}
+
+fn parse_setting(settings: &mut Settings, name: &str, value: &str) -> Result<(), FileErrorKind> {
+    let invalid = || FileErrorKind::InvalidValue(truncate(name));
+    let duration = || {
+        value
+            .parse::<u32>()
+            .map(|ms| Duration::from_millis(ms as u64))
+            .map_err(|_| invalid())
+    };
+    match name {
+        "tapping_term_ms" => settings.tapping_term = duration()?,
+        "tap_dance_term_ms" => settings.tap_dance_term = duration()?,
+        "one_shot_timeout_ms" => settings.one_shot_timeout = duration()?,
+        "combo_timeout_ms" => settings.combo_timeout = duration()?,
+        "typing_delay_ms" => settings.typing_delay = duration()?,
+        "host_layout" => settings.host_layout = HostLayout::from_name(value).ok_or_else(invalid)?,
+        "unicode_mode" => {
+            settings.unicode_mode = UnicodeMode::from_name(value).ok_or_else(invalid)?
+        }
+        _ => return Err(FileErrorKind::UnknownSetting(truncate(name))),
+    }
+    Ok(())
+}
+
+/// Reads the content of a file as text.
+///
+/// The error is on the line of the first byte that is not UTF-8.
+pub fn file_text(bytes: &[u8]) -> Result<&str, FileError> {
+    core::str::from_utf8(bytes).map_err(|e| {
+        let valid = &bytes[..e.valid_up_to()];
+        FileError {
+            line: valid.iter().filter(|&&byte| byte == b'\n').count() + 1,
+            kind: FileErrorKind::InvalidText,
+        }
+    })
+}
+
+/// Writes the errors of a file, one per line, or that there are none.
+pub fn write_errors<W: Write>(w: &mut W, file: &str, errors: &[FileError]) -> fmt::Result {
+    if errors.is_empty() {
+        return writeln!(w, "{}: no errors", file);
+    }
+    writeln!(w, "{} was not applied:", file)?;
+    for error in errors {
+        writeln!(w, "{}", error)?;
+    }
+    if errors.len() == MAX_FILE_ERRORS {
+        writeln!(w, "Only the first {} errors are listed.", MAX_FILE_ERRORS)?;
+    }
+    Ok(())
+}
+
+/// Removes the comment and the surrounding whitespace of a line.
+fn strip_comment(line: &str) -> &str {
+    line.split('#').next().unwrap_or_default().trim()
+}
+
+/// Reads the layer of a `[layer n]` header.
+fn parse_header(line: &str) -> Option<usize> {
+    let header = line.strip_prefix('[')?.strip_suffix(']')?;
+    header.trim().strip_prefix("layer")?.trim().parse().ok()
+}
+
+fn write_key<W: Write>(w: &mut W, key: Option<KeyAction>) -> fmt::Result {
+    let write_config = |w: &mut W, config: HoldTapConfig| match config {
+        HoldTapConfig::Default => Ok(()),
+        config => write!(w, ",{:?}", config),
+    };
+    let Some(key) = key.filter(|&key| is_representable(key)) else {
+        return w.write_str(FIRMWARE_KEY);
+    };
+    match key {
+        KeyAction::NoOp => w.write_str("NoOp"),
+        KeyAction::Transparent => w.write_str("Transparent"),
+        KeyAction::Single(action) => write_action(w, action),
+        KeyAction::Layer(layer) => write!(w, "Layer({})", layer),
+        KeyAction::ToggleLayer(layer) => write!(w, "ToggleLayer({})", layer),
+        KeyAction::ToLayer(layer) => write!(w, "ToLayer({})", layer),
+        KeyAction::OneShotLayer(layer) => write!(w, "OneShotLayer({})", layer),
+        KeyAction::DefaultLayer(layer) => write!(w, "DefaultLayer({})", layer),
+        KeyAction::LayerTap(action) => {
+            write!(w, "LayerTap({},", action.layer)?;
+            write_action(w, action.tap)?;
+            write_config(w, action.config)?;
+            w.write_char(')')
+        }
+        KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)) => {
+            let name = match key {
+                KeyAction::HoldTap(_) => "HoldTap",
+                _ => "TapDance",
+            };
+            write!(w, "{}(", name)?;
+            write_action(w, action.hold)?;
+            w.write_char(',')?;
+            write_action(w, action.tap)?;
+            write_config(w, action.config)?;
+            w.write_char(')')
+        }
+        KeyAction::OneShot(key) => write!(w, "OneShot({:?})", key),
+        KeyAction::DynamicMacro(index) => write!(w, "DynamicMacro({})", index),
+        KeyAction::TapDance(TapDanceAction::Table(_)) | KeyAction::Macro(_) => {
+            w.write_str(FIRMWARE_KEY)
+        }
+    }
+}
+
+fn write_action<W: Write>(w: &mut W, action: Action) -> fmt::Result {
+    match action {
+        Action::Keyboard(key) => write!(w, "{:?}", key),
+        Action::Consumer(key) => write!(w, "Consumer({:?})", key),
+        Action::System(key) => write!(w, "System({:?})", key),
+        Action::Mouse(key) => write!(w, "Mouse({:?})", key),
+        Action::Unicode(_) | Action::UnicodeString(_) | Action::Midi(_) => {
+            w.write_str(FIRMWARE_KEY)
+        }
+    }
+}
+
+/// Checks if a key can be written in a file and read back.
+fn is_representable(key: KeyAction) -> bool {
+    let is_representable = |action: Action| {
+        matches!(
+            action,
+            Action::Keyboard(_) | Action::Consumer(_) | Action::System(_) | Action::Mouse(_)
+        )
+    };
+    match key {
+        KeyAction::Single(action) | KeyAction::LayerTap(LayerTapAction { tap: action, .. }) => {
+            is_representable(action)
+        }
+        KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)) => {
+            is_representable(action.hold) && is_representable(action.tap)
+        }
+        KeyAction::TapDance(TapDanceAction::Table(_)) | KeyAction::Macro(_) => false,
+        _ => true,
+    }
+}
+
+/// Reads a key written by [`write_key`], checking that its layer is below
+/// `layers`.
+fn parse_key(name: &str, layers: usize) -> Result<Option<KeyAction>, FileErrorKind> {
+    let unknown = || FileErrorKind::UnknownKey(truncate(name));
+    let key = match name {
+        FIRMWARE_KEY => return Ok(None),
+        "NoOp" => KeyAction::NoOp,
+        "Transparent" => KeyAction::Transparent,
+        _ => match split_call(name)
+            .as_ref()
+            .map(|(f, args)| (*f, args.as_slice()))
+        {
+            Some(("Layer", [layer])) => KeyAction::Layer(parse_layer(layer, layers)?),
+            Some(("ToggleLayer", [layer])) => KeyAction::ToggleLayer(parse_layer(layer, layers)?),
+            Some(("ToLayer", [layer])) => KeyAction::ToLayer(parse_layer(layer, layers)?),
+            Some(("OneShotLayer", [layer])) => KeyAction::OneShotLayer(parse_layer(layer, layers)?),
+            Some(("DefaultLayer", [layer])) => KeyAction::DefaultLayer(parse_layer(layer, layers)?),
+            Some(("LayerTap", [layer, tap, config @ ..])) if config.len() <= 1 => {
+                KeyAction::LayerTap(LayerTapAction {
+                    layer: parse_layer(layer, layers)?,
+                    tap: parse_action(tap).ok_or_else(unknown)?,
+                    config: parse_config(config).ok_or_else(unknown)?,
+                })
+            }
+            Some((kind @ ("HoldTap" | "TapDance"), [hold, tap, config @ ..]))
+                if config.len() <= 1 =>
+            {
+                let action = HoldTapAction {
+                    hold: parse_action(hold).ok_or_else(unknown)?,
+                    tap: parse_action(tap).ok_or_else(unknown)?,
+                    config: parse_config(config).ok_or_else(unknown)?,
+                };
+                if kind == "HoldTap" {
+                    KeyAction::HoldTap(action)
+                } else {
+                    KeyAction::TapDance(TapDanceAction::Repeat(action))
+                }
+            }
+            Some(("OneShot", [key])) => {
+                KeyAction::OneShot(parse_keyboard(key).ok_or_else(unknown)?)
+            }
+            Some(("DynamicMacro", [index])) => {
+                KeyAction::DynamicMacro(index.parse().map_err(|_| unknown())?)
+            }
+            _ => KeyAction::Single(parse_action(name).ok_or_else(unknown)?),
+        },
+    };
+    Ok(Some(key))
+}
+
+fn parse_action(name: &str) -> Option<Action> {
+    let action = match split_call(name)
+        .as_ref()
+        .map(|(f, args)| (*f, args.as_slice()))
+    {
+        Some(("Consumer", [key])) => {
+            // The consumer usages stop well before 0x1000
+            Action::Consumer((0..0x1000).map(Consumer::from).find(|c| is_named(c, key))?)
+        }
+        Some(("System", [key])) => Action::System(
+            SYSTEM_KEYS
+                .into_iter()
+                .find(|system| is_named(system, key))?,
+        ),
+        Some(("Mouse", [key])) => {
+            Action::Mouse(MOUSE_KEYS.into_iter().find(|mouse| is_named(mouse, key))?)
+        }
+        Some(_) => return None,
+        None => Action::Keyboard(parse_keyboard(name)?),
+    };
+    Some(action)
+}
+
+fn parse_keyboard(name: &str) -> Option<Keyboard> {
+    (0..=u8::MAX)
+        .map(Keyboard::from)
+        .find(|key| is_named(key, name))
+}
+
+fn parse_config(config: &[&str]) -> Option<HoldTapConfig> {
+    match config {
+        [] => Some(HoldTapConfig::Default),
+        [name] => HOLD_TAP_CONFIGS
+            .into_iter()
+            .find(|config| is_named(config, name)),
+        _ => None,
+    }
+}
+
+fn parse_layer(layer: &str, layers: usize) -> Result<usize, FileErrorKind> {
+    let layer = layer
+        .parse()
+        .map_err(|_| FileErrorKind::UnknownKey(truncate(layer)))?;
+    if layer < layers {
+        Ok(layer)
+    } else {
+        Err(FileErrorKind::InvalidLayer(layer))
+    }
+}
+
+/// Splits a `Name(a,b,c)` key into its name and arguments, which may
+/// themselves have arguments.
+fn split_call(name: &str) -> Option<(&str, Vec<&str, MAX_ARGS>)> {
+    let (function, rest) = name.split_once('(')?;
+    let rest = rest.strip_suffix(')')?;
+    let mut args = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in rest.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                args.push(&rest[start..i]).ok()?;
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(&rest[start..]).ok()?;
+    Some((function, args))
+}
+
+/// Checks if the `Debug` name of a value is `name`, without a buffer.
+fn is_named<T: Debug>(value: &T, name: &str) -> bool {
+    /// Strips the written strings from the start of the name.
+    struct Matcher<'a>(&'a str);
+
+    impl Write for Matcher<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let mut matcher = Matcher(name);
+    write!(matcher, "{:?}", value).is_ok() && matcher.0.is_empty()
+}
+
+fn truncate(name: &str) -> String<MAX_NAME_SIZE> {
+    let mut truncated = String::new();
+    for c in name.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+/// Counts the characters written to it.
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.chars().count();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+    use super::*;
+    use crate::keyboard::action::{c, k, lt, m, mo, osm, repeat, s, td, uc, Mouse, TapDance};
+
+    type TestKeys = FileKeys<2, 2, 3>;
+
+    const INTERFACES: KeyInterfaces = KeyInterfaces {
+        mouse: true,
+        midi: true,
+    };
+
+    static DANCE: TapDance = TapDance {
+        taps: &[k(Keyboard::A)],
+        holds: &[],
+    };
+
+    fn keys() -> TestKeys {
+        let hold_tap = KeyAction::HoldTap(HoldTapAction {
+            hold: k(Keyboard::LeftShift),
+            tap: c(Consumer::Mute),
+            config: HoldTapConfig::PermissiveHold,
+        });
+        [
+            [
+                [
+                    Some(KeyAction::Single(k(Keyboard::Escape))),
+                    Some(KeyAction::Single(c(Consumer::PlayPause))),
+                    Some(lt(1, k(Keyboard::Space))),
+                ],
+                [Some(mo(1)), Some(hold_tap), None],
+            ],
+            [
+                [
+                    Some(KeyAction::Transparent),
+                    Some(KeyAction::Single(s(System::Sleep))),
+                    Some(KeyAction::Single(m(Mouse::ScrollUp))),
+                ],
+                [
+                    Some(osm(Keyboard::LeftControl)),
+                    Some(repeat(k(Keyboard::B), k(Keyboard::DeleteBackspace))),
+                    Some(KeyAction::DynamicMacro(3)),
+                ],
+            ],
+        ]
+    }
+
+    fn error(line: usize, kind: FileErrorKind) -> FileError {
+        FileError { line, kind }
+    }
+
+    fn name(name: &str) -> String<MAX_NAME_SIZE> {
+        truncate(name)
+    }
+
+    #[test]
+    fn keymap_round_trip() {
+        let mut text = String::<1024>::new();
+        write_keymap(&mut text, &keys()).unwrap();
+        assert!(text.contains(
+            "[layer 0]\n\
+             Escape   Consumer(PlayPause)                              LayerTap(1,Space)\n\
+             Layer(1) HoldTap(LeftShift,Consumer(Mute),PermissiveHold) Firmware\n"
+        ));
+
+        let mut parsed = [[[None; 3]; 2]; 2];
+        let mut errors = FileErrors::new();
+        parse_keymap(&text, &mut parsed, INTERFACES, &mut errors);
+        assert_eq!(errors, []);
+        assert_eq!(parsed, keys());
+    }
+
+    #[test]
+    fn firmware_keys() {
+        let mut keys = keys();
+        keys[1][0][0] = Some(td(&DANCE));
+        keys[1][0][1] = Some(KeyAction::Single(uc('é')));
+        keys[1][0][2] = Some(lt(1, uc('é')));
+        let mut text = String::<1024>::new();
+        write_keymap(&mut text, &keys).unwrap();
+        assert!(text.contains(
+            "[layer 1]\n\
+             Firmware             Firmware                    Firmware\n"
+        ));
+    }
+
+    #[test]
+    fn keymap_edits() {
+        let text = "\
+            # Comment\n\
+            [ layer 1 ]\n\
+            A  Consumer(VolumeIncrement)  HoldTap(Mouse(LeftClick),Keyboard1) # Trailing\n\
+            \n\
+            ToggleLayer(0) TapDance(A,B,HoldOnOtherKeyPress) Firmware\n";
+        let mut parsed = keys();
+        let mut errors = FileErrors::new();
+        parse_keymap(text, &mut parsed, INTERFACES, &mut errors);
+        assert_eq!(errors, []);
+
+        let mut expected = keys();
+        expected[1] = [
+            [
+                Some(KeyAction::Single(k(Keyboard::A))),
+                Some(KeyAction::Single(c(Consumer::VolumeIncrement))),
+                Some(KeyAction::HoldTap(HoldTapAction {
+                    hold: m(Mouse::LeftClick),
+                    tap: k(Keyboard::Keyboard1),
+                    config: HoldTapConfig::Default,
+                })),
+            ],
+            [
+                Some(KeyAction::ToggleLayer(0)),
+                Some(KeyAction::TapDance(TapDanceAction::Repeat(HoldTapAction {
+                    hold: k(Keyboard::A),
+                    tap: k(Keyboard::B),
+                    config: HoldTapConfig::HoldOnOtherKeyPress,
+                }))),
+                None,
+            ],
+        ];
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn unavailable_keys() {
+        let text = "\
+            [layer 0]\n\
+            A B C\n\
+            Mouse(LeftClick) HoldTap(Mouse(RightClick),A) NoOp\n";
+        let mut parsed = keys();
+        let mut errors = FileErrors::new();
+        let interfaces = KeyInterfaces {
+            mouse: false,
+            midi: true,
+        };
+        parse_keymap(text, &mut parsed, interfaces, &mut errors);
+        assert_eq!(
+            errors,
+            [
+                error(3, FileErrorKind::UnavailableKey(name("Mouse(LeftClick)"))),
+                error(
+                    3,
+                    FileErrorKind::UnavailableKey(name("HoldTap(Mouse(RightClick),A)"))
+                ),
+            ]
+        );
+        assert_eq!(parsed, keys());
+    }
+
+    #[test]
+    fn keymap_errors() {
+        let text = "\
+            A B C\n\
+            [layer 0]\n\
+            A B Foo\n\
+            A B\n\
+            [layer 0]\n\
+            [layer 2]\n\
+            A B C\n\
+            [row 1]\n\
+            [layer 1]\n\
+            Layer(2) HoldTap(A) Consumer(Nothing)\n\
+            NoOp NoOp NoOp\n\
+            NoOp NoOp NoOp\n";
+        let mut parsed = keys();
+        let mut errors = FileErrors::new();
+        parse_keymap(text, &mut parsed, INTERFACES, &mut errors);
+        assert_eq!(
+            errors,
+            [
+                error(1, FileErrorKind::MissingLayer),
+                error(3, FileErrorKind::UnknownKey(name("Foo"))),
+                error(
+                    4,
+                    FileErrorKind::WrongKeyCount {
+                        expected: 3,
+                        found: 2
+                    }
+                ),
+                error(5, FileErrorKind::DuplicateLayer(0)),
+                error(6, FileErrorKind::InvalidLayer(2)),
+                error(8, FileErrorKind::InvalidHeader),
+                error(10, FileErrorKind::InvalidLayer(2)),
+                error(10, FileErrorKind::UnknownKey(name("HoldTap(A)"))),
+                error(10, FileErrorKind::UnknownKey(name("Consumer(Nothing)"))),
+                error(
+                    9,
+                    FileErrorKind::WrongRowCount {
+                        expected: 2,
+                        found: 3
+                    }
+                ),
+            ]
+        );
+        assert_eq!(parsed, keys());
+
+        let mut text = String::<512>::new();
+        write_errors(&mut text, "KEYMAP.TXT", &errors[..2]).unwrap();
+        assert_eq!(
+            text,
+            "KEYMAP.TXT was not applied:\n\
+             line 1: keys must follow a layer header\n\
+             line 3: unknown key `Foo`\n"
+        );
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = Settings {
+            tapping_term: Duration::from_millis(180),
+            host_layout: HostLayout::GermanQwertz,
+            unicode_mode: UnicodeMode::MacOs,
+            ..Settings::new()
+        };
+        let mut text = String::<1024>::new();
+        write_settings(&mut text, &settings).unwrap();
+        assert!(text.contains("tapping_term_ms = 180\n"));
+        assert!(text.contains("# One of us uk fr de dvorak\nhost_layout = de\n"));
+        let mut parsed = Settings::new();
+        let mut errors = FileErrors::new();
+        parse_settings(&text, &mut parsed, &mut errors);
+        assert_eq!(errors, []);
+        assert_eq!(parsed, settings);
+    }
+
+    #[test]
+    fn settings_errors() {
+        let text = "typing_delay_ms = 12\nunicode_mode=windows\n";
+        let expected = Settings {
+            typing_delay: Duration::from_millis(12),
+            unicode_mode: UnicodeMode::WindowsAltCode,
+            ..Settings::new()
+        };
+        let mut parsed = Settings::new();
+        let mut errors = FileErrors::new();
+        parse_settings(text, &mut parsed, &mut errors);
+        assert_eq!(errors, []);
+        assert_eq!(parsed, expected);
+
+        let text = "combo_timeout_ms = -1\nhost_layout = colemak\ncolor = red\ndebug\n";
+        parse_settings(text, &mut parsed, &mut errors);
+        assert_eq!(
+            errors,
+            [
+                error(1, FileErrorKind::InvalidValue(name("combo_timeout_ms"))),
+                error(2, FileErrorKind::InvalidValue(name("host_layout"))),
+                error(3, FileErrorKind::UnknownSetting(name("color"))),
+                error(4, FileErrorKind::InvalidSetting),
+            ]
+        );
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn invalid_text() {
+        assert_eq!(file_text(b"[layer 0]\nA B"), Ok("[layer 0]\nA B"));
+        assert_eq!(
+            file_text(b"[layer 0]\nA \xFF"),
+            Err(error(2, FileErrorKind::InvalidText))
+        );
+    }
+}
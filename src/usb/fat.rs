@@ -0,0 +1,421 @@
+use core::fmt;
+
+/// Size in bytes of a sector, which is also the size of a cluster.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Label of the volume, as shown by the host.
+const VOLUME_LABEL: &[u8; 11] = b"WAVE-RS    ";
+/// Serial number of the volume.
+const VOLUME_ID: u32 = 0x5741_5645;
+
+/// Size in bytes of a directory entry.
+const ENTRY_SIZE: usize = 32;
+/// Number of entries of the root directory, which fill one sector.
+const ROOT_ENTRIES: usize = SECTOR_SIZE / ENTRY_SIZE;
+/// First cluster of the data area, the first two entries of the FAT being
+/// reserved.
+const FIRST_CLUSTER: usize = 2;
+/// Marks the last cluster of a file in the FAT.
+const END_OF_CHAIN: u16 = 0xFFF;
+
+const ATTRIBUTE_VOLUME_LABEL: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// Attributes of the entries holding a part of a long file name.
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+/// First byte of the name of a deleted entry.
+const DELETED_ENTRY: u8 = 0xE5;
+
+/// Error while reading or writing a file of the disk.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FatError {
+    /// The name is not a valid 8.3 file name.
+    InvalidName,
+    /// The root directory is full.
+    TooManyFiles,
+    /// The disk is full.
+    DiskFull,
+    /// No file of the root directory has this name.
+    NotFound,
+    /// The file is larger than the buffer it is read in.
+    FileTooLarge,
+    /// The clusters of the file are not a valid chain.
+    Corrupted,
+}
+
+/// FAT12 disk in RAM, of `SIZE` bytes.
+///
+/// The firmware formats the disk with its files, which the host can then read
+/// and write sector by sector like any disk. The files written back by the
+/// host are read through the FAT, wherever the host stored them. Files are
+/// only looked up in the root directory, by their 8.3 name.
+///
+/// Clusters are one sector, and the root directory is the sector after the
+/// FAT, so the disk must stay below 2 MiB.
+pub struct FatDisk<const SIZE: usize> {
+    image: [u8; SIZE],
+    /// First cluster not used by the files of the firmware.
+    next_cluster: usize,
+}
+
+impl<const SIZE: usize> FatDisk<SIZE> {
+    const SECTORS: usize = SIZE / SECTOR_SIZE;
+    /// Sectors of the FAT, which takes one and a half byte per cluster.
+    const FAT_SECTORS: usize = ((Self::SECTORS + FIRST_CLUSTER) * 3 / 2).div_ceil(SECTOR_SIZE);
+    const FAT_START: usize = 1;
+    const ROOT_START: usize = Self::FAT_START + Self::FAT_SECTORS;
+    const DATA_START: usize = Self::ROOT_START + 1;
+    const CLUSTERS: usize = Self::SECTORS - Self::DATA_START;
+
+    /// Creates an empty disk, which must be formatted before it is used.
+    pub const fn new() -> Self {
+        Self {
+            image: [0; SIZE],
+            next_cluster: FIRST_CLUSTER,
+        }
+    }
+
+    /// Returns the number of sectors of the disk.
+    pub fn sectors(&self) -> u32 {
+        Self::SECTORS as u32
+    }
+
+    /// Returns a sector, or `None` if it is past the end of the disk.
+    pub fn sector(&self, lba: u32) -> Option<&[u8]> {
+        let start = (lba as usize).checked_mul(SECTOR_SIZE)?;
+        self.image.get(start..start + SECTOR_SIZE)
+    }
+
+    /// Returns a sector to write, or `None` if it is past the end of the disk.
+    pub fn sector_mut(&mut self, lba: u32) -> Option<&mut [u8]> {
+        let start = (lba as usize).checked_mul(SECTOR_SIZE)?;
+        self.image.get_mut(start..start + SECTOR_SIZE)
+    }
+
+    /// Erases the disk and formats it, leaving an empty root directory.
+    pub fn format(&mut self) {
+        self.image.fill(0);
+        self.next_cluster = FIRST_CLUSTER;
+
+        let boot = &mut self.image[..SECTOR_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[3..11].copy_from_slice(b"MSDOS5.0");
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = 1; // Sectors per cluster
+        boot[14..16].copy_from_slice(&(Self::FAT_START as u16).to_le_bytes());
+        boot[16] = 1; // Number of FATs
+        boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+        boot[19..21].copy_from_slice(&(Self::SECTORS as u16).to_le_bytes());
+        boot[21] = 0xF8; // Fixed disk
+        boot[22..24].copy_from_slice(&(Self::FAT_SECTORS as u16).to_le_bytes());
+        boot[24..26].copy_from_slice(&1u16.to_le_bytes()); // Sectors per track
+        boot[26..28].copy_from_slice(&1u16.to_le_bytes()); // Heads
+        boot[36] = 0x80; // Drive number
+        boot[38] = 0x29; // Extended boot signature
+        boot[39..43].copy_from_slice(&VOLUME_ID.to_le_bytes());
+        boot[43..54].copy_from_slice(VOLUME_LABEL);
+        boot[54..62].copy_from_slice(b"FAT12   ");
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        // The first two entries are reserved, the first one holding the media
+        self.set_fat_entry(0, 0xF00 | 0xF8);
+        self.set_fat_entry(1, END_OF_CHAIN);
+
+        let label = Self::ROOT_START * SECTOR_SIZE;
+        self.image[label..label + 11].copy_from_slice(VOLUME_LABEL);
+        self.image[label + 11] = ATTRIBUTE_VOLUME_LABEL;
+    }
+
+    /// Creates a file after the files created since the disk was formatted.
+    ///
+    /// The file is written through the returned [`FatFile`], and is complete
+    /// once it is dropped.
+    pub fn create(&mut self, name: &str) -> Result<FatFile<'_, SIZE>, FatError> {
+        let name = short_name(name).ok_or(FatError::InvalidName)?;
+        let entry = (0..ROOT_ENTRIES)
+            .map(|i| Self::ROOT_START * SECTOR_SIZE + i * ENTRY_SIZE)
+            .find(|&entry| self.image[entry] == 0)
+            .ok_or(FatError::TooManyFiles)?;
+        if self.next_cluster >= FIRST_CLUSTER + Self::CLUSTERS {
+            return Err(FatError::DiskFull);
+        }
+        self.image[entry..entry + 11].copy_from_slice(&name);
+        self.image[entry + 11] = ATTRIBUTE_ARCHIVE;
+        Ok(FatFile {
+            disk: self,
+            entry,
+            len: 0,
+        })
+    }
+
+    /// Reads a file of the root directory in a buffer.
+    pub fn read_file<'a>(&self, name: &str, buf: &'a mut [u8]) -> Result<&'a [u8], FatError> {
+        let name = short_name(name).ok_or(FatError::InvalidName)?;
+        let root = Self::ROOT_START * SECTOR_SIZE;
+        let entry = self.image[root..root + SECTOR_SIZE]
+            .chunks_exact(ENTRY_SIZE)
+            .take_while(|entry| entry[0] != 0)
+            .filter(|entry| entry[0] != DELETED_ENTRY)
+            .filter(|entry| entry[11] & ATTRIBUTE_LONG_NAME != ATTRIBUTE_LONG_NAME)
+            .filter(|entry| entry[11] & (ATTRIBUTE_VOLUME_LABEL | ATTRIBUTE_DIRECTORY) == 0)
+            .find(|entry| entry[..11] == name)
+            .ok_or(FatError::NotFound)?;
+
+        let len = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]) as usize;
+        let buf = buf.get_mut(..len).ok_or(FatError::FileTooLarge)?;
+        let mut cluster = u16::from_le_bytes([entry[26], entry[27]]);
+        for chunk in buf.chunks_mut(SECTOR_SIZE) {
+            let index = cluster as usize;
+            if !(FIRST_CLUSTER..FIRST_CLUSTER + Self::CLUSTERS).contains(&index) {
+                return Err(FatError::Corrupted);
+            }
+            let start = Self::cluster_start(index);
+            chunk.copy_from_slice(&self.image[start..start + chunk.len()]);
+            cluster = self.fat_entry(index);
+        }
+        Ok(buf)
+    }
+
+    /// Returns the offset of the first byte of a cluster.
+    fn cluster_start(cluster: usize) -> usize {
+        (Self::DATA_START + cluster - FIRST_CLUSTER) * SECTOR_SIZE
+    }
+
+    fn fat_entry(&self, cluster: usize) -> u16 {
+        let offset = Self::FAT_START * SECTOR_SIZE + cluster * 3 / 2;
+        let pair = u16::from_le_bytes([self.image[offset], self.image[offset + 1]]);
+        if cluster.is_multiple_of(2) {
+            pair & 0x0FFF
+        } else {
+            pair >> 4
+        }
+    }
+
+    fn set_fat_entry(&mut self, cluster: usize, value: u16) {
+        let offset = Self::FAT_START * SECTOR_SIZE + cluster * 3 / 2;
+        let pair = u16::from_le_bytes([self.image[offset], self.image[offset + 1]]);
+        let pair = if cluster.is_multiple_of(2) {
+            pair & 0xF000 | value & 0x0FFF
+        } else {
+            pair & 0x000F | value << 4
+        };
+        self.image[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+    }
+}
+
+impl<const SIZE: usize> Default for FatDisk<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// File being written by the firmware, in the clusters following the files
+/// created before it.
+///
+/// Writing past the end of the disk fails, keeping what fits.
+pub struct FatFile<'a, const SIZE: usize> {
+    disk: &'a mut FatDisk<SIZE>,
+    /// Offset of the directory entry of the file.
+    entry: usize,
+    len: usize,
+}
+
+impl<const SIZE: usize> FatFile<'_, SIZE> {
+    /// Appends bytes to the file.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), FatError> {
+        let start = FatDisk::<SIZE>::cluster_start(self.disk.next_cluster) + self.len;
+        let end = FatDisk::<SIZE>::SECTORS * SECTOR_SIZE;
+        let len = bytes.len().min(end - start);
+        self.disk.image[start..start + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        if len == bytes.len() {
+            Ok(())
+        } else {
+            Err(FatError::DiskFull)
+        }
+    }
+}
+
+impl<const SIZE: usize> fmt::Write for FatFile<'_, SIZE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<const SIZE: usize> Drop for FatFile<'_, SIZE> {
+    fn drop(&mut self) {
+        let disk = &mut *self.disk;
+        let first = disk.next_cluster;
+        let clusters = self.len.div_ceil(SECTOR_SIZE);
+        for cluster in first..first + clusters {
+            let next = if cluster + 1 == first + clusters {
+                END_OF_CHAIN
+            } else {
+                cluster as u16 + 1
+            };
+            disk.set_fat_entry(cluster, next);
+        }
+        disk.next_cluster += clusters;
+
+        // Empty files have no cluster
+        let first = if clusters == 0 { 0 } else { first as u16 };
+        let entry = &mut disk.image[self.entry..self.entry + ENTRY_SIZE];
+        entry[26..28].copy_from_slice(&first.to_le_bytes());
+        entry[28..32].copy_from_slice(&(self.len as u32).to_le_bytes());
+    }
+}
+
+/// Converts a file name such as `KEYMAP.TXT` to the padded name of its
+/// directory entry, or `None` if it is not an upper case 8.3 name.
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let is_valid = |part: &str| {
+        part.bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    if !is_valid(base) || !is_valid(extension) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+
+    type TestDisk = FatDisk<{ 64 * SECTOR_SIZE }>;
+
+    fn disk(files: &[(&str, &[u8])]) -> TestDisk {
+        let mut disk = TestDisk::new();
+        disk.format();
+        for (name, content) in files {
+            disk.create(name).unwrap().write(content).unwrap();
+        }
+        disk
+    }
+
+    /// Writes a file like a host would, in the given clusters.
+    fn host_write(
+        disk: &mut TestDisk,
+        entry: usize,
+        name: &[u8; 11],
+        clusters: &[usize],
+        content: &[u8],
+    ) {
+        for (i, (&cluster, chunk)) in clusters.iter().zip(content.chunks(SECTOR_SIZE)).enumerate() {
+            let lba = (TestDisk::DATA_START + cluster - FIRST_CLUSTER) as u32;
+            disk.sector_mut(lba).unwrap()[..chunk.len()].copy_from_slice(chunk);
+            let next = clusters
+                .get(i + 1)
+                .map_or(END_OF_CHAIN, |&next| next as u16);
+            disk.set_fat_entry(cluster, next);
+        }
+        let root = disk.sector_mut(TestDisk::ROOT_START as u32).unwrap();
+        let entry = &mut root[entry * ENTRY_SIZE..(entry + 1) * ENTRY_SIZE];
+        entry[..11].copy_from_slice(name);
+        entry[11] = ATTRIBUTE_ARCHIVE;
+        entry[26..28].copy_from_slice(&(clusters[0] as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&(content.len() as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn boot_sector() {
+        let disk = disk(&[]);
+        let boot = disk.sector(0).unwrap();
+        assert_eq!(&boot[510..], &[0x55, 0xAA]);
+        assert_eq!(u16::from_le_bytes([boot[11], boot[12]]), 512);
+        assert_eq!(u16::from_le_bytes([boot[19], boot[20]]), 64);
+        assert_eq!(u16::from_le_bytes([boot[22], boot[23]]), 1);
+        assert_eq!(&boot[54..62], b"FAT12   ");
+        assert_eq!(&disk.sector(1).unwrap()[..3], &[0xF8, 0xFF, 0xFF]);
+        assert_eq!(disk.sectors(), 64);
+        assert_eq!(disk.sector(64), None);
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let long = [b'x'; 1300];
+        let mut disk = disk(&[
+            ("INFO.TXT", b"wave-rs"),
+            ("EMPTY", b""),
+            ("LONG.TXT", &long),
+        ]);
+        let mut file = disk.create("FMT.TXT").unwrap();
+        write!(file, "{} layers", 2).unwrap();
+        drop(file);
+
+        let mut buf = [0; 2048];
+        assert_eq!(disk.read_file("INFO.TXT", &mut buf), Ok(&b"wave-rs"[..]));
+        assert_eq!(disk.read_file("EMPTY", &mut buf), Ok(&b""[..]));
+        assert_eq!(disk.read_file("LONG.TXT", &mut buf), Ok(&long[..]));
+        assert_eq!(disk.read_file("FMT.TXT", &mut buf), Ok(&b"2 layers"[..]));
+        assert_eq!(
+            disk.read_file("NONE.TXT", &mut buf),
+            Err(FatError::NotFound)
+        );
+        assert_eq!(
+            disk.read_file("LONG.TXT", &mut buf[..1000]),
+            Err(FatError::FileTooLarge)
+        );
+
+        // The files follow each other in the FAT
+        assert_eq!(disk.fat_entry(2), END_OF_CHAIN);
+        assert_eq!(disk.fat_entry(3), 4);
+        assert_eq!(disk.fat_entry(5), END_OF_CHAIN);
+        assert_eq!(disk.fat_entry(6), END_OF_CHAIN);
+    }
+
+    #[test]
+    fn host_writes() {
+        let mut disk = disk(&[("KEYMAP.TXT", b"old")]);
+        let content = [b'k'; 700];
+
+        // The old entry is deleted and a long name precedes the new one
+        let root = disk.sector_mut(TestDisk::ROOT_START as u32).unwrap();
+        root[ENTRY_SIZE] = DELETED_ENTRY;
+        root[2 * ENTRY_SIZE..2 * ENTRY_SIZE + 11].copy_from_slice(b"Akeymap    ");
+        root[2 * ENTRY_SIZE + 11] = ATTRIBUTE_LONG_NAME;
+        host_write(&mut disk, 3, b"KEYMAP  TXT", &[40, 12], &content);
+
+        let mut buf = [0; 2048];
+        assert_eq!(disk.read_file("KEYMAP.TXT", &mut buf), Ok(&content[..]));
+
+        // A chain shorter than the file
+        disk.set_fat_entry(40, END_OF_CHAIN);
+        assert_eq!(
+            disk.read_file("KEYMAP.TXT", &mut buf),
+            Err(FatError::Corrupted)
+        );
+    }
+
+    #[test]
+    fn full_disk() {
+        let mut disk = disk(&[]);
+        let mut file = disk.create("BIG.BIN").unwrap();
+        assert_eq!(file.write(&[0; 64 * SECTOR_SIZE]), Err(FatError::DiskFull));
+        drop(file);
+        assert_eq!(disk.create("MORE.BIN").err(), Some(FatError::DiskFull));
+
+        let mut disk = self::disk(&[]);
+        for i in 0..ROOT_ENTRIES - 1 {
+            let mut name = heapless::String::<8>::new();
+            write!(name, "F{}", i).unwrap();
+            disk.create(&name).unwrap();
+        }
+        assert_eq!(disk.create("LAST").err(), Some(FatError::TooManyFiles));
+        assert_eq!(disk.create("keymap.txt").err(), Some(FatError::InvalidName));
+        assert_eq!(
+            disk.create("TOOLONGNAME").err(),
+            Some(FatError::InvalidName)
+        );
+    }
+}
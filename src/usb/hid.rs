@@ -1,21 +1,239 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use defmt::*;
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex,
+    pubsub::WaitResult,
+};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::{
-    class::hid::{self, HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler, State},
+    class::hid::{
+        self, HidBootProtocol, HidProtocolMode, HidReader, HidReaderWriter, HidSubclass, HidWriter,
+        ReportId, RequestHandler, State,
+    },
     control::OutResponse,
+    driver::EndpointError,
     Builder,
 };
+use packed_struct::PackedStruct;
 use static_cell::StaticCell;
-use usbd_human_interface_device::device::{
-    keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR, mouse::BOOT_MOUSE_REPORT_DESCRIPTOR,
-};
+use usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR;
 
-use crate::usb::{
-    HID_KEYBOARD_MAX_PACKET_SIZE, HID_KEYBOARD_POLL_MS, HID_KEYBOARD_READER_N,
-    HID_KEYBOARD_WRITER_N, HID_MOUSE_MAX_PACKET_SIZE, HID_MOUSE_POLL_MS, HID_MOUSE_WRITER_N,
+use crate::{
+    config::{LAYOUT, MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER, NUMBER_LAYERS},
+    keyboard::{
+        action::System,
+        event::KEY_EVENTS,
+        keymap::{Keymap, KEYMAP_COMMANDS},
+        layers::Layers,
+        leds::{HostLeds, HOST_LEDS},
+        macros::MACROS,
+        mouse::MOUSE_EVENTS,
+        report::{ConsumerReport, KeyboardReport, SystemReport},
+        scan::MATRIX_STATE,
+        settings::settings,
+        storage::{update_live_keys, KEYMAP_CHANGED},
+    },
+    usb::{
+        midi::MIDI_MESSAGES,
+        raw_hid::{self, KeyInterfaces},
+        usb_device::REMOTE_WAKEUP,
+        HID_EXTRA_MAX_PACKET_SIZE, HID_EXTRA_POLL_MS, HID_EXTRA_WRITER_N,
+        HID_KEYBOARD_MAX_PACKET_SIZE, HID_KEYBOARD_POLL_MS, HID_KEYBOARD_READER_N,
+        HID_KEYBOARD_RETRY_MS, HID_KEYBOARD_WRITER_N, HID_RAW_MAX_PACKET_SIZE, HID_RAW_POLL_MS,
+        HID_RAW_REPORT_SIZE, MIDI_RETRY_MS,
+    },
 };
 
+/// Maximum number of consumer control reports waiting to be sent.
+pub const CONSUMER_REPORTS_SIZE: usize = 8;
+
+/// Channel on which the consumer control reports of the keymap are sent to the
+/// consumer control writer.
+pub static CONSUMER_REPORTS: Channel<
+    CriticalSectionRawMutex,
+    ConsumerReport,
+    CONSUMER_REPORTS_SIZE,
+> = Channel::new();
+
+/// Maximum number of system control reports waiting to be sent.
+pub const SYSTEM_REPORTS_SIZE: usize = 4;
+
+/// Channel on which the system control reports of the keymap are sent to the
+/// system control writer.
+pub static SYSTEM_REPORTS: Channel<CriticalSectionRawMutex, SystemReport, SYSTEM_REPORTS_SIZE> =
+    Channel::new();
+
+/// Channel on which the requests of the raw HID interface are sent to the
+/// keymap.
+pub static RAW_HID_REQUESTS: Channel<CriticalSectionRawMutex, [u8; HID_RAW_REPORT_SIZE], 1> =
+    Channel::new();
+
+/// Channel on which the responses to the raw HID requests are sent back to the
+/// raw HID interface.
+pub static RAW_HID_RESPONSES: Channel<CriticalSectionRawMutex, [u8; HID_RAW_REPORT_SIZE], 1> =
+    Channel::new();
+
+/// Held while a request waits for its response, so that the responses to the
+/// raw HID interface and to the serial shell are not mixed up.
+static RAW_HID_CLIENT: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Number of keyboard reports sent to the host since boot.
+pub static KEYBOARD_REPORTS: AtomicU32 = AtomicU32::new(0);
+
+/// Writer of the extra keys device, shared by the consumer control, system
+/// control and mouse writers.
+pub type ExtraKeysWriter = Mutex<
+    CriticalSectionRawMutex,
+    HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_EXTRA_WRITER_N>,
+>;
+
+/// Report ID of the consumer control reports of the extra keys device.
+pub const CONSUMER_REPORT_ID: u8 = 1;
+/// Report ID of the system control reports of the extra keys device.
+pub const SYSTEM_REPORT_ID: u8 = 2;
+/// Report ID of the mouse reports of the extra keys device.
+pub const MOUSE_REPORT_ID: u8 = 3;
+
+/// Report descriptor of the consumer control keys of the extra keys device.
+///
+/// The report holds the usages of up to 4 keys pressed at once, each on 2
+/// bytes, or 0 in the unused slots.
+#[rustfmt::skip]
+pub const CONSUMER_CONTROL_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,               // Usage Page (Consumer)
+    0x09, 0x01,               // Usage (Consumer Control)
+    0xA1, 0x01,               // Collection (Application)
+    0x85, CONSUMER_REPORT_ID, //   Report ID (1)
+    0x19, 0x00,               //   Usage Minimum (Unassigned)
+    0x2A, 0x9C, 0x02,         //   Usage Maximum (0x029C)
+    0x15, 0x00,               //   Logical Minimum (0)
+    0x26, 0x9C, 0x02,         //   Logical Maximum (0x029C)
+    0x75, 0x10,               //   Report Size (16)
+    0x95, 0x04,               //   Report Count (4)
+    0x81, 0x00,               //   Input (Data, Array, Absolute)
+    0xC0,                     // End Collection
+];
+
+/// Report descriptor of the system control keys of the extra keys device.
+///
+/// The report is a single byte holding the usage of the key pressed, from
+/// System Power Down (0x81) to System Wake Up (0x83), or 0 when no key is
+/// pressed.
+#[rustfmt::skip]
+pub const SYSTEM_CONTROL_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x80,             // Usage (System Control)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, SYSTEM_REPORT_ID, //   Report ID (2)
+    0x19, 0x81,             //   Usage Minimum (System Power Down)
+    0x29, 0x83,             //   Usage Maximum (System Wake Up)
+    0x15, 0x81,             //   Logical Minimum (0x81)
+    0x25, 0x83,             //   Logical Maximum (0x83)
+    0x75, 0x08,             //   Report Size (8)
+    0x95, 0x01,             //   Report Count (1)
+    0x81, 0x00,             //   Input (Data, Array, Absolute)
+    0xC0,                   // End Collection
+];
+
+/// Report descriptor of the mouse of the extra keys device.
+///
+/// The report holds the state of 8 buttons, followed by the movement on the X
+/// and Y axes, the vertical wheel and the horizontal wheel, each on a signed
+/// byte.
+#[rustfmt::skip]
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,            // Usage Page (Generic Desktop)
+    0x09, 0x02,            // Usage (Mouse)
+    0xA1, 0x01,            // Collection (Application)
+    0x85, MOUSE_REPORT_ID, //   Report ID (3)
+    0x09, 0x01,            //   Usage (Pointer)
+    0xA1, 0x00,            //   Collection (Physical)
+    0x05, 0x09,            //     Usage Page (Button)
+    0x19, 0x01,            //     Usage Minimum (1)
+    0x29, 0x08,            //     Usage Maximum (8)
+    0x15, 0x00,            //     Logical Minimum (0)
+    0x25, 0x01,            //     Logical Maximum (1)
+    0x75, 0x01,            //     Report Size (1)
+    0x95, 0x08,            //     Report Count (8)
+    0x81, 0x02,            //     Input (Data, Variable, Absolute)
+    0x05, 0x01,            //     Usage Page (Generic Desktop)
+    0x09, 0x30,            //     Usage (X)
+    0x09, 0x31,            //     Usage (Y)
+    0x09, 0x38,            //     Usage (Wheel)
+    0x15, 0x81,            //     Logical Minimum (-127)
+    0x25, 0x7F,            //     Logical Maximum (127)
+    0x75, 0x08,            //     Report Size (8)
+    0x95, 0x03,            //     Report Count (3)
+    0x81, 0x06,            //     Input (Data, Variable, Relative)
+    0x05, 0x0C,            //     Usage Page (Consumer)
+    0x0A, 0x38, 0x02,      //     Usage (AC Pan)
+    0x95, 0x01,            //     Report Count (1)
+    0x81, 0x06,            //     Input (Data, Variable, Relative)
+    0xC0,                  //   End Collection
+    0xC0,                  // End Collection
+];
+
+/// Report descriptor of the extra keys device without the mouse.
+const EXTRA_KEYS_REPORT_DESCRIPTOR: [u8; CONSUMER_CONTROL_REPORT_DESCRIPTOR.len()
+    + SYSTEM_CONTROL_REPORT_DESCRIPTOR.len()] = concat(&[
+    CONSUMER_CONTROL_REPORT_DESCRIPTOR,
+    SYSTEM_CONTROL_REPORT_DESCRIPTOR,
+]);
+
+/// Report descriptor of the extra keys device with the mouse.
+const EXTRA_KEYS_MOUSE_REPORT_DESCRIPTOR: [u8; CONSUMER_CONTROL_REPORT_DESCRIPTOR.len()
+    + SYSTEM_CONTROL_REPORT_DESCRIPTOR.len()
+    + MOUSE_REPORT_DESCRIPTOR.len()] = concat(&[
+    CONSUMER_CONTROL_REPORT_DESCRIPTOR,
+    SYSTEM_CONTROL_REPORT_DESCRIPTOR,
+    MOUSE_REPORT_DESCRIPTOR,
+]);
+
+/// Report descriptor of the raw HID device.
+///
+/// The vendor-defined usage page and usages are the ones used by QMK, which
+/// configuration tools look for. Both the input and output reports are
+/// [`HID_RAW_REPORT_SIZE`] bytes of opaque data.
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,                // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,                      // Usage (0x61)
+    0xA1, 0x01,                      // Collection (Application)
+    0x09, 0x62,                      //   Usage (0x62)
+    0x15, 0x00,                      //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,                //   Logical Maximum (255)
+    0x75, 0x08,                      //   Report Size (8)
+    0x95, HID_RAW_REPORT_SIZE as u8, //   Report Count (32)
+    0x81, 0x02,                      //   Input (Data, Variable, Absolute)
+    0x09, 0x63,                      //   Usage (0x63)
+    0x15, 0x00,                      //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,                //   Logical Maximum (255)
+    0x75, 0x08,                      //   Report Size (8)
+    0x95, HID_RAW_REPORT_SIZE as u8, //   Report Count (32)
+    0x91, 0x02,                      //   Output (Data, Variable, Absolute)
+    0xC0,                            // End Collection
+];
+
+/// Idle rate of the keyboard in milliseconds, as set by the host.
+///
+/// While no key changes, the keyboard report is repeated at this rate. A rate
+/// of 0 means the report is only sent when it changes.
+static KEYBOARD_IDLE_MS: AtomicU32 = AtomicU32::new(0);
+/// Idle rate of the extra keys in milliseconds, as set by the host.
+static EXTRA_IDLE_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the host switched the keyboard to the boot protocol.
+///
+/// BIOS and KVM switches select the boot protocol to receive 6KRO reports. The
+/// keyboard goes back to the report protocol, and NKRO, when the bus is reset.
+pub static KEYBOARD_BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
 /// Initializes an HID keyboard device.
+///
+/// The keyboard supports the boot protocol, see [`KEYBOARD_BOOT_PROTOCOL`].
 pub async fn init_hid_keyboard(
     builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
 ) -> (
@@ -23,11 +241,17 @@ pub async fn init_hid_keyboard(
     hid::HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_KEYBOARD_WRITER_N>,
 ) {
     // Create classes on the builder
+    static HID_KEYBOARD_HANDLER: StaticCell<HIDRequestHandler> = StaticCell::new();
     let config = hid::Config {
         report_descriptor: NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
-        request_handler: None,
+        request_handler: Some(HID_KEYBOARD_HANDLER.init(HIDRequestHandler::new(
+            &KEYBOARD_IDLE_MS,
+            Some(&KEYBOARD_BOOT_PROTOCOL),
+        ))),
         poll_ms: HID_KEYBOARD_POLL_MS,
         max_packet_size: HID_KEYBOARD_MAX_PACKET_SIZE,
+        hid_subclass: HidSubclass::Boot,
+        hid_boot_protocol: HidBootProtocol::Keyboard,
     };
 
     // Create the hid reader/writer
@@ -43,39 +267,372 @@ pub async fn init_hid_keyboard(
     (reader, writer)
 }
 
-/// Initializes an HID mouse device.
-pub async fn init_hid_mouse(
+/// Initializes an HID device for the keys that are not keyboard keys.
+///
+/// The consumer control and system control keys share this device with the
+/// mouse, if `mouse` is set, and their reports are told apart by their report
+/// ID, so they take a single endpoint.
+pub async fn init_hid_extra(
+    builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
+    mouse: bool,
+) -> &'static ExtraKeysWriter {
+    // Create classes on the builder
+    static HID_EXTRA_HANDLER: StaticCell<HIDRequestHandler> = StaticCell::new();
+    let report_descriptor: &'static [u8] = if mouse {
+        &EXTRA_KEYS_MOUSE_REPORT_DESCRIPTOR
+    } else {
+        &EXTRA_KEYS_REPORT_DESCRIPTOR
+    };
+    let config = hid::Config {
+        report_descriptor,
+        request_handler: Some(HID_EXTRA_HANDLER.init(HIDRequestHandler::new(&EXTRA_IDLE_MS, None))),
+        poll_ms: HID_EXTRA_POLL_MS,
+        max_packet_size: HID_EXTRA_MAX_PACKET_SIZE,
+        hid_subclass: HidSubclass::No,
+        hid_boot_protocol: HidBootProtocol::None,
+    };
+
+    // Create the writer, shared by the writers of each kind of report
+    static HID_EXTRA_STATE: StaticCell<State> = StaticCell::new();
+    static HID_EXTRA_WRITER: StaticCell<ExtraKeysWriter> = StaticCell::new();
+    let writer = HidWriter::<_, HID_EXTRA_WRITER_N>::new(
+        builder,
+        HID_EXTRA_STATE.init(State::new()),
+        config,
+    );
+    HID_EXTRA_WRITER.init(Mutex::new(writer))
+}
+
+/// Initializes an HID raw device, used by the configuration tools.
+pub async fn init_hid_raw(
     builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
-) -> hid::HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_MOUSE_WRITER_N> {
+) -> (
+    hid::HidReader<'static, Driver<'static, USB_OTG_HS>, HID_RAW_REPORT_SIZE>,
+    hid::HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_RAW_REPORT_SIZE>,
+) {
     // Create classes on the builder
-    static HID_MOUSE_HANDLER: StaticCell<HIDRequestHandler> = StaticCell::new();
     let config = hid::Config {
-        report_descriptor: BOOT_MOUSE_REPORT_DESCRIPTOR,
-        request_handler: Some(HID_MOUSE_HANDLER.init(HIDRequestHandler {})),
-        poll_ms: HID_MOUSE_POLL_MS,
-        max_packet_size: HID_MOUSE_MAX_PACKET_SIZE,
+        report_descriptor: RAW_HID_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: HID_RAW_POLL_MS,
+        max_packet_size: HID_RAW_MAX_PACKET_SIZE,
+        hid_subclass: HidSubclass::No,
+        hid_boot_protocol: HidBootProtocol::None,
     };
 
-    // Create the writer
-    static HID_MOUSE_STATE: StaticCell<State> = StaticCell::new();
-    let writer = HidWriter::<_, HID_MOUSE_WRITER_N>::new(
+    // Create the hid reader/writer
+    static HID_RAW_STATE: StaticCell<State> = StaticCell::new();
+    let hid = HidReaderWriter::<_, HID_RAW_REPORT_SIZE, HID_RAW_REPORT_SIZE>::new(
         builder,
-        HID_MOUSE_STATE.init(State::new()),
+        HID_RAW_STATE.init(State::new()),
         config,
     );
-    writer
+
+    // Split the reader and writer
+    let (reader, writer) = hid.split();
+    (reader, writer)
 }
 
 /// Runs a HID reader task.
+///
+/// The output reports of the host hold the state of the keyboard LEDs, which
+/// is published on [`HOST_LEDS`].
 #[embassy_executor::task]
 pub async fn hid_keyboard_reader_task(
     reader: HidReader<'static, Driver<'static, USB_OTG_HS>, HID_KEYBOARD_READER_N>,
 ) -> ! {
-    let mut request_handler = HIDRequestHandler {};
+    let mut request_handler =
+        HIDRequestHandler::new(&KEYBOARD_IDLE_MS, Some(&KEYBOARD_BOOT_PROTOCOL));
     reader.run(false, &mut request_handler).await;
 }
 
-struct HIDRequestHandler {}
+/// Runs a HID writer task.
+///
+/// Key events and keymap commands are resolved through the keymap and the
+/// resulting reports are sent to the host. Macros triggered by the keymap are
+/// handed over to the macro task, the mouse keys to the mouse task and the
+/// consumer and system control reports and MIDI messages to their writers. Pressing
+/// [`System::WakeUp`] also requests a USB remote wakeup. The requests of the
+/// raw HID interface are answered here too, as they edit the live keymap,
+/// which starts from `layers`, and the edits are copied by [`update_live_keys`]
+/// and saved through [`KEYMAP_CHANGED`]. Keys needing an interface missing from `interfaces` are
+/// refused.
+///
+/// When a report fails to be sent, the intermediate reports are dropped and the
+/// latest state of the keymap is sent again until the host receives it, so no
+/// key stays stuck down on the host. While nothing changes, the latest report
+/// is repeated at the idle rate set by the host.
+#[embassy_executor::task]
+pub async fn hid_keyboard_writer_task(
+    mut writer: HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_KEYBOARD_WRITER_N>,
+    layers: Layers<NUMBER_LAYERS, MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER>,
+    interfaces: KeyInterfaces,
+) -> ! {
+    let mut subscriber = KEY_EVENTS
+        .subscriber()
+        .expect("Failed to subscribe to key events");
+    let mut keymap = Keymap::new(layers);
+    keymap.apply_settings(settings());
+    update_live_keys(keymap.layers());
+    let mut host_leds = HOST_LEDS.receiver().expect("Failed to watch the host LEDs");
+    let mut retry_at: Option<Instant> = None;
+    let mut idle_at: Option<Instant> = None;
+    let mut midi_retry_at: Option<Instant> = None;
+
+    loop {
+        // Wait for the next key event, for the next command, for the LEDs to
+        // change, for the next deadline of the keymap, or for the time to
+        // retry a failed report, to repeat it or to retry the MIDI messages
+        let deadline = [keymap.next_deadline(), retry_at, idle_at, midi_retry_at]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(Instant::MAX);
+        match select4(
+            subscriber.next_message(),
+            select(KEYMAP_COMMANDS.receive(), RAW_HID_REQUESTS.receive()),
+            host_leds.changed(),
+            Timer::at(deadline),
+        )
+        .await
+        {
+            Either4::First(WaitResult::Message(event)) => keymap.process(event),
+            Either4::First(WaitResult::Lagged(n)) => {
+                warn!("HID | Missed {} key events, releasing all keys", n);
+                keymap.release_all();
+            }
+            Either4::Second(Either::First(command)) => keymap.command(command),
+            Either4::Second(Either::Second(request)) => {
+                let matrix = MATRIX_STATE.lock(|state| state.get());
+                let response = raw_hid::respond(
+                    &request,
+                    keymap.layers_mut(),
+                    &LAYOUT,
+                    &matrix,
+                    interfaces,
+                    Instant::now(),
+                );
+                if raw_hid::edits_keymap(&request) {
+                    update_live_keys(keymap.layers());
+                    KEYMAP_CHANGED.signal(());
+                }
+                RAW_HID_RESPONSES.send(response).await;
+            }
+            Either4::Third(leds) => keymap.set_host_leds(leds),
+            Either4::Fourth(_) => keymap.tick(Instant::now()),
+        }
+
+        while let Some(event) = keymap.pop_mouse_event() {
+            if MOUSE_EVENTS.try_send(event).is_err() {
+                warn!("HID | Too many mouse key events waiting to be applied");
+            }
+        }
+
+        while let Some(report) = keymap.pop_consumer_report() {
+            if CONSUMER_REPORTS.try_send(report).is_err() {
+                warn!("HID | Too many consumer control reports waiting to be sent");
+            }
+        }
+
+        while let Some(report) = keymap.pop_system_report() {
+            if report.key() == Some(System::WakeUp) {
+                REMOTE_WAKEUP.signal(());
+            }
+            if SYSTEM_REPORTS.try_send(report).is_err() {
+                warn!("HID | Too many system control reports waiting to be sent");
+            }
+        }
+
+        while let Some(steps) = keymap.pop_macro() {
+            if MACROS.try_send(steps).is_err() {
+                warn!("HID | Too many macros waiting to be played");
+            }
+        }
+
+        // MIDI messages wait in the keymap while the MIDI writer is behind, so
+        // no note off is lost
+        while !MIDI_MESSAGES.is_full() {
+            let Some(message) = keymap.pop_midi_message() else {
+                break;
+            };
+            let _ = MIDI_MESSAGES.try_send(message);
+        }
+        midi_retry_at = keymap
+            .has_midi_messages()
+            .then(|| Instant::now() + Duration::from_millis(MIDI_RETRY_MS));
+
+        // Resend the latest state of the keymap after a failure
+        if retry_at.is_some() {
+            keymap.clear_reports();
+            retry_at = if write_keyboard_report(&mut writer, &keymap.current_report()).await {
+                idle_at = idle_deadline();
+                None
+            } else {
+                Some(Instant::now() + Duration::from_millis(HID_KEYBOARD_RETRY_MS))
+            };
+            continue;
+        }
+
+        let mut sent = false;
+        while let Some(report) = keymap.pop_report() {
+            if !write_keyboard_report(&mut writer, &report).await {
+                keymap.clear_reports();
+                retry_at = Some(Instant::now() + Duration::from_millis(HID_KEYBOARD_RETRY_MS));
+                break;
+            }
+            sent = true;
+        }
+
+        // Repeat the latest report when the idle rate elapsed
+        if !sent && idle_at.is_some_and(|at| Instant::now() >= at) {
+            sent = write_keyboard_report(&mut writer, &keymap.current_report()).await;
+        }
+        if sent {
+            idle_at = idle_deadline();
+        }
+    }
+}
+
+/// Returns when the keyboard report must be repeated if nothing changes until
+/// then, according to the idle rate set by the host.
+fn idle_deadline() -> Option<Instant> {
+    let idle_ms = KEYBOARD_IDLE_MS.load(Ordering::Relaxed);
+    (idle_ms != 0).then(|| Instant::now() + Duration::from_millis(idle_ms as u64))
+}
+
+/// Runs a HID writer task.
+///
+/// The consumer control reports sent on [`CONSUMER_REPORTS`] are written to
+/// the host in order.
+#[embassy_executor::task]
+pub async fn hid_consumer_writer_task(writer: &'static ExtraKeysWriter) -> ! {
+    loop {
+        let report = CONSUMER_REPORTS.receive().await;
+        let report = report.to_multiple().pack().unwrap();
+        if let Err(e) = write_extra_report(writer, CONSUMER_REPORT_ID, &report).await {
+            warn!("HID | Failed to send consumer control report: {:?}", e);
+        }
+    }
+}
+
+/// Runs a HID writer task.
+///
+/// The system control reports sent on [`SYSTEM_REPORTS`] are written to the
+/// host in order.
+#[embassy_executor::task]
+pub async fn hid_system_writer_task(writer: &'static ExtraKeysWriter) -> ! {
+    loop {
+        let report = SYSTEM_REPORTS.receive().await;
+        if let Err(e) = write_extra_report(writer, SYSTEM_REPORT_ID, &report.to_bytes()).await {
+            warn!("HID | Failed to send system control report: {:?}", e);
+        }
+    }
+}
+
+/// Runs a HID reader and writer task.
+///
+/// Each request of the configuration tools is answered by the keymap, see
+/// [`raw_hid::RawHidCommand`], and its response is written back to the host.
+#[embassy_executor::task]
+pub async fn hid_raw_task(
+    mut reader: HidReader<'static, Driver<'static, USB_OTG_HS>, HID_RAW_REPORT_SIZE>,
+    mut writer: HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_RAW_REPORT_SIZE>,
+) -> ! {
+    let mut request = [0; HID_RAW_REPORT_SIZE];
+    loop {
+        match reader.read(&mut request).await {
+            Ok(_) => {}
+            Err(e) => {
+                warn!("HID | Failed to read raw HID request: {:?}", e);
+                continue;
+            }
+        }
+
+        let response = raw_hid_request(request).await;
+        if let Err(e) = writer.write(&response).await {
+            warn!("HID | Failed to send raw HID response: {:?}", e);
+        }
+    }
+}
+
+/// Sends a raw HID request to the keymap and waits for its response.
+pub async fn raw_hid_request(request: [u8; HID_RAW_REPORT_SIZE]) -> [u8; HID_RAW_REPORT_SIZE] {
+    let _client = RAW_HID_CLIENT.lock().await;
+    RAW_HID_REQUESTS.send(request).await;
+    RAW_HID_RESPONSES.receive().await
+}
+
+/// Sends a report of the extra keys device to the host, prefixed with its
+/// report ID.
+pub async fn write_extra_report(
+    writer: &ExtraKeysWriter,
+    id: u8,
+    report: &[u8],
+) -> Result<(), EndpointError> {
+    let mut buf = [0; HID_EXTRA_WRITER_N];
+    buf[0] = id;
+    buf[1..=report.len()].copy_from_slice(report);
+    writer.lock().await.write(&buf[..=report.len()]).await
+}
+
+/// Sends a keyboard report to the host.
+///
+/// The report is a 6KRO boot report in boot protocol, and a NKRO report
+/// otherwise. Returns `false` if the report could not be sent.
+async fn write_keyboard_report(
+    writer: &mut HidWriter<'static, Driver<'static, USB_OTG_HS>, HID_KEYBOARD_WRITER_N>,
+    report: &KeyboardReport,
+) -> bool {
+    let result = if KEYBOARD_BOOT_PROTOCOL.load(Ordering::Relaxed) {
+        writer.write(&report.to_boot()).await
+    } else {
+        writer.write(&report.to_nkro().pack().unwrap()).await
+    };
+    match result {
+        Ok(()) => {
+            KEYBOARD_REPORTS.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(e) => {
+            warn!("HID | Failed to send keyboard report: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Concatenates report descriptors.
+const fn concat<const N: usize>(descriptors: &[&[u8]]) -> [u8; N] {
+    let mut result = [0; N];
+    let mut len = 0;
+    let mut i = 0;
+    while i < descriptors.len() {
+        let mut j = 0;
+        while j < descriptors[i].len() {
+            result[len] = descriptors[i][j];
+            len += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    assert!(len == N);
+    result
+}
+
+struct HIDRequestHandler {
+    /// Idle rate of the interface, shared with its writer.
+    idle_ms: &'static AtomicU32,
+    /// Whether the interface is in boot protocol, if it supports it.
+    boot_protocol: Option<&'static AtomicBool>,
+}
+
+impl HIDRequestHandler {
+    const fn new(idle_ms: &'static AtomicU32, boot_protocol: Option<&'static AtomicBool>) -> Self {
+        Self {
+            idle_ms,
+            boot_protocol,
+        }
+    }
+}
 
 impl RequestHandler for HIDRequestHandler {
     fn get_report(&mut self, id: ReportId, _buf: &mut [u8]) -> Option<usize> {
@@ -84,16 +641,48 @@ impl RequestHandler for HIDRequestHandler {
     }
 
     fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
-        info!("HID | Set report for {:?}: {=[u8]}", id, data);
-        OutResponse::Accepted
+        match (id, data) {
+            // The only output report is the one of the keyboard LEDs
+            (ReportId::Out(_), [leds, ..]) => {
+                let leds = HostLeds::from_report(*leds);
+                debug!("HID | Host LEDs set to {:?}", leds);
+                HOST_LEDS.sender().send(leds);
+                OutResponse::Accepted
+            }
+            _ => {
+                warn!("HID | Unsupported report for {:?}: {=[u8]}", id, data);
+                OutResponse::Rejected
+            }
+        }
     }
 
     fn set_idle_ms(&mut self, id: Option<ReportId>, dur: u32) {
         info!("HID | Set idle rate for {:?} to {:?}", id, dur);
+        // The rate of an interface applies to all of its reports
+        self.idle_ms.store(dur, Ordering::Relaxed);
     }
 
     fn get_idle_ms(&mut self, id: Option<ReportId>) -> Option<u32> {
         info!("HID | Get idle rate for {:?}", id);
-        None
+        Some(self.idle_ms.load(Ordering::Relaxed))
+    }
+
+    fn get_protocol(&self) -> HidProtocolMode {
+        match self.boot_protocol {
+            Some(boot) if boot.load(Ordering::Relaxed) => HidProtocolMode::Boot,
+            _ => HidProtocolMode::Report,
+        }
+    }
+
+    fn set_protocol(&mut self, protocol: HidProtocolMode) -> OutResponse {
+        info!("HID | Set protocol to {:?}", protocol);
+        match (self.boot_protocol, protocol) {
+            (Some(boot), _) => {
+                boot.store(protocol == HidProtocolMode::Boot, Ordering::Relaxed);
+                OutResponse::Accepted
+            }
+            (None, HidProtocolMode::Report) => OutResponse::Accepted,
+            (None, HidProtocolMode::Boot) => OutResponse::Rejected,
+        }
     }
 }
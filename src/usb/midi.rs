@@ -0,0 +1,35 @@
+use defmt::warn;
+use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_usb::{class::midi::MidiClass, Builder};
+
+use crate::{keyboard::midi::MidiMessage, usb::MIDI_MAX_PACKET_SIZE};
+
+/// Maximum number of MIDI messages waiting to be sent.
+pub const MIDI_MESSAGES_SIZE: usize = 16;
+
+/// Channel on which the MIDI messages of the keymap are sent to the MIDI
+/// writer.
+pub static MIDI_MESSAGES: Channel<CriticalSectionRawMutex, MidiMessage, MIDI_MESSAGES_SIZE> =
+    Channel::new();
+
+/// Initializes a USB MIDI streaming class with one input and one output jack.
+pub async fn init_midi(
+    builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
+) -> MidiClass<'static, Driver<'static, USB_OTG_HS>> {
+    MidiClass::new(builder, 1, 1, MIDI_MAX_PACKET_SIZE)
+}
+
+/// Runs a MIDI writer task.
+///
+/// The MIDI messages sent on [`MIDI_MESSAGES`] are written to the host in
+/// order, on the first cable.
+#[embassy_executor::task]
+pub async fn midi_writer_task(mut class: MidiClass<'static, Driver<'static, USB_OTG_HS>>) -> ! {
+    loop {
+        let message = MIDI_MESSAGES.receive().await;
+        if let Err(e) = class.write_packet(&message.to_packet(0)).await {
+            warn!("MIDI | Failed to send MIDI message: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,742 @@
+use core::fmt::{self, Write};
+
+use defmt::{error, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::{
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::{self, Endpoint, EndpointError, EndpointIn, EndpointOut},
+    types::InterfaceNumber,
+    Builder, Handler,
+};
+use static_cell::{ConstStaticCell, StaticCell};
+
+use crate::{
+    config::{MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER, NUMBER_LAYERS},
+    keyboard::{
+        settings::{set_settings, settings},
+        storage::{read_keys, save_keymap},
+    },
+    usb::{
+        dfu::DfuFlash,
+        disk_files::{
+            file_text, parse_keymap, parse_settings, write_errors, write_keymap, write_settings,
+            FileErrors,
+        },
+        fat::{FatDisk, FatError, FatFile, SECTOR_SIZE},
+        hid::raw_hid_request,
+        raw_hid::{encode_key, response_payload, KeyInterfaces, RawHidCommand, KEY_SIZE},
+        serial::request,
+        MSC_DISK_SIZE, MSC_FILE_SIZE, MSC_MAX_PACKET_SIZE, MSC_SYNC_DELAY_MS,
+    },
+};
+
+/// Mass storage interface class.
+const MSC_CLASS: u8 = 0x08;
+/// SCSI transparent command set subclass.
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+/// Bulk-only transport protocol.
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+/// Class request resetting the bulk-only transport.
+const REQUEST_RESET: u8 = 0xFF;
+/// Class request returning the highest logical unit number.
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+
+/// Size in bytes of a command block wrapper.
+const CBW_SIZE: usize = 31;
+/// Signature of a command block wrapper, `USBC`.
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+/// Signature of a command status wrapper, `USBS`.
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+const STATUS_PASSED: u8 = 0x00;
+const STATUS_FAILED: u8 = 0x01;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+
+/// Size in bytes of the longest response that is not a sector, the inquiry.
+const RESPONSE_SIZE: usize = 36;
+
+const INFO_FILE: &str = "INFO.TXT";
+const KEYMAP_FILE: &str = "KEYMAP.TXT";
+const CONFIG_FILE: &str = "CONFIG.TXT";
+const ERRORS_FILE: &str = "ERRORS.TXT";
+
+/// The disk shown to the host.
+type Disk = FatDisk<MSC_DISK_SIZE>;
+
+/// Initializes a USB mass storage class.
+///
+/// The host sees a small disk holding the keymap and the settings as text
+/// files, see [`msc_task`].
+pub async fn init_msc(
+    builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
+) -> MscClass<'static, Driver<'static, USB_OTG_HS>> {
+    static MSC_CONTROL: StaticCell<MscControl> = StaticCell::new();
+    MscClass::new(builder, MSC_CONTROL.init(MscControl::new()))
+}
+
+/// Runs the mass storage task.
+///
+/// The disk is formatted with `INFO.TXT`, `KEYMAP.TXT`, `CONFIG.TXT` and
+/// `ERRORS.TXT`. Once the host stops writing for [`MSC_SYNC_DELAY_MS`], the
+/// keymap and settings files it changed are parsed. A valid keymap, whose keys
+/// only need the USB `interfaces` created at boot, is applied to the live
+/// keymap and saved in flash, and valid settings replace the
+/// settings in use. The errors of the files are written to `ERRORS.TXT`, and
+/// the disk is then formatted again and reported to the host as changed.
+#[embassy_executor::task]
+pub async fn msc_task(
+    mut class: MscClass<'static, Driver<'static, USB_OTG_HS>>,
+    flash: &'static DfuFlash,
+    interfaces: KeyInterfaces,
+) -> ! {
+    static MSC_DISK: ConstStaticCell<Disk> = ConstStaticCell::new(FatDisk::new());
+    static MSC_FILE: ConstStaticCell<[u8; MSC_FILE_SIZE]> =
+        ConstStaticCell::new([0; MSC_FILE_SIZE]);
+    let disk = MSC_DISK.take();
+    let buf = MSC_FILE.take();
+
+    let mut files = DiskFiles::new(interfaces);
+    files.refresh(disk, buf);
+    let mut scsi = Scsi::new();
+    let mut sync_at: Option<Instant> = None;
+
+    loop {
+        match select(
+            class.read_command(),
+            Timer::at(sync_at.unwrap_or(Instant::MAX)),
+        )
+        .await
+        {
+            Either::First(Ok(Some(command))) => {
+                match scsi.execute(&mut class, disk, &command).await {
+                    Ok(true) => {
+                        sync_at = Some(Instant::now() + Duration::from_millis(MSC_SYNC_DELAY_MS))
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!("MSC | Failed to answer a command: {:?}", e),
+                }
+            }
+            Either::First(Ok(None)) => warn!("MSC | Invalid command block"),
+            Either::First(Err(EndpointError::Disabled)) => class.wait_connection().await,
+            Either::First(Err(e)) => warn!("MSC | Failed to read a command: {:?}", e),
+            Either::Second(_) => {
+                sync_at = None;
+                if files.sync(disk, buf, flash).await {
+                    files.refresh(disk, buf);
+                    scsi.medium_changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// Mass storage class using the bulk-only transport and SCSI commands.
+pub struct MscClass<'d, D: driver::Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+}
+
+impl<'d, D: driver::Driver<'d>> MscClass<'d, D> {
+    /// Creates the interface and its bulk endpoints.
+    pub fn new(builder: &mut Builder<'d, D>, control: &'d mut MscControl) -> Self {
+        let mut func = builder.function(MSC_CLASS, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT);
+        let mut iface = func.interface();
+        control.interface = iface.interface_number();
+        let mut alt = iface.alt_setting(MSC_CLASS, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT, None);
+        let read_ep = alt.endpoint_bulk_out(None, MSC_MAX_PACKET_SIZE);
+        let write_ep = alt.endpoint_bulk_in(None, MSC_MAX_PACKET_SIZE);
+        drop(func);
+        builder.handler(control);
+
+        Self { read_ep, write_ep }
+    }
+
+    /// Waits for the host to configure the interface.
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Reads the next command, or `None` if its wrapper is invalid.
+    async fn read_command(&mut self) -> Result<Option<Command>, EndpointError> {
+        let mut packet = [0; MSC_MAX_PACKET_SIZE as usize];
+        let len = self.read_ep.read(&mut packet).await?;
+        Ok(Command::parse(&packet[..len]))
+    }
+
+    /// Sends the data of a command, padded with zeros to `len` bytes.
+    async fn write_data(&mut self, data: &[u8], len: usize) -> Result<(), EndpointError> {
+        let mut packet = [0; MSC_MAX_PACKET_SIZE as usize];
+        let mut sent = 0;
+        while sent < len {
+            let size = (len - sent).min(packet.len());
+            let data = data.get(sent..).unwrap_or_default();
+            let copied = data.len().min(size);
+            packet.fill(0);
+            packet[..copied].copy_from_slice(&data[..copied]);
+            self.write_ep.write(&packet[..size]).await?;
+            sent += size;
+        }
+        Ok(())
+    }
+
+    /// Receives the data of a command, dropping what does not fit in `buf`.
+    async fn read_data(&mut self, buf: &mut [u8], len: usize) -> Result<(), EndpointError> {
+        let mut packet = [0; MSC_MAX_PACKET_SIZE as usize];
+        let mut received = 0;
+        while received < len {
+            let size = self.read_ep.read(&mut packet).await?;
+            if let Some(dest) = buf.get_mut(received..) {
+                let copied = dest.len().min(size);
+                dest[..copied].copy_from_slice(&packet[..copied]);
+            }
+            received += size;
+            // A short packet ends the transfer
+            if size < packet.len() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_status(
+        &mut self,
+        tag: u32,
+        residue: u32,
+        status: u8,
+    ) -> Result<(), EndpointError> {
+        let mut csw = [0; 13];
+        csw[..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = status;
+        self.write_ep.write(&csw).await
+    }
+}
+
+/// Answers the class requests of the mass storage interface.
+pub struct MscControl {
+    interface: InterfaceNumber,
+}
+
+impl MscControl {
+    const fn new() -> Self {
+        Self {
+            interface: InterfaceNumber(0),
+        }
+    }
+
+    fn accepts(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+    }
+}
+
+impl Handler for MscControl {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.accepts(&req) {
+            return None;
+        }
+        match req.request {
+            REQUEST_RESET => Some(OutResponse::Accepted),
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.accepts(&req) {
+            return None;
+        }
+        match req.request {
+            // The disk is the only logical unit
+            REQUEST_GET_MAX_LUN => {
+                buf[0] = 0;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// Command block wrapper sent by the host before each command.
+struct Command {
+    tag: u32,
+    /// Number of bytes of data the host expects to transfer.
+    len: usize,
+    /// Whether the data goes to the host.
+    data_in: bool,
+    block: [u8; 16],
+}
+
+impl Command {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != CBW_SIZE || bytes[..4] != CBW_SIGNATURE.to_le_bytes() {
+            return None;
+        }
+        let mut block = [0; 16];
+        block.copy_from_slice(&bytes[15..CBW_SIZE]);
+        Some(Self {
+            tag: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            len: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            data_in: bytes[12] & 0x80 != 0,
+            block,
+        })
+    }
+
+    /// Reads the logical block address and block count of a 10 byte command.
+    fn blocks(&self) -> (u32, u32) {
+        let block = &self.block;
+        let lba = u32::from_be_bytes([block[2], block[3], block[4], block[5]]);
+        let count = u16::from_be_bytes([block[7], block[8]]) as u32;
+        (lba, count)
+    }
+}
+
+/// Sense key and additional sense code of the last failed command.
+#[derive(Copy, Clone)]
+struct Sense {
+    key: u8,
+    code: u8,
+}
+
+impl Sense {
+    const NONE: Self = Self {
+        key: 0x00,
+        code: 0x00,
+    };
+    const INVALID_COMMAND: Self = Self {
+        key: 0x05,
+        code: 0x20,
+    };
+    const OUT_OF_RANGE: Self = Self {
+        key: 0x05,
+        code: 0x21,
+    };
+    const INVALID_FIELD: Self = Self {
+        key: 0x05,
+        code: 0x24,
+    };
+    const MEDIUM_CHANGED: Self = Self {
+        key: 0x06,
+        code: 0x28,
+    };
+}
+
+/// Data phase of a command.
+enum Data {
+    None,
+    /// The first bytes of the response buffer go to the host.
+    In(usize),
+    /// Sectors go to the host.
+    Read {
+        lba: u32,
+        count: u32,
+    },
+    /// Sectors come from the host.
+    Write {
+        lba: u32,
+        count: u32,
+    },
+}
+
+/// State of the SCSI disk.
+struct Scsi {
+    sense: Sense,
+    /// Whether the next command must report that the medium changed, so the
+    /// host reads the disk again.
+    medium_changed: bool,
+}
+
+impl Scsi {
+    const fn new() -> Self {
+        Self {
+            sense: Sense::NONE,
+            medium_changed: false,
+        }
+    }
+
+    /// Executes a command and sends its status. Returns whether the host wrote
+    /// to the disk.
+    async fn execute<'d, D: driver::Driver<'d>>(
+        &mut self,
+        class: &mut MscClass<'d, D>,
+        disk: &mut Disk,
+        command: &Command,
+    ) -> Result<bool, EndpointError> {
+        let mut response = [0; RESPONSE_SIZE];
+        let data = self.prepare(disk, command, &mut response);
+        // The data must go in the direction the host expects
+        let data = match data {
+            Ok(Data::In(_) | Data::Read { .. }) if !command.data_in && command.len > 0 => {
+                Err(Sense::INVALID_FIELD)
+            }
+            Ok(Data::Write { .. }) if command.data_in => Err(Sense::INVALID_FIELD),
+            data => data,
+        };
+
+        // Bytes of the command moved over the bus, and those that were data
+        let mut moved = 0;
+        let mut transferred = 0;
+        let mut wrote = false;
+        let status = match data {
+            Ok(Data::None) => STATUS_PASSED,
+            Ok(Data::In(len)) => {
+                transferred = len.min(command.len);
+                class
+                    .write_data(&response[..transferred], command.len)
+                    .await?;
+                moved = command.len;
+                STATUS_PASSED
+            }
+            Ok(Data::Read { lba, count }) => {
+                for lba in lba..lba + count {
+                    let sector = disk.sector(lba).unwrap_or_default();
+                    class.write_data(sector, SECTOR_SIZE).await?;
+                    transferred += SECTOR_SIZE;
+                }
+                moved = transferred;
+                STATUS_PASSED
+            }
+            Ok(Data::Write { lba, count }) => {
+                for lba in lba..lba + count {
+                    let sector = disk.sector_mut(lba).unwrap_or_default();
+                    class.read_data(sector, SECTOR_SIZE).await?;
+                    transferred += SECTOR_SIZE;
+                }
+                moved = transferred;
+                wrote = true;
+                STATUS_PASSED
+            }
+            Err(sense) => {
+                self.sense = sense;
+                STATUS_FAILED
+            }
+        };
+
+        // The host expects every byte it announced, padded or discarded
+        if moved < command.len {
+            if command.data_in {
+                class.write_data(&[], command.len - moved).await?;
+            } else {
+                class.read_data(&mut [], command.len - moved).await?;
+            }
+        }
+
+        let residue = (command.len - transferred) as u32;
+        class.write_status(command.tag, residue, status).await?;
+        Ok(wrote)
+    }
+
+    /// Checks a command and fills the response of the commands that are not
+    /// sector transfers.
+    fn prepare(
+        &mut self,
+        disk: &Disk,
+        command: &Command,
+        response: &mut [u8; RESPONSE_SIZE],
+    ) -> Result<Data, Sense> {
+        let block = &command.block;
+        if self.medium_changed && !matches!(block[0], INQUIRY | REQUEST_SENSE) {
+            self.medium_changed = false;
+            return Err(Sense::MEDIUM_CHANGED);
+        }
+
+        let sectors = disk.sectors();
+        match block[0] {
+            TEST_UNIT_READY
+            | START_STOP_UNIT
+            | PREVENT_ALLOW_MEDIUM_REMOVAL
+            | VERIFY_10
+            | SYNCHRONIZE_CACHE_10 => Ok(Data::None),
+            REQUEST_SENSE => {
+                response[..18].copy_from_slice(&[
+                    0x70, // Current error, fixed format
+                    0,
+                    self.sense.key,
+                    0,
+                    0,
+                    0,
+                    0,
+                    10, // Additional sense length
+                    0,
+                    0,
+                    0,
+                    0,
+                    self.sense.code,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ]);
+                self.sense = Sense::NONE;
+                Ok(Data::In(18))
+            }
+            INQUIRY => {
+                // Vital product data pages are not supported
+                if block[1] & 0x01 != 0 {
+                    return Err(Sense::INVALID_FIELD);
+                }
+                response[..8].copy_from_slice(&[
+                    0x00, // Direct access block device
+                    0x80, // Removable
+                    0x04, // SPC-2
+                    0x02, // Response data format
+                    RESPONSE_SIZE as u8 - 5,
+                    0,
+                    0,
+                    0,
+                ]);
+                response[8..16].copy_from_slice(b"wave-rs ");
+                response[16..32].copy_from_slice(b"Keymap disk     ");
+                response[32..36].copy_from_slice(b"1.0 ");
+                Ok(Data::In(RESPONSE_SIZE))
+            }
+            MODE_SENSE_6 => {
+                // No mode page, and the disk is not write protected
+                response[..4].copy_from_slice(&[3, 0, 0, 0]);
+                Ok(Data::In(4))
+            }
+            MODE_SENSE_10 => {
+                response[..8].copy_from_slice(&[0, 6, 0, 0, 0, 0, 0, 0]);
+                Ok(Data::In(8))
+            }
+            READ_FORMAT_CAPACITIES => {
+                response[..4].copy_from_slice(&[0, 0, 0, 8]);
+                response[4..8].copy_from_slice(&sectors.to_be_bytes());
+                // Formatted media, then the size of a sector on 3 bytes
+                response[8..12].copy_from_slice(&(0x0200_0000 | SECTOR_SIZE as u32).to_be_bytes());
+                Ok(Data::In(12))
+            }
+            READ_CAPACITY_10 => {
+                response[..4].copy_from_slice(&(sectors - 1).to_be_bytes());
+                response[4..8].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+                Ok(Data::In(8))
+            }
+            READ_10 | WRITE_10 => {
+                let (lba, count) = command.blocks();
+                if lba.checked_add(count).is_none_or(|end| end > sectors) {
+                    return Err(Sense::OUT_OF_RANGE);
+                }
+                if count as usize * SECTOR_SIZE > command.len {
+                    return Err(Sense::INVALID_FIELD);
+                }
+                if block[0] == READ_10 {
+                    Ok(Data::Read { lba, count })
+                } else {
+                    Ok(Data::Write { lba, count })
+                }
+            }
+            _ => Err(Sense::INVALID_COMMAND),
+        }
+    }
+}
+
+/// Files of the disk generated by the firmware.
+struct DiskFiles {
+    /// Hash of the keymap file when it was generated.
+    keymap_hash: Option<u32>,
+    /// Hash of the settings file when it was generated.
+    config_hash: Option<u32>,
+    keymap_errors: FileErrors,
+    config_errors: FileErrors,
+    /// USB interfaces the keys of the keymap file can use.
+    interfaces: KeyInterfaces,
+}
+
+impl DiskFiles {
+    const fn new(interfaces: KeyInterfaces) -> Self {
+        Self {
+            keymap_hash: None,
+            config_hash: None,
+            interfaces,
+            keymap_errors: FileErrors::new(),
+            config_errors: FileErrors::new(),
+        }
+    }
+
+    /// Formats the disk with the live keymap, the settings in use and the
+    /// errors of the files last written by the host.
+    fn refresh(&mut self, disk: &mut Disk, buf: &mut [u8]) {
+        let keys = read_keys();
+        let settings = settings();
+
+        disk.format();
+        create_file(disk, INFO_FILE, |w| write_info(w));
+        create_file(disk, KEYMAP_FILE, |w| write_keymap(w, &keys));
+        create_file(disk, CONFIG_FILE, |w| write_settings(w, &settings));
+        create_file(disk, ERRORS_FILE, |w| {
+            write_errors(w, KEYMAP_FILE, &self.keymap_errors)?;
+            write_errors(w, CONFIG_FILE, &self.config_errors)
+        });
+
+        self.keymap_hash = disk.read_file(KEYMAP_FILE, buf).ok().map(file_hash);
+        self.config_hash = disk.read_file(CONFIG_FILE, buf).ok().map(file_hash);
+    }
+
+    /// Applies the keymap and settings files changed by the host. Returns
+    /// whether any was.
+    async fn sync(&mut self, disk: &Disk, buf: &mut [u8], flash: &DfuFlash) -> bool {
+        let mut changed = false;
+
+        if let Some(bytes) = changed_file(disk, KEYMAP_FILE, buf, self.keymap_hash) {
+            changed = true;
+            self.keymap_errors.clear();
+            match file_text(bytes) {
+                Ok(text) => {
+                    apply_keymap(text, self.interfaces, &mut self.keymap_errors, flash).await
+                }
+                Err(error) => {
+                    let _ = self.keymap_errors.push(error);
+                }
+            }
+        }
+
+        if let Some(bytes) = changed_file(disk, CONFIG_FILE, buf, self.config_hash) {
+            changed = true;
+            self.config_errors.clear();
+            match file_text(bytes) {
+                Ok(text) => apply_settings(text, &mut self.config_errors).await,
+                Err(error) => {
+                    let _ = self.config_errors.push(error);
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+/// Reads a file of the disk if its content changed since it was generated.
+fn changed_file<'a>(
+    disk: &Disk,
+    name: &str,
+    buf: &'a mut [u8],
+    hash: Option<u32>,
+) -> Option<&'a [u8]> {
+    match disk.read_file(name, buf) {
+        Ok(bytes) if Some(file_hash(bytes)) != hash => Some(bytes),
+        Ok(_) => None,
+        // The file is recreated when the disk is formatted again
+        Err(FatError::NotFound) => None,
+        Err(e) => {
+            warn!("MSC | Failed to read {}: {:?}", name, e);
+            None
+        }
+    }
+}
+
+/// Applies a keymap file to the live keymap, and saves it in flash.
+async fn apply_keymap(
+    text: &str,
+    interfaces: KeyInterfaces,
+    errors: &mut FileErrors,
+    flash: &DfuFlash,
+) {
+    let mut keys = read_keys();
+    parse_keymap(text, &mut keys, interfaces, errors);
+    if !errors.is_empty() {
+        warn!("MSC | {} has {} errors", KEYMAP_FILE, errors.len());
+        return;
+    }
+
+    for (layer, rows) in keys.iter().enumerate() {
+        for (row, cols) in rows.iter().enumerate() {
+            for (col, key) in cols.iter().enumerate() {
+                let Some(Ok(key)) = key.map(encode_key) else {
+                    continue;
+                };
+                let mut args = [0; 4 + KEY_SIZE];
+                args[..4].copy_from_slice(&[
+                    RawHidCommand::SetKey as u8,
+                    layer as u8,
+                    row as u8,
+                    col as u8,
+                ]);
+                args[4..].copy_from_slice(&key);
+                let response = raw_hid_request(request(&args)).await;
+                if let Err(status) = response_payload(&response) {
+                    warn!(
+                        "MSC | Failed to set the key at layer {}, row {}, col {}: {:?}",
+                        layer, row, col, status
+                    );
+                }
+            }
+        }
+    }
+    save_keymap(flash, &read_keys());
+    info!("MSC | Applied {}", KEYMAP_FILE);
+}
+
+/// Applies a settings file to the settings in use.
+async fn apply_settings(text: &str, errors: &mut FileErrors) {
+    let mut new = settings();
+    parse_settings(text, &mut new, errors);
+    if !errors.is_empty() {
+        warn!("MSC | {} has {} errors", CONFIG_FILE, errors.len());
+        return;
+    }
+    if new != settings() {
+        set_settings(new).await;
+    }
+    info!("MSC | Applied {}", CONFIG_FILE);
+}
+
+/// Creates a file of the disk and writes its content.
+fn create_file(
+    disk: &mut Disk,
+    name: &str,
+    write: impl FnOnce(&mut FatFile<'_, MSC_DISK_SIZE>) -> fmt::Result,
+) {
+    let result = disk
+        .create(name)
+        .and_then(|mut file| write(&mut file).map_err(|_| FatError::DiskFull));
+    if let Err(e) = result {
+        error!("MSC | Failed to write {}: {:?}", name, e);
+    }
+}
+
+/// Writes the content of `INFO.TXT`.
+fn write_info<W: Write>(w: &mut W) -> fmt::Result {
+    writeln!(w, "wave-rs {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(
+        w,
+        "{} layers of {} rows of {} keys\n",
+        NUMBER_LAYERS, MATRIX_ROWS_NUMBER, MATRIX_COLUMNS_NUMBER
+    )?;
+    writeln!(w, "{}  keys of each layer", KEYMAP_FILE)?;
+    writeln!(w, "{}  settings", CONFIG_FILE)?;
+    writeln!(w, "{}  errors of the files last saved\n", ERRORS_FILE)?;
+    writeln!(
+        w,
+        "Edit {} or {} and save it. Once the file is valid, the keyboard",
+        KEYMAP_FILE, CONFIG_FILE
+    )?;
+    writeln!(
+        w,
+        "applies it, keeps it across reboots and reloads the disk."
+    )
+}
+
+/// Hashes the content of a file with FNV-1a.
+fn file_hash(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
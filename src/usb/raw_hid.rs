@@ -0,0 +1,443 @@
+use embassy_time::Instant;
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use crate::{
+    keyboard::{
+        action::{
+            Action, HoldTapAction, HoldTapConfig, KeyAction, LayerTapAction, Mouse, System,
+            TapDanceAction,
+        },
+        dma::LinkedListWord,
+        layers::Layers,
+    },
+    usb::{via, HID_RAW_REPORT_SIZE},
+};
+
+/// Version of the raw HID protocol, increased on every breaking change.
+pub const RAW_HID_PROTOCOL_VERSION: u8 = 2;
+
+/// Size in bytes of an encoded key.
+pub const KEY_SIZE: usize = 8;
+
+/// Size in bytes of an encoded action.
+const ACTION_SIZE: usize = 3;
+
+/// Offset of the status in a response.
+const STATUS_OFFSET: usize = 1;
+/// Offset of the payload in a response.
+const PAYLOAD_OFFSET: usize = 2;
+/// Offset of the key in the arguments of [`RawHidCommand::SetKey`] and in the
+/// payload of the responses to [`RawHidCommand::GetKey`] and
+/// [`RawHidCommand::SetKey`], after its layer, row and column.
+const KEY_OFFSET: usize = 3;
+
+/// Mouse keys, in the order of their encoding.
+pub(crate) const MOUSE_KEYS: [Mouse; 13] = [
+    Mouse::LeftClick,
+    Mouse::RightClick,
+    Mouse::MiddleClick,
+    Mouse::ScrollUp,
+    Mouse::ScrollDown,
+    Mouse::ScrollLeft,
+    Mouse::ScrollRight,
+    Mouse::MoveUp,
+    Mouse::MoveDown,
+    Mouse::MoveLeft,
+    Mouse::MoveRight,
+    Mouse::SpeedUp,
+    Mouse::SpeedDown,
+];
+
+/// Optional USB interfaces the keys can be sent through.
+///
+/// The mouse and MIDI interfaces are only created at boot when the keymap uses
+/// them, so keys needing an interface that was not created are refused.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyInterfaces {
+    pub mouse: bool,
+    pub midi: bool,
+}
+
+impl KeyInterfaces {
+    /// Checks if the interfaces needed by a key were created.
+    pub fn supports(&self, key: KeyAction) -> bool {
+        (self.mouse || !key.has_mouse_action()) && (self.midi || !key.has_midi_action())
+    }
+}
+
+/// Command of a raw HID request.
+///
+/// Configuration tools send a request in each output report and receive the
+/// response in the next input report. A request starts with its command,
+/// followed by its arguments. A response starts with the command it answers
+/// and a [`RawHidStatus`], followed by its payload. Multi-byte values are
+/// little-endian, and both reports are padded with zeros.
+///
+/// Commands start at `0x80`, the commands below being left to VIA.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum RawHidCommand {
+    /// Returns the protocol version, the number of layers, rows and columns,
+    /// and the firmware version as a NUL-terminated string.
+    GetVersion = 0x80,
+    /// Takes a layer, row and column, and returns them followed by the key,
+    /// encoded with [`encode_key`].
+    GetKey = 0x81,
+    /// Takes a layer, row, column and key, and returns them once the key is
+    /// set in the live keymap.
+    SetKey = 0x82,
+    /// Returns the bitmask of the active layers as a `u32`, which includes the
+    /// default layer, followed by the default layer.
+    GetLayerState = 0x83,
+    /// Takes the bitmask of the active layers as a `u32` and the default
+    /// layer, and returns the new layer state like [`GetLayerState`].
+    ///
+    /// [`GetLayerState`]: RawHidCommand::GetLayerState
+    SetLayerState = 0x84,
+    /// Returns the number of rows and columns, followed by the rows held down
+    /// in each column as a `u16` bitmask, after debouncing.
+    GetMatrixState = 0x85,
+}
+
+impl TryFrom<u8> for RawHidCommand {
+    type Error = RawHidStatus;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x80 => Ok(Self::GetVersion),
+            0x81 => Ok(Self::GetKey),
+            0x82 => Ok(Self::SetKey),
+            0x83 => Ok(Self::GetLayerState),
+            0x84 => Ok(Self::SetLayerState),
+            0x85 => Ok(Self::GetMatrixState),
+            _ => Err(RawHidStatus::UnknownCommand),
+        }
+    }
+}
+
+/// Status of a raw HID response.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum RawHidStatus {
+    Ok = 0x00,
+    /// The command does not exist.
+    UnknownCommand = 0x01,
+    /// An argument is out of range, such as a layer that does not exist.
+    InvalidArgument = 0x02,
+    /// The key cannot be encoded, such as a tap dance table or a macro,
+    /// which only exist in the firmware.
+    Unsupported = 0x03,
+}
+
+impl TryFrom<u8> for RawHidStatus {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Ok),
+            0x01 => Ok(Self::UnknownCommand),
+            0x02 => Ok(Self::InvalidArgument),
+            0x03 => Ok(Self::Unsupported),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Answers a raw HID request.
+///
+/// Keys and the layer state are read from and written to the live `layers`,
+/// and `matrix` is the current state of the matrix. Keys are only set if
+/// `interfaces` supports them. Requests below `0x80` are answered by
+/// [`via::respond`].
+pub fn respond<const L: usize, const M: usize, const N: usize>(
+    request: &[u8; HID_RAW_REPORT_SIZE],
+    layers: &mut Layers<L, M, N>,
+    default_layers: &Layers<L, M, N>,
+    matrix: &[LinkedListWord; N],
+    interfaces: KeyInterfaces,
+    now: Instant,
+) -> [u8; HID_RAW_REPORT_SIZE] {
+    if request[0] < 0x80 {
+        return via::respond(request, layers, default_layers, matrix, interfaces, now);
+    }
+
+    let mut response = [0; HID_RAW_REPORT_SIZE];
+    response[0] = request[0];
+    let status = match RawHidCommand::try_from(request[0]) {
+        Ok(command) => execute(
+            command,
+            &request[1..],
+            layers,
+            matrix,
+            interfaces,
+            &mut response[PAYLOAD_OFFSET..],
+        ),
+        Err(status) => Err(status),
+    };
+    response[STATUS_OFFSET] = status.err().unwrap_or(RawHidStatus::Ok) as u8;
+    response
+}
+
+/// Reads the payload of a response.
+///
+/// Returns the status of the response if the request failed. Statuses that
+/// this firmware does not know are read as [`RawHidStatus::Unsupported`].
+pub fn response_payload(response: &[u8; HID_RAW_REPORT_SIZE]) -> Result<&[u8], RawHidStatus> {
+    match RawHidStatus::try_from(response[STATUS_OFFSET]) {
+        Ok(RawHidStatus::Ok) => Ok(&response[PAYLOAD_OFFSET..]),
+        Ok(status) => Err(status),
+        Err(()) => Err(RawHidStatus::Unsupported),
+    }
+}
+
+/// Reads the key of the payload of a response to [`RawHidCommand::GetKey`] or
+/// [`RawHidCommand::SetKey`].
+pub fn read_key(payload: &[u8]) -> Result<KeyAction, RawHidStatus> {
+    decode_key(&payload[KEY_OFFSET..KEY_OFFSET + KEY_SIZE])
+}
+
+/// Reads the bitmask of the active layers and the default layer of the payload
+/// of a response to [`RawHidCommand::GetLayerState`] or
+/// [`RawHidCommand::SetLayerState`].
+pub fn read_layer_state(payload: &[u8]) -> (u32, usize) {
+    let state = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    (state, payload[4] as usize)
+}
+
+/// Checks if a raw HID request edits the keymap, which must then be saved.
+///
+/// Requests below `0x80` are checked by [`via::edits_keymap`].
+pub fn edits_keymap(request: &[u8; HID_RAW_REPORT_SIZE]) -> bool {
+    if request[0] < 0x80 {
+        return via::edits_keymap(request);
+    }
+    request[0] == RawHidCommand::SetKey as u8
+}
+
+fn execute<const L: usize, const M: usize, const N: usize>(
+    command: RawHidCommand,
+    args: &[u8],
+    layers: &mut Layers<L, M, N>,
+    matrix: &[LinkedListWord; N],
+    interfaces: KeyInterfaces,
+    payload: &mut [u8],
+) -> Result<(), RawHidStatus> {
+    match command {
+        RawHidCommand::GetVersion => {
+            payload[..4].copy_from_slice(&[RAW_HID_PROTOCOL_VERSION, L as u8, M as u8, N as u8]);
+            // Keep the last byte for the NUL terminator
+            let version = env!("CARGO_PKG_VERSION").as_bytes();
+            let len = version.len().min(payload.len() - 5);
+            payload[4..4 + len].copy_from_slice(&version[..len]);
+        }
+        RawHidCommand::GetKey => {
+            let (layer, row, col) = key_position::<L, M, N>(args)?;
+            let key = layers.get_layer(layer)[(row, col)];
+            payload[..KEY_OFFSET].copy_from_slice(&args[..KEY_OFFSET]);
+            payload[KEY_OFFSET..KEY_OFFSET + KEY_SIZE].copy_from_slice(&encode_key(key)?);
+        }
+        RawHidCommand::SetKey => {
+            let (layer, row, col) = key_position::<L, M, N>(args)?;
+            let key = decode_key(&args[KEY_OFFSET..KEY_OFFSET + KEY_SIZE])?;
+            if layers.get_layer_from_key(key).is_none() && is_layer_action(key)
+                || !interfaces.supports(key)
+            {
+                return Err(RawHidStatus::InvalidArgument);
+            }
+            layers.set_key_from_layer(layer, row, col, key);
+            payload[..KEY_OFFSET + KEY_SIZE].copy_from_slice(&args[..KEY_OFFSET + KEY_SIZE]);
+        }
+        RawHidCommand::GetLayerState => {
+            write_layer_state(layers, payload);
+        }
+        RawHidCommand::SetLayerState => {
+            let state = u32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+            layers
+                .set_default_layer(args[4] as usize)
+                .map_err(|_| RawHidStatus::InvalidArgument)?;
+            layers.set_state(state);
+            write_layer_state(layers, payload);
+        }
+        RawHidCommand::GetMatrixState => {
+            payload[..2].copy_from_slice(&[M as u8, N as u8]);
+            for (bytes, rows) in payload[2..].chunks_exact_mut(2).zip(matrix) {
+                bytes.copy_from_slice(&(*rows as u16).to_le_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the layer, row and column of a key request.
+fn key_position<const L: usize, const M: usize, const N: usize>(
+    args: &[u8],
+) -> Result<(usize, usize, usize), RawHidStatus> {
+    let (layer, row, col) = (args[0] as usize, args[1] as usize, args[2] as usize);
+    if layer < L && row < M && col < N {
+        Ok((layer, row, col))
+    } else {
+        Err(RawHidStatus::InvalidArgument)
+    }
+}
+
+pub(crate) fn is_layer_action(key: KeyAction) -> bool {
+    key.is_layer_key() || matches!(key, KeyAction::LayerTap(_))
+}
+
+fn write_layer_state<const L: usize, const M: usize, const N: usize>(
+    layers: &Layers<L, M, N>,
+    payload: &mut [u8],
+) {
+    payload[..4].copy_from_slice(&layers.state().to_le_bytes());
+    payload[4] = layers.get_default_layer() as u8;
+}
+
+/// Encodes a key.
+///
+/// The first byte is the kind of key, followed by its fields:
+///
+/// | Kind | Key                 | Fields                           |
+/// |------|---------------------|----------------------------------|
+/// | 0x00 | No operation        |                                  |
+/// | 0x01 | Transparent         |                                  |
+/// | 0x02 | Single action       | action                           |
+/// | 0x03 | Momentary layer     | layer                            |
+/// | 0x04 | Toggle layer        | layer                            |
+/// | 0x05 | To layer            | layer                            |
+/// | 0x06 | One-shot layer      | layer                            |
+/// | 0x07 | Default layer       | layer                            |
+/// | 0x08 | Layer-tap           | layer, config, tap action        |
+/// | 0x09 | Hold-tap            | config, hold action, tap action  |
+/// | 0x0A | Repeat tap dance    | config, hold action, tap action  |
+/// | 0x0B | One-shot modifier   | keyboard usage                   |
+/// | 0x0C | Dynamic macro       | index                            |
+///
+/// An action is its kind (keyboard 0, consumer 1, system 2, mouse 3) and its
+/// usage as a `u16`. Mouse keys are numbered from 0, in the order of
+/// [`Mouse`]. Hold-tap configurations are numbered from 0, in the order of
+/// [`HoldTapConfig`].
+pub fn encode_key(key: KeyAction) -> Result<[u8; KEY_SIZE], RawHidStatus> {
+    let mut bytes = [0; KEY_SIZE];
+    match key {
+        KeyAction::NoOp => bytes[0] = 0x00,
+        KeyAction::Transparent => bytes[0] = 0x01,
+        KeyAction::Single(action) => {
+            bytes[0] = 0x02;
+            bytes[1..1 + ACTION_SIZE].copy_from_slice(&encode_action(action)?);
+        }
+        KeyAction::Layer(layer)
+        | KeyAction::ToggleLayer(layer)
+        | KeyAction::ToLayer(layer)
+        | KeyAction::OneShotLayer(layer)
+        | KeyAction::DefaultLayer(layer) => {
+            bytes[0] = match key {
+                KeyAction::Layer(_) => 0x03,
+                KeyAction::ToggleLayer(_) => 0x04,
+                KeyAction::ToLayer(_) => 0x05,
+                KeyAction::OneShotLayer(_) => 0x06,
+                _ => 0x07,
+            };
+            bytes[1] = layer as u8;
+        }
+        KeyAction::LayerTap(action) => {
+            bytes[..3].copy_from_slice(&[0x08, action.layer as u8, action.config as u8]);
+            bytes[3..3 + ACTION_SIZE].copy_from_slice(&encode_action(action.tap)?);
+        }
+        KeyAction::HoldTap(action) | KeyAction::TapDance(TapDanceAction::Repeat(action)) => {
+            bytes[0] = if matches!(key, KeyAction::HoldTap(_)) {
+                0x09
+            } else {
+                0x0A
+            };
+            bytes[1] = action.config as u8;
+            bytes[2..2 + ACTION_SIZE].copy_from_slice(&encode_action(action.hold)?);
+            bytes[5..5 + ACTION_SIZE].copy_from_slice(&encode_action(action.tap)?);
+        }
+        KeyAction::OneShot(key) => bytes[..2].copy_from_slice(&[0x0B, key.into()]),
+        KeyAction::DynamicMacro(index) => bytes[..2].copy_from_slice(&[0x0C, index]),
+        KeyAction::TapDance(TapDanceAction::Table(_)) | KeyAction::Macro(_) => {
+            return Err(RawHidStatus::Unsupported)
+        }
+    }
+    Ok(bytes)
+}
+
+/// Decodes a key encoded by [`encode_key`].
+pub fn decode_key(bytes: &[u8]) -> Result<KeyAction, RawHidStatus> {
+    let layer = bytes[1] as usize;
+    let key = match bytes[0] {
+        0x00 => KeyAction::NoOp,
+        0x01 => KeyAction::Transparent,
+        0x02 => KeyAction::Single(decode_action(&bytes[1..])?),
+        0x03 => KeyAction::Layer(layer),
+        0x04 => KeyAction::ToggleLayer(layer),
+        0x05 => KeyAction::ToLayer(layer),
+        0x06 => KeyAction::OneShotLayer(layer),
+        0x07 => KeyAction::DefaultLayer(layer),
+        0x08 => KeyAction::LayerTap(LayerTapAction {
+            layer,
+            config: decode_config(bytes[2])?,
+            tap: decode_action(&bytes[3..])?,
+        }),
+        kind @ (0x09 | 0x0A) => {
+            let action = HoldTapAction {
+                config: decode_config(bytes[1])?,
+                hold: decode_action(&bytes[2..])?,
+                tap: decode_action(&bytes[5..])?,
+            };
+            if kind == 0x09 {
+                KeyAction::HoldTap(action)
+            } else {
+                KeyAction::TapDance(TapDanceAction::Repeat(action))
+            }
+        }
+        0x0B => KeyAction::OneShot(Keyboard::from(bytes[1])),
+        0x0C => KeyAction::DynamicMacro(bytes[1]),
+        _ => return Err(RawHidStatus::InvalidArgument),
+    };
+    Ok(key)
+}
+
+fn encode_action(action: Action) -> Result<[u8; ACTION_SIZE], RawHidStatus> {
+    let (kind, usage): (u8, u16) = match action {
+        Action::Keyboard(key) => (0, u8::from(key) as u16),
+        Action::Consumer(key) => (1, u16::from(key)),
+        Action::System(key) => (2, key as u16),
+        Action::Mouse(key) => (3, key as u16),
+        Action::Unicode(_) | Action::UnicodeString(_) | Action::Midi(_) => {
+            return Err(RawHidStatus::Unsupported)
+        }
+    };
+    let [low, high] = usage.to_le_bytes();
+    Ok([kind, low, high])
+}
+
+fn decode_action(bytes: &[u8]) -> Result<Action, RawHidStatus> {
+    let usage = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let action = match (bytes[0], usage) {
+        (0, usage) if usage <= u8::MAX as u16 => Action::Keyboard(Keyboard::from(usage as u8)),
+        (1, usage) => Action::Consumer(Consumer::from(usage)),
+        (2, 0x81) => Action::System(System::PowerDown),
+        (2, 0x82) => Action::System(System::Sleep),
+        (2, 0x83) => Action::System(System::WakeUp),
+        (3, usage) => Action::Mouse(
+            *MOUSE_KEYS
+                .get(usage as usize)
+                .ok_or(RawHidStatus::InvalidArgument)?,
+        ),
+        _ => return Err(RawHidStatus::InvalidArgument),
+    };
+    Ok(action)
+}
+
+fn decode_config(byte: u8) -> Result<HoldTapConfig, RawHidStatus> {
+    match byte {
+        0 => Ok(HoldTapConfig::Default),
+        1 => Ok(HoldTapConfig::HoldOnOtherKeyPress),
+        2 => Ok(HoldTapConfig::PermissiveHold),
+        _ => Err(RawHidStatus::InvalidArgument),
+    }
+}
@@ -0,0 +1,322 @@
+use core::sync::atomic::Ordering;
+
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    pubsub::{Subscriber, WaitResult},
+};
+use embassy_time::{Duration, Instant};
+use embassy_usb::{class::cdc_acm::CdcAcmClass, driver::EndpointError};
+use heapless::{String, Vec};
+use wave_rs_protocol::{
+    self as protocol, encode, CobsAccumulator, Diagnostics, Error, Event, FeedResult, Info,
+    KeyEvent, KeyPosition, Keycode, LayerState, Message, Request, RequestBody, RequestHeader,
+    Response, ResponseBody, Settings, FRAME_DELIMITER, MAX_FRAME_SIZE, PROTOCOL_VERSION,
+};
+
+use crate::{
+    config::{LAYOUT, MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER, NUMBER_LAYERS},
+    keyboard::{
+        event::{
+            self, KEY_EVENTS, KEY_EVENTS_CAPACITY, KEY_EVENTS_PUBLISHERS, KEY_EVENTS_SUBSCRIBERS,
+        },
+        host_layout::HostLayout,
+        scan::{KEY_PRESSES, MATRIX_STATE, SCANS},
+        send_string::{send_string, SendStringError},
+        settings::{self, set_settings},
+        unicode::UnicodeMode,
+    },
+    usb::{
+        hid::{raw_hid_request, KEYBOARD_REPORTS},
+        raw_hid::{
+            is_layer_action, read_layer_state, response_payload, RawHidCommand, RawHidStatus,
+        },
+        serial::{request, write_all},
+        via::{from_keycode, response_keycode, ViaCommand},
+        HID_RAW_REPORT_SIZE, SERIAL_MAX_PACKET_SIZE,
+    },
+};
+
+/// Subscription of the serial port to the key events.
+type KeyEventSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    event::KeyEvent,
+    KEY_EVENTS_CAPACITY,
+    KEY_EVENTS_SUBSCRIBERS,
+    KEY_EVENTS_PUBLISHERS,
+>;
+
+/// Answers the requests of host tools on a serial connection, until it is
+/// closed.
+///
+/// Requests and messages are COBS frames of the [`wave_rs_protocol`] crate.
+/// `received` holds the bytes received after the delimiter that switched the
+/// port from the shell to this protocol.
+pub async fn serve(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    received: &[u8],
+) -> Result<(), EndpointError> {
+    info!("RPC | Started");
+    // Ends the output of the shell, which hosts then drop as a malformed frame
+    write_all(class, &[FRAME_DELIMITER]).await?;
+    let mut accumulator = CobsAccumulator::<MAX_FRAME_SIZE>::new();
+    // Decodes the headers of the same frames, for the id of malformed requests
+    let mut headers = CobsAccumulator::<MAX_FRAME_SIZE>::new();
+    let mut subscriber = None;
+    let mut buf = [0; SERIAL_MAX_PACKET_SIZE as usize];
+    let mut n = received.len();
+    buf[..n].copy_from_slice(received);
+
+    loop {
+        let mut window = &buf[..n];
+        while !window.is_empty() {
+            let header = headers.feed::<RequestHeader>(window);
+            let (response, remaining) = match accumulator.feed::<Request>(window) {
+                FeedResult::Consumed => break,
+                FeedResult::OverFull(remaining) | FeedResult::DeserError(remaining) => {
+                    let id = match header {
+                        FeedResult::Success { data, .. } => data.id,
+                        _ => 0,
+                    };
+                    warn!("RPC | Malformed request {}", id);
+                    (Response::new(id, Err(Error::Malformed)), remaining)
+                }
+                FeedResult::Success { data, remaining } => {
+                    (respond(data, &mut subscriber).await, remaining)
+                }
+            };
+            send(class, &Message::Response(response)).await?;
+            window = remaining;
+        }
+
+        n = match subscriber.as_mut() {
+            Some(subscriber) => {
+                match select(class.read_packet(&mut buf), subscriber.next_message()).await {
+                    Either::First(result) => result?,
+                    Either::Second(message) => {
+                        let event = match message {
+                            WaitResult::Message(event) => Event::Key(KeyEvent {
+                                row: event.row,
+                                col: event.col,
+                                pressed: event.pressed,
+                                at_us: event.at.as_micros(),
+                            }),
+                            WaitResult::Lagged(count) => Event::Lagged(count),
+                        };
+                        send(class, &Message::Event(event)).await?;
+                        0
+                    }
+                }
+            }
+            None => class.read_packet(&mut buf).await?,
+        };
+    }
+}
+
+/// Answers a request.
+async fn respond(request: Request, subscriber: &mut Option<KeyEventSubscriber>) -> Response {
+    if request.version != PROTOCOL_VERSION {
+        return Response::new(request.id, Err(Error::UnsupportedVersion));
+    }
+    Response::new(request.id, execute(request.body, subscriber).await)
+}
+
+async fn execute(
+    body: RequestBody,
+    subscriber: &mut Option<KeyEventSubscriber>,
+) -> Result<ResponseBody, Error> {
+    let response = match body {
+        RequestBody::GetInfo => {
+            let mut firmware_version = String::new();
+            let _ = firmware_version.push_str(env!("CARGO_PKG_VERSION"));
+            ResponseBody::Info(Info {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version,
+                layers: NUMBER_LAYERS as u8,
+                rows: MATRIX_ROWS_NUMBER as u8,
+                columns: MATRIX_COLUMNS_NUMBER as u8,
+            })
+        }
+        RequestBody::GetKey(key) => {
+            check_key(key)?;
+            let response = raw_hid_request(request(&[
+                ViaCommand::DynamicKeymapGetKeycode as u8,
+                key.layer,
+                key.row,
+                key.col,
+            ]))
+            .await;
+            ResponseBody::Key(key, response_keycode(&response).ok_or(Error::OutOfRange)?)
+        }
+        RequestBody::SetKey(key, keycode) => {
+            check_key(key)?;
+            check_keycode(keycode)?;
+            let [high, low] = keycode.to_be_bytes();
+            let response = raw_hid_request(request(&[
+                ViaCommand::DynamicKeymapSetKeycode as u8,
+                key.layer,
+                key.row,
+                key.col,
+                high,
+                low,
+            ]))
+            .await;
+            ResponseBody::Key(
+                key,
+                response_keycode(&response).ok_or(Error::UnsupportedKeycode)?,
+            )
+        }
+        RequestBody::GetLayerState => {
+            let response = raw_hid_request(request(&[RawHidCommand::GetLayerState as u8])).await;
+            ResponseBody::LayerState(layer_state(&response)?)
+        }
+        RequestBody::SetLayerState(state) => {
+            let [a, b, c, d] = state.active.to_le_bytes();
+            let response = raw_hid_request(request(&[
+                RawHidCommand::SetLayerState as u8,
+                a,
+                b,
+                c,
+                d,
+                state.default_layer,
+            ]))
+            .await;
+            ResponseBody::LayerState(layer_state(&response)?)
+        }
+        RequestBody::GetSettings => ResponseBody::Settings(to_settings(settings::settings())),
+        RequestBody::SetSettings(settings) => {
+            set_settings(from_settings(settings)).await;
+            ResponseBody::Settings(settings)
+        }
+        RequestBody::GetDiagnostics => {
+            let matrix = MATRIX_STATE.lock(|state| state.get());
+            ResponseBody::Diagnostics(Diagnostics {
+                uptime_ms: Instant::now().as_millis(),
+                scans: SCANS.load(Ordering::Relaxed),
+                key_presses: KEY_PRESSES.load(Ordering::Relaxed),
+                keyboard_reports: KEYBOARD_REPORTS.load(Ordering::Relaxed),
+                matrix: Vec::from_slice(&matrix).unwrap_or_default(),
+            })
+        }
+        RequestBody::SubscribeKeyEvents => {
+            if subscriber.is_none() {
+                *subscriber = Some(KEY_EVENTS.subscriber().map_err(|_| Error::Busy)?);
+            }
+            ResponseBody::Done
+        }
+        RequestBody::UnsubscribeKeyEvents => {
+            *subscriber = None;
+            ResponseBody::Done
+        }
+        RequestBody::SendString(text) => {
+            send_string(&text).map_err(|e| match e {
+                SendStringError::UnsupportedCharacter(c, _) => Error::UnsupportedCharacter(c),
+                SendStringError::TooLong | SendStringError::QueueFull => Error::Busy,
+            })?;
+            ResponseBody::Done
+        }
+    };
+    Ok(response)
+}
+
+/// Checks that a key is in the keymap.
+fn check_key(key: KeyPosition) -> Result<(), Error> {
+    if (key.layer as usize) < NUMBER_LAYERS
+        && (key.row as usize) < MATRIX_ROWS_NUMBER
+        && (key.col as usize) < MATRIX_COLUMNS_NUMBER
+    {
+        Ok(())
+    } else {
+        Err(Error::OutOfRange)
+    }
+}
+
+/// Checks that a keycode can be set in the keymap.
+fn check_keycode(keycode: Keycode) -> Result<(), Error> {
+    match from_keycode(keycode) {
+        Some(key) if !is_layer_action(key) || LAYOUT.get_layer_from_key(key).is_some() => Ok(()),
+        _ => Err(Error::UnsupportedKeycode),
+    }
+}
+
+/// Reads the layer state of a raw HID response.
+fn layer_state(response: &[u8; HID_RAW_REPORT_SIZE]) -> Result<LayerState, Error> {
+    let (active, default_layer) = read_layer_state(response_payload(response).map_err(to_error)?);
+    Ok(LayerState {
+        active,
+        default_layer: default_layer as u8,
+    })
+}
+
+/// Converts the status of a failed raw HID request to an error.
+fn to_error(status: RawHidStatus) -> Error {
+    match status {
+        RawHidStatus::InvalidArgument => Error::OutOfRange,
+        RawHidStatus::Unsupported => Error::UnsupportedKeycode,
+        RawHidStatus::Ok | RawHidStatus::UnknownCommand => Error::Malformed,
+    }
+}
+
+fn to_settings(settings: settings::Settings) -> Settings {
+    Settings {
+        tapping_term_ms: settings.tapping_term.as_millis() as u32,
+        tap_dance_term_ms: settings.tap_dance_term.as_millis() as u32,
+        one_shot_timeout_ms: settings.one_shot_timeout.as_millis() as u32,
+        combo_timeout_ms: settings.combo_timeout.as_millis() as u32,
+        host_layout: match settings.host_layout {
+            HostLayout::Us => protocol::HostLayout::Us,
+            HostLayout::Uk => protocol::HostLayout::Uk,
+            HostLayout::FrenchAzerty => protocol::HostLayout::FrenchAzerty,
+            HostLayout::GermanQwertz => protocol::HostLayout::GermanQwertz,
+            HostLayout::Dvorak => protocol::HostLayout::Dvorak,
+        },
+        typing_delay_ms: settings.typing_delay.as_millis() as u32,
+        unicode_mode: match settings.unicode_mode {
+            UnicodeMode::Linux => protocol::UnicodeMode::Linux,
+            UnicodeMode::MacOs => protocol::UnicodeMode::MacOs,
+            UnicodeMode::WinCompose => protocol::UnicodeMode::WinCompose,
+            UnicodeMode::WindowsAltCode => protocol::UnicodeMode::WindowsAltCode,
+        },
+    }
+}
+
+fn from_settings(settings: Settings) -> settings::Settings {
+    settings::Settings {
+        tapping_term: Duration::from_millis(settings.tapping_term_ms as u64),
+        tap_dance_term: Duration::from_millis(settings.tap_dance_term_ms as u64),
+        one_shot_timeout: Duration::from_millis(settings.one_shot_timeout_ms as u64),
+        combo_timeout: Duration::from_millis(settings.combo_timeout_ms as u64),
+        host_layout: match settings.host_layout {
+            protocol::HostLayout::Us => HostLayout::Us,
+            protocol::HostLayout::Uk => HostLayout::Uk,
+            protocol::HostLayout::FrenchAzerty => HostLayout::FrenchAzerty,
+            protocol::HostLayout::GermanQwertz => HostLayout::GermanQwertz,
+            protocol::HostLayout::Dvorak => HostLayout::Dvorak,
+        },
+        typing_delay: Duration::from_millis(settings.typing_delay_ms as u64),
+        unicode_mode: match settings.unicode_mode {
+            protocol::UnicodeMode::Linux => UnicodeMode::Linux,
+            protocol::UnicodeMode::MacOs => UnicodeMode::MacOs,
+            protocol::UnicodeMode::WinCompose => UnicodeMode::WinCompose,
+            protocol::UnicodeMode::WindowsAltCode => UnicodeMode::WindowsAltCode,
+        },
+    }
+}
+
+/// Sends a message to the host.
+async fn send(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    message: &Message,
+) -> Result<(), EndpointError> {
+    let mut buf = [0; MAX_FRAME_SIZE];
+    match encode(message, &mut buf) {
+        Ok(frame) => write_all(class, frame).await,
+        Err(_) => {
+            warn!("RPC | Message too large");
+            Ok(())
+        }
+    }
+}
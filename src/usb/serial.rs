@@ -1,50 +1,377 @@
-use defmt::{info, panic};
-use embassy_stm32::{
-    peripherals::USB_OTG_HS,
-    usb::{Driver, Instance},
-};
+use core::{fmt::Write, sync::atomic::Ordering};
+
+use cortex_m::peripheral::SCB;
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_time::{Instant, Timer};
 use embassy_usb::{
     class::cdc_acm::{self, CdcAcmClass},
     driver::EndpointError,
     Builder,
 };
+use heapless::String;
 use static_cell::StaticCell;
+use wave_rs_protocol::FRAME_DELIMITER;
+
+use crate::{
+    config::{
+        debounce::{DEBOUNCE_ALGORITHM, DEBOUNCE_TIME},
+        scan::FREQUENCY,
+        LAYOUT, MATRIX_COLUMNS_NUMBER, MATRIX_ROWS_NUMBER, NUMBER_LAYERS,
+    },
+    keyboard::{
+        scan::{KEY_PRESSES, MATRIX_STATE, SCANS},
+        send_string::send_string,
+        settings::{set_settings, settings, Settings},
+    },
+    usb::{
+        dfu::{reboot_into_bootloader, DfuFlash},
+        hid::{raw_hid_request, KEYBOARD_REPORTS},
+        raw_hid::{is_layer_action, read_layer_state, response_payload, RawHidCommand},
+        rpc,
+        shell::{Command, Input, LineEditor, ParseError, HELP, PROMPT},
+        via::{from_keycode, response_keycode, ViaCommand, FIRMWARE_KEYCODE},
+        HID_RAW_REPORT_SIZE, SERIAL_MAX_PACKET_SIZE, SHELL_MATRIX_REFRESH_MS, SHELL_OUTPUT_SIZE,
+        SHELL_REBOOT_DELAY_MS, USB_PID, USB_VID,
+    },
+};
+
+/// Output of the shell, sent once a command is done.
+type Output = String<SHELL_OUTPUT_SIZE>;
 
 /// Initializes a serial class.
 pub async fn init_serial(
     builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
 ) -> CdcAcmClass<'static, Driver<'static, USB_OTG_HS>> {
     static STATE_SERIAL: StaticCell<cdc_acm::State> = StaticCell::new();
-    let class_serial = CdcAcmClass::new(builder, STATE_SERIAL.init(cdc_acm::State::new()), 64);
+    let class_serial = CdcAcmClass::new(
+        builder,
+        STATE_SERIAL.init(cdc_acm::State::new()),
+        SERIAL_MAX_PACKET_SIZE,
+    );
     class_serial
 }
 
-/// Runs a USB serial stack.
+/// Runs a command shell on the USB serial port.
 ///
-/// It waits for a connection, reads incoming data, echos it back, and then waits for a new connection.
+/// It waits for a connection, then reads lines typed in the terminal and runs
+/// them as [`Command`]s until the terminal disconnects. Host tools switch the
+/// connection to the binary protocol of [`rpc`] by sending a frame delimiter,
+/// which a terminal never sends.
 #[embassy_executor::task]
-pub async fn usb_serial_task(mut class: CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>) {
+pub async fn usb_serial_task(
+    mut class: CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    dfu_flash: &'static DfuFlash,
+) {
     loop {
         class.wait_connection().await;
         info!("SERIAL | Connected");
-        match echo(&mut class).await {
+        match shell(&mut class, dfu_flash).await {
             Ok(_) => {}
             Err(EndpointError::Disabled) => {}
-            Err(EndpointError::BufferOverflow) => panic!("SERIAL | Buffer overflow"),
+            Err(EndpointError::BufferOverflow) => warn!("SERIAL | Buffer overflow"),
         };
         info!("SERIAL | Disconnected");
     }
 }
 
-/// Reads data from a serial connection and echos it back.
-async fn echo<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+/// Reads lines from a serial connection and runs them.
+async fn shell(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    dfu_flash: &'static DfuFlash,
 ) -> Result<(), EndpointError> {
-    let mut buf = [0; 64];
+    let mut editor = LineEditor::new();
+    let mut output = Output::new();
+    let mut buf = [0; SERIAL_MAX_PACKET_SIZE as usize];
+
+    let _ = write!(
+        output,
+        "wave-rs {}, type `help` to list the commands\r\n{}",
+        env!("CARGO_PKG_VERSION"),
+        PROMPT
+    );
+    flush(class, &mut output).await?;
+
     loop {
         let n = class.read_packet(&mut buf).await?;
-        let data = &buf[..n];
-        info!("SERIAL | Data: {:?}", data);
-        class.write_packet(data).await?;
+        for (i, &byte) in buf[..n].iter().enumerate() {
+            if byte == FRAME_DELIMITER {
+                return rpc::serve(class, &buf[i + 1..n]).await;
+            }
+            match editor.feed(byte, &mut output) {
+                Input::Pending => continue,
+                Input::Line(line) => match Command::parse(&line) {
+                    Ok(command) => {
+                        info!("SERIAL | {}", line.as_str());
+                        flush(class, &mut output).await?;
+                        execute(command, class, &mut output, dfu_flash).await?;
+                    }
+                    Err(ParseError::Empty) => {}
+                    Err(e) => {
+                        let _ = write!(output, "Error: {}\r\n", e);
+                    }
+                },
+                Input::Overflow => {
+                    let _ = write!(output, "Error: line too long\r\n");
+                }
+                Input::Cancel => {}
+            }
+            let _ = output.push_str(PROMPT);
+        }
+        flush(class, &mut output).await?;
+    }
+}
+
+/// Runs a command of the shell.
+async fn execute(
+    command: Command<'_>,
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    output: &mut Output,
+    dfu_flash: &'static DfuFlash,
+) -> Result<(), EndpointError> {
+    match command {
+        Command::Help => {
+            let _ = output.push_str(HELP);
+        }
+        Command::Layers => {
+            let response = raw_hid_request(request(&[RawHidCommand::GetLayerState as u8])).await;
+            let (state, default_layer) = match response_payload(&response) {
+                Ok(payload) => read_layer_state(payload),
+                Err(status) => {
+                    let _ = write!(output, "Error: {:?}\r\n", status);
+                    return Ok(());
+                }
+            };
+            for layer in 0..NUMBER_LAYERS {
+                let active = if state & (1 << layer) != 0 {
+                    "active"
+                } else {
+                    "inactive"
+                };
+                let default = if layer == default_layer {
+                    " (default)"
+                } else {
+                    ""
+                };
+                let _ = write!(output, "Layer {}: {}{}\r\n", layer, active, default);
+            }
+        }
+        Command::KeymapGet { layer, row, col } => {
+            if !key_exists(layer, row, col, output) {
+                return Ok(());
+            }
+            let response = raw_hid_request(request(&[
+                ViaCommand::DynamicKeymapGetKeycode as u8,
+                layer,
+                row,
+                col,
+            ]))
+            .await;
+            let Some(keycode) = response_keycode(&response) else {
+                let _ = write!(output, "Error: the key could not be read\r\n");
+                return Ok(());
+            };
+            let _ = match from_keycode(keycode) {
+                Some(key) if keycode != FIRMWARE_KEYCODE => {
+                    write!(output, "{:#06x} {:?}\r\n", keycode, key)
+                }
+                _ => write!(
+                    output,
+                    "{:#06x} (only defined in the firmware)\r\n",
+                    keycode
+                ),
+            };
+        }
+        Command::KeymapSet {
+            layer,
+            row,
+            col,
+            keycode,
+        } => {
+            if !key_exists(layer, row, col, output) {
+                return Ok(());
+            }
+            let Some(key) = from_keycode(keycode) else {
+                let _ = write!(output, "Error: unsupported keycode {:#06x}\r\n", keycode);
+                return Ok(());
+            };
+            if is_layer_action(key) && LAYOUT.get_layer_from_key(key).is_none() {
+                let _ = write!(output, "Error: the layer does not exist\r\n");
+                return Ok(());
+            }
+            let [high, low] = keycode.to_be_bytes();
+            let response = raw_hid_request(request(&[
+                ViaCommand::DynamicKeymapSetKeycode as u8,
+                layer,
+                row,
+                col,
+                high,
+                low,
+            ]))
+            .await;
+            if response_keycode(&response).is_none() {
+                let _ = write!(output, "Error: the key needs a disabled USB interface\r\n");
+                return Ok(());
+            }
+            let _ = write!(output, "{:?}\r\n", key);
+        }
+        Command::Matrix => show_matrix(class, output).await?,
+        Command::Stats => {
+            let uptime = Instant::now().as_millis();
+            let _ = write!(
+                output,
+                "Uptime: {}.{:03} s\r\nScans: {}\r\nKey presses: {}\r\nKeyboard reports: {}\r\n",
+                uptime / 1000,
+                uptime % 1000,
+                SCANS.load(Ordering::Relaxed),
+                KEY_PRESSES.load(Ordering::Relaxed),
+                KEYBOARD_REPORTS.load(Ordering::Relaxed),
+            );
+        }
+        Command::Config => {
+            let settings = settings();
+            let _ = write!(
+                output,
+                "Firmware: wave-rs {}\r\nUSB: {:04x}:{:04x}\r\nMatrix: {} rows, {} columns\r\n\
+                 Layers: {}\r\nScan rate: {} Hz\r\nDebounce: {:?}, {} ms\r\nTapping term: {} ms\r\n\
+                 Host layout: {}\r\nUnicode mode: {}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                USB_VID,
+                USB_PID,
+                MATRIX_ROWS_NUMBER,
+                MATRIX_COLUMNS_NUMBER,
+                NUMBER_LAYERS,
+                FREQUENCY.0,
+                DEBOUNCE_ALGORITHM,
+                DEBOUNCE_TIME.as_millis(),
+                settings.tapping_term.as_millis(),
+                settings.host_layout.name(),
+                settings.unicode_mode.name(),
+            );
+        }
+        Command::Layout(None) => {
+            let _ = write!(output, "{}\r\n", settings().host_layout.name());
+        }
+        Command::Layout(Some(host_layout)) => {
+            set_settings(Settings {
+                host_layout,
+                ..settings()
+            })
+            .await;
+            let _ = write!(output, "{}\r\n", host_layout.name());
+        }
+        Command::Send(text) => {
+            if let Err(e) = send_string(text) {
+                let _ = write!(output, "Error: {}\r\n", e);
+            }
+        }
+        Command::Unicode(None) => {
+            let _ = write!(output, "{}\r\n", settings().unicode_mode.name());
+        }
+        Command::Unicode(Some(unicode_mode)) => {
+            set_settings(Settings {
+                unicode_mode,
+                ..settings()
+            })
+            .await;
+            let _ = write!(output, "{}\r\n", unicode_mode.name());
+        }
+        Command::Reboot => {
+            let _ = output.push_str("Rebooting...\r\n");
+            flush(class, output).await?;
+            // Give the host time to read the output
+            Timer::after_millis(SHELL_REBOOT_DELAY_MS).await;
+            SCB::sys_reset();
+        }
+        Command::Bootloader => {
+            let _ = output.push_str("Rebooting into the bootloader...\r\n");
+            flush(class, output).await?;
+            Timer::after_millis(SHELL_REBOOT_DELAY_MS).await;
+            reboot_into_bootloader(dfu_flash);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a key is in the keymap, or writes an error.
+fn key_exists(layer: u8, row: u8, col: u8, output: &mut Output) -> bool {
+    let exists = (layer as usize) < NUMBER_LAYERS
+        && (row as usize) < MATRIX_ROWS_NUMBER
+        && (col as usize) < MATRIX_COLUMNS_NUMBER;
+    if !exists {
+        let _ = write!(output, "Error: the key is out of the keymap\r\n");
+    }
+    exists
+}
+
+/// Shows the keys held down, until a key is typed in the terminal.
+async fn show_matrix(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    output: &mut Output,
+) -> Result<(), EndpointError> {
+    let _ = output.push_str("Press any key to stop\r\n");
+    let mut buf = [0; SERIAL_MAX_PACKET_SIZE as usize];
+    let mut shown = None;
+    loop {
+        let matrix = MATRIX_STATE.lock(|state| state.get());
+        if shown != Some(matrix) {
+            // Draw over the previous matrix
+            if shown.is_some() {
+                let _ = write!(output, "\x1b[{}A", MATRIX_ROWS_NUMBER);
+            }
+            for row in 0..MATRIX_ROWS_NUMBER {
+                for rows in matrix {
+                    let key = if rows & (1 << row) != 0 { " X" } else { " ." };
+                    let _ = output.push_str(key);
+                }
+                let _ = output.push_str("\r\n");
+            }
+            flush(class, output).await?;
+            shown = Some(matrix);
+        }
+
+        match select(
+            class.read_packet(&mut buf),
+            Timer::after_millis(SHELL_MATRIX_REFRESH_MS),
+        )
+        .await
+        {
+            Either::First(result) => return result.map(|_| ()),
+            Either::Second(_) => {}
+        }
+    }
+}
+
+/// Builds a raw HID request.
+pub(crate) fn request(bytes: &[u8]) -> [u8; HID_RAW_REPORT_SIZE] {
+    let mut request = [0; HID_RAW_REPORT_SIZE];
+    request[..bytes.len()].copy_from_slice(bytes);
+    request
+}
+
+/// Sends the output of the shell.
+async fn flush(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    output: &mut Output,
+) -> Result<(), EndpointError> {
+    write_all(class, output.as_bytes()).await?;
+    output.clear();
+    Ok(())
+}
+
+/// Sends bytes over a serial connection.
+pub(crate) async fn write_all(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB_OTG_HS>>,
+    bytes: &[u8],
+) -> Result<(), EndpointError> {
+    let packet_size = SERIAL_MAX_PACKET_SIZE as usize;
+    for packet in bytes.chunks(packet_size) {
+        class.write_packet(packet).await?;
+    }
+    // A full packet tells the host that more data follows
+    if !bytes.is_empty() && bytes.len() % packet_size == 0 {
+        class.write_packet(&[]).await?;
     }
+    Ok(())
 }
@@ -0,0 +1,510 @@
+use core::fmt::{self, Write};
+
+use heapless::{Deque, String, Vec};
+
+use crate::{
+    keyboard::{host_layout::HostLayout, unicode::UnicodeMode},
+    usb::{SHELL_HISTORY_SIZE, SHELL_LINE_SIZE},
+};
+
+/// Prompt printed before each line.
+pub const PROMPT: &str = "wave-rs> ";
+
+/// Help printed by the `help` command.
+pub const HELP: &str = "\
+Commands:\r
+  help                                 Show this help\r
+  layers                               Show the active layers\r
+  keymap get <layer> <row> <col>       Show a key and its VIA keycode\r
+  keymap set <layer> <row> <col> <kc>  Set a key from its VIA keycode\r
+  matrix                               Show the keys held down until a key is typed\r
+  stats                                Show the uptime and the number of key presses\r
+  config                               Show the configuration of the firmware\r
+  layout [us|uk|fr|de|dvorak]          Show or select the keyboard layout of the host\r
+  send <text>                          Type a text on the host\r
+  unicode [<mode>]                     Show or select the Unicode input mode of the host\r
+  reboot                               Reset the keyboard\r
+  bootloader                           Reset into the bootloader to update over DFU\r
+";
+
+/// A line typed in the shell.
+pub type Line = String<SHELL_LINE_SIZE>;
+
+/// What a byte received by the [`LineEditor`] did.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Input {
+    /// The line is still being typed.
+    Pending,
+    /// A line was entered.
+    Line(Line),
+    /// A line was entered but it did not fit, so it was discarded.
+    Overflow,
+    /// The line was discarded with Ctrl-C.
+    Cancel,
+}
+
+/// Progress of an ANSI escape sequence.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Escape {
+    None,
+    /// After `ESC`.
+    Started,
+    /// After `ESC [`, until the final byte.
+    Csi,
+}
+
+/// Edits the line typed in a terminal.
+///
+/// Supports UTF-8, backspace, Ctrl-C and a history of the last lines browsed
+/// with the up and down arrows. Characters typed past the end of a full line
+/// are dropped, and the line is rejected once entered unless they were erased
+/// with backspace.
+pub struct LineEditor {
+    line: Line,
+    /// Number of characters typed past the end of the full line.
+    overflow: usize,
+    escape: Escape,
+    /// Bytes of a UTF-8 character received so far.
+    utf8: Vec<u8, 4>,
+    /// Whether the last byte was a carriage return, so that a line feed right
+    /// after it does not enter an empty line.
+    carriage_return: bool,
+    /// The last lines entered, the most recent first.
+    history: Deque<Line, SHELL_HISTORY_SIZE>,
+    /// Index in the history of the line shown while browsing it.
+    browsing: Option<usize>,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: String::new(),
+            overflow: 0,
+            escape: Escape::None,
+            utf8: Vec::new(),
+            carriage_return: false,
+            history: Deque::new(),
+            browsing: None,
+        }
+    }
+
+    /// Handles a byte received from the terminal, and writes what the terminal
+    /// should show to `echo`.
+    pub fn feed(&mut self, byte: u8, echo: &mut impl Write) -> Input {
+        let carriage_return = core::mem::replace(&mut self.carriage_return, byte == b'\r');
+
+        match (self.escape, byte) {
+            (Escape::None, _) => {}
+            (Escape::Started, b'[') => {
+                self.escape = Escape::Csi;
+                return Input::Pending;
+            }
+            (Escape::Csi, b'0'..=b'9' | b';') => return Input::Pending,
+            (Escape::Csi, b'A') => {
+                self.escape = Escape::None;
+                self.browse_older(echo);
+                return Input::Pending;
+            }
+            (Escape::Csi, b'B') => {
+                self.escape = Escape::None;
+                self.browse_newer(echo);
+                return Input::Pending;
+            }
+            // Other sequences, such as the left and right arrows, are ignored
+            _ => {
+                self.escape = Escape::None;
+                return Input::Pending;
+            }
+        }
+
+        match byte {
+            0x1B => self.escape = Escape::Started,
+            b'\n' if carriage_return => {}
+            b'\r' | b'\n' => return self.enter(echo),
+            // Backspace and delete, which first erase the dropped characters
+            // the terminal never showed
+            0x08 | 0x7F if self.overflow > 0 => self.overflow -= 1,
+            0x08 | 0x7F if self.line.pop().is_some() => {
+                let _ = echo.write_str("\x08 \x08");
+            }
+            // Ctrl-C
+            0x03 => {
+                self.line.clear();
+                self.overflow = 0;
+                self.browsing = None;
+                let _ = echo.write_str("^C\r\n");
+                return Input::Cancel;
+            }
+            0x20..=0x7E => self.push(byte as char, echo),
+            0x80..=0xFF => {
+                if self.utf8.push(byte).is_err() {
+                    self.utf8.clear();
+                }
+                match core::str::from_utf8(&self.utf8) {
+                    Ok(c) => {
+                        let c = c.chars().next().unwrap_or_default();
+                        self.utf8.clear();
+                        self.push(c, echo);
+                    }
+                    // Invalid bytes are dropped
+                    Err(e) if e.error_len().is_some() => self.utf8.clear(),
+                    Err(_) => {}
+                }
+            }
+            _ => {}
+        }
+        Input::Pending
+    }
+
+    fn push(&mut self, c: char, echo: &mut impl Write) {
+        if self.line.push(c).is_ok() {
+            let _ = echo.write_char(c);
+        } else {
+            self.overflow += 1;
+            // Ring the bell
+            let _ = echo.write_char('\x07');
+        }
+    }
+
+    fn enter(&mut self, echo: &mut impl Write) -> Input {
+        let _ = echo.write_str("\r\n");
+        self.browsing = None;
+        let line = core::mem::take(&mut self.line);
+        if core::mem::take(&mut self.overflow) > 0 {
+            return Input::Overflow;
+        }
+
+        if !line.trim().is_empty() && self.history.front() != Some(&line) {
+            if self.history.is_full() {
+                self.history.pop_back();
+            }
+            let _ = self.history.push_front(line.clone());
+        }
+        Input::Line(line)
+    }
+
+    fn browse_older(&mut self, echo: &mut impl Write) {
+        let index = self.browsing.map_or(0, |index| index + 1);
+        if let Some(line) = self.history.iter().nth(index) {
+            self.browsing = Some(index);
+            self.line = line.clone();
+            self.redraw(echo);
+        }
+    }
+
+    fn browse_newer(&mut self, echo: &mut impl Write) {
+        match self.browsing {
+            None => return,
+            Some(0) => {
+                self.browsing = None;
+                self.line.clear();
+            }
+            Some(index) => {
+                self.browsing = Some(index - 1);
+                self.line = self
+                    .history
+                    .iter()
+                    .nth(index - 1)
+                    .cloned()
+                    .unwrap_or_default();
+            }
+        }
+        self.redraw(echo);
+    }
+
+    /// Replaces the line shown by the terminal.
+    fn redraw(&mut self, echo: &mut impl Write) {
+        self.overflow = 0;
+        let _ = write!(echo, "\r\x1b[K{}{}", PROMPT, self.line);
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A command of the shell.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    Layers,
+    KeymapGet {
+        layer: u8,
+        row: u8,
+        col: u8,
+    },
+    /// Sets a key from its VIA keycode, see [`crate::usb::via::from_keycode`].
+    KeymapSet {
+        layer: u8,
+        row: u8,
+        col: u8,
+        keycode: u16,
+    },
+    Matrix,
+    Stats,
+    Config,
+    /// Shows the host layout, or selects it.
+    Layout(Option<HostLayout>),
+    /// Types a text on the host, see [`crate::keyboard::send_string`].
+    Send(&'a str),
+    /// Shows the Unicode mode, or selects it.
+    Unicode(Option<UnicodeMode>),
+    Reboot,
+    Bootloader,
+}
+
+/// Error while parsing a [`Command`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    TooManyArguments,
+    /// An argument is not a number, or is too large.
+    InvalidNumber,
+    UnknownLayout,
+    UnknownUnicodeMode,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ParseError::Empty => "empty command",
+            ParseError::UnknownCommand => "unknown command, type `help` to list the commands",
+            ParseError::MissingArgument => "missing argument",
+            ParseError::TooManyArguments => "too many arguments",
+            ParseError::InvalidNumber => "invalid number",
+            ParseError::UnknownLayout => "unknown layout",
+            ParseError::UnknownUnicodeMode => {
+                "unknown Unicode mode, expected linux, macos, wincompose or windows"
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl<'a> Command<'a> {
+    /// Parses a line typed in the shell.
+    ///
+    /// Numbers are decimal, or hexadecimal when prefixed by `0x`.
+    pub fn parse(line: &'a str) -> Result<Self, ParseError> {
+        // The text of `send` is kept as is, spaces included
+        if let Some(text) = line.trim_start().strip_prefix("send ") {
+            if text.trim().is_empty() {
+                return Err(ParseError::MissingArgument);
+            }
+            return Ok(Command::Send(text));
+        }
+
+        let mut args = line.split_ascii_whitespace();
+        let command = match args.next().ok_or(ParseError::Empty)? {
+            "help" | "?" => Command::Help,
+            "layers" => Command::Layers,
+            "keymap" => match args.next().ok_or(ParseError::MissingArgument)? {
+                "get" => Command::KeymapGet {
+                    layer: parse_number(args.next())?,
+                    row: parse_number(args.next())?,
+                    col: parse_number(args.next())?,
+                },
+                "set" => Command::KeymapSet {
+                    layer: parse_number(args.next())?,
+                    row: parse_number(args.next())?,
+                    col: parse_number(args.next())?,
+                    keycode: parse_number(args.next())?,
+                },
+                _ => return Err(ParseError::UnknownCommand),
+            },
+            "matrix" => Command::Matrix,
+            "stats" => Command::Stats,
+            "config" => Command::Config,
+            "layout" => match args.next() {
+                Some(name) => Command::Layout(Some(
+                    HostLayout::from_name(name).ok_or(ParseError::UnknownLayout)?,
+                )),
+                None => Command::Layout(None),
+            },
+            "send" => return Err(ParseError::MissingArgument),
+            "unicode" => match args.next() {
+                Some(name) => Command::Unicode(Some(
+                    UnicodeMode::from_name(name).ok_or(ParseError::UnknownUnicodeMode)?,
+                )),
+                None => Command::Unicode(None),
+            },
+            "reboot" => Command::Reboot,
+            "bootloader" => Command::Bootloader,
+            _ => return Err(ParseError::UnknownCommand),
+        };
+
+        if args.next().is_some() {
+            return Err(ParseError::TooManyArguments);
+        }
+        Ok(command)
+    }
+}
+
+fn parse_number<T: TryFrom<u32>>(arg: Option<&str>) -> Result<T, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument)?;
+    let number = match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    number
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or(ParseError::InvalidNumber)
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+
+    /// Feeds bytes to the editor, and returns the inputs and the echo.
+    fn feed(editor: &mut LineEditor, bytes: &[u8]) -> (Vec<Input, 8>, String<256>) {
+        let mut inputs = Vec::new();
+        let mut echo = String::new();
+        for &byte in bytes {
+            match editor.feed(byte, &mut echo) {
+                Input::Pending => {}
+                input => inputs.push(input).unwrap(),
+            }
+        }
+        (inputs, echo)
+    }
+
+    fn line(line: &str) -> Input {
+        Input::Line(Line::try_from(line).unwrap())
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut editor = LineEditor::new();
+        let (inputs, echo) = feed(&mut editor, b"layerz\x7fs\r");
+        assert_eq!(inputs, [line("layers")]);
+        assert_eq!(echo, "layerz\x08 \x08s\r\n");
+
+        // The line feed of CRLF does not enter an empty line
+        let (inputs, _) = feed(&mut editor, b"help\r\nstats\n\r");
+        assert_eq!(inputs, [line("help"), line("stats"), line("")]);
+        let (inputs, echo) = feed(&mut editor, b"stats\x03");
+        assert_eq!(inputs, [Input::Cancel]);
+        assert_eq!(echo, "stats^C\r\n");
+
+        // UTF-8 characters are removed at once, and invalid bytes are dropped
+        let (inputs, echo) = feed(&mut editor, "send été\x7f\x7fe".as_bytes());
+        assert!(inputs.is_empty());
+        assert_eq!(echo, "send été\x08 \x08\x08 \x08e");
+        assert_eq!(feed(&mut editor, b"\xFF!\r").0, [line("send ée!")]);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut editor = LineEditor::new();
+        let long = [b'a'; SHELL_LINE_SIZE + 1];
+        let (inputs, echo) = feed(&mut editor, &long);
+        assert!(inputs.is_empty());
+        assert!(echo.ends_with('\x07'));
+        assert_eq!(feed(&mut editor, b"\r").0, [Input::Overflow]);
+        assert_eq!(feed(&mut editor, b"help\r").0, [line("help")]);
+
+        // The overflow lasts until the line is entered, unless the dropped
+        // characters are erased
+        feed(&mut editor, &long);
+        let (inputs, echo) = feed(&mut editor, b"\x7fbb\x7f");
+        assert!(inputs.is_empty());
+        assert_eq!(echo, "\x07\x07");
+        assert_eq!(feed(&mut editor, b"\r").0, [Input::Overflow]);
+        feed(&mut editor, &long);
+        let (inputs, echo) = feed(&mut editor, b"\x7f\x7fb\r");
+        assert_eq!(echo, "\x08 \x08b\r\n");
+        let mut expected = Line::new();
+        expected.push_str(&"a".repeat(SHELL_LINE_SIZE - 1)).unwrap();
+        expected.push('b').unwrap();
+        assert_eq!(inputs, [Input::Line(expected)]);
+    }
+
+    #[test]
+    fn history() {
+        let mut editor = LineEditor::new();
+        feed(&mut editor, b"layers\rstats\rstats\r");
+
+        let (_, echo) = feed(&mut editor, b"\x1b[A");
+        assert_eq!(echo, "\r\x1b[Kwave-rs> stats");
+        // Lines entered twice in a row are only kept once
+        assert_eq!(feed(&mut editor, b"\x1b[A\r").0, [line("layers")]);
+        assert_eq!(
+            feed(&mut editor, b"\x1b[A\x1b[A\x1b[A\x1b[B\r").0,
+            [line("stats")]
+        );
+        // Going past the most recent line clears it
+        assert_eq!(feed(&mut editor, b"\x1b[A\x1b[Bhelp\r").0, [line("help")]);
+        // Other escape sequences are ignored
+        assert_eq!(feed(&mut editor, b"ma\x1b[1;5Dtrix\r").0, [line("matrix")]);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse("  help "), Ok(Command::Help));
+        assert_eq!(
+            Command::parse("keymap get 1 2 3"),
+            Ok(Command::KeymapGet {
+                layer: 1,
+                row: 2,
+                col: 3
+            })
+        );
+        assert_eq!(
+            Command::parse("keymap set 0 0 4 0x5221"),
+            Ok(Command::KeymapSet {
+                layer: 0,
+                row: 0,
+                col: 4,
+                keycode: 0x5221
+            })
+        );
+        assert_eq!(Command::parse(""), Err(ParseError::Empty));
+        assert_eq!(Command::parse("flash"), Err(ParseError::UnknownCommand));
+        assert_eq!(
+            Command::parse("keymap get 0 1"),
+            Err(ParseError::MissingArgument)
+        );
+        assert_eq!(
+            Command::parse("keymap get 256 0 0"),
+            Err(ParseError::InvalidNumber)
+        );
+        assert_eq!(
+            Command::parse("keymap set 0 0 0 0x1zz"),
+            Err(ParseError::InvalidNumber)
+        );
+        assert_eq!(
+            Command::parse("reboot now"),
+            Err(ParseError::TooManyArguments)
+        );
+        assert_eq!(
+            Command::parse("send  Hello, world! "),
+            Ok(Command::Send(" Hello, world! "))
+        );
+        assert_eq!(Command::parse("send "), Err(ParseError::MissingArgument));
+        assert_eq!(
+            Command::parse("layout fr"),
+            Ok(Command::Layout(Some(HostLayout::FrenchAzerty)))
+        );
+        assert_eq!(Command::parse("layout"), Ok(Command::Layout(None)));
+        assert_eq!(
+            Command::parse("layout colemak"),
+            Err(ParseError::UnknownLayout)
+        );
+        assert_eq!(
+            Command::parse("unicode windows"),
+            Ok(Command::Unicode(Some(UnicodeMode::WindowsAltCode)))
+        );
+        assert_eq!(Command::parse("unicode"), Ok(Command::Unicode(None)));
+        assert_eq!(
+            Command::parse("unicode emacs"),
+            Err(ParseError::UnknownUnicodeMode)
+        );
+    }
+}
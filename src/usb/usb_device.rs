@@ -1,23 +1,50 @@
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use defmt::info;
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
 use embassy_stm32::{
     peripherals::USB_OTG_HS,
     usb::{DmPin, DpPin, Driver},
     Peri,
 };
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::Watch};
 use embassy_usb::{Builder, Handler, UsbDevice, UsbVersion};
 use static_cell::StaticCell;
 
 use crate::{
     usb::{
-        USB_BOS_DESC_SIZE, USB_CONFIG_DESC_SIZE, USB_CONTROL_BUF_SIZE, USB_MANUFACTURER,
-        USB_MSOS_DESC_SIZE, USB_OUTPUT_BUFFER_SIZE, USB_PID, USB_PRODUCT, USB_RELEASE_VERSION,
-        USB_SN, USB_VID,
+        hid::KEYBOARD_BOOT_PROTOCOL, USB_BOS_DESC_SIZE, USB_CONFIG_DESC_SIZE, USB_CONTROL_BUF_SIZE,
+        USB_MANUFACTURER, USB_MSOS_DESC_SIZE, USB_OUTPUT_BUFFER_SIZE, USB_PID, USB_PRODUCT,
+        USB_RELEASE_VERSION, USB_SN, USB_VID,
     },
     Irqs,
 };
 
+/// Signaled when the host configures the USB device.
+pub static USB_CONFIGURED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signaled to wake the host up while the USB device is suspended.
+pub static REMOTE_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the host allowed the device to wake it up.
+pub static REMOTE_WAKEUP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Maximum number of tasks watching the state of the USB bus.
+pub const USB_STATE_RECEIVERS: usize = 4;
+
+/// The state of the USB bus, published on every suspend and resume.
+pub static USB_STATE: Watch<CriticalSectionRawMutex, UsbState, USB_STATE_RECEIVERS> = Watch::new();
+
+/// The state of the USB bus.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UsbState {
+    /// The host is running.
+    Active,
+    /// The host suspended the bus, usually because it went to sleep.
+    Suspended,
+}
+
 /// Initializes a USB peripheral builder.
 ///
 /// The USB device is configured as a composite device. Its maximum current draw is 100 mA and it
@@ -84,9 +111,26 @@ pub async fn init_usb(
 }
 
 /// Runs a USB device.
+///
+/// While the device is suspended, signaling [`REMOTE_WAKEUP`] wakes the host
+/// up.
 #[embassy_executor::task]
 pub async fn usb_task(mut device: UsbDevice<'static, Driver<'static, USB_OTG_HS>>) -> ! {
-    device.run().await
+    loop {
+        device.run_until_suspend().await;
+
+        // Only wakeups requested during the suspension count
+        REMOTE_WAKEUP.reset();
+        match select(device.wait_resume(), REMOTE_WAKEUP.wait()).await {
+            Either::First(_) => {}
+            Either::Second(_) => {
+                info!("USB | Waking the host up");
+                if let Err(e) = device.remote_wakeup().await {
+                    warn!("USB | Failed to wake the host up: {:?}", e);
+                }
+            }
+        }
+    }
 }
 
 struct USBDeviceHandler {
@@ -113,6 +157,9 @@ impl Handler for USBDeviceHandler {
 
     fn reset(&mut self) {
         self.configured.store(false, Ordering::Relaxed);
+        REMOTE_WAKEUP_ENABLED.store(false, Ordering::Relaxed);
+        // The HID protocol goes back to the report protocol on a bus reset
+        KEYBOARD_BOOT_PROTOCOL.store(false, Ordering::Relaxed);
         info!("USB | Bus reset, the Vbus current limit is 100mA");
     }
 
@@ -124,6 +171,7 @@ impl Handler for USBDeviceHandler {
     fn configured(&mut self, configured: bool) {
         self.configured.store(configured, Ordering::Relaxed);
         if configured {
+            USB_CONFIGURED.signal(());
             info!(
                 "USB | Device configured, it may now draw up to the configured current limit from Vbus."
             )
@@ -131,4 +179,23 @@ impl Handler for USBDeviceHandler {
             info!("USB | Device is no longer configured, the Vbus current limit is 100mA.");
         }
     }
+
+    fn suspended(&mut self, suspended: bool) {
+        let state = if suspended {
+            UsbState::Suspended
+        } else {
+            UsbState::Active
+        };
+        info!("USB | Bus state: {:?}", state);
+        USB_STATE.sender().send(state);
+    }
+
+    fn remote_wakeup_enabled(&mut self, enabled: bool) {
+        REMOTE_WAKEUP_ENABLED.store(enabled, Ordering::Relaxed);
+        if enabled {
+            info!("USB | Remote wakeup enabled");
+        } else {
+            info!("USB | Remote wakeup disabled");
+        }
+    }
 }
@@ -0,0 +1,866 @@
+use defmt::{debug, warn};
+use embassy_time::Instant;
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use crate::{
+    keyboard::{
+        action::{Action, HoldTapAction, HoldTapConfig, KeyAction, LayerTapAction, Mouse, System},
+        dma::LinkedListWord,
+        layers::Layers,
+        macros::{DYNAMIC_MACROS, DYNAMIC_MACRO_BUFFER_SIZE, DYNAMIC_MACRO_COUNT},
+    },
+    usb::{
+        raw_hid::{is_layer_action, KeyInterfaces},
+        HID_RAW_REPORT_SIZE,
+    },
+};
+
+/// Version of the VIA protocol implemented by the firmware.
+pub const VIA_PROTOCOL_VERSION: u16 = 0x000C;
+
+/// Version of the firmware reported to VIA, as `0x00MMmmpp`.
+pub const VIA_FIRMWARE_VERSION: u32 = parse_version(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+    | parse_version(env!("CARGO_PKG_VERSION_MINOR")) << 8
+    | parse_version(env!("CARGO_PKG_VERSION_PATCH"));
+
+/// Answer of VIA to a command it does not handle.
+const VIA_UNHANDLED: u8 = 0xFF;
+
+/// Offset of the keycode in the requests and responses of
+/// [`ViaCommand::DynamicKeymapGetKeycode`] and
+/// [`ViaCommand::DynamicKeymapSetKeycode`], after the layer, row and column.
+const VIA_KEYCODE_OFFSET: usize = 4;
+
+/// Maximum number of bytes of a keymap or macro buffer request.
+const VIA_BUFFER_CHUNK_SIZE: usize = HID_RAW_REPORT_SIZE - 4;
+
+/// Keycode of the keys that only exist in the firmware, such as tap dances.
+///
+/// It is the first keyboard-specific keycode of QMK. Setting a key to it keeps
+/// the key as is, so VIA can write a whole keymap back without losing them.
+pub const FIRMWARE_KEYCODE: u16 = 0x7E00;
+
+const KC_NO: u16 = 0x0000;
+const KC_TRANSPARENT: u16 = 0x0001;
+const QK_MOD_TAP: u16 = 0x2000;
+const QK_MOD_TAP_MAX: u16 = 0x3FFF;
+const QK_LAYER_TAP: u16 = 0x4000;
+const QK_LAYER_TAP_MAX: u16 = 0x4FFF;
+const QK_TO: u16 = 0x5200;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_DEF_LAYER: u16 = 0x5240;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_ONE_SHOT_LAYER: u16 = 0x5280;
+const QK_ONE_SHOT_MOD: u16 = 0x52A0;
+const QK_MACRO: u16 = 0x7700;
+const QK_MACRO_MAX: u16 = 0x777F;
+
+/// Keycodes of the system control keys, in the basic keycode range.
+const SYSTEM_KEYCODES: [(u8, System); 3] = [
+    (0xA5, System::PowerDown),
+    (0xA6, System::Sleep),
+    (0xA7, System::WakeUp),
+];
+
+/// Keycodes of the consumer control keys, in the basic keycode range, and
+/// their usage.
+const CONSUMER_KEYCODES: [(u8, u16); 23] = [
+    (0xA8, 0x00E2), // Mute
+    (0xA9, 0x00E9), // Volume Increment
+    (0xAA, 0x00EA), // Volume Decrement
+    (0xAB, 0x00B5), // Scan Next Track
+    (0xAC, 0x00B6), // Scan Previous Track
+    (0xAD, 0x00B7), // Stop
+    (0xAE, 0x00CD), // Play/Pause
+    (0xAF, 0x0183), // AL Consumer Control Configuration
+    (0xB0, 0x00B8), // Eject
+    (0xB1, 0x018A), // AL Email Reader
+    (0xB2, 0x0192), // AL Calculator
+    (0xB3, 0x0194), // AL Local Machine Browser
+    (0xB4, 0x0221), // AC Search
+    (0xB5, 0x0223), // AC Home
+    (0xB6, 0x0224), // AC Back
+    (0xB7, 0x0225), // AC Forward
+    (0xB8, 0x0226), // AC Stop
+    (0xB9, 0x0227), // AC Refresh
+    (0xBA, 0x022A), // AC Bookmarks
+    (0xBB, 0x00B3), // Fast Forward
+    (0xBC, 0x00B4), // Rewind
+    (0xBD, 0x006F), // Display Brightness Increment
+    (0xBE, 0x0070), // Display Brightness Decrement
+];
+
+/// Keycodes of the mouse keys, in the basic keycode range.
+///
+/// The slowest and fastest acceleration presets of QMK step the speed down and
+/// up.
+const MOUSE_KEYCODES: [(u8, Mouse); 13] = [
+    (0xCD, Mouse::MoveUp),
+    (0xCE, Mouse::MoveDown),
+    (0xCF, Mouse::MoveLeft),
+    (0xD0, Mouse::MoveRight),
+    (0xD1, Mouse::LeftClick),
+    (0xD2, Mouse::RightClick),
+    (0xD3, Mouse::MiddleClick),
+    (0xD9, Mouse::ScrollUp),
+    (0xDA, Mouse::ScrollDown),
+    (0xDB, Mouse::ScrollLeft),
+    (0xDC, Mouse::ScrollRight),
+    (0xDD, Mouse::SpeedDown),
+    (0xDF, Mouse::SpeedUp),
+];
+
+/// Command of a VIA request.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum ViaCommand {
+    GetProtocolVersion = 0x01,
+    GetKeyboardValue = 0x02,
+    SetKeyboardValue = 0x03,
+    DynamicKeymapGetKeycode = 0x04,
+    DynamicKeymapSetKeycode = 0x05,
+    DynamicKeymapReset = 0x06,
+    EepromReset = 0x0A,
+    DynamicKeymapMacroGetCount = 0x0C,
+    DynamicKeymapMacroGetBufferSize = 0x0D,
+    DynamicKeymapMacroGetBuffer = 0x0E,
+    DynamicKeymapMacroSetBuffer = 0x0F,
+    DynamicKeymapMacroReset = 0x10,
+    DynamicKeymapGetLayerCount = 0x11,
+    DynamicKeymapGetBuffer = 0x12,
+    DynamicKeymapSetBuffer = 0x13,
+}
+
+impl TryFrom<u8> for ViaCommand {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let command = match value {
+            0x01 => Self::GetProtocolVersion,
+            0x02 => Self::GetKeyboardValue,
+            0x03 => Self::SetKeyboardValue,
+            0x04 => Self::DynamicKeymapGetKeycode,
+            0x05 => Self::DynamicKeymapSetKeycode,
+            0x06 => Self::DynamicKeymapReset,
+            0x0A => Self::EepromReset,
+            0x0C => Self::DynamicKeymapMacroGetCount,
+            0x0D => Self::DynamicKeymapMacroGetBufferSize,
+            0x0E => Self::DynamicKeymapMacroGetBuffer,
+            0x0F => Self::DynamicKeymapMacroSetBuffer,
+            0x10 => Self::DynamicKeymapMacroReset,
+            0x11 => Self::DynamicKeymapGetLayerCount,
+            0x12 => Self::DynamicKeymapGetBuffer,
+            0x13 => Self::DynamicKeymapSetBuffer,
+            _ => return Err(()),
+        };
+        Ok(command)
+    }
+}
+
+/// Value of the keyboard read or written by VIA.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum ViaKeyboardValue {
+    Uptime = 0x01,
+    LayoutOptions = 0x02,
+    SwitchMatrixState = 0x03,
+    FirmwareVersion = 0x04,
+    DeviceIndication = 0x05,
+}
+
+impl TryFrom<u8> for ViaKeyboardValue {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let value = match value {
+            0x01 => Self::Uptime,
+            0x02 => Self::LayoutOptions,
+            0x03 => Self::SwitchMatrixState,
+            0x04 => Self::FirmwareVersion,
+            0x05 => Self::DeviceIndication,
+            _ => return Err(()),
+        };
+        Ok(value)
+    }
+}
+
+/// Answers a VIA request.
+///
+/// Like QMK, the response is the request with the values read filled in, or
+/// with its command replaced by `0xFF` when it is not handled. Keys are read
+/// from and written to the live `layers`, and resetting the keymap restores
+/// `default_layers`. Keys that `interfaces` does not support are ignored. The
+/// requests editing the keymap are told by [`edits_keymap`], so it can be
+/// saved.
+pub fn respond<const L: usize, const M: usize, const N: usize>(
+    request: &[u8; HID_RAW_REPORT_SIZE],
+    layers: &mut Layers<L, M, N>,
+    default_layers: &Layers<L, M, N>,
+    matrix: &[LinkedListWord; N],
+    interfaces: KeyInterfaces,
+    now: Instant,
+) -> [u8; HID_RAW_REPORT_SIZE] {
+    let mut data = *request;
+    let handled = match ViaCommand::try_from(data[0]) {
+        Ok(command) => {
+            debug!("VIA | {:?}", command);
+            execute(
+                command,
+                &mut data,
+                layers,
+                default_layers,
+                matrix,
+                interfaces,
+                now,
+            )
+        }
+        Err(()) => false,
+    };
+    if !handled {
+        data[0] = VIA_UNHANDLED;
+    }
+    data
+}
+
+/// Reads the keycode of a response to [`ViaCommand::DynamicKeymapGetKeycode`]
+/// or [`ViaCommand::DynamicKeymapSetKeycode`].
+///
+/// Returns `None` if the request was not handled.
+pub fn response_keycode(response: &[u8; HID_RAW_REPORT_SIZE]) -> Option<u16> {
+    (response[0] != VIA_UNHANDLED).then(|| {
+        u16::from_be_bytes([
+            response[VIA_KEYCODE_OFFSET],
+            response[VIA_KEYCODE_OFFSET + 1],
+        ])
+    })
+}
+
+/// Checks if a VIA request edits the keymap.
+pub fn edits_keymap(request: &[u8; HID_RAW_REPORT_SIZE]) -> bool {
+    matches!(
+        ViaCommand::try_from(request[0]),
+        Ok(ViaCommand::DynamicKeymapSetKeycode
+            | ViaCommand::DynamicKeymapReset
+            | ViaCommand::EepromReset
+            | ViaCommand::DynamicKeymapSetBuffer)
+    )
+}
+
+fn execute<const L: usize, const M: usize, const N: usize>(
+    command: ViaCommand,
+    data: &mut [u8; HID_RAW_REPORT_SIZE],
+    layers: &mut Layers<L, M, N>,
+    default_layers: &Layers<L, M, N>,
+    matrix: &[LinkedListWord; N],
+    interfaces: KeyInterfaces,
+    now: Instant,
+) -> bool {
+    match command {
+        ViaCommand::GetProtocolVersion => {
+            data[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+        }
+        ViaCommand::GetKeyboardValue => match ViaKeyboardValue::try_from(data[1]) {
+            Ok(ViaKeyboardValue::Uptime) => {
+                data[2..6].copy_from_slice(&(now.as_millis() as u32).to_be_bytes());
+            }
+            Ok(ViaKeyboardValue::LayoutOptions) => data[2..6].fill(0),
+            Ok(ViaKeyboardValue::SwitchMatrixState) => write_matrix::<M, N>(matrix, &mut data[2..]),
+            Ok(ViaKeyboardValue::FirmwareVersion) => {
+                data[2..6].copy_from_slice(&VIA_FIRMWARE_VERSION.to_be_bytes());
+            }
+            _ => return false,
+        },
+        ViaCommand::SetKeyboardValue => match ViaKeyboardValue::try_from(data[1]) {
+            // There is a single layout and nothing to identify the keyboard
+            Ok(ViaKeyboardValue::LayoutOptions | ViaKeyboardValue::DeviceIndication) => {}
+            _ => return false,
+        },
+        ViaCommand::DynamicKeymapGetKeycode => {
+            let keycode = key_index::<L, M, N>(data[1], data[2], data[3])
+                .map_or(KC_NO, |index| get_keycode(layers, index));
+            data[VIA_KEYCODE_OFFSET..VIA_KEYCODE_OFFSET + 2]
+                .copy_from_slice(&keycode.to_be_bytes());
+        }
+        ViaCommand::DynamicKeymapSetKeycode => {
+            if let Some(index) = key_index::<L, M, N>(data[1], data[2], data[3]) {
+                let keycode =
+                    u16::from_be_bytes([data[VIA_KEYCODE_OFFSET], data[VIA_KEYCODE_OFFSET + 1]]);
+                // The host is told when the key is left unchanged
+                if !set_keycode(layers, index, keycode, interfaces) {
+                    return false;
+                }
+            }
+        }
+        ViaCommand::DynamicKeymapReset => reset_keymap(layers, default_layers),
+        ViaCommand::EepromReset => {
+            reset_keymap(layers, default_layers);
+            reset_macros();
+        }
+        ViaCommand::DynamicKeymapMacroGetCount => data[1] = DYNAMIC_MACRO_COUNT,
+        ViaCommand::DynamicKeymapMacroGetBufferSize => {
+            data[1..3].copy_from_slice(&(DYNAMIC_MACRO_BUFFER_SIZE as u16).to_be_bytes());
+        }
+        ViaCommand::DynamicKeymapMacroGetBuffer => {
+            let (offset, chunk) = buffer_chunk(data);
+            DYNAMIC_MACROS.lock(|buffer| {
+                let buffer = buffer.borrow();
+                for (i, byte) in chunk.iter_mut().enumerate() {
+                    *byte = buffer.get(offset + i).copied().unwrap_or(0);
+                }
+            });
+        }
+        ViaCommand::DynamicKeymapMacroSetBuffer => {
+            let (offset, chunk) = buffer_chunk(data);
+            DYNAMIC_MACROS.lock(|buffer| {
+                let mut buffer = buffer.borrow_mut();
+                for (i, &byte) in chunk.iter().enumerate() {
+                    if let Some(slot) = buffer.get_mut(offset + i) {
+                        *slot = byte;
+                    }
+                }
+            });
+        }
+        ViaCommand::DynamicKeymapMacroReset => reset_macros(),
+        ViaCommand::DynamicKeymapGetLayerCount => data[1] = L as u8,
+        ViaCommand::DynamicKeymapGetBuffer => {
+            let (offset, chunk) = buffer_chunk(data);
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                let position = offset + i;
+                *byte = if position < L * M * N * 2 {
+                    get_keycode(layers, position / 2).to_be_bytes()[position % 2]
+                } else {
+                    0
+                };
+            }
+        }
+        ViaCommand::DynamicKeymapSetBuffer => {
+            let (offset, chunk) = buffer_chunk(data);
+            let end = (offset + chunk.len()).min(L * M * N * 2);
+            // Keys cut by the chunk keep the byte they already have
+            for index in offset / 2..end.div_ceil(2) {
+                let mut bytes = get_keycode(layers, index).to_be_bytes();
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    if let Some(&new) = (index * 2 + i)
+                        .checked_sub(offset)
+                        .and_then(|i| chunk.get(i))
+                    {
+                        *byte = new;
+                    }
+                }
+                set_keycode(layers, index, u16::from_be_bytes(bytes), interfaces);
+            }
+        }
+    }
+    true
+}
+
+/// Returns the offset and the bytes of a keymap or macro buffer request.
+fn buffer_chunk(data: &mut [u8; HID_RAW_REPORT_SIZE]) -> (usize, &mut [u8]) {
+    let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let size = (data[3] as usize).min(VIA_BUFFER_CHUNK_SIZE);
+    (offset, &mut data[4..4 + size])
+}
+
+/// Returns the index of a key in the keymap buffer, layer by layer then row by
+/// row, if it exists.
+fn key_index<const L: usize, const M: usize, const N: usize>(
+    layer: u8,
+    row: u8,
+    col: u8,
+) -> Option<usize> {
+    let (layer, row, col) = (layer as usize, row as usize, col as usize);
+    (layer < L && row < M && col < N).then_some((layer * M + row) * N + col)
+}
+
+fn get_keycode<const L: usize, const M: usize, const N: usize>(
+    layers: &Layers<L, M, N>,
+    index: usize,
+) -> u16 {
+    let (layer, row, col) = (index / (M * N), index / N % M, index % N);
+    to_keycode(layers.get_layer(layer)[(row, col)])
+}
+
+/// Sets a key of the keymap buffer from its keycode.
+///
+/// Keys set to [`FIRMWARE_KEYCODE`] are kept as is, while unknown keycodes,
+/// layers that do not exist and keys that `interfaces` does not support are
+/// ignored.
+///
+/// Returns whether the keycode was accepted.
+fn set_keycode<const L: usize, const M: usize, const N: usize>(
+    layers: &mut Layers<L, M, N>,
+    index: usize,
+    keycode: u16,
+    interfaces: KeyInterfaces,
+) -> bool {
+    if keycode == FIRMWARE_KEYCODE {
+        return true;
+    }
+    let (layer, row, col) = (index / (M * N), index / N % M, index % N);
+    match from_keycode(keycode) {
+        Some(key)
+            if (layers.get_layer_from_key(key).is_some() || !is_layer_action(key))
+                && interfaces.supports(key) =>
+        {
+            layers.set_key_from_layer(layer, row, col, key);
+            true
+        }
+        _ => {
+            warn!("VIA | Unsupported keycode {:#06x}", keycode);
+            false
+        }
+    }
+}
+
+fn reset_keymap<const L: usize, const M: usize, const N: usize>(
+    layers: &mut Layers<L, M, N>,
+    default_layers: &Layers<L, M, N>,
+) {
+    for layer in 0..L {
+        for row in 0..M {
+            for col in 0..N {
+                let key = default_layers.get_layer(layer)[(row, col)];
+                layers.set_key_from_layer(layer, row, col, key);
+            }
+        }
+    }
+}
+
+fn reset_macros() {
+    DYNAMIC_MACROS.lock(|buffer| buffer.borrow_mut().fill(0));
+}
+
+/// Writes the switch matrix state: for each row, the bitmask of the columns
+/// held down, in big-endian.
+fn write_matrix<const M: usize, const N: usize>(matrix: &[LinkedListWord; N], data: &mut [u8]) {
+    let row_size = N.div_ceil(8);
+    for (row, bytes) in data.chunks_exact_mut(row_size).take(M).enumerate() {
+        let columns = matrix
+            .iter()
+            .enumerate()
+            .filter(|(_, &rows)| rows & (1 << row) != 0)
+            .fold(0u32, |columns, (col, _)| columns | 1 << col);
+        bytes.copy_from_slice(&columns.to_be_bytes()[4 - row_size..]);
+    }
+}
+
+/// Returns the VIA keycode of a key, or [`FIRMWARE_KEYCODE`] if VIA cannot
+/// represent it.
+pub fn to_keycode(key: KeyAction) -> u16 {
+    let layer = |base: u16, layer: usize| (layer < 32).then_some(base | layer as u16);
+    let keycode = match key {
+        KeyAction::NoOp => Some(KC_NO),
+        KeyAction::Transparent => Some(KC_TRANSPARENT),
+        KeyAction::Single(action) => to_basic(action).map(u16::from),
+        KeyAction::Layer(l) => layer(QK_MOMENTARY, l),
+        KeyAction::ToggleLayer(l) => layer(QK_TOGGLE_LAYER, l),
+        KeyAction::ToLayer(l) => layer(QK_TO, l),
+        KeyAction::OneShotLayer(l) => layer(QK_ONE_SHOT_LAYER, l),
+        KeyAction::DefaultLayer(l) => layer(QK_DEF_LAYER, l),
+        KeyAction::LayerTap(LayerTapAction {
+            layer,
+            tap,
+            config: HoldTapConfig::Default,
+        }) if layer < 16 => {
+            to_basic(tap).map(|tap| QK_LAYER_TAP | (layer as u16) << 8 | tap as u16)
+        }
+        KeyAction::HoldTap(HoldTapAction {
+            hold: Action::Keyboard(modifier),
+            tap,
+            config: HoldTapConfig::Default,
+        }) => to_mods(modifier)
+            .zip(to_basic(tap))
+            .map(|(mods, tap)| QK_MOD_TAP | (mods as u16) << 8 | tap as u16),
+        KeyAction::OneShot(modifier) => to_mods(modifier).map(|mods| QK_ONE_SHOT_MOD | mods as u16),
+        KeyAction::DynamicMacro(index) => {
+            (index as u16 <= QK_MACRO_MAX - QK_MACRO).then_some(QK_MACRO | index as u16)
+        }
+        _ => None,
+    };
+    keycode.unwrap_or(FIRMWARE_KEYCODE)
+}
+
+/// Returns the key of a VIA keycode, if the firmware supports it.
+///
+/// The layers of layer keys are not checked.
+pub fn from_keycode(keycode: u16) -> Option<KeyAction> {
+    let low = keycode as u8;
+    let layer = (keycode & 0x1F) as usize;
+    let key = match keycode {
+        KC_NO => KeyAction::NoOp,
+        KC_TRANSPARENT => KeyAction::Transparent,
+        0x0002..=0x00FF => KeyAction::Single(from_basic(low)?),
+        QK_MOD_TAP..=QK_MOD_TAP_MAX => KeyAction::HoldTap(HoldTapAction {
+            hold: Action::Keyboard(from_mods((keycode >> 8) as u8 & 0x1F)?),
+            tap: from_basic(low)?,
+            config: HoldTapConfig::Default,
+        }),
+        QK_LAYER_TAP..=QK_LAYER_TAP_MAX => KeyAction::LayerTap(LayerTapAction {
+            layer: (keycode >> 8 & 0x0F) as usize,
+            tap: from_basic(low)?,
+            config: HoldTapConfig::Default,
+        }),
+        0x5200..=0x521F => KeyAction::ToLayer(layer),
+        0x5220..=0x523F => KeyAction::Layer(layer),
+        0x5240..=0x525F => KeyAction::DefaultLayer(layer),
+        0x5260..=0x527F => KeyAction::ToggleLayer(layer),
+        0x5280..=0x529F => KeyAction::OneShotLayer(layer),
+        0x52A0..=0x52BF => KeyAction::OneShot(from_mods(keycode as u8 & 0x1F)?),
+        QK_MACRO..=QK_MACRO_MAX => KeyAction::DynamicMacro((keycode - QK_MACRO) as u8),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Returns the basic keycode of an action, if it has one.
+fn to_basic(action: Action) -> Option<u8> {
+    match action {
+        Action::Keyboard(key) => {
+            let usage = u8::from(key);
+            // The usages past the keypad are used by the keycodes below
+            matches!(usage, 0x02..=0xA4 | 0xE0..=0xE7).then_some(usage)
+        }
+        Action::Consumer(key) => CONSUMER_KEYCODES
+            .iter()
+            .find(|&&(_, usage)| usage == u16::from(key))
+            .map(|&(keycode, _)| keycode),
+        Action::System(key) => SYSTEM_KEYCODES
+            .iter()
+            .find(|&&(_, system)| system == key)
+            .map(|&(keycode, _)| keycode),
+        Action::Mouse(key) => MOUSE_KEYCODES
+            .iter()
+            .find(|&&(_, mouse)| mouse == key)
+            .map(|&(keycode, _)| keycode),
+        Action::Unicode(_) | Action::UnicodeString(_) | Action::Midi(_) => None,
+    }
+}
+
+/// Returns the action of a basic keycode, if the firmware supports it.
+fn from_basic(keycode: u8) -> Option<Action> {
+    match keycode {
+        0x02..=0xA4 | 0xE0..=0xE7 => Some(Action::Keyboard(Keyboard::from(keycode))),
+        0xA5..=0xA7 => find_keycode(&SYSTEM_KEYCODES, keycode).map(Action::System),
+        0xA8..=0xBE => find_keycode(&CONSUMER_KEYCODES, keycode)
+            .map(|usage| Action::Consumer(Consumer::from(usage))),
+        0xCD..=0xDF => find_keycode(&MOUSE_KEYCODES, keycode).map(Action::Mouse),
+        _ => None,
+    }
+}
+
+fn find_keycode<T: Copy>(keycodes: &[(u8, T)], keycode: u8) -> Option<T> {
+    keycodes
+        .iter()
+        .find(|&&(code, _)| code == keycode)
+        .map(|&(_, value)| value)
+}
+
+/// Returns the 5-bit modifier mask of QMK for a modifier key: control, shift,
+/// alt and GUI, then whether it is the right-hand one.
+fn to_mods(modifier: Keyboard) -> Option<u8> {
+    let index = u8::from(modifier).checked_sub(0xE0).filter(|&i| i < 8)?;
+    Some(1 << (index & 0b11) | (index & 0b100) << 2)
+}
+
+/// Returns the modifier key of a 5-bit modifier mask, if it holds a single
+/// modifier.
+fn from_mods(mods: u8) -> Option<Keyboard> {
+    let bits = mods & 0x0F;
+    if bits.count_ones() != 1 {
+        return None;
+    }
+    let right = (mods & 0x10) >> 2;
+    Some(Keyboard::from(0xE0 + bits.trailing_zeros() as u8 + right))
+}
+
+/// Parses a part of the version of the crate.
+const fn parse_version(part: &str) -> u32 {
+    let bytes = part.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+    use super::*;
+    use crate::keyboard::{
+        action::{c, k, lt, m, mo, osm, repeat, s},
+        layers::Layer,
+        macros::dynamic_macro,
+    };
+
+    type TestLayers = Layers<2, 2, 3>;
+
+    fn layers() -> TestLayers {
+        Layers::new([
+            Layer::new([
+                [
+                    KeyAction::Single(k(Keyboard::Escape)),
+                    KeyAction::Single(k(Keyboard::A)),
+                    KeyAction::Single(c(Consumer::Mute)),
+                ],
+                [
+                    mo(1),
+                    KeyAction::Single(s(System::Sleep)),
+                    KeyAction::Single(m(Mouse::LeftClick)),
+                ],
+            ]),
+            Layer::new([
+                [
+                    KeyAction::Transparent,
+                    KeyAction::NoOp,
+                    repeat(k(Keyboard::LeftAlt), k(Keyboard::X)),
+                ],
+                [KeyAction::Transparent; 3],
+            ]),
+        ])
+    }
+
+    /// Sends a packet recorded from VIA, padded with zeros, and returns the
+    /// response.
+    fn send(layers: &mut TestLayers, packet: &[u8]) -> [u8; HID_RAW_REPORT_SIZE] {
+        let interfaces = KeyInterfaces {
+            mouse: true,
+            midi: true,
+        };
+        send_to(layers, interfaces, packet)
+    }
+
+    /// Sends a packet like [`send`] to a keyboard with only the given
+    /// interfaces.
+    fn send_to(
+        layers: &mut TestLayers,
+        interfaces: KeyInterfaces,
+        packet: &[u8],
+    ) -> [u8; HID_RAW_REPORT_SIZE] {
+        let mut request = [0; HID_RAW_REPORT_SIZE];
+        request[..packet.len()].copy_from_slice(packet);
+        let matrix = [0b01, 0b10, 0b01];
+        respond(
+            &request,
+            layers,
+            &self::layers(),
+            &matrix,
+            interfaces,
+            Instant::from_millis(0x0102_0304),
+        )
+    }
+
+    #[test]
+    fn keycodes() {
+        let keys = [
+            (0x0000, KeyAction::NoOp),
+            (0x0001, KeyAction::Transparent),
+            (0x0029, KeyAction::Single(k(Keyboard::Escape))),
+            (0x00E5, KeyAction::Single(k(Keyboard::RightShift))),
+            (0x00A6, KeyAction::Single(s(System::Sleep))),
+            (0x00A8, KeyAction::Single(c(Consumer::Mute))),
+            (0x00D1, KeyAction::Single(m(Mouse::LeftClick))),
+            (0x00DF, KeyAction::Single(m(Mouse::SpeedUp))),
+            (0x5221, mo(1)),
+            (0x5203, KeyAction::ToLayer(3)),
+            (0x5242, KeyAction::DefaultLayer(2)),
+            (0x5261, KeyAction::ToggleLayer(1)),
+            (0x5281, KeyAction::OneShotLayer(1)),
+            (0x52A2, osm(Keyboard::LeftShift)),
+            (0x52B4, osm(Keyboard::RightAlt)),
+            (0x412C, lt(1, k(Keyboard::Space))),
+            (
+                0x2204,
+                KeyAction::HoldTap(HoldTapAction {
+                    hold: k(Keyboard::LeftShift),
+                    tap: k(Keyboard::A),
+                    config: HoldTapConfig::Default,
+                }),
+            ),
+            (0x7703, KeyAction::DynamicMacro(3)),
+        ];
+        for (keycode, key) in keys {
+            assert_eq!(to_keycode(key), keycode);
+            assert_eq!(from_keycode(keycode), Some(key));
+        }
+
+        // Keys that VIA cannot represent
+        assert_eq!(
+            to_keycode(repeat(k(Keyboard::LeftAlt), k(Keyboard::X))),
+            FIRMWARE_KEYCODE
+        );
+        assert_eq!(
+            to_keycode(KeyAction::HoldTap(HoldTapAction {
+                hold: k(Keyboard::LeftShift),
+                tap: k(Keyboard::A),
+                config: HoldTapConfig::PermissiveHold,
+            })),
+            FIRMWARE_KEYCODE
+        );
+        // Several modifiers, and a modifier with a keycode
+        assert_eq!(from_keycode(0x2304), None);
+        assert_eq!(from_keycode(0x0204), None);
+    }
+
+    #[test]
+    fn protocol() {
+        let mut layers = layers();
+        assert_eq!(send(&mut layers, &[0x01])[..3], [0x01, 0x00, 0x0C]);
+        assert_eq!(send(&mut layers, &[0x11])[..2], [0x11, 0x02]);
+        assert_eq!(
+            send(&mut layers, &[0x02, 0x01])[..6],
+            [0x02, 0x01, 0x01, 0x02, 0x03, 0x04]
+        );
+        // Columns 0 and 2 of the first row, column 1 of the second one
+        assert_eq!(
+            send(&mut layers, &[0x02, 0x03])[..4],
+            [0x02, 0x03, 0b101, 0b010]
+        );
+        // Unhandled commands are sent back with 0xFF
+        assert_eq!(
+            send(&mut layers, &[0x07, 0x00, 0x01])[..3],
+            [0xFF, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn keymap() {
+        let mut layers = layers();
+        assert_eq!(
+            send(&mut layers, &[0x04, 0x00, 0x01, 0x00])[..6],
+            [0x04, 0x00, 0x01, 0x00, 0x52, 0x21]
+        );
+        assert_eq!(
+            response_keycode(&send(&mut layers, &[0x04, 0x00, 0x01, 0x00])),
+            Some(0x5221)
+        );
+        assert_eq!(response_keycode(&send(&mut layers, &[0x07])), None);
+        assert_eq!(
+            send(&mut layers, &[0x12, 0x00, 0x00, 0x06])[..10],
+            [0x12, 0x00, 0x00, 0x06, 0x00, 0x29, 0x00, 0x04, 0x00, 0xA8]
+        );
+        // The tap dance of the second layer
+        assert_eq!(
+            send(&mut layers, &[0x12, 0x00, 0x10, 0x02])[4..6],
+            [0x7E, 0x00]
+        );
+
+        send(&mut layers, &[0x05, 0x00, 0x00, 0x01, 0x00, 0x05]);
+        assert_eq!(
+            layers.get_layer(0)[(0, 1)],
+            KeyAction::Single(k(Keyboard::B))
+        );
+        // Layers that do not exist are ignored
+        send(&mut layers, &[0x05, 0x00, 0x00, 0x01, 0x52, 0x23]);
+        assert_eq!(
+            layers.get_layer(0)[(0, 1)],
+            KeyAction::Single(k(Keyboard::B))
+        );
+
+        // VIA writes back the firmware keys it read
+        send(
+            &mut layers,
+            &[0x13, 0x00, 0x0C, 0x06, 0x00, 0x01, 0x00, 0x00, 0x7E, 0x00],
+        );
+        assert_eq!(layers.get_layer(1)[(0, 1)], KeyAction::NoOp);
+        assert_eq!(
+            layers.get_layer(1)[(0, 2)],
+            repeat(k(Keyboard::LeftAlt), k(Keyboard::X))
+        );
+        // A chunk starting in the middle of a key
+        send(&mut layers, &[0x13, 0x00, 0x0F, 0x01, 0x2C]);
+        assert_eq!(
+            layers.get_layer(1)[(0, 1)],
+            KeyAction::Single(k(Keyboard::Space))
+        );
+
+        send(&mut layers, &[0x06]);
+        assert_eq!(
+            layers.get_layer(0)[(0, 1)],
+            KeyAction::Single(k(Keyboard::A))
+        );
+        assert_eq!(layers.get_layer(1)[(0, 1)], KeyAction::NoOp);
+    }
+
+    #[test]
+    fn missing_interfaces() {
+        let mut layers = layers();
+        let interfaces = KeyInterfaces {
+            mouse: false,
+            midi: true,
+        };
+        let response = send_to(
+            &mut layers,
+            interfaces,
+            &[0x05, 0x00, 0x00, 0x01, 0x00, 0xD1],
+        );
+        assert_eq!(response_keycode(&response), None);
+        assert_eq!(
+            layers.get_layer(0)[(0, 1)],
+            KeyAction::Single(k(Keyboard::A))
+        );
+        send_to(
+            &mut layers,
+            interfaces,
+            &[0x13, 0x00, 0x02, 0x02, 0x00, 0xD2],
+        );
+        assert_eq!(
+            layers.get_layer(0)[(0, 1)],
+            KeyAction::Single(k(Keyboard::A))
+        );
+        // Other keys are still set
+        let response = send_to(
+            &mut layers,
+            interfaces,
+            &[0x05, 0x00, 0x00, 0x01, 0x00, 0x05],
+        );
+        assert_eq!(response_keycode(&response), Some(0x0005));
+        assert_eq!(
+            layers.get_layer(0)[(0, 1)],
+            KeyAction::Single(k(Keyboard::B))
+        );
+    }
+
+    #[test]
+    fn keymap_edits() {
+        let request = |command| {
+            let mut request = [0; HID_RAW_REPORT_SIZE];
+            request[0] = command;
+            request
+        };
+        for command in [0x05, 0x06, 0x0A, 0x13] {
+            assert!(edits_keymap(&request(command)));
+        }
+        // Reads, macros and unknown commands leave the keymap as is
+        for command in [0x04, 0x0F, 0x12, 0x42] {
+            assert!(!edits_keymap(&request(command)));
+        }
+    }
+
+    #[test]
+    fn macros() {
+        let mut layers = layers();
+        assert_eq!(send(&mut layers, &[0x0C])[..2], [0x0C, 0x10]);
+        assert_eq!(send(&mut layers, &[0x0D])[..3], [0x0D, 0x02, 0x00]);
+
+        // "hi" then Enter, and an empty second macro
+        let data = [b'h', b'i', 0x01, 0x01, 0x28, 0x00, 0x00];
+        let mut packet = [0x0F, 0x00, 0x00, data.len() as u8].to_vec();
+        packet.extend_from_slice(&data);
+        send(&mut layers, &packet);
+        assert_eq!(send(&mut layers, &[0x0E, 0x00, 0x00, 0x07])[4..11], data);
+        DYNAMIC_MACROS.lock(|buffer| {
+            let buffer = buffer.borrow();
+            assert_eq!(dynamic_macro(&*buffer, 0), &data[..5]);
+            assert_eq!(dynamic_macro(&*buffer, 1), &[]);
+        });
+
+        send(&mut layers, &[0x10]);
+        assert_eq!(send(&mut layers, &[0x0E, 0x00, 0x00, 0x07])[4..11], [0; 7]);
+    }
+}
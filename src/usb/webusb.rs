@@ -0,0 +1,95 @@
+use defmt::warn;
+use embassy_stm32::{peripherals::USB_OTG_HS, usb::Driver};
+use embassy_usb::{
+    class::web_usb::{Config as WebUsbConfig, State, Url, WebUsb},
+    driver::{self, Endpoint, EndpointError, EndpointIn, EndpointOut},
+    msos::{self, windows_version},
+    Builder,
+};
+use static_cell::StaticCell;
+
+use crate::usb::{
+    hid::raw_hid_request, HID_RAW_REPORT_SIZE, MSOS_VENDOR_CODE, WEBUSB_INTERFACE_GUIDS,
+    WEBUSB_LANDING_URL, WEBUSB_MAX_PACKET_SIZE, WEBUSB_VENDOR_CODE,
+};
+
+/// Vendor specific interface class.
+const VENDOR_CLASS: u8 = 0xFF;
+
+/// Initializes the WebUSB descriptors and a vendor interface for browser
+/// configurators.
+///
+/// The WebUSB platform capability points browsers to [`WEBUSB_LANDING_URL`].
+/// The MS OS 2.0 descriptors make Windows bind WinUSB to the vendor interface,
+/// so browsers can claim it without any driver install.
+pub async fn init_webusb(
+    builder: &mut Builder<'static, Driver<'static, USB_OTG_HS>>,
+) -> WebUsbClass<'static, Driver<'static, USB_OTG_HS>> {
+    static WEBUSB_CONFIG: StaticCell<WebUsbConfig<'static>> = StaticCell::new();
+    let config = WEBUSB_CONFIG.init(WebUsbConfig {
+        max_packet_size: WEBUSB_MAX_PACKET_SIZE,
+        vendor_code: WEBUSB_VENDOR_CODE,
+        landing_url: Some(Url::new(WEBUSB_LANDING_URL)),
+    });
+    static WEBUSB_STATE: StaticCell<State<'static>> = StaticCell::new();
+    WebUsb::configure(builder, WEBUSB_STATE.init(State::new()), config);
+
+    builder.msos_descriptor(windows_version::WIN8_1, MSOS_VENDOR_CODE);
+    WebUsbClass::new(builder)
+}
+
+/// Runs the WebUSB task.
+///
+/// Each packet received on the vendor interface is a raw HID request, see
+/// [`raw_hid::RawHidCommand`](crate::usb::raw_hid::RawHidCommand), and its
+/// response is written back in the next packet.
+#[embassy_executor::task]
+pub async fn webusb_task(mut class: WebUsbClass<'static, Driver<'static, USB_OTG_HS>>) -> ! {
+    let mut packet = [0; WEBUSB_MAX_PACKET_SIZE as usize];
+    loop {
+        let len = match class.read_ep.read(&mut packet).await {
+            Ok(len) => len,
+            Err(EndpointError::Disabled) => {
+                class.read_ep.wait_enabled().await;
+                continue;
+            }
+            Err(e) => {
+                warn!("WEBUSB | Failed to read request: {:?}", e);
+                continue;
+            }
+        };
+
+        // Short requests are padded with zeros like the raw HID reports
+        let mut request = [0; HID_RAW_REPORT_SIZE];
+        let len = len.min(HID_RAW_REPORT_SIZE);
+        request[..len].copy_from_slice(&packet[..len]);
+        let response = raw_hid_request(request).await;
+        if let Err(e) = class.write_ep.write(&response).await {
+            warn!("WEBUSB | Failed to send response: {:?}", e);
+        }
+    }
+}
+
+/// Vendor interface with a pair of bulk endpoints.
+pub struct WebUsbClass<'d, D: driver::Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+}
+
+impl<'d, D: driver::Driver<'d>> WebUsbClass<'d, D> {
+    /// Creates the interface and its endpoints, and binds it to WinUSB.
+    pub fn new(builder: &mut Builder<'d, D>) -> Self {
+        let mut func = builder.function(VENDOR_CLASS, 0x00, 0x00);
+        func.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+        func.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+            "DeviceInterfaceGUIDs",
+            msos::PropertyData::RegMultiSz(WEBUSB_INTERFACE_GUIDS),
+        ));
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(VENDOR_CLASS, 0x00, 0x00, None);
+        let write_ep = alt.endpoint_bulk_in(None, WEBUSB_MAX_PACKET_SIZE);
+        let read_ep = alt.endpoint_bulk_out(None, WEBUSB_MAX_PACKET_SIZE);
+
+        Self { read_ep, write_ep }
+    }
+}